@@ -0,0 +1,2 @@
+pub mod pickle;
+pub mod tagged;
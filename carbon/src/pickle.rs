@@ -0,0 +1,518 @@
+//! Restricted pickle reader and writer of carbon protocols: pickled metrics and
+//! carbonlink requests and responses.
+//!
+//! Only opcodes building plain values are accepted, anything that could import or call
+//! Python objects is rejected. Values are pickled with protocol 2, as carbon does.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<Value>),
+    Tuple(Vec<Value>),
+    Dict(Vec<(Value, Value)>),
+}
+
+impl Value {
+    /// Value of a dict with string keys.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(items) => items
+                .iter()
+                .find(|(k, _)| matches!(k, Value::Str(k) if k == key))
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Numeric value, numbers may be sent as strings.
+    pub fn number(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            Value::Str(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Item {
+    Mark,
+    Value(Value),
+}
+
+/// Malformed pickle or one with an opcode that is not allowed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PickleError(pub String);
+
+impl Display for PickleError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for PickleError {}
+
+fn error(message: impl Into<String>) -> PickleError {
+    PickleError(message.into())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], PickleError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| error("Unexpected end of pickle"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, PickleError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], PickleError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn line(&mut self) -> Result<&'a str, PickleError> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| error("Unexpected end of pickle"))?;
+        let line = std::str::from_utf8(&rest[..len]).map_err(|e| error(e.to_string()))?;
+        self.pos += len + 1;
+        Ok(line)
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, PickleError> {
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| error(e.to_string()))
+    }
+}
+
+fn parse_int(s: &str) -> Result<i64, PickleError> {
+    match s {
+        // Booleans of protocol 0
+        "00" => Ok(0),
+        "01" => Ok(1),
+        _ => s
+            .trim_end_matches('L')
+            .parse()
+            .map_err(|_| error(format!("Invalid integer '{}'", s))),
+    }
+}
+
+/// Little-endian two's complement integer of `LONG1`.
+fn parse_long(bytes: &[u8]) -> Result<i64, PickleError> {
+    if bytes.len() > 8 {
+        return Err(error("Integer is too large"));
+    }
+    let fill = if bytes.last().is_some_and(|b| b & 0x80 != 0) {
+        0xff
+    } else {
+        0
+    };
+    let mut buf = [fill; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    Ok(i64::from_le_bytes(buf))
+}
+
+/// Quoted string of protocol 0 `STRING`, escapes are not expected in metric names.
+fn parse_quoted(s: &str) -> Result<String, PickleError> {
+    let unquoted = s
+        .strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+        .or_else(|| s.strip_prefix('"').and_then(|s| s.strip_suffix('"')))
+        .ok_or_else(|| error(format!("Invalid string {}", s)))?;
+    if unquoted.contains('\\') {
+        return Err(error(format!("Unsupported string escape in {}", s)));
+    }
+    Ok(unquoted.to_owned())
+}
+
+fn set_items(dict: Value, items: Vec<Value>) -> Result<Value, PickleError> {
+    let Value::Dict(mut dict) = dict else {
+        return Err(error("SETITEM to a non-dict"));
+    };
+    if !items.len().is_multiple_of(2) {
+        return Err(error("Odd number of dict items"));
+    }
+    let mut items = items.into_iter();
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        dict.retain(|(k, _)| *k != key);
+        dict.push((key, value));
+    }
+    Ok(Value::Dict(dict))
+}
+
+/// Nodes copied to and from the memo by a message. Every other opcode decodes one node at
+/// most, while a memo store or fetch copies a whole value, doubling it with every
+/// `GET GET TUPLE2 PUT` and repeating it with every `MEMOIZE`.
+const MAX_MEMO_NODES: usize = 1 << 20;
+
+/// Number of nodes of `value`, `None` when there are more than `limit`.
+fn nodes(value: &Value, limit: usize) -> Option<usize> {
+    let mut count = 0;
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        count += 1;
+        if count > limit {
+            return None;
+        }
+        match value {
+            Value::List(items) | Value::Tuple(items) => pending.extend(items),
+            Value::Dict(items) => {
+                for (key, value) in items {
+                    pending.push(key);
+                    pending.push(value);
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(count)
+}
+
+pub fn unpickle(data: &[u8]) -> Result<Value, PickleError> {
+    let mut reader = Reader { data, pos: 0 };
+    let mut stack: Vec<Item> = Vec::new();
+    let mut memo: HashMap<u32, Value> = HashMap::new();
+    let mut memo_nodes = 0;
+
+    let pop = |stack: &mut Vec<Item>| match stack.pop() {
+        Some(Item::Value(value)) => Ok(value),
+        _ => Err(error("Stack underflow")),
+    };
+    let pop_mark = |stack: &mut Vec<Item>| -> Result<Vec<Value>, PickleError> {
+        let mark = stack
+            .iter()
+            .rposition(|item| matches!(item, Item::Mark))
+            .ok_or_else(|| error("Mark not found"))?;
+        Ok(stack
+            .split_off(mark)
+            .into_iter()
+            .skip(1)
+            .filter_map(|item| match item {
+                Item::Value(value) => Some(value),
+                Item::Mark => None,
+            })
+            .collect())
+    };
+    let top = |stack: &Vec<Item>| match stack.last() {
+        Some(Item::Value(value)) => Ok(value.clone()),
+        _ => Err(error("Stack underflow")),
+    };
+
+    loop {
+        let opcode = reader.byte()?;
+        let value = match opcode {
+            // PROTO
+            0x80 => {
+                reader.byte()?;
+                continue;
+            }
+            // FRAME
+            0x95 => {
+                reader.take(8)?;
+                continue;
+            }
+            // STOP
+            b'.' => return pop(&mut stack),
+            // MARK
+            b'(' => {
+                stack.push(Item::Mark);
+                continue;
+            }
+            // EMPTY_LIST
+            b']' => Value::List(Vec::new()),
+            // EMPTY_TUPLE
+            b')' => Value::Tuple(Vec::new()),
+            // EMPTY_DICT
+            b'}' => Value::Dict(Vec::new()),
+            // DICT
+            b'd' => set_items(Value::Dict(Vec::new()), pop_mark(&mut stack)?)?,
+            // SETITEM
+            b's' => {
+                let value = pop(&mut stack)?;
+                let key = pop(&mut stack)?;
+                set_items(pop(&mut stack)?, vec![key, value])?
+            }
+            // SETITEMS
+            b'u' => {
+                let items = pop_mark(&mut stack)?;
+                set_items(pop(&mut stack)?, items)?
+            }
+            // NONE
+            b'N' => Value::None,
+            // NEWTRUE, NEWFALSE
+            0x88 => Value::Bool(true),
+            0x89 => Value::Bool(false),
+            // LIST
+            b'l' => Value::List(pop_mark(&mut stack)?),
+            // TUPLE
+            b't' => Value::Tuple(pop_mark(&mut stack)?),
+            // TUPLE1, TUPLE2, TUPLE3
+            0x85..=0x87 => {
+                let n = usize::from(opcode - 0x84);
+                let mut items = (0..n)
+                    .map(|_| pop(&mut stack))
+                    .collect::<Result<Vec<_>, _>>()?;
+                items.reverse();
+                Value::Tuple(items)
+            }
+            // APPEND
+            b'a' => {
+                let item = pop(&mut stack)?;
+                match pop(&mut stack)? {
+                    Value::List(mut list) => {
+                        list.push(item);
+                        Value::List(list)
+                    }
+                    _ => return Err(error("APPEND to a non-list")),
+                }
+            }
+            // APPENDS
+            b'e' => {
+                let items = pop_mark(&mut stack)?;
+                match pop(&mut stack)? {
+                    Value::List(mut list) => {
+                        list.extend(items);
+                        Value::List(list)
+                    }
+                    _ => return Err(error("APPENDS to a non-list")),
+                }
+            }
+            // INT
+            b'I' => Value::Int(parse_int(reader.line()?)?),
+            // LONG
+            b'L' => Value::Int(parse_int(reader.line()?)?),
+            // BININT
+            b'J' => Value::Int(i64::from(i32::from_le_bytes(reader.array()?))),
+            // BININT1
+            b'K' => Value::Int(i64::from(reader.byte()?)),
+            // BININT2
+            b'M' => Value::Int(i64::from(u16::from_le_bytes(reader.array()?))),
+            // LONG1
+            0x8a => {
+                let len = usize::from(reader.byte()?);
+                Value::Int(parse_long(reader.take(len)?)?)
+            }
+            // FLOAT
+            b'F' => {
+                let line = reader.line()?;
+                Value::Float(
+                    line.parse()
+                        .map_err(|_| error(format!("Invalid float '{}'", line)))?,
+                )
+            }
+            // BINFLOAT
+            b'G' => Value::Float(f64::from_be_bytes(reader.array()?)),
+            // STRING
+            b'S' => Value::Str(parse_quoted(reader.line()?)?),
+            // UNICODE
+            b'V' => Value::Str(reader.line()?.to_owned()),
+            // BINSTRING, BINUNICODE
+            b'T' | b'X' => {
+                let len = u32::from_le_bytes(reader.array()?) as usize;
+                Value::Str(reader.string(len)?)
+            }
+            // SHORT_BINSTRING, SHORT_BINUNICODE
+            b'U' | 0x8c => {
+                let len = usize::from(reader.byte()?);
+                Value::Str(reader.string(len)?)
+            }
+            // PUT, BINPUT, LONG_BINPUT, MEMOIZE
+            b'p' | b'q' | b'r' | 0x94 => {
+                let index = match opcode {
+                    b'p' => reader
+                        .line()?
+                        .parse()
+                        .map_err(|_| error("Invalid memo index"))?,
+                    b'q' => u32::from(reader.byte()?),
+                    b'r' => u32::from_le_bytes(reader.array()?),
+                    _ => memo.len() as u32,
+                };
+                let value = top(&stack)?;
+                memo_nodes += nodes(&value, MAX_MEMO_NODES - memo_nodes)
+                    .ok_or_else(|| error("Too many memo references"))?;
+                memo.insert(index, value);
+                continue;
+            }
+            // GET, BINGET, LONG_BINGET
+            b'g' | b'h' | b'j' => {
+                let index: u32 = match opcode {
+                    b'g' => reader
+                        .line()?
+                        .parse()
+                        .map_err(|_| error("Invalid memo index"))?,
+                    b'h' => u32::from(reader.byte()?),
+                    _ => u32::from_le_bytes(reader.array()?),
+                };
+                let value = memo
+                    .get(&index)
+                    .ok_or_else(|| error(format!("Memo index {} not found", index)))?;
+                memo_nodes += nodes(value, MAX_MEMO_NODES - memo_nodes)
+                    .ok_or_else(|| error("Too many memo references"))?;
+                value.clone()
+            }
+            _ => {
+                return Err(error(format!(
+                    "Opcode 0x{:02x} is not allowed at {}",
+                    opcode,
+                    reader.pos - 1
+                )));
+            }
+        };
+        stack.push(Item::Value(value));
+    }
+}
+
+/// Encodes a value with protocol 2.
+pub fn pickle(value: &Value) -> Vec<u8> {
+    fn write(data: &mut Vec<u8>, value: &Value) {
+        match value {
+            Value::None => data.push(b'N'),
+            Value::Bool(true) => data.push(0x88),
+            Value::Bool(false) => data.push(0x89),
+            Value::Int(i) => match i32::try_from(*i) {
+                // BININT
+                Ok(i) => {
+                    data.push(b'J');
+                    data.extend_from_slice(&i.to_le_bytes());
+                }
+                // LONG1
+                Err(_) => {
+                    data.extend_from_slice(&[0x8a, 8]);
+                    data.extend_from_slice(&i.to_le_bytes());
+                }
+            },
+            // BINFLOAT
+            Value::Float(f) => {
+                data.push(b'G');
+                data.extend_from_slice(&f.to_be_bytes());
+            }
+            // BINUNICODE
+            Value::Str(s) => {
+                data.push(b'X');
+                data.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                data.extend_from_slice(s.as_bytes());
+            }
+            // EMPTY_LIST, MARK, APPENDS
+            Value::List(items) => {
+                data.extend_from_slice(b"](");
+                items.iter().for_each(|item| write(data, item));
+                data.push(b'e');
+            }
+            // MARK, TUPLE
+            Value::Tuple(items) => {
+                data.push(b'(');
+                items.iter().for_each(|item| write(data, item));
+                data.push(b't');
+            }
+            // EMPTY_DICT, MARK, SETITEMS
+            Value::Dict(items) => {
+                data.extend_from_slice(b"}(");
+                for (key, value) in items {
+                    write(data, key);
+                    write(data, value);
+                }
+                data.push(b'u');
+            }
+        }
+    }
+
+    // PROTO 2
+    let mut data = vec![0x80, 2];
+    write(&mut data, value);
+    // STOP
+    data.push(b'.');
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpickle_dicts() {
+        // pickle.dumps({'type': 'cache-query', 'metric': 'a.b', 'ok': True, 'n': None}, 2)
+        let data = b"\x80\x02}q\x00(X\x04\x00\x00\x00typeq\x01X\x0b\x00\x00\x00cache-queryq\x02X\x06\x00\x00\x00metricq\x03X\x03\x00\x00\x00a.bq\x04X\x02\x00\x00\x00okq\x05\x88X\x01\x00\x00\x00nq\x06Nu.";
+        let value = unpickle(data).unwrap();
+        assert_eq!(
+            value.get("type"),
+            Some(&Value::Str("cache-query".to_owned()))
+        );
+        assert_eq!(value.get("metric"), Some(&Value::Str("a.b".to_owned())));
+        assert_eq!(value.get("ok"), Some(&Value::Bool(true)));
+        assert_eq!(value.get("n"), Some(&Value::None));
+        assert_eq!(value.get("missing"), None);
+        assert_eq!(unpickle(&pickle(&value)).unwrap(), value);
+
+        // Protocol 0: {'metric': 'a.b'}
+        let value = unpickle(b"(dp0\nVmetric\np1\nVa.b\np2\ns.").unwrap();
+        assert_eq!(value.get("metric"), Some(&Value::Str("a.b".to_owned())));
+    }
+
+    #[test]
+    fn pickle_values() {
+        let value = Value::List(vec![
+            Value::Tuple(vec![Value::Int(-1), Value::Int(i64::MAX)]),
+            Value::Float(2.5),
+            Value::Bool(false),
+            Value::Str("a.b".to_owned()),
+        ]);
+        assert_eq!(unpickle(&pickle(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn unpickle_rejects_globals() {
+        // pickle.dumps([('a', (1, os.system))], 2)
+        let data =
+            b"\x80\x02]q\x00X\x01\x00\x00\x00aq\x01K\x01cposix\nsystem\nq\x02\x86q\x03\x86q\x04a.";
+        assert!(unpickle(data).unwrap_err().0.starts_with("Opcode 0x63"));
+    }
+
+    #[test]
+    fn unpickle_malformed() {
+        assert!(unpickle(b"").is_err());
+        assert!(unpickle(b"\x80\x02]q\x00(X\x03\x00").is_err());
+        assert!(unpickle(b"a.").is_err());
+        assert!(unpickle(b"h\x05.").is_err());
+        assert!(unpickle(b"K\x01a.").is_err());
+    }
+
+    #[test]
+    fn unpickle_memo_bomb() {
+        // Every repeat doubles the tuple in memo 0
+        let mut data = b"\x80\x02K\x01q\x00".to_vec();
+        for _ in 0..64 {
+            data.extend_from_slice(b"h\x00h\x00\x86q\x00");
+        }
+        data.push(b'.');
+        assert_eq!(unpickle(&data).unwrap_err().0, "Too many memo references");
+
+        // Every MEMOIZE stores another copy of the same list
+        let mut data = b"\x80\x04](".to_vec();
+        data.extend_from_slice(&b"K\x01".repeat(1000));
+        data.push(b'e');
+        data.extend_from_slice(&[0x94; 2000]);
+        data.push(b'.');
+        assert_eq!(unpickle(&data).unwrap_err().0, "Too many memo references");
+    }
+}
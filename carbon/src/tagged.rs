@@ -0,0 +1,132 @@
+//! Tagged series names and their files, as `TaggedSeries` of carbon.
+//!
+//! Shared by the writer of a database and its readers, so that both agree on which names
+//! are valid and where their files are.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Directory of tagged series, relative to the database root.
+pub const TAGGED_DIR: &str = "_tagged";
+
+/// Names of all tagged series, one per line, inside `TAGGED_DIR`.
+pub const TAG_INDEX_FILE: &str = "tags.idx";
+
+/// Graphite tagged series name like `disk.used;host=a;dc=eu`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaggedName {
+    pub name: String,
+    /// Tags sorted by name, without `name`.
+    pub tags: BTreeMap<String, String>,
+}
+
+/// `/` would split the file name of a series into directories.
+fn validate_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && !tag.contains([';', '!', '^', '=', '/'])
+        && !tag.contains(char::is_whitespace)
+}
+
+fn validate_value(value: &str) -> bool {
+    !value.is_empty() && !value.contains([';', '~', '/']) && !value.contains(char::is_whitespace)
+}
+
+impl FromStr for TaggedName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid tagged series '{}'", s);
+        let mut parts = s.split(';');
+        let name = parts.next().unwrap_or_default();
+        if name.is_empty() || name.contains('/') {
+            return Err(invalid());
+        }
+
+        let mut tags = BTreeMap::new();
+        for part in parts {
+            match part.split_once('=') {
+                Some((tag, value)) if validate_tag(tag) && validate_value(value) => {
+                    tags.insert(tag.to_owned(), value.to_owned());
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        // `name` tag is the series name itself
+        tags.remove("name");
+
+        Ok(TaggedName {
+            name: name.to_owned(),
+            tags,
+        })
+    }
+}
+
+/// Normalized form with tags sorted by name.
+impl Display for TaggedName {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (tag, value) in &self.tags {
+            write!(f, ";{}={}", tag, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl TaggedName {
+    /// Whisper file of the series relative to the database root.
+    pub fn path(&self) -> PathBuf {
+        series_path(&self.to_string())
+    }
+}
+
+/// Whisper file of a normalized series name relative to the database root, laid out as
+/// carbon does: `_tagged/<first 3 hex digits of sha256>/<next 3>/<name with dots escaped>.wsp`.
+pub fn series_path(normalized: &str) -> PathBuf {
+    let hash = format!("{:x}", Sha256::digest(normalized.as_bytes()));
+    Path::new(TAGGED_DIR)
+        .join(&hash[0..3])
+        .join(&hash[3..6])
+        .join(format!("{}.wsp", normalized.replace('.', "_DOT_")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tagged_name_parse() {
+        let name: TaggedName = "disk.used;host=a;dc=eu".parse().unwrap();
+        assert_eq!(name.name, "disk.used");
+        assert_eq!(name.to_string(), "disk.used;dc=eu;host=a");
+        assert_eq!(
+            name.path(),
+            PathBuf::from("_tagged/1da/753/disk_DOT_used;dc=eu;host=a.wsp")
+        );
+
+        let name: TaggedName = "disk.used;name=ignored;dc=eu".parse().unwrap();
+        assert_eq!(name.to_string(), "disk.used;dc=eu");
+
+        assert!("disk.used;host".parse::<TaggedName>().is_err());
+        assert!("disk.used;host=".parse::<TaggedName>().is_err());
+        assert!("disk.used;ho!st=a".parse::<TaggedName>().is_err());
+        assert!("disk.used;ho st=a".parse::<TaggedName>().is_err());
+        assert!("disk.used;host=a~b".parse::<TaggedName>().is_err());
+        assert!("disk.used;host=a/b".parse::<TaggedName>().is_err());
+        assert!("disk.used;a/b=host".parse::<TaggedName>().is_err());
+        assert!("disk/used;host=a".parse::<TaggedName>().is_err());
+        assert!(";host=a".parse::<TaggedName>().is_err());
+    }
+
+    #[test]
+    fn tagged_name_path() {
+        let name: TaggedName = "some.metric;tag1=value2;tag2=value.2".parse().unwrap();
+        // The example of carbon's `TaggedSeries.encode`
+        assert_eq!(
+            name.path(),
+            PathBuf::from("_tagged/eff/aae/some_DOT_metric;tag1=value2;tag2=value_DOT_2.wsp")
+        );
+    }
+}
@@ -3,6 +3,7 @@ use actix_web::web::{ServiceConfig, resource};
 use crate::context::Context;
 use crate::find::*;
 use crate::render::*;
+use crate::tags::*;
 
 pub fn app_config(ctx: Context) -> impl Fn(&mut ServiceConfig) {
     move |config: &mut ServiceConfig| {
@@ -10,6 +11,11 @@ pub fn app_config(ctx: Context) -> impl Fn(&mut ServiceConfig) {
             .app_data(ctx.clone())
             .service(resource("/render").to(render_handler))
             .service(resource("/metrics/find").to(find_handler))
-            .service(resource("/metrics").to(find_handler));
+            .service(resource("/metrics").to(find_handler))
+            .service(resource("/tags").to(tags_handler))
+            .service(resource("/tags/autoComplete/tags").to(auto_complete_tags_handler))
+            .service(resource("/tags/autoComplete/values").to(auto_complete_values_handler))
+            .service(resource("/tags/findSeries").to(find_series_handler))
+            .service(resource("/tags/{tag}").to(tag_handler));
     }
 }
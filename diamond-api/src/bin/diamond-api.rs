@@ -4,11 +4,14 @@ use clap::Parser;
 use diamond_api::application::app_config;
 use diamond_api::context::Context;
 use diamond_api::opts::Args;
+use diamond_api::storage::Storage;
+use diamond_api::storage::carbonlink::{Carbonlink, CarbonlinkStorage};
 use diamond_api::storage::whisper_fs::WhisperFileSystemStorage;
 use std::fs::create_dir;
 use std::io;
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[actix_web::main]
 async fn run(args: Args) -> io::Result<()> {
@@ -36,11 +39,21 @@ async fn run(args: Args) -> io::Result<()> {
 
     let listen = format!("127.0.0.1:{}", &args.port);
 
-    let ctx = Context {
-        storage: Arc::new(WhisperFileSystemStorage(args.path.clone())),
-        args,
+    let storage = WhisperFileSystemStorage(args.path.clone());
+    let storage: Arc<dyn Storage + Send + Sync> = if args.carbonlink.is_empty() {
+        Arc::new(storage)
+    } else {
+        Arc::new(CarbonlinkStorage {
+            storage,
+            carbonlink: Carbonlink {
+                hosts: args.carbonlink.clone(),
+                timeout: Duration::from_millis(args.carbonlink_timeout),
+            },
+        })
     };
 
+    let ctx = Context { storage, args };
+
     HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
@@ -56,6 +69,8 @@ async fn run(args: Args) -> io::Result<()> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
+    whisper::header_cache::set_capacity(args.header_cache);
     if let Err(err) = run(args) {
         eprintln!("{}", err);
         exit(1);
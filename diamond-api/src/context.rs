@@ -1,5 +1,7 @@
+use crate::error::ResponseError;
 use crate::opts::Args;
 use crate::storage::Storage;
+use actix_web::web;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -7,3 +9,16 @@ pub struct Context {
     pub args: Args,
     pub storage: Arc<dyn Storage + Send + Sync>,
 }
+
+impl Context {
+    /// Runs `f` with the storage on the blocking thread pool, as storage reads files
+    /// and may wait for their locks.
+    pub async fn with_storage<F, T>(&self, f: F) -> Result<T, actix_web::Error>
+    where
+        F: FnOnce(&(dyn Storage + Send + Sync)) -> Result<T, ResponseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let storage = self.storage.clone();
+        Ok(web::block(move || f(storage.as_ref())).await??)
+    }
+}
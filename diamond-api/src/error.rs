@@ -66,6 +66,7 @@ pub enum ResponseError {
     SystemTime(Duration),
     NotFound,
     Path,
+    Function(String),
     Kind(String),
 }
 
@@ -81,6 +82,7 @@ impl fmt::Display for ResponseError {
                 "Second time provided was later than self in duration {:?}",
                 d
             ),
+            ResponseError::Function(s) => write!(f, "{}", s),
             ResponseError::Kind(s) => write!(f, "{}", s),
         }
     }
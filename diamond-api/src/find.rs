@@ -5,11 +5,14 @@ use futures::future::{FutureExt, LocalBoxFuture};
 use serde::*;
 use std::convert::From;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use whisper::interval::Interval;
 
 use crate::context::Context;
+use crate::error::ResponseError;
 use crate::parse::de_time_parse;
-use crate::render_target::PathExpression;
-use crate::storage::MetricResponseLeaf;
+use crate::render_target::{Expression, PathExpression};
+use crate::storage::{MetricName, MetricResponseLeaf};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct MetricResponse {
@@ -89,20 +92,68 @@ impl FromRequest for FindQuery {
     }
 }
 
+/// Path expression of a find query, templates are resolved to the path they produce.
+fn find_path_expression(query: &str) -> Result<PathExpression, String> {
+    let expression = match Expression::from_str(query)? {
+        Expression::Template(template) => template.resolve()?,
+        expression => expression,
+    };
+
+    match expression {
+        Expression::Path(path_expression) => Ok(path_expression),
+        _ => Err(format!("Find query must be a path expression: {}", query)),
+    }
+}
+
+/// The `*` entry graphite-web offers when a query matches several nodes, in place of
+/// the last node of the query.
+fn wildcard_leaf(query: &str, metrics: &[MetricResponseLeaf]) -> Option<MetricResponseLeaf> {
+    if metrics.len() < 2 {
+        return None;
+    }
+    let mut parent: MetricName = query.parse().unwrap_or_default();
+    parent.0.pop();
+    Some(MetricResponseLeaf {
+        name: parent.join("*"),
+        is_leaf: metrics.iter().all(|m| m.is_leaf),
+    })
+}
+
 pub async fn find_handler(ctx: Data<Context>, query: FindQuery) -> Result<HttpResponse> {
-    let path_expression =
-        PathExpression::from_str(&query.query).map_err(ErrorInternalServerError)?;
-
-    Ok(ctx.storage.find(&path_expression).map(|metrics| {
-        if query.format == FindFormat::TreeJson {
-            let metrics_json: Vec<JsonTreeLeaf> =
-                metrics.into_iter().map(JsonTreeLeaf::from).collect();
-            HttpResponse::Ok().json(metrics_json)
-        } else {
-            let metrics_completer = MetricResponse { metrics };
-            HttpResponse::Ok().json(metrics_completer)
-        }
-    })?)
+    let path_expression = find_path_expression(&query.query).map_err(ErrorInternalServerError)?;
+
+    // Without `from` and `until` stale metrics are listed too
+    let interval = if query.from == 0 && query.until == u32::MAX {
+        None
+    } else {
+        Some(Interval::new(query.from, query.until).map_err(ResponseError::Kind)?)
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time travel beyond Unix epoch is forbidden by Temporal Police.")
+        .as_secs();
+
+    ctx.with_storage(move |storage| storage.find(&path_expression, interval, now))
+        .await
+        .map(|mut metrics| {
+            let wildcard = if query.wildcards != 0 {
+                wildcard_leaf(&query.query, &metrics)
+            } else {
+                None
+            };
+            if query.format == FindFormat::TreeJson {
+                let metrics_json: Vec<JsonTreeLeaf> = wildcard
+                    .into_iter()
+                    .chain(metrics)
+                    .map(JsonTreeLeaf::from)
+                    .collect();
+                HttpResponse::Ok().json(metrics_json)
+            } else {
+                metrics.extend(wildcard);
+                let metrics_completer = MetricResponse { metrics };
+                HttpResponse::Ok().json(metrics_completer)
+            }
+        })
 }
 
 #[cfg(test)]
@@ -189,6 +240,44 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn find_query_template() {
+        assert_eq!(
+            find_path_expression(r#"template(hosts.$host.*,host="web01")"#),
+            PathExpression::from_str("hosts.web01.*")
+        );
+        assert_eq!(
+            find_path_expression("hosts.web01.*"),
+            PathExpression::from_str("hosts.web01.*")
+        );
+        assert!(find_path_expression("sumSeries(hosts.*.cpu)").is_err());
+        assert!(find_path_expression("template(hosts.$host.*)").is_err());
+    }
+
+    #[test]
+    fn wildcard() {
+        let leaf = |name: &str, is_leaf| MetricResponseLeaf {
+            name: name.parse().unwrap(),
+            is_leaf,
+        };
+
+        assert_eq!(wildcard_leaf("a.*", &[]), None);
+        assert_eq!(wildcard_leaf("a.*", &[leaf("a.b", true)]), None);
+        assert_eq!(
+            wildcard_leaf("a.*", &[leaf("a.b", true), leaf("a.c", true)]),
+            Some(leaf("a.*", true))
+        );
+        assert_eq!(
+            wildcard_leaf("*", &[leaf("b", true), leaf("c", false)]),
+            Some(leaf("*", false))
+        );
+        // From the query, not from the first match
+        assert_eq!(
+            wildcard_leaf("*.b", &[leaf("a.b", true), leaf("c.b", true)]),
+            Some(leaf("*.*", true))
+        );
+    }
+
     #[test]
     fn metric_response_convertion() {
         let mleaf: JsonTreeLeaf = MetricResponseLeaf {
@@ -0,0 +1,74 @@
+use super::Series;
+
+pub fn alias(series: &[Series], new_name: &str) -> Vec<Series> {
+    series.iter().map(|s| s.renamed(new_name)).collect()
+}
+
+/// Innermost metric path of a series name, e.g. `a.b.c` for `scale(a.b.c,2)`.
+fn first_path(name: &str) -> &str {
+    let start = name.rfind('(').map_or(0, |index| index + 1);
+    let tail = &name[start..];
+    let end = tail.find([',', ')']).unwrap_or(tail.len());
+    &tail[..end]
+}
+
+pub fn alias_by_node(series: &[Series], nodes: &[i64]) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| {
+            let parts: Vec<&str> = first_path(&s.name).split('.').collect();
+            let name = nodes
+                .iter()
+                .filter_map(|node| {
+                    let index = if *node < 0 {
+                        parts.len().checked_sub(node.unsigned_abs() as usize)?
+                    } else {
+                        *node as usize
+                    };
+                    parts.get(index).cloned()
+                })
+                .collect::<Vec<_>>()
+                .join(".");
+            s.renamed(name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_path() {
+        assert_eq!(first_path("a.b.c"), "a.b.c");
+        assert_eq!(first_path("scale(a.b.c,2)"), "a.b.c");
+        assert_eq!(first_path("derivative(scale(a.b.c,2))"), "a.b.c");
+        assert_eq!(first_path("integral(a.b.c)"), "a.b.c");
+    }
+
+    #[test]
+    fn test_alias_by_node() {
+        let input = vec![
+            Series::new("servers.web01.cpu", vec![]),
+            Series::new("scale(servers.web02.cpu,10)", vec![]),
+        ];
+
+        let names: Vec<String> = alias_by_node(&input, &[1])
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["web01", "web02"]);
+
+        let names: Vec<String> = alias_by_node(&input, &[1, -1])
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["web01.cpu", "web02.cpu"]);
+    }
+
+    #[test]
+    fn test_alias() {
+        let input = vec![Series::new("a.b", vec![])];
+        assert_eq!(alias(&input, "x"), vec![Series::new("x", vec![])]);
+    }
+}
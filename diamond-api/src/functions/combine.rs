@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+
+use super::Series;
+use crate::error::ResponseError;
+use crate::storage::RenderPoint;
+
+/// Merges series by timestamp, `f` receives the known values of every instant in series order.
+fn combine(name: &str, series: &[Series], f: impl Fn(&[f64]) -> f64) -> Vec<Series> {
+    if series.is_empty() {
+        return Vec::new();
+    }
+
+    let mut buckets: BTreeMap<u32, Vec<f64>> = BTreeMap::new();
+    for s in series {
+        for RenderPoint(value, time) in &s.points {
+            let bucket = buckets.entry(*time).or_default();
+            if let Some(value) = value {
+                bucket.push(*value);
+            }
+        }
+    }
+
+    let points = buckets
+        .into_iter()
+        .map(|(time, values)| {
+            let value = if values.is_empty() {
+                None
+            } else {
+                Some(f(&values))
+            };
+            RenderPoint(value, time)
+        })
+        .collect();
+
+    vec![Series::new(name, points)]
+}
+
+pub fn sum_series(name: &str, series: &[Series]) -> Vec<Series> {
+    combine(name, series, |values| values.iter().sum())
+}
+
+pub fn average_series(name: &str, series: &[Series]) -> Vec<Series> {
+    combine(name, series, |values| {
+        values.iter().sum::<f64>() / values.len() as f64
+    })
+}
+
+pub fn max_series(name: &str, series: &[Series]) -> Vec<Series> {
+    combine(name, series, |values| {
+        values.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
+    })
+}
+
+pub fn min_series(name: &str, series: &[Series]) -> Vec<Series> {
+    combine(name, series, |values| {
+        values.iter().cloned().fold(f64::INFINITY, f64::min)
+    })
+}
+
+pub fn diff_series(name: &str, series: &[Series]) -> Vec<Series> {
+    combine(name, series, |values| {
+        values[0] - values[1..].iter().sum::<f64>()
+    })
+}
+
+/// Applies `f` to every point of `series` and the point of `other` with the same timestamp.
+fn zip_with(
+    name: String,
+    series: &Series,
+    other: &Series,
+    f: impl Fn(f64, f64) -> Option<f64>,
+) -> Series {
+    let other_values: BTreeMap<u32, Option<f64>> = other
+        .points
+        .iter()
+        .map(|RenderPoint(value, time)| (*time, *value))
+        .collect();
+
+    let points = series
+        .points
+        .iter()
+        .map(|RenderPoint(value, time)| {
+            let other_value = other_values.get(time).cloned().flatten();
+            let result = match (value, other_value) {
+                (Some(a), Some(b)) => f(*a, b),
+                _ => None,
+            };
+            RenderPoint(result, *time)
+        })
+        .collect();
+
+    Series {
+        name,
+        points,
+        consolidation: series.consolidation,
+    }
+}
+
+fn safe_divide(a: f64, b: f64) -> Option<f64> {
+    if b == 0.0 { None } else { Some(a / b) }
+}
+
+pub fn divide_series(
+    dividends: &[Series],
+    divisors: &[Series],
+) -> Result<Vec<Series>, ResponseError> {
+    let divisor = match divisors {
+        [divisor] => divisor,
+        _ => {
+            return Err(ResponseError::Function(format!(
+                "divideSeries: divisor must be a single series, got {}",
+                divisors.len()
+            )));
+        }
+    };
+
+    Ok(dividends
+        .iter()
+        .map(|dividend| {
+            let name = format!("divideSeries({},{})", dividend.name, divisor.name);
+            zip_with(name, dividend, divisor, safe_divide)
+        })
+        .collect())
+}
+
+fn percent(a: f64, b: f64) -> Option<f64> {
+    safe_divide(a, b).map(|v| v * 100.0)
+}
+
+pub fn as_percent(series: &[Series]) -> Vec<Series> {
+    let total_name = format!(
+        "sumSeries({})",
+        series
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    match sum_series(&total_name, series).pop() {
+        Some(total) => series
+            .iter()
+            .map(|s| {
+                let name = format!("asPercent({},{})", s.name, total.name);
+                zip_with(name, s, &total, percent)
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+pub fn as_percent_of_value(series: &[Series], total: f64) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| {
+            let name = format!("asPercent({},{})", s.name, total);
+            s.with_values(name, s.values().map(|v| v.and_then(|v| percent(v, total))))
+        })
+        .collect()
+}
+
+pub fn as_percent_of_series(
+    series: &[Series],
+    totals: &[Series],
+) -> Result<Vec<Series>, ResponseError> {
+    let total_for = |index: usize| match totals {
+        [total] => Ok(total),
+        _ if totals.len() == series.len() => Ok(&totals[index]),
+        _ => Err(ResponseError::Function(format!(
+            "asPercent: total must be a single series or match the series count, got {}",
+            totals.len()
+        ))),
+    };
+
+    series
+        .iter()
+        .enumerate()
+        .map(|(index, s)| {
+            let total = total_for(index)?;
+            let name = format!("asPercent({},{})", s.name, total.name);
+            Ok(zip_with(name, s, total, percent))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(name: &str, values: &[Option<f64>]) -> Series {
+        Series::new(
+            name,
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, v)| RenderPoint(*v, 60 * i as u32))
+                .collect(),
+        )
+    }
+
+    fn values(series: &[Series]) -> Vec<Vec<Option<f64>>> {
+        series.iter().map(|s| s.values().collect()).collect()
+    }
+
+    #[test]
+    fn test_combine() {
+        let input = vec![
+            series("a", &[Some(1.0), None, Some(3.0), None]),
+            series("b", &[Some(4.0), Some(2.0), Some(1.0), None]),
+        ];
+
+        assert_eq!(
+            values(&sum_series("s", &input)),
+            vec![vec![Some(5.0), Some(2.0), Some(4.0), None]]
+        );
+        assert_eq!(
+            values(&average_series("s", &input)),
+            vec![vec![Some(2.5), Some(2.0), Some(2.0), None]]
+        );
+        assert_eq!(
+            values(&max_series("s", &input)),
+            vec![vec![Some(4.0), Some(2.0), Some(3.0), None]]
+        );
+        assert_eq!(
+            values(&min_series("s", &input)),
+            vec![vec![Some(1.0), Some(2.0), Some(1.0), None]]
+        );
+        assert_eq!(
+            values(&diff_series("s", &input)),
+            vec![vec![Some(-3.0), Some(2.0), Some(2.0), None]]
+        );
+        assert_eq!(sum_series("s", &[]), vec![]);
+    }
+
+    #[test]
+    fn test_combine_unaligned() {
+        let a = Series::new(
+            "a",
+            vec![RenderPoint(Some(1.0), 0), RenderPoint(Some(2.0), 60)],
+        );
+        let b = Series::new(
+            "b",
+            vec![RenderPoint(Some(5.0), 60), RenderPoint(Some(7.0), 120)],
+        );
+        assert_eq!(
+            sum_series("s", &[a, b])[0].points,
+            vec![
+                RenderPoint(Some(1.0), 0),
+                RenderPoint(Some(7.0), 60),
+                RenderPoint(Some(7.0), 120),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_divide_series() {
+        let a = series("a", &[Some(4.0), Some(2.0), None]);
+        let b = series("b", &[Some(2.0), Some(0.0), Some(1.0)]);
+
+        let result = divide_series(std::slice::from_ref(&a), std::slice::from_ref(&b)).unwrap();
+        assert_eq!(result[0].name, "divideSeries(a,b)");
+        assert_eq!(values(&result), vec![vec![Some(2.0), None, None]]);
+
+        assert!(divide_series(std::slice::from_ref(&a), &[a.clone(), b]).is_err());
+    }
+
+    #[test]
+    fn test_as_percent() {
+        let input = vec![
+            series("a", &[Some(1.0), Some(0.0)]),
+            series("b", &[Some(3.0), Some(0.0)]),
+        ];
+
+        let result = as_percent(&input);
+        assert_eq!(result[0].name, "asPercent(a,sumSeries(a,b))");
+        assert_eq!(
+            values(&result),
+            vec![vec![Some(25.0), None], vec![Some(75.0), None]]
+        );
+
+        let result = as_percent_of_value(&input, 4.0);
+        assert_eq!(result[1].name, "asPercent(b,4)");
+        assert_eq!(
+            values(&result),
+            vec![vec![Some(25.0), Some(0.0)], vec![Some(75.0), Some(0.0)]]
+        );
+
+        let total = series("t", &[Some(10.0), Some(10.0)]);
+        let result = as_percent_of_series(&input, &[total]).unwrap();
+        assert_eq!(result[0].name, "asPercent(a,t)");
+        assert_eq!(
+            values(&result),
+            vec![vec![Some(10.0), Some(0.0)], vec![Some(30.0), Some(0.0)]]
+        );
+    }
+}
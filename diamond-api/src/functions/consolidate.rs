@@ -0,0 +1,105 @@
+use whisper::aggregation::AggregationMethod;
+
+use super::Series;
+use crate::storage::RenderPoint;
+
+pub fn consolidate_by(series: &[Series], consolidation: AggregationMethod) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| Series {
+            consolidation,
+            ..s.renamed(format!("consolidateBy({},\"{}\")", s.name, consolidation))
+        })
+        .collect()
+}
+
+/// Merges adjacent points with the series consolidation method until at most
+/// `max_data_points` are left. Each merged point keeps the time of its first point.
+pub fn consolidate(series: Series, max_data_points: usize) -> Series {
+    let len = series.points.len();
+    if max_data_points == 0 || len <= max_data_points {
+        return series;
+    }
+
+    let values_per_point = len.div_ceil(max_data_points);
+    let points = series
+        .points
+        .chunks(values_per_point)
+        .map(|chunk| {
+            let values: Vec<Option<f64>> = chunk.iter().map(|RenderPoint(v, _)| *v).collect();
+            let value = if values.iter().all(Option::is_none) {
+                None
+            } else {
+                series.consolidation.aggregate(&values).ok()
+            };
+            RenderPoint(value, chunk[0].1)
+        })
+        .collect();
+
+    Series { points, ..series }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series() -> Series {
+        Series::new(
+            "a",
+            vec![
+                RenderPoint(Some(1.0), 0),
+                RenderPoint(Some(5.0), 10),
+                RenderPoint(None, 20),
+                RenderPoint(None, 30),
+                RenderPoint(Some(2.0), 40),
+                RenderPoint(None, 50),
+                RenderPoint(Some(4.0), 60),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_consolidate_average() {
+        assert_eq!(
+            consolidate(series(), 4).points,
+            vec![
+                RenderPoint(Some(3.0), 0),
+                RenderPoint(None, 20),
+                RenderPoint(Some(2.0), 40),
+                RenderPoint(Some(4.0), 60),
+            ]
+        );
+        assert_eq!(
+            consolidate(series(), 3).points,
+            vec![
+                RenderPoint(Some(3.0), 0),
+                RenderPoint(Some(2.0), 30),
+                RenderPoint(Some(4.0), 60),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_consolidate_noop() {
+        assert_eq!(consolidate(series(), 7), series());
+        assert_eq!(consolidate(series(), 100), series());
+        assert_eq!(consolidate(series(), 0), series());
+    }
+
+    #[test]
+    fn test_consolidate_by() {
+        let result = consolidate_by(&[series()], AggregationMethod::Sum);
+        assert_eq!(result[0].name, "consolidateBy(a,\"sum\")");
+        assert_eq!(result[0].consolidation, AggregationMethod::Sum);
+        assert_eq!(
+            consolidate(result[0].clone(), 2).points,
+            vec![RenderPoint(Some(6.0), 0), RenderPoint(Some(6.0), 40)]
+        );
+
+        let result = consolidate_by(&[series()], AggregationMethod::Max);
+        assert_eq!(
+            consolidate(result[0].clone(), 2).points,
+            vec![RenderPoint(Some(5.0), 0), RenderPoint(Some(4.0), 40)]
+        );
+    }
+}
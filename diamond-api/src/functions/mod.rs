@@ -0,0 +1,470 @@
+use whisper::aggregation::AggregationMethod;
+use whisper::interval::Interval;
+
+use crate::error::ResponseError;
+use crate::parse::duration_parse;
+use crate::render_target::{Arg, Call, Expression, LiteralValue, PathExpression};
+use crate::storage::{RenderPoint, Storage};
+use crate::tags::index::{TagExpression, TagIndex};
+
+mod alias;
+mod combine;
+mod consolidate;
+mod transform;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub name: String,
+    pub points: Vec<RenderPoint>,
+    /// Applied when points are merged to fit `maxDataPoints`.
+    pub consolidation: AggregationMethod,
+}
+
+impl Series {
+    pub fn new(name: impl Into<String>, points: Vec<RenderPoint>) -> Self {
+        Self {
+            name: name.into(),
+            points,
+            consolidation: AggregationMethod::Average,
+        }
+    }
+
+    pub fn renamed(&self, name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..self.clone()
+        }
+    }
+
+    /// Distance between two consecutive points, falls back to one second for short series.
+    pub fn step(&self) -> u32 {
+        match self.points.as_slice() {
+            [RenderPoint(_, first), RenderPoint(_, second), ..] if second > first => second - first,
+            _ => 1,
+        }
+    }
+
+    fn values(&self) -> impl Iterator<Item = Option<f64>> + '_ {
+        self.points.iter().map(|RenderPoint(value, _)| *value)
+    }
+
+    fn with_values(&self, name: String, values: impl IntoIterator<Item = Option<f64>>) -> Self {
+        let points = self
+            .points
+            .iter()
+            .zip(values)
+            .map(|(RenderPoint(_, time), value)| RenderPoint(value, *time))
+            .collect();
+        Self {
+            name,
+            points,
+            consolidation: self.consolidation,
+        }
+    }
+
+    fn map(&self, name: String, f: impl Fn(f64) -> f64) -> Self {
+        self.with_values(name, self.values().map(|value| value.map(&f)))
+    }
+}
+
+pub use consolidate::consolidate;
+
+/// Tagged series matching all tag expressions, each named by its normalized tagged name.
+fn series_by_tag(args: &CallArgs) -> Result<Vec<Series>, ResponseError> {
+    let expressions = args
+        .call
+        .args
+        .iter()
+        .map(|arg| match arg {
+            Arg::Literal(LiteralValue::String(s)) => s.parse().map_err(|e| args.error(e)),
+            _ => Err(args.error(format!("{} must be a tag expression string", arg))),
+        })
+        .collect::<Result<Vec<TagExpression>, ResponseError>>()?;
+
+    let ctx = args.ctx;
+    let index = TagIndex::new(ctx.storage.tagged_series()?);
+    let mut series = Vec::new();
+    for tagged in index.find_series(&expressions).map_err(|e| args.error(e))? {
+        match ctx
+            .storage
+            .query_tagged(&tagged.name, ctx.interval, ctx.now)
+        {
+            Ok(response) => series.push(Series::new(tagged.name.clone(), response.data)),
+            // Listed in the index but not written yet
+            Err(ResponseError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(series)
+}
+
+pub struct EvalContext<'a> {
+    pub storage: &'a dyn Storage,
+    pub interval: Interval,
+    pub now: u64,
+}
+
+pub fn evaluate(expression: &Expression, ctx: &EvalContext) -> Result<Vec<Series>, ResponseError> {
+    match expression {
+        Expression::Path(path) => fetch(path, ctx),
+        Expression::Call(call) => call_function(call, ctx),
+        Expression::Template(template) => {
+            evaluate(&template.resolve().map_err(ResponseError::Function)?, ctx)
+        }
+    }
+}
+
+fn fetch(path: &PathExpression, ctx: &EvalContext) -> Result<Vec<Series>, ResponseError> {
+    Ok(ctx
+        .storage
+        .query(path, ctx.interval, ctx.now)?
+        .into_iter()
+        .map(|response| Series::new(response.name.0.join("."), response.data))
+        .collect())
+}
+
+fn call_function(call: &Call, ctx: &EvalContext) -> Result<Vec<Series>, ResponseError> {
+    let args = CallArgs { call, ctx };
+    match call.function.as_str() {
+        "sumSeries" | "sum" => Ok(combine::sum_series(&args.display(), &args.series_lists()?)),
+        "averageSeries" | "avg" => Ok(combine::average_series(
+            &args.display(),
+            &args.series_lists()?,
+        )),
+        "maxSeries" => Ok(combine::max_series(&args.display(), &args.series_lists()?)),
+        "minSeries" => Ok(combine::min_series(&args.display(), &args.series_lists()?)),
+        "diffSeries" => Ok(combine::diff_series(&args.display(), &args.series_lists()?)),
+        "divideSeries" => combine::divide_series(
+            &args.series_list(0, "dividendSeriesList")?,
+            &args.series_list(1, "divisorSeries")?,
+        ),
+        "asPercent" => {
+            let series = args.series_list(0, "seriesList")?;
+            match args.get(1, "total") {
+                None | Some(Arg::Literal(LiteralValue::None)) => Ok(combine::as_percent(&series)),
+                Some(Arg::Expression(expression)) => {
+                    combine::as_percent_of_series(&series, &evaluate(expression, ctx)?)
+                }
+                Some(_) => Ok(combine::as_percent_of_value(
+                    &series,
+                    args.float(1, "total")?.unwrap_or(100.0),
+                )),
+            }
+        }
+        "scale" => Ok(transform::scale(
+            &args.series_list(0, "seriesList")?,
+            args.required_float(1, "factor")?,
+        )),
+        "offset" => Ok(transform::offset(
+            &args.series_list(0, "seriesList")?,
+            args.required_float(1, "factor")?,
+        )),
+        "derivative" => Ok(transform::derivative(&args.series_list(0, "seriesList")?)),
+        "nonNegativeDerivative" => Ok(transform::non_negative_derivative(
+            &args.series_list(0, "seriesList")?,
+            args.float(1, "maxValue")?,
+        )),
+        "perSecond" => Ok(transform::per_second(
+            &args.series_list(0, "seriesList")?,
+            args.float(1, "maxValue")?,
+        )),
+        "integral" => Ok(transform::integral(&args.series_list(0, "seriesList")?)),
+        "movingAverage" => {
+            let series = args.series_list(0, "seriesList")?;
+            let window_arg = args.get(1, "windowSize");
+            let window = match window_arg {
+                Some(Arg::Literal(LiteralValue::String(s))) => transform::Window::Seconds(
+                    duration_parse(s).map_err(|e| args.error(e.to_string()))?,
+                ),
+                _ => transform::Window::Points(args.required_count(1, "windowSize")?),
+            };
+            let label = window_arg.map(ToString::to_string).unwrap_or_default();
+            Ok(transform::moving_average(&series, window, &label))
+        }
+        "keepLastValue" => Ok(transform::keep_last_value(
+            &args.series_list(0, "seriesList")?,
+            args.count(1, "limit")?,
+        )),
+        "transformNull" => Ok(transform::transform_null(
+            &args.series_list(0, "seriesList")?,
+            args.float(1, "default")?.unwrap_or(0.0),
+        )),
+        "consolidateBy" => {
+            let method = args.required_string(1, "consolidationFunc")?;
+            let consolidation = match method.as_str() {
+                "avg" => AggregationMethod::Average,
+                name => name.parse().map_err(|e: String| args.error(e))?,
+            };
+            Ok(consolidate::consolidate_by(
+                &args.series_list(0, "seriesList")?,
+                consolidation,
+            ))
+        }
+        "alias" => Ok(alias::alias(
+            &args.series_list(0, "seriesList")?,
+            &args.required_string(1, "newName")?,
+        )),
+        "aliasByNode" => Ok(alias::alias_by_node(
+            &args.series_list(0, "seriesList")?,
+            &args.ints_from(1)?,
+        )),
+        "seriesByTag" => series_by_tag(&args),
+        _ => Err(args.error("Unknown function")),
+    }
+}
+
+/// Accessor for arguments of a single function call.
+struct CallArgs<'a> {
+    call: &'a Call,
+    ctx: &'a EvalContext<'a>,
+}
+
+impl CallArgs<'_> {
+    fn error(&self, message: impl AsRef<str>) -> ResponseError {
+        ResponseError::Function(format!("{}: {}", self.call.function, message.as_ref()))
+    }
+
+    /// Arguments as they were written, used to name combined series.
+    fn display(&self) -> String {
+        self.call.to_string()
+    }
+
+    fn get(&self, index: usize, name: &str) -> Option<&Arg> {
+        self.call.args.get(index).or_else(|| {
+            self.call
+                .named_args
+                .iter()
+                .find(|(arg_name, _)| arg_name == name)
+                .map(|(_, arg)| arg)
+        })
+    }
+
+    fn series_list(&self, index: usize, name: &str) -> Result<Vec<Series>, ResponseError> {
+        match self.get(index, name) {
+            Some(Arg::Expression(expression)) => evaluate(expression, self.ctx),
+            Some(Arg::Literal(_)) => Err(self.error(format!("{} must be a series list", name))),
+            None => Err(self.error(format!("{} is required", name))),
+        }
+    }
+
+    /// All positional arguments evaluated and joined into one list.
+    fn series_lists(&self) -> Result<Vec<Series>, ResponseError> {
+        let mut series = Vec::new();
+        for arg in &self.call.args {
+            match arg {
+                Arg::Expression(expression) => series.extend(evaluate(expression, self.ctx)?),
+                Arg::Literal(literal) => {
+                    return Err(self.error(format!("{} is not a series list", literal)));
+                }
+            }
+        }
+        Ok(series)
+    }
+
+    fn float(&self, index: usize, name: &str) -> Result<Option<f64>, ResponseError> {
+        match self.get(index, name) {
+            None | Some(Arg::Literal(LiteralValue::None)) => Ok(None),
+            Some(Arg::Literal(LiteralValue::Integer(i))) => Ok(Some(*i as f64)),
+            Some(Arg::Literal(LiteralValue::Float(f))) => Ok(Some(*f)),
+            Some(_) => Err(self.error(format!("{} must be a number", name))),
+        }
+    }
+
+    fn required_float(&self, index: usize, name: &str) -> Result<f64, ResponseError> {
+        self.float(index, name)?
+            .ok_or_else(|| self.error(format!("{} is required", name)))
+    }
+
+    fn int(&self, index: usize, name: &str) -> Result<Option<i64>, ResponseError> {
+        match self.get(index, name) {
+            None | Some(Arg::Literal(LiteralValue::None)) => Ok(None),
+            Some(Arg::Literal(LiteralValue::Integer(i))) => Ok(Some(*i)),
+            Some(_) => Err(self.error(format!("{} must be an integer", name))),
+        }
+    }
+
+    /// Integer that must not be negative, like a number of points.
+    fn count(&self, index: usize, name: &str) -> Result<Option<usize>, ResponseError> {
+        self.int(index, name)?
+            .map(|i| {
+                usize::try_from(i).map_err(|_| self.error(format!("{} must not be negative", name)))
+            })
+            .transpose()
+    }
+
+    fn required_count(&self, index: usize, name: &str) -> Result<usize, ResponseError> {
+        self.count(index, name)?
+            .ok_or_else(|| self.error(format!("{} is required", name)))
+    }
+
+    fn required_string(&self, index: usize, name: &str) -> Result<String, ResponseError> {
+        match self.get(index, name) {
+            Some(Arg::Literal(LiteralValue::String(s))) => Ok(s.clone()),
+            Some(_) => Err(self.error(format!("{} must be a string", name))),
+            None => Err(self.error(format!("{} is required", name))),
+        }
+    }
+
+    fn ints_from(&self, index: usize) -> Result<Vec<i64>, ResponseError> {
+        self.call
+            .args
+            .iter()
+            .skip(index)
+            .map(|arg| match arg {
+                Arg::Literal(LiteralValue::Integer(i)) => Ok(*i),
+                _ => Err(self.error(format!("{} must be an integer", arg))),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::ConstStorage;
+    use std::str::FromStr;
+
+    fn eval(target: &str) -> Result<Vec<Series>, ResponseError> {
+        let storage = ConstStorage(vec![
+            RenderPoint(Some(1.0), 10),
+            RenderPoint(None, 20),
+            RenderPoint(Some(4.0), 30),
+        ]);
+        let ctx = EvalContext {
+            storage: &storage,
+            interval: Interval::new(0, 40).unwrap(),
+            now: 40,
+        };
+        evaluate(&Expression::from_str(target).unwrap(), &ctx)
+    }
+
+    #[test]
+    fn evaluate_path() {
+        assert_eq!(
+            eval("i.am.a.metric").unwrap(),
+            vec![Series::new(
+                "i.am.a.metric",
+                vec![
+                    RenderPoint(Some(1.0), 10),
+                    RenderPoint(None, 20),
+                    RenderPoint(Some(4.0), 30),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn evaluate_nested_calls() {
+        assert_eq!(
+            eval("sumSeries(i.am.a.metric,scale(i.am.a.metric,2))").unwrap(),
+            vec![Series::new(
+                "sumSeries(i.am.a.metric,scale(i.am.a.metric,2))",
+                vec![
+                    RenderPoint(Some(3.0), 10),
+                    RenderPoint(None, 20),
+                    RenderPoint(Some(12.0), 30),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn evaluate_pipe() {
+        assert_eq!(
+            eval("i.am.a.metric|transformNull(-1)|alias(\"x\")").unwrap(),
+            vec![Series::new(
+                "x",
+                vec![
+                    RenderPoint(Some(1.0), 10),
+                    RenderPoint(Some(-1.0), 20),
+                    RenderPoint(Some(4.0), 30),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn evaluate_named_args() {
+        assert_eq!(
+            eval("movingAverage(i.am.a.metric,windowSize=\"20s\")").unwrap()[0].name,
+            "movingAverage(i.am.a.metric,\"20s\")"
+        );
+    }
+
+    #[test]
+    fn evaluate_template() {
+        assert_eq!(
+            eval(r#"template(scale($prefix.a.metric,$1),2,prefix="i.am")"#).unwrap()[0].name,
+            "scale(i.am.a.metric,2)"
+        );
+        assert_eq!(
+            eval("template(i.am.$x.metric)"),
+            Err(ResponseError::Function(
+                "Template variable x is not bound.".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn evaluate_series_by_tag() {
+        let names = |target| -> Result<Vec<String>, ResponseError> {
+            Ok(eval(target)?.into_iter().map(|s| s.name).collect())
+        };
+        assert_eq!(
+            names(r#"seriesByTag("name=disk.used")"#),
+            Ok(vec![
+                "disk.used;dc=eu;host=a".to_owned(),
+                "disk.used;dc=us;host=b".to_owned()
+            ])
+        );
+        assert_eq!(
+            names(r#"seriesByTag("name=disk.used","dc!=eu")"#),
+            Ok(vec!["disk.used;dc=us;host=b".to_owned()])
+        );
+        assert_eq!(names(r#"seriesByTag("dc=asia")"#), Ok(vec![]));
+        assert!(names(r#"seriesByTag("dc!=eu")"#).is_err());
+        assert!(names("seriesByTag(1)").is_err());
+        assert_eq!(
+            eval(r#"seriesByTag("host=a")"#).unwrap()[0].points,
+            vec![
+                RenderPoint(Some(1.0), 10),
+                RenderPoint(None, 20),
+                RenderPoint(Some(4.0), 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn evaluate_errors() {
+        assert_eq!(
+            eval("noSuchFunction(i.am.a.metric)"),
+            Err(ResponseError::Function(
+                "noSuchFunction: Unknown function".to_owned()
+            ))
+        );
+        assert_eq!(
+            eval("scale(i.am.a.metric)"),
+            Err(ResponseError::Function(
+                "scale: factor is required".to_owned()
+            ))
+        );
+        assert_eq!(
+            eval("alias(i.am.a.metric,1)"),
+            Err(ResponseError::Function(
+                "alias: newName must be a string".to_owned()
+            ))
+        );
+        assert_eq!(
+            eval("movingAverage(i.am.a.metric,-2)"),
+            Err(ResponseError::Function(
+                "movingAverage: windowSize must not be negative".to_owned()
+            ))
+        );
+        assert_eq!(
+            eval("keepLastValue(i.am.a.metric,-1)"),
+            Err(ResponseError::Function(
+                "keepLastValue: limit must not be negative".to_owned()
+            ))
+        );
+    }
+}
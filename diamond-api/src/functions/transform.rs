@@ -0,0 +1,317 @@
+use super::Series;
+
+pub fn scale(series: &[Series], factor: f64) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| s.map(format!("scale({},{})", s.name, factor), |v| v * factor))
+        .collect()
+}
+
+pub fn offset(series: &[Series], factor: f64) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| s.map(format!("offset({},{})", s.name, factor), |v| v + factor))
+        .collect()
+}
+
+/// Differences between consecutive values, `delta` decides what to do with each pair.
+fn deltas(series: &Series, name: String, delta: impl Fn(f64, f64) -> Option<f64>) -> Series {
+    let mut previous: Option<f64> = None;
+    let values: Vec<Option<f64>> = series
+        .values()
+        .map(|value| {
+            let result = match (previous, value) {
+                (Some(prev), Some(value)) => delta(prev, value),
+                _ => None,
+            };
+            previous = value;
+            result
+        })
+        .collect();
+    series.with_values(name, values)
+}
+
+fn non_negative_delta(prev: f64, value: f64, max_value: Option<f64>) -> Option<f64> {
+    let diff = value - prev;
+    if diff >= 0.0 {
+        Some(diff)
+    } else {
+        match max_value {
+            Some(max_value) if max_value >= value => Some(max_value - prev + value + 1.0),
+            _ => None,
+        }
+    }
+}
+
+pub fn derivative(series: &[Series]) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| {
+            deltas(s, format!("derivative({})", s.name), |prev, v| {
+                Some(v - prev)
+            })
+        })
+        .collect()
+}
+
+pub fn non_negative_derivative(series: &[Series], max_value: Option<f64>) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| {
+            deltas(
+                s,
+                format!("nonNegativeDerivative({})", s.name),
+                |prev, v| non_negative_delta(prev, v, max_value),
+            )
+        })
+        .collect()
+}
+
+pub fn per_second(series: &[Series], max_value: Option<f64>) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| {
+            let step = f64::from(s.step());
+            deltas(s, format!("perSecond({})", s.name), |prev, v| {
+                non_negative_delta(prev, v, max_value).map(|delta| delta / step)
+            })
+        })
+        .collect()
+}
+
+pub fn integral(series: &[Series]) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| {
+            let mut current = 0.0;
+            let values: Vec<Option<f64>> = s
+                .values()
+                .map(|value| {
+                    value.map(|v| {
+                        current += v;
+                        current
+                    })
+                })
+                .collect();
+            s.with_values(format!("integral({})", s.name), values)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    Points(usize),
+    Seconds(u32),
+}
+
+/// Averages known values among the last `window` points, the current one included.
+pub fn moving_average(series: &[Series], window: Window, label: &str) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| {
+            let size = match window {
+                Window::Points(points) => points,
+                Window::Seconds(seconds) => (seconds / s.step()) as usize,
+            }
+            .max(1);
+
+            let all: Vec<Option<f64>> = s.values().collect();
+            let values: Vec<Option<f64>> = (0..all.len())
+                .map(|i| {
+                    let known: Vec<f64> = all[(i + 1).saturating_sub(size)..=i]
+                        .iter()
+                        .filter_map(|v| *v)
+                        .collect();
+                    if known.is_empty() {
+                        None
+                    } else {
+                        Some(known.iter().sum::<f64>() / known.len() as f64)
+                    }
+                })
+                .collect();
+            s.with_values(format!("movingAverage({},{})", s.name, label), values)
+        })
+        .collect()
+}
+
+pub fn keep_last_value(series: &[Series], limit: Option<usize>) -> Vec<Series> {
+    let limit = limit.unwrap_or(usize::MAX);
+    series
+        .iter()
+        .map(|s| {
+            let mut values: Vec<Option<f64>> = s.values().collect();
+            let mut last: Option<f64> = None;
+            let mut gap_start = 0;
+            for i in 0..=values.len() {
+                match values.get(i).cloned() {
+                    Some(None) => continue,
+                    value => {
+                        if let Some(last) = last
+                            && i - gap_start <= limit
+                        {
+                            values[gap_start..i].fill(Some(last));
+                        }
+                        if let Some(Some(v)) = value {
+                            last = Some(v);
+                        }
+                        gap_start = i + 1;
+                    }
+                }
+            }
+
+            let name = if limit == usize::MAX {
+                format!("keepLastValue({})", s.name)
+            } else {
+                format!("keepLastValue({},{})", s.name, limit)
+            };
+            s.with_values(name, values)
+        })
+        .collect()
+}
+
+pub fn transform_null(series: &[Series], default: f64) -> Vec<Series> {
+    series
+        .iter()
+        .map(|s| {
+            let name = format!("transformNull({},{})", s.name, default);
+            s.with_values(name, s.values().map(|v| v.or(Some(default))))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RenderPoint;
+
+    fn series(values: &[Option<f64>]) -> Vec<Series> {
+        vec![Series::new(
+            "a",
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, v)| RenderPoint(*v, 10 * i as u32))
+                .collect(),
+        )]
+    }
+
+    fn values(series: &[Series]) -> Vec<Option<f64>> {
+        series[0].values().collect()
+    }
+
+    #[test]
+    fn test_scale_offset() {
+        let input = series(&[Some(1.0), None, Some(3.0)]);
+        let result = scale(&input, 2.5);
+        assert_eq!(result[0].name, "scale(a,2.5)");
+        assert_eq!(values(&result), vec![Some(2.5), None, Some(7.5)]);
+
+        let result = offset(&input, -1.0);
+        assert_eq!(result[0].name, "offset(a,-1)");
+        assert_eq!(values(&result), vec![Some(0.0), None, Some(2.0)]);
+    }
+
+    #[test]
+    fn test_derivatives() {
+        let input = series(&[Some(1.0), Some(4.0), None, Some(6.0), Some(8.0), Some(2.0)]);
+        assert_eq!(
+            values(&derivative(&input)),
+            vec![None, Some(3.0), None, None, Some(2.0), Some(-6.0)]
+        );
+        assert_eq!(
+            values(&non_negative_derivative(&input, None)),
+            vec![None, Some(3.0), None, None, Some(2.0), None]
+        );
+        assert_eq!(
+            values(&non_negative_derivative(&input, Some(10.0))),
+            vec![None, Some(3.0), None, None, Some(2.0), Some(5.0)]
+        );
+        assert_eq!(
+            values(&per_second(&input, None)),
+            vec![None, Some(0.3), None, None, Some(0.2), None]
+        );
+    }
+
+    #[test]
+    fn test_integral() {
+        let input = series(&[Some(1.0), None, Some(3.0), Some(-2.0)]);
+        let result = integral(&input);
+        assert_eq!(result[0].name, "integral(a)");
+        assert_eq!(values(&result), vec![Some(1.0), None, Some(4.0), Some(2.0)]);
+    }
+
+    #[test]
+    fn test_moving_average() {
+        let input = series(&[Some(1.0), Some(3.0), None, Some(5.0), None, None]);
+        let result = moving_average(&input, Window::Points(2), "2");
+        assert_eq!(result[0].name, "movingAverage(a,2)");
+        assert_eq!(
+            values(&result),
+            vec![Some(1.0), Some(2.0), Some(3.0), Some(5.0), Some(5.0), None]
+        );
+
+        let result = moving_average(&input, Window::Seconds(30), "\"30s\"");
+        assert_eq!(result[0].name, "movingAverage(a,\"30s\")");
+        assert_eq!(
+            values(&result),
+            vec![
+                Some(1.0),
+                Some(2.0),
+                Some(2.0),
+                Some(4.0),
+                Some(5.0),
+                Some(5.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_last_value() {
+        let input = series(&[
+            None,
+            Some(1.0),
+            None,
+            Some(2.0),
+            None,
+            None,
+            Some(3.0),
+            None,
+        ]);
+        assert_eq!(
+            values(&keep_last_value(&input, None)),
+            vec![
+                None,
+                Some(1.0),
+                Some(1.0),
+                Some(2.0),
+                Some(2.0),
+                Some(2.0),
+                Some(3.0),
+                Some(3.0)
+            ]
+        );
+        let result = keep_last_value(&input, Some(1));
+        assert_eq!(result[0].name, "keepLastValue(a,1)");
+        assert_eq!(
+            values(&result),
+            vec![
+                None,
+                Some(1.0),
+                Some(1.0),
+                Some(2.0),
+                None,
+                None,
+                Some(3.0),
+                Some(3.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transform_null() {
+        let input = series(&[None, Some(1.0), None]);
+        let result = transform_null(&input, 0.0);
+        assert_eq!(result[0].name, "transformNull(a,0)");
+        assert_eq!(values(&result), vec![Some(0.0), Some(1.0), Some(0.0)]);
+    }
+}
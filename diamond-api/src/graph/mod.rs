@@ -0,0 +1,561 @@
+use chrono::DateTime;
+use std::collections::BTreeMap;
+
+use crate::storage::RenderPoint;
+
+mod options;
+mod png;
+mod svg;
+
+pub use options::{AreaMode, Color, GraphOptions};
+pub use png::to_png;
+pub use svg::to_svg;
+
+/// Glyph size of the monospace font used for every label.
+pub const CHAR_WIDTH: f32 = 6.0;
+pub const CHAR_HEIGHT: f32 = 10.0;
+
+const PADDING: f32 = 10.0;
+const LABEL_GAP: f32 = 4.0;
+const LEGEND_SWATCH: f32 = 8.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element {
+    Rect {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        color: Color,
+    },
+    Polyline {
+        points: Vec<(f32, f32)>,
+        color: Color,
+        width: f32,
+    },
+    Polygon {
+        points: Vec<(f32, f32)>,
+        color: Color,
+    },
+    /// A single line of text, `y` is its top edge.
+    Text {
+        x: f32,
+        y: f32,
+        text: String,
+        color: Color,
+        anchor: Anchor,
+    },
+}
+
+/// Backend independent description of a graph.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scene {
+    pub width: u32,
+    pub height: u32,
+    pub elements: Vec<Element>,
+}
+
+pub struct Plot<'a> {
+    pub name: &'a str,
+    pub points: &'a [RenderPoint],
+}
+
+/// Rounds `raw` up to 1, 2 or 5 times a power of ten.
+fn nice_step(raw: f64) -> f64 {
+    let magnitude = 10f64.powf(raw.log10().floor());
+    let fraction = raw / magnitude;
+    let nice = if fraction <= 1.0 {
+        1.0
+    } else if fraction <= 2.0 {
+        2.0
+    } else if fraction <= 5.0 {
+        5.0
+    } else {
+        10.0
+    };
+    nice * magnitude
+}
+
+/// Largest width and height of a graph in pixels.
+const MAX_SIZE: u32 = 4096;
+
+/// Most labels of the y axis, `yMin` and `yMax` may be far apart from the data step.
+const MAX_Y_TICKS: usize = 64;
+
+/// Labeled values at multiples of `step` from `low` to `high`. The count is computed up
+/// front, as adding `step` does not move values beyond the precision of `f64`.
+fn y_ticks(low: f64, high: f64, step: f64) -> Vec<(f64, String)> {
+    let first = (low / step).ceil() * step;
+    let count = ((high - first) / step).round();
+    if !count.is_finite() || count < 0.0 {
+        return Vec::new();
+    }
+    (0..=(count as usize).min(MAX_Y_TICKS))
+        .map(|i| first + i as f64 * step)
+        .map(|tick| (tick, format_value(tick, step)))
+        .collect()
+}
+
+fn format_value(value: f64, step: f64) -> String {
+    const UNITS: [(f64, &str); 4] = [(1e12, "T"), (1e9, "G"), (1e6, "M"), (1e3, "K")];
+    let (divisor, suffix) = UNITS
+        .iter()
+        .find(|(divisor, _)| step >= *divisor)
+        .copied()
+        .unwrap_or((1.0, ""));
+    let scaled_step = step / divisor;
+    let decimals = if scaled_step >= 1.0 {
+        0
+    } else {
+        (-scaled_step.log10().floor()) as usize
+    };
+    // Adding zero turns a negative zero into a positive one.
+    format!("{:.*}{}", decimals, value / divisor + 0.0, suffix)
+}
+
+fn time_step(span: u32, max_labels: u32) -> u32 {
+    const STEPS: [u32; 20] = [
+        1, 5, 10, 15, 30, 60, 300, 600, 900, 1800, 3600, 7200, 10800, 21600, 43200, 86400, 172_800,
+        604_800, 2_592_000, 31_536_000,
+    ];
+    STEPS
+        .iter()
+        .copied()
+        .find(|step| span / step <= max_labels.max(1))
+        .unwrap_or(31_536_000)
+}
+
+fn format_time(time: u32, step: u32) -> String {
+    let format = if step < 60 {
+        "%H:%M:%S"
+    } else if step < 86400 {
+        "%H:%M"
+    } else {
+        "%m/%d"
+    };
+    DateTime::from_timestamp(i64::from(time), 0)
+        .map(|dt| dt.format(format).to_string())
+        .unwrap_or_else(|| time.to_string())
+}
+
+/// Values to draw for every plot, accumulated over previous plots in stacked mode.
+fn plot_values(plots: &[Plot], area_mode: AreaMode) -> Vec<Vec<(u32, Option<f64>)>> {
+    let mut totals: BTreeMap<u32, f64> = BTreeMap::new();
+    plots
+        .iter()
+        .map(|plot| {
+            plot.points
+                .iter()
+                .map(|RenderPoint(value, time)| {
+                    if area_mode == AreaMode::Stacked {
+                        let total = totals.entry(*time).or_default();
+                        *total += value.unwrap_or_default();
+                        (*time, value.map(|_| *total))
+                    } else {
+                        (*time, *value)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Runs of consecutive known points.
+fn segments(points: &[(f32, Option<f32>)]) -> Vec<Vec<(f32, f32)>> {
+    let mut result = Vec::new();
+    let mut current = Vec::new();
+    for (x, y) in points {
+        match y {
+            Some(y) => current.push((*x, *y)),
+            None if !current.is_empty() => result.push(std::mem::take(&mut current)),
+            None => {}
+        }
+    }
+    if !current.is_empty() {
+        result.push(current);
+    }
+    result
+}
+
+fn parse_color(s: &str) -> Result<Color, String> {
+    s.trim().parse()
+}
+
+pub fn draw(plots: &[Plot], options: &GraphOptions) -> Result<Scene, String> {
+    if options.width > MAX_SIZE || options.height > MAX_SIZE {
+        return Err(format!(
+            "Graph {}x{} is larger than {}x{}",
+            options.width, options.height, MAX_SIZE, MAX_SIZE
+        ));
+    }
+    let width = options.width as f32;
+    let height = options.height as f32;
+    let bgcolor = parse_color(&options.bgcolor)?;
+    let fgcolor = parse_color(&options.fgcolor)?;
+    let grid_color = fgcolor.with_alpha(64);
+    let colors: Vec<Color> = options
+        .color_list
+        .split(',')
+        .map(parse_color)
+        .collect::<Result<_, _>>()?;
+
+    let mut elements = vec![Element::Rect {
+        x: 0.0,
+        y: 0.0,
+        width,
+        height,
+        color: bgcolor,
+    }];
+
+    let mut top = PADDING;
+    if let Some(title) = &options.title {
+        elements.push(Element::Text {
+            x: width / 2.0,
+            y: top,
+            text: title.clone(),
+            color: fgcolor,
+            anchor: Anchor::Middle,
+        });
+        top += CHAR_HEIGHT + PADDING;
+    }
+
+    let mut bottom = height - PADDING;
+    if !options.hide_legend && !plots.is_empty() {
+        let longest_name = plots.iter().map(|p| p.name.len()).max().unwrap_or(0);
+        let item_width = longest_name as f32 * CHAR_WIDTH + LEGEND_SWATCH + 2.0 * LABEL_GAP;
+        let columns = (((width - 2.0 * PADDING) / item_width) as usize).max(1);
+        let rows = plots.len().div_ceil(columns);
+        let row_height = CHAR_HEIGHT + 2.0;
+        let legend_top = bottom - rows as f32 * row_height;
+
+        for (index, plot) in plots.iter().enumerate() {
+            let x = PADDING + (index % columns) as f32 * item_width;
+            let y = legend_top + (index / columns) as f32 * row_height;
+            elements.push(Element::Rect {
+                x,
+                y: y + 1.0,
+                width: LEGEND_SWATCH,
+                height: LEGEND_SWATCH,
+                color: colors[index % colors.len()],
+            });
+            elements.push(Element::Text {
+                x: x + LEGEND_SWATCH + LABEL_GAP,
+                y,
+                text: plot.name.to_owned(),
+                color: fgcolor,
+                anchor: Anchor::Start,
+            });
+        }
+        bottom = legend_top - PADDING;
+    }
+    // Time labels under the plot
+    bottom -= CHAR_HEIGHT + LABEL_GAP;
+
+    let values = plot_values(plots, options.area_mode);
+    let known = || values.iter().flatten().filter_map(|(_, v)| *v);
+    let mut low = options
+        .y_min
+        .unwrap_or_else(|| known().fold(f64::INFINITY, f64::min));
+    let mut high = options
+        .y_max
+        .unwrap_or_else(|| known().fold(f64::NEG_INFINITY, f64::max));
+    if !low.is_finite() || !high.is_finite() {
+        low = options.y_min.unwrap_or(0.0);
+        high = options.y_max.unwrap_or(low + 1.0);
+    }
+    if options.area_mode != AreaMode::None && options.y_min.is_none() {
+        low = low.min(0.0);
+    }
+    if high <= low {
+        high = low + 1.0;
+    }
+
+    let y_step = nice_step((high - low) / 4.0);
+    if options.y_min.is_none() {
+        low = (low / y_step).floor() * y_step;
+    }
+    if options.y_max.is_none() {
+        high = (high / y_step).ceil() * y_step;
+    }
+
+    let y_ticks = y_ticks(low, high, y_step);
+
+    let label_width = y_ticks
+        .iter()
+        .map(|(_, label)| label.len())
+        .max()
+        .unwrap_or(0) as f32
+        * CHAR_WIDTH;
+    let left = PADDING + label_width + LABEL_GAP;
+    let right = width - PADDING;
+
+    if right - left < 1.0 || bottom - top < 1.0 {
+        return Err(format!(
+            "Graph {}x{} is too small to fit its labels",
+            options.width, options.height
+        ));
+    }
+
+    let times = || values.iter().flatten().map(|(time, _)| *time);
+    let first_time = times().min().unwrap_or(0);
+    let last_time = times().max().unwrap_or(0).max(first_time + 1);
+
+    let to_x = |time: u32| {
+        left + (time - first_time) as f32 / (last_time - first_time) as f32 * (right - left)
+    };
+    let to_y = |value: f64| {
+        let ratio = ((value - low) / (high - low)).clamp(0.0, 1.0);
+        bottom - ratio as f32 * (bottom - top)
+    };
+
+    for (value, label) in &y_ticks {
+        let y = to_y(*value);
+        elements.push(Element::Polyline {
+            points: vec![(left, y), (right, y)],
+            color: grid_color,
+            width: 1.0,
+        });
+        elements.push(Element::Text {
+            x: left - LABEL_GAP,
+            y: y - CHAR_HEIGHT / 2.0,
+            text: label.clone(),
+            color: fgcolor,
+            anchor: Anchor::End,
+        });
+    }
+
+    let max_labels = ((right - left) / (CHAR_WIDTH * 10.0)) as u32;
+    let x_step = time_step(last_time - first_time, max_labels);
+    let mut time = first_time.div_ceil(x_step) * x_step;
+    while time <= last_time {
+        let x = to_x(time);
+        elements.push(Element::Polyline {
+            points: vec![(x, top), (x, bottom)],
+            color: grid_color,
+            width: 1.0,
+        });
+        elements.push(Element::Text {
+            x,
+            y: bottom + LABEL_GAP,
+            text: format_time(time, x_step),
+            color: fgcolor,
+            anchor: Anchor::Middle,
+        });
+        time = match time.checked_add(x_step) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    elements.push(Element::Polyline {
+        points: vec![(left, top), (left, bottom), (right, bottom)],
+        color: fgcolor,
+        width: 1.0,
+    });
+
+    let plot_segments: Vec<Vec<Vec<(f32, f32)>>> = values
+        .iter()
+        .map(|points| {
+            let mapped: Vec<(f32, Option<f32>)> = points
+                .iter()
+                .map(|(time, value)| (to_x(*time), value.map(to_y)))
+                .collect();
+            segments(&mapped)
+        })
+        .collect();
+
+    let baseline = to_y(0.0_f64.clamp(low, high));
+    let filled: Vec<usize> = match options.area_mode {
+        AreaMode::None => vec![],
+        AreaMode::First => vec![0],
+        AreaMode::All => (0..plots.len()).collect(),
+        // Higher stacks go first so that lower ones are painted over them
+        AreaMode::Stacked => (0..plots.len()).rev().collect(),
+    };
+    for index in filled.into_iter().filter(|index| *index < plots.len()) {
+        for segment in &plot_segments[index] {
+            let mut points = segment.clone();
+            points.push((segment[segment.len() - 1].0, baseline));
+            points.push((segment[0].0, baseline));
+            elements.push(Element::Polygon {
+                points,
+                color: colors[index % colors.len()],
+            });
+        }
+    }
+
+    for (index, segments) in plot_segments.into_iter().enumerate() {
+        for mut points in segments {
+            if points.len() == 1 {
+                let (x, y) = points[0];
+                points = vec![(x - 0.5, y), (x + 0.5, y)];
+            }
+            elements.push(Element::Polyline {
+                points,
+                color: colors[index % colors.len()],
+                width: options.line_width,
+            });
+        }
+    }
+
+    Ok(Scene {
+        width: options.width,
+        height: options.height,
+        elements,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nice_step() {
+        assert_eq!(nice_step(0.3), 0.5);
+        assert_eq!(nice_step(1.0), 1.0);
+        assert_eq!(nice_step(1.5), 2.0);
+        assert_eq!(nice_step(26.0), 50.0);
+        assert_eq!(nice_step(7000.0), 10000.0);
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(0.5, 0.5), "0.5");
+        assert_eq!(format_value(-0.0, 1.0), "0");
+        assert_eq!(format_value(20.0, 10.0), "20");
+        assert_eq!(format_value(4000.0, 2000.0), "4K");
+        assert_eq!(format_value(2_500_000.0, 500_000.0), "2500K");
+        assert_eq!(format_value(3e9, 1e9), "3G");
+    }
+
+    #[test]
+    fn test_y_ticks() {
+        let values = |ticks: Vec<(f64, String)>| ticks.into_iter().map(|(v, _)| v).collect();
+        let ticks: Vec<f64> = values(y_ticks(0.0, 2.0, 0.5));
+        assert_eq!(ticks, [0.0, 0.5, 1.0, 1.5, 2.0]);
+        assert!(y_ticks(2.0, 1.0, 0.5).is_empty());
+
+        // Steps below the precision of the values
+        let ticks: Vec<f64> = values(y_ticks(1.7e18, 1.7e18 + 256.0, 50.0));
+        assert_eq!(ticks.len(), 6);
+        let ticks: Vec<f64> = values(y_ticks(1e17, 1e17 + 16.0, 5.0));
+        assert_eq!(ticks.len(), 4);
+        assert_eq!(y_ticks(0.0, 1e9, 1.0).len(), MAX_Y_TICKS + 1);
+    }
+
+    #[test]
+    fn test_time_labels() {
+        assert_eq!(time_step(3600, 6), 600);
+        assert_eq!(time_step(86400, 6), 21600);
+        assert_eq!(time_step(0, 6), 1);
+        assert_eq!(format_time(1_564_432_988, 60), "20:43");
+        assert_eq!(format_time(1_564_432_988, 86400), "07/29");
+    }
+
+    #[test]
+    fn test_segments() {
+        assert_eq!(
+            segments(&[
+                (0.0, Some(1.0)),
+                (1.0, Some(2.0)),
+                (2.0, None),
+                (3.0, Some(3.0)),
+                (4.0, None)
+            ]),
+            vec![vec![(0.0, 1.0), (1.0, 2.0)], vec![(3.0, 3.0)]]
+        );
+    }
+
+    #[test]
+    fn test_stacked_values() {
+        let a = [RenderPoint(Some(1.0), 0), RenderPoint(None, 10)];
+        let b = [RenderPoint(Some(2.0), 0), RenderPoint(Some(3.0), 10)];
+        let plots = [
+            Plot {
+                name: "a",
+                points: &a,
+            },
+            Plot {
+                name: "b",
+                points: &b,
+            },
+        ];
+        assert_eq!(
+            plot_values(&plots, AreaMode::Stacked),
+            vec![
+                vec![(0, Some(1.0)), (10, None)],
+                vec![(0, Some(3.0)), (10, Some(3.0))]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_draw() {
+        let points = [
+            RenderPoint(Some(1.0), 1_564_432_980),
+            RenderPoint(Some(3.0), 1_564_433_040),
+        ];
+        let plots = [Plot {
+            name: "a.b",
+            points: &points,
+        }];
+        let options = GraphOptions {
+            title: Some("Title".to_owned()),
+            ..GraphOptions::default()
+        };
+        let scene = draw(&plots, &options).unwrap();
+        assert_eq!((scene.width, scene.height), (330, 250));
+        assert!(scene.elements.contains(&Element::Text {
+            x: 165.0,
+            y: PADDING,
+            text: "Title".to_owned(),
+            color: Color::rgb(255, 255, 255),
+            anchor: Anchor::Middle,
+        }));
+        assert!(scene.elements.iter().any(|e| matches!(
+            e,
+            Element::Polyline { points, color, .. }
+                if points.len() == 2 && *color == Color::rgb(100, 100, 255)
+        )));
+
+        let tiny = GraphOptions {
+            width: 20,
+            height: 20,
+            ..GraphOptions::default()
+        };
+        assert!(draw(&plots, &tiny).is_err());
+
+        let large = GraphOptions {
+            width: 100_000,
+            height: 100_000,
+            ..GraphOptions::default()
+        };
+        assert_eq!(
+            draw(&plots, &large),
+            Err("Graph 100000x100000 is larger than 4096x4096".to_owned())
+        );
+
+        let huge = GraphOptions {
+            y_min: Some(1e17),
+            y_max: Some(1e17 + 16.0),
+            ..GraphOptions::default()
+        };
+        assert!(draw(&plots, &huge).is_ok());
+
+        let bad_color = GraphOptions {
+            bgcolor: "nocolor".to_owned(),
+            ..GraphOptions::default()
+        };
+        assert_eq!(
+            draw(&plots, &bad_color),
+            Err("Unknown color 'nocolor'".to_owned())
+        );
+    }
+}
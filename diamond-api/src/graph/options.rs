@@ -0,0 +1,196 @@
+use serde::*;
+use std::str::FromStr;
+
+use crate::error::ParseError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AreaMode {
+    #[default]
+    None,
+    First,
+    All,
+    Stacked,
+}
+
+impl FromStr for AreaMode {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AreaMode::None),
+            "first" => Ok(AreaMode::First),
+            "all" => Ok(AreaMode::All),
+            "stacked" => Ok(AreaMode::Stacked),
+            _ => Err(ParseError::Query(format!("Unknown areaMode '{}'", s))),
+        }
+    }
+}
+
+/// Parameters of `png` and `svg` render formats, named as in graphite-web.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GraphOptions {
+    pub width: u32,
+    pub height: u32,
+    pub title: Option<String>,
+    pub color_list: String,
+    pub y_min: Option<f64>,
+    pub y_max: Option<f64>,
+    pub area_mode: AreaMode,
+    pub line_width: f32,
+    pub bgcolor: String,
+    pub fgcolor: String,
+    pub hide_legend: bool,
+}
+
+impl Default for GraphOptions {
+    fn default() -> Self {
+        Self {
+            width: 330,
+            height: 250,
+            title: None,
+            color_list: "blue,green,red,purple,brown,yellow,aqua,grey,magenta,pink,gold,rose"
+                .to_owned(),
+            y_min: None,
+            y_max: None,
+            area_mode: AreaMode::None,
+            line_width: 1.2,
+            bgcolor: "black".to_owned(),
+            fgcolor: "white".to_owned(),
+            hide_legend: false,
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, ParseError> {
+    value
+        .parse()
+        .map_err(|_| ParseError::Query(format!("Invalid value of {}: '{}'", key, value)))
+}
+
+impl GraphOptions {
+    /// Applies a query string parameter, parameters unrelated to graphs are ignored.
+    pub fn set(&mut self, key: &str, value: String) -> Result<(), ParseError> {
+        match key {
+            "width" => self.width = parse_value(key, &value)?,
+            "height" => self.height = parse_value(key, &value)?,
+            "title" => self.title = Some(value),
+            "colorList" => self.color_list = value,
+            "yMin" => self.y_min = Some(parse_value(key, &value)?),
+            "yMax" => self.y_max = Some(parse_value(key, &value)?),
+            "areaMode" => self.area_mode = value.parse()?,
+            "lineWidth" => self.line_width = parse_value(key, &value)?,
+            "bgcolor" => self.bgcolor = value,
+            "fgcolor" => self.fgcolor = value,
+            "hideLegend" => self.hide_legend = parse_value(key, &value)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b, a: 255 }
+    }
+
+    pub fn with_alpha(self, a: u8) -> Self {
+        Self { a, ..self }
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    /// Graphite color aliases or hex `rrggbb`/`rrggbbaa`, with or without leading `#`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let color = match s {
+            "black" => Color::rgb(0, 0, 0),
+            "white" => Color::rgb(255, 255, 255),
+            "blue" => Color::rgb(100, 100, 255),
+            "green" => Color::rgb(0, 200, 0),
+            "red" => Color::rgb(200, 0, 50),
+            "yellow" => Color::rgb(255, 255, 0),
+            "orange" => Color::rgb(255, 165, 0),
+            "purple" => Color::rgb(200, 100, 255),
+            "brown" => Color::rgb(150, 100, 50),
+            "cyan" => Color::rgb(0, 255, 255),
+            "aqua" => Color::rgb(0, 150, 150),
+            "gray" | "grey" => Color::rgb(175, 175, 175),
+            "magenta" => Color::rgb(255, 0, 255),
+            "pink" => Color::rgb(255, 100, 100),
+            "gold" => Color::rgb(200, 200, 0),
+            "rose" => Color::rgb(200, 150, 200),
+            "darkblue" => Color::rgb(0, 0, 255),
+            "darkgreen" => Color::rgb(0, 255, 0),
+            "darkred" => Color::rgb(255, 0, 0),
+            "darkgray" | "darkgrey" => Color::rgb(111, 111, 111),
+            _ => {
+                let hex = s.strip_prefix('#').unwrap_or(s);
+                let byte = |index: usize| {
+                    hex.get(index..index + 2)
+                        .and_then(|h| u8::from_str_radix(h, 16).ok())
+                        .ok_or_else(|| format!("Unknown color '{}'", s))
+                };
+                match hex.len() {
+                    6 => Color::rgb(byte(0)?, byte(2)?, byte(4)?),
+                    8 => Color::rgb(byte(0)?, byte(2)?, byte(4)?).with_alpha(byte(6)?),
+                    _ => return Err(format!("Unknown color '{}'", s)),
+                }
+            }
+        };
+        Ok(color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_parse() {
+        assert_eq!("red".parse(), Ok(Color::rgb(200, 0, 50)));
+        assert_eq!("#0a0B0c".parse(), Ok(Color::rgb(10, 11, 12)));
+        assert_eq!(
+            "ff000080".parse(),
+            Ok(Color::rgb(255, 0, 0).with_alpha(128))
+        );
+        assert!("nocolor".parse::<Color>().is_err());
+        assert!("#12345".parse::<Color>().is_err());
+        assert!("#zz0000".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn options_set() -> Result<(), ParseError> {
+        let mut options = GraphOptions::default();
+        options.set("width", "800".to_owned())?;
+        options.set("yMin", "-1.5".to_owned())?;
+        options.set("areaMode", "stacked".to_owned())?;
+        options.set("hideLegend", "true".to_owned())?;
+        options.set("unrelated", "x".to_owned())?;
+
+        assert_eq!(
+            options,
+            GraphOptions {
+                width: 800,
+                y_min: Some(-1.5),
+                area_mode: AreaMode::Stacked,
+                hide_legend: true,
+                ..GraphOptions::default()
+            }
+        );
+
+        assert!(options.set("height", "tall".to_owned()).is_err());
+        assert!(options.set("areaMode", "some".to_owned()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+use embedded_graphics::mono_font::{MonoTextStyle, ascii::FONT_6X10};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Alignment, Baseline, Text, TextStyleBuilder};
+use std::convert::Infallible;
+use tiny_skia::{FillRule, Paint, PathBuilder, Pixmap, Rect, Stroke, Transform};
+
+use super::{Anchor, Color, Element, Scene};
+
+fn paint(color: &Color, anti_alias: bool) -> Paint<'static> {
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(color.r, color.g, color.b, color.a);
+    paint.anti_alias = anti_alias;
+    paint
+}
+
+fn path(points: &[(f32, f32)], close: bool) -> Option<tiny_skia::Path> {
+    let mut builder = PathBuilder::new();
+    let (first, rest) = points.split_first()?;
+    builder.move_to(first.0, first.1);
+    for (x, y) in rest {
+        builder.line_to(*x, *y);
+    }
+    if close {
+        builder.close();
+    }
+    builder.finish()
+}
+
+/// Lets the bitmap fonts of embedded-graphics draw onto a pixmap.
+struct Canvas<'a> {
+    pixmap: &'a mut Pixmap,
+    alpha: u8,
+}
+
+impl OriginDimensions for Canvas<'_> {
+    fn size(&self) -> Size {
+        Size::new(self.pixmap.width(), self.pixmap.height())
+    }
+}
+
+impl DrawTarget for Canvas<'_> {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let color = Color::rgb(color.r(), color.g(), color.b()).with_alpha(self.alpha);
+            if let Some(rect) = Rect::from_xywh(point.x as f32, point.y as f32, 1.0, 1.0) {
+                self.pixmap
+                    .fill_rect(rect, &paint(&color, false), Transform::identity(), None);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn to_png(scene: &Scene) -> Result<Vec<u8>, String> {
+    let mut pixmap = Pixmap::new(scene.width, scene.height)
+        .ok_or_else(|| format!("Invalid image size {}x{}", scene.width, scene.height))?;
+
+    for element in &scene.elements {
+        match element {
+            Element::Rect {
+                x,
+                y,
+                width,
+                height,
+                color,
+            } => {
+                if let Some(rect) = Rect::from_xywh(*x, *y, *width, *height) {
+                    pixmap.fill_rect(rect, &paint(color, false), Transform::identity(), None);
+                }
+            }
+            Element::Polyline {
+                points,
+                color,
+                width,
+            } => {
+                if let Some(path) = path(points, false) {
+                    let stroke = Stroke {
+                        width: *width,
+                        ..Stroke::default()
+                    };
+                    pixmap.stroke_path(
+                        &path,
+                        &paint(color, true),
+                        &stroke,
+                        Transform::identity(),
+                        None,
+                    );
+                }
+            }
+            Element::Polygon { points, color } => {
+                if let Some(path) = path(points, true) {
+                    pixmap.fill_path(
+                        &path,
+                        &paint(color, true),
+                        FillRule::Winding,
+                        Transform::identity(),
+                        None,
+                    );
+                }
+            }
+            Element::Text {
+                x,
+                y,
+                text,
+                color,
+                anchor,
+            } => {
+                let alignment = match anchor {
+                    Anchor::Start => Alignment::Left,
+                    Anchor::Middle => Alignment::Center,
+                    Anchor::End => Alignment::Right,
+                };
+                let style = TextStyleBuilder::new()
+                    .alignment(alignment)
+                    .baseline(Baseline::Top)
+                    .build();
+                let character_style =
+                    MonoTextStyle::new(&FONT_6X10, Rgb888::new(color.r, color.g, color.b));
+                let mut canvas = Canvas {
+                    pixmap: &mut pixmap,
+                    alpha: color.a,
+                };
+                let position = Point::new(x.round() as i32, y.round() as i32);
+                let _ =
+                    Text::with_text_style(text, position, character_style, style).draw(&mut canvas);
+            }
+        }
+    }
+
+    pixmap.encode_png().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_png() {
+        let scene = Scene {
+            width: 40,
+            height: 20,
+            elements: vec![
+                Element::Rect {
+                    x: 0.0,
+                    y: 0.0,
+                    width: 40.0,
+                    height: 20.0,
+                    color: Color::rgb(0, 0, 0),
+                },
+                Element::Text {
+                    x: 0.0,
+                    y: 0.0,
+                    text: "abc".to_owned(),
+                    color: Color::rgb(255, 255, 255),
+                    anchor: Anchor::Start,
+                },
+            ],
+        };
+        let png = to_png(&scene).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        let pixmap = Pixmap::decode_png(&png).unwrap();
+        assert_eq!((pixmap.width(), pixmap.height()), (40, 20));
+        assert!(pixmap.pixels().iter().any(|p| p.red() == 255));
+        assert!(pixmap.pixels().iter().all(|p| p.alpha() == 255));
+    }
+
+    #[test]
+    fn test_empty_png() {
+        let scene = Scene {
+            width: 0,
+            height: 10,
+            elements: vec![],
+        };
+        assert!(to_png(&scene).is_err());
+    }
+}
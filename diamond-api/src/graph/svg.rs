@@ -0,0 +1,147 @@
+use std::fmt::Write;
+
+use super::{Anchor, CHAR_HEIGHT, Color, Element, Scene};
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Color attribute with a separate opacity, e.g. `fill="rgb(0,0,0)" fill-opacity="1.000"`.
+fn paint(attribute: &str, color: &Color) -> String {
+    format!(
+        "{0}=\"rgb({1},{2},{3})\" {0}-opacity=\"{4:.3}\"",
+        attribute,
+        color.r,
+        color.g,
+        color.b,
+        f32::from(color.a) / 255.0
+    )
+}
+
+fn points(points: &[(f32, f32)]) -> String {
+    points
+        .iter()
+        .map(|(x, y)| format!("{:.1},{:.1}", x, y))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn to_svg(scene: &Scene) -> String {
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+        scene.width, scene.height
+    );
+    for element in &scene.elements {
+        // Writing into a String cannot fail
+        let _ = match element {
+            Element::Rect {
+                x,
+                y,
+                width,
+                height,
+                color,
+            } => writeln!(
+                svg,
+                "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" {}/>",
+                x,
+                y,
+                width,
+                height,
+                paint("fill", color)
+            ),
+            Element::Polyline {
+                points: line,
+                color,
+                width,
+            } => writeln!(
+                svg,
+                "<polyline points=\"{}\" fill=\"none\" {} stroke-width=\"{:.1}\"/>",
+                points(line),
+                paint("stroke", color),
+                width
+            ),
+            Element::Polygon {
+                points: area,
+                color,
+            } => writeln!(
+                svg,
+                "<polygon points=\"{}\" {}/>",
+                points(area),
+                paint("fill", color)
+            ),
+            Element::Text {
+                x,
+                y,
+                text,
+                color,
+                anchor,
+            } => {
+                let anchor = match anchor {
+                    Anchor::Start => "start",
+                    Anchor::Middle => "middle",
+                    Anchor::End => "end",
+                };
+                writeln!(
+                    svg,
+                    "<text x=\"{:.1}\" y=\"{:.1}\" font-family=\"monospace\" font-size=\"{}\" text-anchor=\"{}\" {}>{}</text>",
+                    x,
+                    y + CHAR_HEIGHT - 2.0,
+                    CHAR_HEIGHT,
+                    anchor,
+                    paint("fill", color),
+                    escape(text)
+                )
+            }
+        };
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_svg() {
+        let scene = Scene {
+            width: 10,
+            height: 20,
+            elements: vec![
+                Element::Rect {
+                    x: 0.0,
+                    y: 0.0,
+                    width: 10.0,
+                    height: 20.0,
+                    color: Color::rgb(0, 0, 0),
+                },
+                Element::Polyline {
+                    points: vec![(0.0, 1.0), (2.5, 3.0)],
+                    color: Color::rgb(255, 0, 0).with_alpha(0),
+                    width: 1.2,
+                },
+                Element::Text {
+                    x: 1.0,
+                    y: 2.0,
+                    text: "a<b>&\"c\"".to_owned(),
+                    color: Color::rgb(255, 255, 255),
+                    anchor: Anchor::End,
+                },
+            ],
+        };
+
+        assert_eq!(
+            to_svg(&scene),
+            concat!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"10\" height=\"20\" viewBox=\"0 0 10 20\">\n",
+                "<rect x=\"0.0\" y=\"0.0\" width=\"10.0\" height=\"20.0\" fill=\"rgb(0,0,0)\" fill-opacity=\"1.000\"/>\n",
+                "<polyline points=\"0.0,1.0 2.5,3.0\" fill=\"none\" stroke=\"rgb(255,0,0)\" stroke-opacity=\"0.000\" stroke-width=\"1.2\"/>\n",
+                "<text x=\"1.0\" y=\"10.0\" font-family=\"monospace\" font-size=\"10\" text-anchor=\"end\" fill=\"rgb(255,255,255)\" fill-opacity=\"1.000\">a&lt;b&gt;&amp;&quot;c&quot;</text>\n",
+                "</svg>\n"
+            )
+        );
+    }
+}
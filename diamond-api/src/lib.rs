@@ -5,8 +5,12 @@ pub mod storage;
 
 pub(crate) mod error;
 pub(crate) mod find;
+pub(crate) mod functions;
+pub(crate) mod graph;
 pub(crate) mod parse;
 pub(crate) mod render;
 pub(crate) mod render_target;
+pub(crate) mod response_format;
+pub(crate) mod tags;
 #[cfg(test)]
 pub(crate) mod test_utils;
@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, clap::Parser)]
@@ -13,4 +14,23 @@ pub struct Args {
     /// Port to listen on
     #[arg(name = "port", short = 'p', long = "port", default_value = "8080")]
     pub port: u16,
+
+    /// Carbonlink address of a cache to query for datapoints not written yet, may be repeated
+    #[arg(name = "carbonlink", long = "carbonlink")]
+    pub carbonlink: Vec<SocketAddr>,
+
+    /// Timeout of carbonlink queries in milliseconds
+    #[arg(
+        name = "carbonlink-timeout",
+        long = "carbonlink-timeout",
+        default_value = "1000"
+    )]
+    pub carbonlink_timeout: u64,
+
+    /// Number of whisper file headers to keep in memory, 0 turns the cache off
+    #[arg(name = "header-cache", long = "header-cache", default_value = "0")]
+    pub header_cache: usize,
+
+    #[command(flatten)]
+    pub lock: whisper::lock::LockArgs,
 }
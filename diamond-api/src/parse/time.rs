@@ -1,4 +1,6 @@
 use crate::error::ParseError;
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone};
+use chrono_tz::Tz;
 use serde::{Deserialize, Deserializer};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -10,38 +12,192 @@ where
 }
 
 pub fn time_parse(s: String) -> Result<u32, ParseError> {
-    if s.starts_with('-') {
-        // Relative time
-        let (multi, count) = match &s.chars().last().unwrap() {
-            's' => (1, 1),
-            'h' => (3600, 1),
-            'd' => (3600 * 24, 1),
-            'w' => (3600 * 24 * 7, 1),
-            'y' => (3600 * 24 * 365, 1),
-            'n' if s.ends_with("min") => (60, 3),
-            'n' if s.ends_with("mon") => (3600 * 24 * 30, 3),
-            _ => return Err(ParseError::Time),
-        };
+    time_parse_tz(&s, Tz::UTC)
+}
 
-        let s2 = &s[1..s.len() - count];
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+/// Parses a graphite `from`/`until` value, absolute dates are taken in time zone `tz`.
+pub fn time_parse_tz(s: &str, tz: Tz) -> Result<u32, ParseError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+    at_time_parse(s, tz, now)
+}
 
-        let v = now - s2.parse::<u32>()? * multi;
-        Ok(v)
-    } else {
-        // Absolute time
-        match s.as_str() {
-            "now" => Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32),
-            "yesterday" => {
-                Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32 - 3600 * 24)
+/// Parses a `tz` query parameter, e.g. `Europe/Kyiv`.
+pub fn tz_parse(s: &str) -> Result<Tz, ParseError> {
+    s.parse()
+        .map_err(|_| ParseError::Query(format!("Unknown time zone '{}'", s)))
+}
+
+/// Graphite-web `at` time: a reference such as `now`, `noon`, `20:00_20190729` or
+/// `07/29/19`, optionally followed by an offset like `-1d2h`, or a unix timestamp.
+fn at_time_parse(s: &str, tz: Tz, now: u32) -> Result<u32, ParseError> {
+    let s: String = s
+        .chars()
+        .filter(|c| !matches!(c, '_' | ',' | ' '))
+        .collect::<String>()
+        .to_lowercase();
+
+    match s.as_str() {
+        "" => return Err(ParseError::EmptyString),
+        // Kept as exactly one day ago rather than graphite's midnight of the previous day
+        "yesterday" => return Ok(now - 3600 * 24),
+        _ => {}
+    }
+
+    if s.bytes().all(|c| c.is_ascii_digit()) && !is_yyyymmdd(&s) {
+        // Unix timestamp
+        return Ok(s.parse::<u32>()?);
+    }
+
+    let (reference, offset) = match s.find(['+', '-']) {
+        Some(index) => s.split_at(index),
+        None => (s.as_str(), ""),
+    };
+
+    let time = reference_parse(reference, tz, now)? + offset_parse(offset)?;
+    u32::try_from(time).map_err(|_| ParseError::Time)
+}
+
+fn is_yyyymmdd(s: &str) -> bool {
+    s.len() == 8 && NaiveDate::parse_from_str(s, "%Y%m%d").is_ok()
+}
+
+fn number(s: &str) -> Result<u32, ParseError> {
+    s.parse::<u32>().map_err(|_| ParseError::Time)
+}
+
+/// Seconds since epoch of a time reference like `noon`, `8am_tomorrow` or `20:00_20190729`.
+fn reference_parse(reference: &str, tz: Tz, now: u32) -> Result<i64, ParseError> {
+    let now = tz
+        .timestamp_opt(i64::from(now), 0)
+        .single()
+        .ok_or(ParseError::Time)?;
+    if reference.is_empty() || reference == "now" {
+        return Ok(now.timestamp());
+    }
+
+    let mut rest = reference;
+    let (mut hour, mut minute) = (0, 0);
+
+    // HH:MM with optional am/pm
+    if let Some(index) = rest.find(':').filter(|i| (1..3).contains(i)) {
+        hour = number(&rest[..index])?;
+        minute = number(rest.get(index + 1..index + 3).ok_or(ParseError::Time)?)?;
+        rest = &rest[index + 3..];
+        if let Some(tail) = rest.strip_prefix("am") {
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("pm") {
+            hour = (hour + 12) % 24;
+            rest = tail;
+        }
+    }
+    // Xam or XXam, Xpm or XXpm
+    for (suffix, shift) in [("am", 0), ("pm", 12)] {
+        if let Some(index) = rest.find(suffix).filter(|i| (1..3).contains(i)) {
+            hour = (number(&rest[..index])? + shift) % 24;
+            rest = &rest[index + 2..];
+        }
+    }
+    for (name, named_hour) in [("noon", 12), ("midnight", 0), ("teatime", 16)] {
+        if let Some(tail) = rest.strip_prefix(name) {
+            (hour, minute) = (named_hour, 0);
+            rest = tail;
+            break;
+        }
+    }
+
+    let today = now.date_naive();
+    let date = match rest {
+        "" | "today" => today,
+        "yesterday" => today.pred_opt().ok_or(ParseError::Time)?,
+        "tomorrow" => today.succ_opt().ok_or(ParseError::Time)?,
+        _ if rest.matches('/').count() == 2 => {
+            // MM/DD/YY or MM/DD/YYYY
+            let parts: Vec<&str> = rest.split('/').collect();
+            let (month, day) = (number(parts[0])?, number(parts[1])?);
+            let mut year = number(parts[2])? as i32;
+            if year < 1900 {
+                year += 1900;
             }
-            "" => Err(ParseError::EmptyString),
-            // Unix timestamp parse as default
-            _ => {
-                // Unix timestamp
-                Ok(s.parse::<u32>()?)
+            if year < 1970 {
+                year += 100;
             }
+            NaiveDate::from_ymd_opt(year, month, day).ok_or(ParseError::Time)?
         }
+        _ if is_yyyymmdd(rest) => {
+            NaiveDate::parse_from_str(rest, "%Y%m%d").map_err(|_| ParseError::Time)?
+        }
+        // Neither a date nor a timestamp, reported as before dates were supported
+        _ => {
+            return Err(rest
+                .parse::<u32>()
+                .err()
+                .map_or(ParseError::Time, From::from));
+        }
+    };
+
+    let time = NaiveTime::from_hms_opt(hour, minute, 0).ok_or(ParseError::Time)?;
+    tz.from_local_datetime(&date.and_time(time))
+        .earliest()
+        .map(|dt: DateTime<Tz>| dt.timestamp())
+        .ok_or(ParseError::Time)
+}
+
+/// Signed offset in seconds like `-1d2h` or `+30min`.
+fn offset_parse(offset: &str) -> Result<i64, ParseError> {
+    let (sign, duration) = if let Some(duration) = offset.strip_prefix('-') {
+        (-1, duration)
+    } else if let Some(duration) = offset.strip_prefix('+') {
+        (1, duration)
+    } else if offset.is_empty() {
+        return Ok(0);
+    } else {
+        return Err(ParseError::Time);
+    };
+    Ok(sign * i64::from(duration_parse(duration)?))
+}
+
+/// Parses a duration like `30s`, `5min`, `2w` or `1d12h` into seconds.
+pub fn duration_parse(s: &str) -> Result<u32, ParseError> {
+    let mut rest = s;
+    let mut total: u32 = 0;
+    loop {
+        let unit_start = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or(ParseError::Time)?;
+        let (count, tail) = rest.split_at(unit_start);
+        let unit_end = tail
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_end);
+
+        let multi = if unit.starts_with('s') {
+            1
+        } else if unit.starts_with("min") {
+            60
+        } else if unit.starts_with('h') {
+            3600
+        } else if unit.starts_with('d') {
+            3600 * 24
+        } else if unit.starts_with('w') {
+            3600 * 24 * 7
+        } else if unit.starts_with("mon") {
+            3600 * 24 * 30
+        } else if unit.starts_with('y') {
+            3600 * 24 * 365
+        } else {
+            return Err(ParseError::Time);
+        };
+
+        total = count
+            .parse::<u32>()?
+            .checked_mul(multi)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or(ParseError::Time)?;
+
+        if tail.is_empty() {
+            return Ok(total);
+        }
+        rest = tail;
     }
 }
 
@@ -49,6 +205,13 @@ pub fn time_parse(s: String) -> Result<u32, ParseError> {
 mod tests {
     use super::*;
 
+    // 2019-07-29 20:43:08 UTC, a Monday
+    const NOW: u32 = 1_564_432_988;
+
+    fn utc(s: &str) -> Result<u32, ParseError> {
+        at_time_parse(s, Tz::UTC, NOW)
+    }
+
     #[test]
     fn test_de_time_parse_ok() {
         let mut de = serde_json::Deserializer::new(serde_json::de::StrRead::new("\"123\""));
@@ -62,4 +225,77 @@ mod tests {
         let err = de_time_parse(&mut de).unwrap_err();
         assert_eq!(err.to_string().as_str(), "invalid digit found in string");
     }
+
+    #[test]
+    fn test_de_time_parse_invalid_date() {
+        let mut de = serde_json::Deserializer::new(serde_json::de::StrRead::new("\"02/30/19\""));
+        let err = de_time_parse(&mut de).unwrap_err();
+        assert_eq!(err.to_string().as_str(), "Time cannot be parsed");
+    }
+
+    #[test]
+    fn test_duration_parse() {
+        assert_eq!(duration_parse("30s"), Ok(30));
+        assert_eq!(duration_parse("5min"), Ok(300));
+        assert_eq!(duration_parse("2h"), Ok(7200));
+        assert_eq!(duration_parse("1d"), Ok(86400));
+        assert_eq!(duration_parse("1d2h"), Ok(86400 + 7200));
+        assert_eq!(duration_parse("3hours15minutes"), Ok(3 * 3600 + 15 * 60));
+        assert_eq!(duration_parse("5"), Err(ParseError::Time));
+        assert_eq!(duration_parse("5x"), Err(ParseError::Time));
+        assert_eq!(duration_parse("1d2"), Err(ParseError::Time));
+        assert!(duration_parse("min").is_err());
+    }
+
+    #[test]
+    fn test_relative() {
+        assert_eq!(utc("now"), Ok(NOW));
+        assert_eq!(utc("-5min"), Ok(NOW - 300));
+        assert_eq!(utc("now-5min"), Ok(NOW - 300));
+        assert_eq!(utc("-1d2h"), Ok(NOW - 86400 - 7200));
+        assert_eq!(utc("now+1h"), Ok(NOW + 3600));
+        assert_eq!(utc("yesterday"), Ok(NOW - 86400));
+        assert_eq!(utc("1564432988"), Ok(NOW));
+    }
+
+    #[test]
+    fn test_named_references() {
+        let midnight = 1_564_358_400; // 2019-07-29 00:00:00 UTC
+        assert_eq!(utc("today"), Ok(midnight));
+        assert_eq!(utc("midnight"), Ok(midnight));
+        assert_eq!(utc("tomorrow"), Ok(midnight + 86400));
+        assert_eq!(utc("noon"), Ok(midnight + 12 * 3600));
+        assert_eq!(utc("teatime"), Ok(midnight + 16 * 3600));
+        assert_eq!(utc("noon+1d"), Ok(midnight + 36 * 3600));
+        assert_eq!(utc("midnight_yesterday"), Ok(midnight - 86400));
+        assert_eq!(utc("8am_tomorrow"), Ok(midnight + 32 * 3600));
+        assert_eq!(utc("3pm"), Ok(midnight + 15 * 3600));
+        assert_eq!(utc("10:30pm"), Ok(midnight + 22 * 3600 + 1800));
+    }
+
+    #[test]
+    fn test_absolute() {
+        assert_eq!(utc("20190729"), Ok(1_564_358_400));
+        assert_eq!(utc("20:43_20190729"), Ok(1_564_432_980));
+        assert_eq!(utc("07/29/19"), Ok(1_564_358_400));
+        assert_eq!(utc("07/29/2019"), Ok(1_564_358_400));
+        assert_eq!(utc("12/31/69"), Ok(3_155_673_600));
+        assert_eq!(utc("20190729-1h"), Ok(1_564_358_400 - 3600));
+        assert_eq!(utc("02/30/19"), Err(ParseError::Time));
+        assert!(matches!(utc("lol"), Err(ParseError::ParseIntError(_))));
+        assert_eq!(utc(""), Err(ParseError::EmptyString));
+    }
+
+    #[test]
+    fn test_time_zone() {
+        let kyiv = tz_parse("Europe/Kyiv").unwrap();
+        assert_eq!(
+            at_time_parse("20:43_20190729", kyiv, NOW),
+            Ok(1_564_432_980 - 3 * 3600)
+        );
+        // Relative times do not depend on the time zone
+        assert_eq!(at_time_parse("-1h", kyiv, NOW), Ok(NOW - 3600));
+        assert_eq!(at_time_parse("1564432988", kyiv, NOW), Ok(NOW));
+        assert!(tz_parse("Mars/Olympus").is_err());
+    }
 }
@@ -1,8 +1,7 @@
-use chrono::DateTime;
-
 use actix_web::error::ErrorInternalServerError;
 use actix_web::web::{Data, Json};
 use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse, dev};
+use chrono_tz::Tz;
 use futures::future::{FutureExt, LocalBoxFuture, ready};
 use serde::*;
 use std::fmt::{Display, Formatter};
@@ -13,18 +12,60 @@ use whisper::interval::Interval;
 
 use crate::context::Context;
 use crate::error::{ParseError, ResponseError};
-use crate::parse::{de_time_parse, time_parse};
-use crate::render_target::{Expression, PathExpression};
+use crate::functions::{EvalContext, consolidate, evaluate};
+use crate::graph::{self, GraphOptions, Plot};
+use crate::parse::{time_parse_tz, tz_parse};
+use crate::render_target::Expression;
+use crate::response_format;
 use crate::storage::RenderPoint;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+#[serde(try_from = "RawRenderQuery")]
 pub struct RenderQuery {
     target: Vec<String>,
     format: RenderFormat,
-    #[serde(deserialize_with = "de_time_parse")]
     from: u32,
-    #[serde(deserialize_with = "de_time_parse")]
     until: u32,
+    #[serde(rename = "maxDataPoints", default)]
+    max_data_points: Option<usize>,
+    #[serde(flatten)]
+    graph: GraphOptions,
+}
+
+/// Render query as sent in a JSON body, `from` and `until` depend on `tz`.
+#[derive(Deserialize)]
+struct RawRenderQuery {
+    target: Vec<String>,
+    format: RenderFormat,
+    from: String,
+    until: String,
+    #[serde(rename = "maxDataPoints", default)]
+    max_data_points: Option<usize>,
+    #[serde(default)]
+    tz: Option<String>,
+    #[serde(flatten)]
+    graph: GraphOptions,
+}
+
+impl TryFrom<RawRenderQuery> for RenderQuery {
+    type Error = ParseError;
+
+    fn try_from(raw: RawRenderQuery) -> Result<Self, Self::Error> {
+        let tz = raw
+            .tz
+            .as_deref()
+            .map(tz_parse)
+            .transpose()?
+            .unwrap_or(Tz::UTC);
+        Ok(RenderQuery {
+            target: raw.target,
+            format: raw.format,
+            from: time_parse_tz(&raw.from, tz)?,
+            until: time_parse_tz(&raw.until, tz)?,
+            max_data_points: raw.max_data_points,
+            graph: raw.graph,
+        })
+    }
 }
 
 impl FromStr for RenderQuery {
@@ -34,15 +75,27 @@ impl FromStr for RenderQuery {
         let raw: Vec<(String, String)> = serde_urlencoded::from_str(s)?;
 
         let mut q = RenderQuery::default();
+        let mut from = None;
+        let mut until = None;
+        let mut tz = Tz::UTC;
         for (key, value) in raw {
             match key.as_str() {
                 "target" => q.target.push(value),
                 "format" => q.format = value.parse()?,
-                "from" => q.from = time_parse(value)?,
-                "until" => q.until = time_parse(value)?,
-                _ => {}
+                "from" => from = Some(value),
+                "until" => until = Some(value),
+                "tz" => tz = tz_parse(&value)?,
+                "maxDataPoints" => q.max_data_points = Some(value.parse()?),
+                _ => q.graph.set(&key, value)?,
             };
         }
+        // Time zone may come after the times it applies to
+        if let Some(from) = from {
+            q.from = time_parse_tz(&from, tz)?;
+        }
+        if let Some(until) = until {
+            q.until = time_parse_tz(&until, tz)?;
+        }
 
         Ok(q)
     }
@@ -78,6 +131,8 @@ pub enum RenderFormat {
     Pdf,
     Dygraph,
     Rickshaw,
+    Pickle,
+    Msgpack,
 }
 
 impl FromStr for RenderFormat {
@@ -93,6 +148,8 @@ impl FromStr for RenderFormat {
             "pdf" => Ok(RenderFormat::Pdf),
             "dygraph" => Ok(RenderFormat::Dygraph),
             "rickshaw" => Ok(RenderFormat::Rickshaw),
+            "pickle" => Ok(RenderFormat::Pickle),
+            "msgpack" => Ok(RenderFormat::Msgpack),
             _ => Err(ParseError::RenderFormat),
         }
     }
@@ -113,8 +170,8 @@ pub struct RenderPath(PathBuf);
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RenderResponseEntry {
-    target: String,
-    datapoints: Vec<RenderPoint>,
+    pub(crate) target: String,
+    pub(crate) datapoints: Vec<RenderPoint>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -132,76 +189,109 @@ pub async fn render_handler(
         .expect("Time travel beyond Unix epoch is forbidden by Temporal Police.")
         .as_secs();
     let format = query.format;
-
-    let mut response: Vec<RenderResponseEntry> = Vec::new();
-
-    for target in query.target {
-        let expression = Expression::from_str(&target).map_err(ErrorInternalServerError)?;
-        let path_expression: &PathExpression = match expression {
-            Expression::Path(ref e) => e,
-            _ => {
-                return Err(ErrorInternalServerError(format!(
-                    "Unsupported type of query: {}. For now only path expressions are supported",
-                    target
-                )));
+    let graph_options = query.graph;
+
+    let expressions = query
+        .target
+        .iter()
+        .map(|target| Expression::from_str(target))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ErrorInternalServerError)?;
+    let max_data_points = query.max_data_points;
+
+    let response = ctx
+        .with_storage(move |storage| {
+            let eval_ctx = EvalContext {
+                storage,
+                interval,
+                now,
+            };
+            let mut response: Vec<RenderResponseEntry> = Vec::new();
+            for expression in &expressions {
+                for mut series in evaluate(expression, &eval_ctx)? {
+                    if let Some(max_data_points) = max_data_points {
+                        series = consolidate(series, max_data_points);
+                    }
+                    response.push(RenderResponseEntry {
+                        target: series.name,
+                        datapoints: series.points,
+                    });
+                }
             }
-        };
-
-        let storage_responses = ctx.storage.query(path_expression, interval, now)?;
-
-        for storage_response in storage_responses {
-            response.push(RenderResponseEntry {
-                target: storage_response.name.0.join("."),
-                datapoints: storage_response.data,
-            });
-        }
-    }
-
-    Ok(format_response(response, format))
-}
+            Ok(response)
+        })
+        .await?;
 
-// TODO: Extract code BELOW to response_format_csv module
-trait IntoCsv {
-    fn into_csv(self) -> String;
+    Ok(format_response(response, format, &graph_options))
 }
 
-impl<T: IntoCsv> IntoCsv for Vec<T> {
-    fn into_csv(self) -> String {
-        self.into_iter()
-            .map(|item| item.into_csv())
-            .collect::<Vec<String>>()
-            .join("\n")
-            + "\n"
-    }
-}
-
-impl IntoCsv for RenderResponseEntry {
-    fn into_csv(self) -> String {
-        let metric = self.target;
-        let lines: Vec<String> = self
-            .datapoints
-            .into_iter()
-            .map(|point| {
-                let RenderPoint(val, ts) = point;
-                let v = val.map(|f| format!("{}", f)).unwrap_or_default();
-                let t = DateTime::from_timestamp(i64::from(ts), 0)
-                    .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
-                    .unwrap_or_else(|| ts.to_string());
-                // TODO: Use `csv` crate instead of "manual" string formatting
-                format!("{},{},{}", metric, t, v)
-            })
-            .collect();
-        lines.join("\n")
+fn render_graph(
+    response: &[RenderResponseEntry],
+    options: &GraphOptions,
+    format: &RenderFormat,
+) -> Result<Vec<u8>, String> {
+    let plots: Vec<Plot> = response
+        .iter()
+        .map(|entry| Plot {
+            name: &entry.target,
+            points: &entry.datapoints,
+        })
+        .collect();
+    let scene = graph::draw(&plots, options)?;
+    match format {
+        RenderFormat::Svg => Ok(graph::to_svg(&scene).into_bytes()),
+        _ => graph::to_png(&scene),
     }
 }
-// TODO: Extract code ABOVE to response_format_csv module
 
-fn format_response(response: Vec<RenderResponseEntry>, format: RenderFormat) -> HttpResponse {
+fn format_response(
+    response: Vec<RenderResponseEntry>,
+    format: RenderFormat,
+    graph_options: &GraphOptions,
+) -> HttpResponse {
     match format {
         RenderFormat::Json => HttpResponse::Ok().json(response),
         RenderFormat::Csv => HttpResponse::Ok()
             .content_type("text/csv")
-            .body(response.into_csv()),
+            .body(response_format::to_csv(&response)),
+        RenderFormat::Raw => HttpResponse::Ok()
+            .content_type("text/plain")
+            .body(response_format::to_raw(&response)),
+        RenderFormat::Pickle => HttpResponse::Ok()
+            .content_type("application/pickle")
+            .body(response_format::to_pickle(&response)),
+        RenderFormat::Dygraph => match response_format::to_dygraph(&response) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type("application/json")
+                .body(body),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        },
+        RenderFormat::Rickshaw => match response_format::to_rickshaw(&response) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type("application/json")
+                .body(body),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        },
+        RenderFormat::Msgpack => match response_format::to_msgpack(&response) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type("application/x-msgpack")
+                .body(body),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        },
+        RenderFormat::Png | RenderFormat::Svg => {
+            match render_graph(&response, graph_options, &format) {
+                Ok(image) => HttpResponse::Ok()
+                    .content_type(if format == RenderFormat::Png {
+                        "image/png"
+                    } else {
+                        "image/svg+xml"
+                    })
+                    .body(image),
+                Err(e) => HttpResponse::BadRequest()
+                    .content_type("text/plain")
+                    .body(e),
+            }
+        }
         _ => HttpResponse::BadRequest()
             .content_type("text/plain")
             .body(format!("Format '{}' not supported", format)),
@@ -211,6 +301,7 @@ fn format_response(response: Vec<RenderResponseEntry>, format: RenderFormat) ->
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::graph::AreaMode;
     use crate::opts::Args;
     use crate::test_utils::ConstStorage;
 
@@ -221,7 +312,10 @@ mod tests {
     use std::sync::Arc;
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    async fn render_response(ctx: Context, query: RenderQuery) -> (StatusCode, String, String) {
+    async fn render_response_bytes(
+        ctx: Context,
+        query: RenderQuery,
+    ) -> (StatusCode, String, Vec<u8>) {
         let response: HttpResponse = render_handler(Data::new(ctx), query).await.ok().unwrap();
         let content_type: String = response
             .head()
@@ -236,29 +330,27 @@ mod tests {
 
         let body = to_bytes(response.into_body()).await.unwrap();
 
-        (
-            status,
-            content_type,
-            String::from_utf8(body.to_vec()).unwrap(),
-        )
+        (status, content_type, body.to_vec())
+    }
+
+    async fn render_response(ctx: Context, query: RenderQuery) -> (StatusCode, String, String) {
+        let (status, content_type, body) = render_response_bytes(ctx, query).await;
+        (status, content_type, String::from_utf8(body).unwrap())
     }
 
     #[actix_rt::test]
     async fn render_handler_unsupported() {
-        let formats: Vec<RenderFormat> = vec![
-            RenderFormat::Png,
-            RenderFormat::Raw,
-            RenderFormat::Svg,
-            RenderFormat::Pdf,
-            RenderFormat::Dygraph,
-            RenderFormat::Rickshaw,
-        ];
+        let formats: Vec<RenderFormat> = vec![RenderFormat::Pdf];
         for format in formats {
             let ctx = Context {
                 args: Args {
                     path: PathBuf::new(),
                     force: false,
                     port: 0,
+                    carbonlink: vec![],
+                    carbonlink_timeout: 1000,
+                    header_cache: 0,
+                    lock: Default::default(),
                 },
                 storage: Arc::new(ConstStorage(vec![])),
             };
@@ -267,6 +359,8 @@ mod tests {
                 format: format.clone(),
                 from: 0,
                 until: 0,
+                max_data_points: None,
+                graph: GraphOptions::default(),
             };
             let (status, ct, response) = render_response(ctx, query).await;
             assert_eq!(status, StatusCode::BAD_REQUEST);
@@ -282,6 +376,10 @@ mod tests {
                 path: PathBuf::new(),
                 force: false,
                 port: 0,
+                carbonlink: vec![],
+                carbonlink_timeout: 1000,
+                header_cache: 0,
+                lock: Default::default(),
             },
             storage: Arc::new(ConstStorage(vec![])),
         };
@@ -290,6 +388,8 @@ mod tests {
             format: RenderFormat::Json,
             from: 0,
             until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
         let (status, ct, response) = render_response(ctx, query).await;
         assert_eq!(status, StatusCode::OK);
@@ -305,6 +405,10 @@ mod tests {
                 path: PathBuf::new(),
                 force: false,
                 port: 0,
+                carbonlink: vec![],
+                carbonlink_timeout: 1000,
+                header_cache: 0,
+                lock: Default::default(),
             },
             storage: Arc::new(ConstStorage(vec![
                 RenderPoint(Some(1.0_f64), t),
@@ -318,6 +422,8 @@ mod tests {
             format: RenderFormat::Json,
             from: 0,
             until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
         let (status, ct, response) = render_response(ctx, query).await;
         assert_eq!(status, StatusCode::OK);
@@ -328,6 +434,82 @@ mod tests {
         )
     }
 
+    #[actix_rt::test]
+    async fn render_handler_json_function() {
+        let t = 1_564_432_988;
+        let ctx = Context {
+            args: Args {
+                path: PathBuf::new(),
+                force: false,
+                port: 0,
+                carbonlink: vec![],
+                carbonlink_timeout: 1000,
+                header_cache: 0,
+                lock: Default::default(),
+            },
+            storage: Arc::new(ConstStorage(vec![
+                RenderPoint(Some(1.0_f64), t),
+                RenderPoint(None, t + 10),
+                RenderPoint(Some(3.0_f64), t + 20),
+            ])),
+        };
+        let query = RenderQuery {
+            target: vec!["aliasByNode(scale(i.am.a.metric,2),1)".to_owned()],
+            format: RenderFormat::Json,
+            from: 0,
+            until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
+        };
+        let (status, ct, response) = render_response(ctx, query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ct, "application/json");
+        assert_eq!(
+            response,
+            "[{\"target\":\"am\",\"datapoints\":[[2.0,1564432988],[null,1564432998],[6.0,1564433008]]}]"
+        )
+    }
+
+    #[actix_rt::test]
+    async fn render_handler_json_max_data_points() {
+        let t = 1_564_432_980;
+        let ctx = Context {
+            args: Args {
+                path: PathBuf::new(),
+                force: false,
+                port: 0,
+                carbonlink: vec![],
+                carbonlink_timeout: 1000,
+                header_cache: 0,
+                lock: Default::default(),
+            },
+            storage: Arc::new(ConstStorage(vec![
+                RenderPoint(Some(1.0_f64), t),
+                RenderPoint(Some(3.0_f64), t + 10),
+                RenderPoint(Some(5.0_f64), t + 20),
+                RenderPoint(None, t + 30),
+            ])),
+        };
+        let query = RenderQuery {
+            target: vec![
+                "i.am.a.metric".to_owned(),
+                "consolidateBy(i.am.a.metric,'max')".to_owned(),
+            ],
+            format: RenderFormat::Json,
+            from: 0,
+            until: 0,
+            max_data_points: Some(2),
+            graph: GraphOptions::default(),
+        };
+        let (status, _, response) = render_response(ctx, query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            response,
+            "[{\"target\":\"i.am.a.metric\",\"datapoints\":[[2.0,1564432980],[5.0,1564433000]]},\
+              {\"target\":\"consolidateBy(i.am.a.metric,\\\"max\\\")\",\"datapoints\":[[3.0,1564432980],[5.0,1564433000]]}]"
+        )
+    }
+
     #[actix_rt::test]
     async fn render_handler_csv_ok_empty() {
         let ctx = Context {
@@ -335,6 +517,10 @@ mod tests {
                 path: PathBuf::new(),
                 force: false,
                 port: 0,
+                carbonlink: vec![],
+                carbonlink_timeout: 1000,
+                header_cache: 0,
+                lock: Default::default(),
             },
             storage: Arc::new(ConstStorage(vec![])),
         };
@@ -343,6 +529,8 @@ mod tests {
             format: RenderFormat::Csv,
             from: 0,
             until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
         let (status, ct, response) = render_response(ctx, query).await;
         assert_eq!(status, StatusCode::OK);
@@ -358,6 +546,10 @@ mod tests {
                 path: PathBuf::new(),
                 force: false,
                 port: 0,
+                carbonlink: vec![],
+                carbonlink_timeout: 1000,
+                header_cache: 0,
+                lock: Default::default(),
             },
             storage: Arc::new(ConstStorage(vec![
                 RenderPoint(Some(1.1_f64), t),
@@ -371,6 +563,8 @@ mod tests {
             format: RenderFormat::Csv,
             from: 0,
             until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
         let (status, ct, response) = render_response(ctx, query).await;
         assert_eq!(status, StatusCode::OK);
@@ -381,6 +575,180 @@ mod tests {
         )
     }
 
+    fn graph_context() -> Context {
+        let t = 1_564_432_980;
+        Context {
+            args: Args {
+                path: PathBuf::new(),
+                force: false,
+                port: 0,
+                carbonlink: vec![],
+                carbonlink_timeout: 1000,
+                header_cache: 0,
+                lock: Default::default(),
+            },
+            storage: Arc::new(ConstStorage(vec![
+                RenderPoint(Some(1.0_f64), t),
+                RenderPoint(None, t + 60),
+                RenderPoint(Some(3.0_f64), t + 120),
+                RenderPoint(Some(2.0_f64), t + 180),
+            ])),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn render_handler_png() {
+        let query = RenderQuery {
+            target: vec!["i.am.a.metric".to_owned()],
+            format: RenderFormat::Png,
+            from: 0,
+            until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
+        };
+        let (status, ct, response) = render_response_bytes(graph_context(), query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ct, "image/png");
+        assert_eq!(&response[..8], b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[actix_rt::test]
+    async fn render_handler_svg() {
+        let query = RenderQuery {
+            target: vec!["i.am.a.metric".to_owned()],
+            format: RenderFormat::Svg,
+            from: 0,
+            until: 0,
+            max_data_points: None,
+            graph: GraphOptions {
+                title: Some("Users & servers".to_owned()),
+                area_mode: AreaMode::Stacked,
+                ..GraphOptions::default()
+            },
+        };
+        let (status, ct, response) = render_response(graph_context(), query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ct, "image/svg+xml");
+        assert!(response.starts_with("<svg"));
+        assert!(response.contains(">Users &amp; servers</text>"));
+        assert!(response.contains(">i.am.a.metric</text>"));
+        assert!(response.contains("<polygon"));
+    }
+
+    #[actix_rt::test]
+    async fn render_handler_svg_bad_color() {
+        let query = RenderQuery {
+            target: vec!["i.am.a.metric".to_owned()],
+            format: RenderFormat::Svg,
+            from: 0,
+            until: 0,
+            max_data_points: None,
+            graph: GraphOptions {
+                color_list: "red,nocolor".to_owned(),
+                ..GraphOptions::default()
+            },
+        };
+        let (status, ct, response) = render_response(graph_context(), query).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(ct, "text/plain");
+        assert_eq!(response, "Unknown color 'nocolor'");
+    }
+
+    #[actix_rt::test]
+    async fn render_handler_raw() {
+        let query = RenderQuery {
+            target: vec!["i.am.a.metric".to_owned()],
+            format: RenderFormat::Raw,
+            from: 0,
+            until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
+        };
+        let (status, ct, response) = render_response(graph_context(), query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ct, "text/plain");
+        assert_eq!(
+            response,
+            "i.am.a.metric,1564432980,1564433220,60|1.0,None,3.0,2.0\n"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn render_handler_pickle() {
+        let query = RenderQuery {
+            target: vec!["i.am.a.metric".to_owned()],
+            format: RenderFormat::Pickle,
+            from: 0,
+            until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
+        };
+        let (status, ct, response) = render_response_bytes(graph_context(), query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ct, "application/pickle");
+        assert_eq!(
+            response,
+            include_bytes!("../tests/golden/render.pickle").to_vec()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn render_handler_msgpack() {
+        let query = RenderQuery {
+            target: vec!["i.am.a.metric".to_owned()],
+            format: RenderFormat::Msgpack,
+            from: 0,
+            until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
+        };
+        let (status, ct, response) = render_response_bytes(graph_context(), query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ct, "application/x-msgpack");
+        assert_eq!(
+            response,
+            include_bytes!("../tests/golden/render.msgpack").to_vec()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn render_handler_dygraph() {
+        let query = RenderQuery {
+            target: vec!["i.am.a.metric".to_owned()],
+            format: RenderFormat::Dygraph,
+            from: 0,
+            until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
+        };
+        let (status, ct, response) = render_response(graph_context(), query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ct, "application/json");
+        assert_eq!(
+            response,
+            r#"{"labels":["Time","i.am.a.metric"],"data":[[1564432980000,1.0],[1564433040000,null],[1564433100000,3.0],[1564433160000,2.0]]}"#
+        );
+    }
+
+    #[actix_rt::test]
+    async fn render_handler_rickshaw() {
+        let query = RenderQuery {
+            target: vec!["i.am.a.metric".to_owned()],
+            format: RenderFormat::Rickshaw,
+            from: 0,
+            until: 0,
+            max_data_points: None,
+            graph: GraphOptions::default(),
+        };
+        let (status, ct, response) = render_response(graph_context(), query).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ct, "application/json");
+        assert_eq!(
+            response,
+            r#"[{"target":"i.am.a.metric","datapoints":[{"x":1564432980,"y":1.0},{"x":1564433040,"y":null},{"x":1564433100,"y":3.0},{"x":1564433160,"y":2.0}]}]"#
+        );
+    }
+
     #[test]
     fn url_deserialize_one() -> Result<(), ParseError> {
         let params = RenderQuery {
@@ -388,6 +756,8 @@ mod tests {
             target: ["app.numUsers".to_owned()].to_vec(),
             from: 0,
             until: 10,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(
@@ -405,6 +775,8 @@ mod tests {
             target: ["app.numUsers".to_owned(), "app.numServers".to_owned()].to_vec(),
             from: 0,
             until: 10,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(
@@ -423,6 +795,8 @@ mod tests {
             target: Vec::new(),
             from: 0,
             until: 10,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(
@@ -440,6 +814,8 @@ mod tests {
             target: vec!["m1".to_owned()],
             from: 0,
             until: 10,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(
@@ -450,6 +826,64 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn url_deserialize_max_data_points() -> Result<(), ParseError> {
+        let params = RenderQuery {
+            format: RenderFormat::Json,
+            target: vec!["m1".to_owned()],
+            from: 0,
+            until: 10,
+            max_data_points: Some(500),
+            graph: GraphOptions::default(),
+        };
+
+        assert_eq!(
+            "target=m1&format=json&from=0&until=10&maxDataPoints=500".parse::<RenderQuery>()?,
+            params
+        );
+
+        assert!(
+            "target=m1&format=json&from=0&until=10&maxDataPoints=many"
+                .parse::<RenderQuery>()
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn url_deserialize_graph_options() -> Result<(), ParseError> {
+        let params = RenderQuery {
+            format: RenderFormat::Png,
+            target: vec!["m1".to_owned()],
+            from: 0,
+            until: 10,
+            max_data_points: None,
+            graph: GraphOptions {
+                width: 800,
+                height: 600,
+                title: Some("CPU".to_owned()),
+                area_mode: AreaMode::Stacked,
+                y_min: Some(0.0),
+                ..GraphOptions::default()
+            },
+        };
+
+        assert_eq!(
+            "target=m1&format=png&from=0&until=10&width=800&height=600&title=CPU&areaMode=stacked&yMin=0"
+                .parse::<RenderQuery>()?,
+            params
+        );
+
+        assert!(
+            "target=m1&format=png&from=0&until=10&width=wide"
+                .parse::<RenderQuery>()
+                .is_err()
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn url_deserialize_time_yesterday_now() -> Result<(), ParseError> {
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
@@ -459,6 +893,8 @@ mod tests {
             target: ["m1".to_owned(), "m2".to_owned()].to_vec(),
             from: now - 3600 * 24,
             until: now,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(
@@ -478,6 +914,8 @@ mod tests {
             target: ["m1".to_owned(), "m2".to_owned()].to_vec(),
             from: now - 5 * 3600 * 24,
             until: now - 5 * 60,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(
@@ -490,6 +928,8 @@ mod tests {
             target: ["m1".to_owned(), "m2".to_owned()].to_vec(),
             from: now - 5 * 3600 * 24 * 7,
             until: now - 5,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(
@@ -502,6 +942,8 @@ mod tests {
             target: ["m1".to_owned(), "m2".to_owned()].to_vec(),
             from: now - 2 * 3600 * 24 * 365,
             until: now - 5 * 3600,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(
@@ -514,6 +956,8 @@ mod tests {
             target: ["m1".to_owned(), "m2".to_owned()].to_vec(),
             from: now - 5 * 3600 * 24 * 30,
             until: now - 60,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(
@@ -524,6 +968,76 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn url_deserialize_time_absolute() -> Result<(), ParseError> {
+        let params = RenderQuery {
+            format: RenderFormat::Json,
+            target: vec!["m1".to_owned()],
+            from: 1_564_358_400,
+            until: 1_564_432_980,
+            max_data_points: None,
+            graph: GraphOptions::default(),
+        };
+
+        assert_eq!(
+            "target=m1&format=json&from=20190729&until=20:43_20190729".parse::<RenderQuery>()?,
+            params
+        );
+        assert_eq!(
+            "target=m1&format=json&from=07/29/19&until=20190729%2B20h43min"
+                .parse::<RenderQuery>()?,
+            params
+        );
+
+        let kyiv = RenderQuery {
+            from: 1_564_358_400 - 3 * 3600,
+            until: 1_564_432_980 - 3 * 3600,
+            ..params
+        };
+        assert_eq!(
+            "target=m1&format=json&from=20190729&until=20:43_20190729&tz=Europe/Kyiv"
+                .parse::<RenderQuery>()?,
+            kyiv
+        );
+        assert!(
+            "target=m1&format=json&from=20190729&until=now&tz=Nowhere"
+                .parse::<RenderQuery>()
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_deserialize_time_zone() {
+        let query: RenderQuery = serde_json::from_str(
+            r#"{"target":["m1"],"format":"svg","from":"20190729","until":"1564432980","tz":"Europe/Kyiv","width":100}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            query,
+            RenderQuery {
+                format: RenderFormat::Svg,
+                target: vec!["m1".to_owned()],
+                from: 1_564_358_400 - 3 * 3600,
+                until: 1_564_432_980,
+                max_data_points: None,
+                graph: GraphOptions {
+                    width: 100,
+                    ..GraphOptions::default()
+                },
+            }
+        );
+
+        assert!(
+            serde_json::from_str::<RenderQuery>(
+                r#"{"target":["m1"],"format":"json","from":"someday","until":"now"}"#
+            )
+            .is_err()
+        );
+    }
+
     #[test]
     fn url_deserialize_time_fail() -> Result<(), ParseError> {
         assert!(
@@ -545,7 +1059,7 @@ mod tests {
         );
 
         assert!(
-            "target=m1&target=m2&format=json&from=tomorrow&until=now"
+            "target=m1&target=m2&format=json&from=someday&until=now"
                 .parse::<RenderQuery>()
                 .is_err()
         );
@@ -621,6 +1135,8 @@ mod tests {
             target: ["app.numUsers".to_owned()].to_vec(),
             from: 0,
             until: 10,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(RenderQuery::extract(&req).await?, params);
@@ -642,6 +1158,8 @@ mod tests {
             target: ["app.numUsers".to_owned()].to_vec(),
             from: 0,
             until: 10,
+            max_data_points: None,
+            graph: GraphOptions::default(),
         };
 
         let res = RenderQuery::from_request(&req, &mut payload).await?;
@@ -666,6 +1184,32 @@ mod tests {
             target: ["app.numUsers".to_owned()].to_vec(),
             from: 0,
             until: 10,
+            max_data_points: None,
+            graph: GraphOptions::default(),
+        };
+
+        assert_eq!(RenderQuery::from_request(&req, &mut pl).await?, params);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn render_request_parse_json_max_data_points() -> Result<(), actix_web::Error> {
+        let s = r#"{ "target":["app.numUsers"],"format":"json","from":"0","until":"10","maxDataPoints":100}"#;
+
+        let (req, mut pl) = TestRequest::with_uri("/render")
+            .insert_header(("content-type", "application/json"))
+            .insert_header((CONTENT_LENGTH, s.len()))
+            .set_payload(s)
+            //.to_http_request();
+            .to_http_parts();
+
+        let params = RenderQuery {
+            format: RenderFormat::Json,
+            target: ["app.numUsers".to_owned()].to_vec(),
+            from: 0,
+            until: 10,
+            max_data_points: Some(100),
+            graph: GraphOptions::default(),
         };
 
         assert_eq!(RenderQuery::from_request(&req, &mut pl).await?, params);
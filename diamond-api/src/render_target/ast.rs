@@ -3,7 +3,7 @@ use std::fmt;
 
 // Literal
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum LiteralValue {
     Boolean(bool),
     Integer(i64),
@@ -12,9 +12,21 @@ pub enum LiteralValue {
     None,
 }
 
+impl fmt::Display for LiteralValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LiteralValue::Boolean(b) => write!(f, "{}", b),
+            LiteralValue::Integer(i) => write!(f, "{}", i),
+            LiteralValue::Float(v) => write!(f, "{}", v),
+            LiteralValue::String(s) => write!(f, "\"{}\"", s),
+            LiteralValue::None => write!(f, "None"),
+        }
+    }
+}
+
 // Path expression
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PathElement {
     Variable(String),
     Partial(String),
@@ -23,7 +35,7 @@ pub enum PathElement {
     Enum(Vec<String>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PathWord(pub Vec<PathElement>);
 
 impl PathWord {
@@ -74,7 +86,7 @@ impl PathWord {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PathExpression(pub Vec<PathWord>);
 
 impl fmt::Display for PathExpression {
@@ -114,28 +126,56 @@ impl fmt::Display for PathExpression {
 
 // Call
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Call {
     pub function: String,
     pub args: Vec<Arg>,
     pub named_args: Vec<(String, Arg)>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Arg {
     Literal(LiteralValue),
     Expression(Expression),
 }
 
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}(", self.function)?;
+        for (index, arg) in self.args.iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", arg)?;
+        }
+        for (index, (name, arg)) in self.named_args.iter().enumerate() {
+            if index > 0 || !self.args.is_empty() {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", name, arg)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arg::Literal(literal) => write!(f, "{}", literal),
+            Arg::Expression(expression) => write!(f, "{}", expression),
+        }
+    }
+}
+
 // Template
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Source {
     Call(Call),
     Path(PathExpression),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Template {
     pub source: Source,
     pub args: Vec<LiteralValue>,
@@ -144,9 +184,41 @@ pub struct Template {
 
 // Expression
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Path(PathExpression),
     Call(Call),
     Template(Template),
 }
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Call(call) => write!(f, "{}", call),
+            Source::Path(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "template({}", self.source)?;
+        for arg in &self.args {
+            write!(f, ",{}", arg)?;
+        }
+        for (name, arg) in &self.named_args {
+            write!(f, ",{}={}", name, arg)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Path(path) => write!(f, "{}", path),
+            Expression::Call(call) => write!(f, "{}", call),
+            Expression::Template(template) => write!(f, "{}", template),
+        }
+    }
+}
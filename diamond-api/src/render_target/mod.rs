@@ -1,5 +1,6 @@
 pub mod ast;
 mod parser;
+mod template;
 
 pub use ast::*;
 
@@ -183,6 +183,15 @@ fn partial_path_element(input: &str) -> IResult<&str, String> {
     }
 }
 
+fn variable_name(input: &str) -> IResult<&str, String> {
+    let (input, name) = recognize(many1(one_of(
+        "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_",
+    )))
+    .parse(input)?;
+
+    Ok((input, name.to_owned()))
+}
+
 fn path_element_enum(input: &str) -> IResult<&str, Vec<String>> {
     delimited(
         c('{'),
@@ -239,10 +248,7 @@ fn path_element(input: &str) -> IResult<&str, PathElement> {
         map(path_element_enum, PathElement::Enum),
         map(path_element_group, PathElement::OneOf),
         map(c('*'), |_| PathElement::Asterisk),
-        map(
-            preceded(c('$'), partial_path_element),
-            PathElement::Variable,
-        ),
+        map(preceded(c('$'), variable_name), PathElement::Variable),
         map(partial_path_element, PathElement::Partial),
     ))
     .parse(input)
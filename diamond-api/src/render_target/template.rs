@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use super::ast::*;
+
+pub type Bindings = HashMap<String, LiteralValue>;
+
+impl Template {
+    /// Values of template variables. Positional arguments are bound to `$1`, `$2` and so on.
+    pub fn bindings(&self) -> Bindings {
+        let mut bindings = Bindings::new();
+        for (index, value) in self.args.iter().enumerate() {
+            bindings.insert((index + 1).to_string(), value.clone());
+        }
+        for (name, value) in &self.named_args {
+            bindings.insert(name.clone(), value.clone());
+        }
+        bindings
+    }
+
+    /// Expression with all variables of the template substituted.
+    pub fn resolve(&self) -> Result<Expression, String> {
+        let bindings = self.bindings();
+        match &self.source {
+            Source::Path(path) => path.substitute(&bindings).map(Expression::Path),
+            Source::Call(call) => call.substitute(&bindings).map(Expression::Call),
+        }
+    }
+}
+
+fn lookup<'a>(bindings: &'a Bindings, name: &str) -> Result<&'a LiteralValue, String> {
+    bindings
+        .get(name)
+        .ok_or_else(|| format!("Template variable {} is not bound.", name))
+}
+
+fn binding_path(value: &LiteralValue) -> Result<PathExpression, String> {
+    let text = match value {
+        LiteralValue::String(s) => s.clone(),
+        LiteralValue::Integer(i) => i.to_string(),
+        LiteralValue::Float(f) => f.to_string(),
+        LiteralValue::Boolean(b) => b.to_string(),
+        LiteralValue::None => return Err("Template variable cannot be None".to_owned()),
+    };
+    PathExpression::from_str(&text)
+}
+
+impl PathExpression {
+    pub fn substitute(&self, bindings: &Bindings) -> Result<PathExpression, String> {
+        let mut words = Vec::with_capacity(self.0.len());
+        for word in &self.0 {
+            let mut current: Vec<PathElement> = Vec::new();
+            for element in &word.0 {
+                match element {
+                    PathElement::Variable(name) => {
+                        let value = binding_path(lookup(bindings, name)?)?;
+                        // A value like `a.b` splits the word it is placed into.
+                        for (index, value_word) in value.0.iter().enumerate() {
+                            if index > 0 {
+                                words.push(PathWord(std::mem::take(&mut current)));
+                            }
+                            current.extend(value_word.0.iter().cloned());
+                        }
+                    }
+                    other => current.push(other.clone()),
+                }
+            }
+            words.push(PathWord(current));
+        }
+        Ok(PathExpression(words))
+    }
+}
+
+impl Call {
+    pub fn substitute(&self, bindings: &Bindings) -> Result<Call, String> {
+        let substitute_arg = |arg: &Arg| match arg {
+            // An argument which is just a variable takes the bound literal as is.
+            Arg::Expression(Expression::Path(PathExpression(words))) if words.len() == 1 => {
+                match words[0].0.as_slice() {
+                    [PathElement::Variable(name)] => {
+                        Ok(Arg::Literal(lookup(bindings, name)?.clone()))
+                    }
+                    _ => Ok(Arg::Expression(Expression::Path(
+                        PathExpression(words.clone()).substitute(bindings)?,
+                    ))),
+                }
+            }
+            Arg::Expression(expression) => expression.substitute(bindings).map(Arg::Expression),
+            Arg::Literal(literal) => Ok(Arg::Literal(literal.clone())),
+        };
+
+        Ok(Call {
+            function: self.function.clone(),
+            args: self
+                .args
+                .iter()
+                .map(substitute_arg)
+                .collect::<Result<_, _>>()?,
+            named_args: self
+                .named_args
+                .iter()
+                .map(|(name, arg)| Ok((name.clone(), substitute_arg(arg)?)))
+                .collect::<Result<_, String>>()?,
+        })
+    }
+}
+
+impl Expression {
+    pub fn substitute(&self, bindings: &Bindings) -> Result<Expression, String> {
+        match self {
+            Expression::Path(path) => path.substitute(bindings).map(Expression::Path),
+            Expression::Call(call) => call.substitute(bindings).map(Expression::Call),
+            // Inner templates bind their own variables first.
+            Expression::Template(template) => template.resolve()?.substitute(bindings),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(s: &str) -> Result<String, String> {
+        match Expression::from_str(s)? {
+            Expression::Template(template) => template.resolve().map(|e| e.to_string()),
+            _ => Err("Not a template".to_owned()),
+        }
+    }
+
+    #[test]
+    fn resolve_named() {
+        assert_eq!(
+            resolve(r#"template(hosts.$host.cpu,host="web01")"#),
+            Ok("hosts.web01.cpu".to_owned())
+        );
+        assert_eq!(
+            resolve(r#"template(hosts.$host-$dc.cpu,host="web01",dc="eu")"#),
+            Ok("hosts.web01-eu.cpu".to_owned())
+        );
+    }
+
+    #[test]
+    fn resolve_positional() {
+        assert_eq!(
+            resolve(r#"template(hosts.$1.$2,"web01",3)"#),
+            Ok("hosts.web01.3".to_owned())
+        );
+    }
+
+    #[test]
+    fn resolve_call() {
+        assert_eq!(
+            resolve(r#"template(sumSeries(hosts.$host.cpu,hosts.$host.mem),host="web*")"#),
+            Ok("sumSeries(hosts.web*.cpu,hosts.web*.mem)".to_owned())
+        );
+    }
+
+    #[test]
+    fn resolve_literal_arg() {
+        let Expression::Template(template) =
+            Expression::from_str(r#"template(alias(a.b,$name),name="x")"#).unwrap()
+        else {
+            unreachable!()
+        };
+        let Expression::Call(call) = template.resolve().unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(
+            call.args[1],
+            Arg::Literal(LiteralValue::String("x".to_owned()))
+        );
+    }
+
+    #[test]
+    fn resolve_multiword_value() {
+        assert_eq!(
+            resolve(r#"template(x$prefix.cpu,prefix="a.b")"#),
+            Ok("xa.b.cpu".to_owned())
+        );
+        let Expression::Template(template) =
+            Expression::from_str(r#"template(x$prefix.cpu,prefix="a.b")"#).unwrap()
+        else {
+            unreachable!()
+        };
+        let Expression::Path(path) = template.resolve().unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(path.0.len(), 3);
+    }
+
+    #[test]
+    fn resolve_unbound() {
+        assert_eq!(
+            resolve(r#"template(hosts.$host.cpu,dc="eu")"#),
+            Err("Template variable host is not bound.".to_owned())
+        );
+        assert!(resolve("template(hosts.$1.cpu,None)").is_err());
+    }
+}
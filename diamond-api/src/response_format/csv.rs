@@ -0,0 +1,30 @@
+use chrono::DateTime;
+
+use crate::render::RenderResponseEntry;
+use crate::storage::RenderPoint;
+
+fn entry_to_csv(entry: &RenderResponseEntry) -> String {
+    let metric = &entry.target;
+    let lines: Vec<String> = entry
+        .datapoints
+        .iter()
+        .map(|RenderPoint(val, ts)| {
+            let v = val.map(|f| format!("{}", f)).unwrap_or_default();
+            let t = DateTime::from_timestamp(i64::from(*ts), 0)
+                .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| ts.to_string());
+            // TODO: Use `csv` crate instead of "manual" string formatting
+            format!("{},{},{}", metric, t, v)
+        })
+        .collect();
+    lines.join("\n")
+}
+
+pub fn to_csv(response: &[RenderResponseEntry]) -> String {
+    response
+        .iter()
+        .map(entry_to_csv)
+        .collect::<Vec<String>>()
+        .join("\n")
+        + "\n"
+}
@@ -0,0 +1,53 @@
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+
+use crate::render::RenderResponseEntry;
+
+#[derive(Serialize)]
+struct Dygraph<'a> {
+    labels: Vec<&'a str>,
+    data: Vec<Vec<Value>>,
+}
+
+/// Column labels and one row per timestamp: time in milliseconds followed by
+/// a value of every series, `null` where a series has no point at that time.
+pub fn to_dygraph(response: &[RenderResponseEntry]) -> Result<String, serde_json::Error> {
+    let mut labels = vec!["Time"];
+    let mut rows: BTreeMap<u32, Vec<Option<f64>>> = BTreeMap::new();
+
+    for (index, entry) in response.iter().enumerate() {
+        labels.push(&entry.target);
+        for point in &entry.datapoints {
+            rows.entry(point.1)
+                .or_insert_with(|| vec![None; response.len()])[index] = point.0;
+        }
+    }
+
+    let data = rows
+        .into_iter()
+        .map(|(time, values)| {
+            let mut row = Vec::with_capacity(values.len() + 1);
+            row.push(json!(u64::from(time) * 1000));
+            row.extend(values.into_iter().map(|value| json!(value)));
+            row
+        })
+        .collect();
+
+    serde_json::to_string(&Dygraph { labels, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response_format::sample_response;
+
+    #[test]
+    fn test_to_dygraph() {
+        assert_eq!(
+            to_dygraph(&sample_response()).unwrap(),
+            r#"{"labels":["Time","a.b","c"],"data":[[60000,1.0,null],[120000,null,-3.0],[180000,2.5,null]]}"#
+        );
+        assert_eq!(to_dygraph(&[]).unwrap(), r#"{"labels":["Time"],"data":[]}"#);
+    }
+}
@@ -0,0 +1,92 @@
+//! Serializers of render responses, one per `format` of the render API.
+
+mod csv;
+mod dygraph;
+mod msgpack;
+mod pickle;
+mod raw;
+mod rickshaw;
+
+pub use self::csv::to_csv;
+pub use self::dygraph::to_dygraph;
+pub use self::msgpack::to_msgpack;
+pub use self::pickle::to_pickle;
+pub use self::raw::to_raw;
+pub use self::rickshaw::to_rickshaw;
+
+use serde::Serialize;
+
+use crate::render::RenderResponseEntry;
+use crate::storage::RenderPoint;
+
+/// Series as graphite-web passes it between federated instances.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct FederatedSeries<'a> {
+    name: &'a str,
+    #[serde(rename = "pathExpression")]
+    path_expression: &'a str,
+    start: u32,
+    end: u32,
+    step: u32,
+    values: Vec<Option<f64>>,
+}
+
+impl<'a> From<&'a RenderResponseEntry> for FederatedSeries<'a> {
+    fn from(entry: &'a RenderResponseEntry) -> Self {
+        let step = match entry.datapoints.as_slice() {
+            [RenderPoint(_, first), RenderPoint(_, second), ..] if second > first => second - first,
+            _ => 1,
+        };
+        let start = entry.datapoints.first().map_or(0, |p| p.1);
+        let end = entry.datapoints.last().map_or(start, |p| p.1 + step);
+        Self {
+            name: &entry.target,
+            path_expression: &entry.target,
+            start,
+            end,
+            step,
+            values: entry.datapoints.iter().map(|p| p.0).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn sample_response() -> Vec<RenderResponseEntry> {
+    vec![
+        RenderResponseEntry {
+            target: "a.b".to_owned(),
+            datapoints: vec![
+                RenderPoint(Some(1.0), 60),
+                RenderPoint(None, 120),
+                RenderPoint(Some(2.5), 180),
+            ],
+        },
+        RenderResponseEntry {
+            target: "c".to_owned(),
+            datapoints: vec![RenderPoint(Some(-3.0), 120)],
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn federated_series() {
+        let response = sample_response();
+        assert_eq!(
+            FederatedSeries::from(&response[0]),
+            FederatedSeries {
+                name: "a.b",
+                path_expression: "a.b",
+                start: 60,
+                end: 240,
+                step: 60,
+                values: vec![Some(1.0), None, Some(2.5)],
+            }
+        );
+        let single = FederatedSeries::from(&response[1]);
+        assert_eq!((single.start, single.end, single.step), (120, 121, 1));
+    }
+}
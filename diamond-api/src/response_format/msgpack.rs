@@ -0,0 +1,8 @@
+use super::FederatedSeries;
+use crate::render::RenderResponseEntry;
+
+/// The same series list as `pickle`, as MessagePack maps keyed by field name.
+pub fn to_msgpack(response: &[RenderResponseEntry]) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    let series: Vec<FederatedSeries> = response.iter().map(FederatedSeries::from).collect();
+    rmp_serde::to_vec_named(&series)
+}
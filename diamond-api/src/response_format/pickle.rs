@@ -0,0 +1,99 @@
+//! Minimal pickle (protocol 2) writer, enough to emit the series list graphite-web
+//! federation clients expect.
+
+use super::FederatedSeries;
+use crate::render::RenderResponseEntry;
+
+const PROTO: u8 = 0x80;
+const STOP: u8 = b'.';
+const MARK: u8 = b'(';
+const EMPTY_LIST: u8 = b']';
+const APPENDS: u8 = b'e';
+const EMPTY_DICT: u8 = b'}';
+const SETITEMS: u8 = b'u';
+const NONE: u8 = b'N';
+const BININT: u8 = b'J';
+const BINFLOAT: u8 = b'G';
+const BINUNICODE: u8 = b'X';
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.push(BINUNICODE);
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Timestamps are written as signed 32-bit integers, as Python would for them until 2038.
+fn write_int(out: &mut Vec<u8>, i: u32) {
+    out.push(BININT);
+    out.extend_from_slice(&(i as i32).to_le_bytes());
+}
+
+fn write_float(out: &mut Vec<u8>, value: Option<f64>) {
+    match value {
+        Some(f) => {
+            out.push(BINFLOAT);
+            out.extend_from_slice(&f.to_be_bytes());
+        }
+        None => out.push(NONE),
+    }
+}
+
+fn write_series(out: &mut Vec<u8>, series: &FederatedSeries) {
+    out.push(EMPTY_DICT);
+    out.push(MARK);
+    write_str(out, "name");
+    write_str(out, series.name);
+    write_str(out, "pathExpression");
+    write_str(out, series.path_expression);
+    write_str(out, "start");
+    write_int(out, series.start);
+    write_str(out, "end");
+    write_int(out, series.end);
+    write_str(out, "step");
+    write_int(out, series.step);
+    write_str(out, "values");
+    out.push(EMPTY_LIST);
+    if !series.values.is_empty() {
+        out.push(MARK);
+        for value in &series.values {
+            write_float(out, *value);
+        }
+        out.push(APPENDS);
+    }
+    out.push(SETITEMS);
+}
+
+/// A list of dicts with `name`, `pathExpression`, `start`, `end`, `step` and `values`.
+pub fn to_pickle(response: &[RenderResponseEntry]) -> Vec<u8> {
+    let mut out = vec![PROTO, 2, EMPTY_LIST];
+    if !response.is_empty() {
+        out.push(MARK);
+        for entry in response {
+            write_series(&mut out, &FederatedSeries::from(entry));
+        }
+        out.push(APPENDS);
+    }
+    out.push(STOP);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response_format::sample_response;
+
+    #[test]
+    fn test_to_pickle() {
+        assert_eq!(to_pickle(&[]), b"\x80\x02].");
+
+        let pickle = to_pickle(&sample_response()[1..]);
+        let expected: &[u8] = b"\x80\x02](}(\
+            X\x04\x00\x00\x00nameX\x01\x00\x00\x00c\
+            X\x0e\x00\x00\x00pathExpressionX\x01\x00\x00\x00c\
+            X\x05\x00\x00\x00startJ\x78\x00\x00\x00\
+            X\x03\x00\x00\x00endJ\x79\x00\x00\x00\
+            X\x04\x00\x00\x00stepJ\x01\x00\x00\x00\
+            X\x06\x00\x00\x00values](G\xc0\x08\x00\x00\x00\x00\x00\x00eue.";
+        assert_eq!(pickle, expected);
+    }
+}
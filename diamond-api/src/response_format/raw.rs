@@ -0,0 +1,44 @@
+use super::FederatedSeries;
+use crate::render::RenderResponseEntry;
+
+/// Formats a value the way Python's `repr` does.
+fn repr(value: Option<f64>) -> String {
+    match value {
+        Some(v) => format!("{:?}", v),
+        None => "None".to_owned(),
+    }
+}
+
+/// One `name,start,end,step|v1,v2,...` line per series.
+pub fn to_raw(response: &[RenderResponseEntry]) -> String {
+    response
+        .iter()
+        .map(|entry| {
+            let series = FederatedSeries::from(entry);
+            let values: Vec<String> = series.values.iter().copied().map(repr).collect();
+            format!(
+                "{},{},{},{}|{}\n",
+                series.name,
+                series.start,
+                series.end,
+                series.step,
+                values.join(",")
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response_format::sample_response;
+
+    #[test]
+    fn test_to_raw() {
+        assert_eq!(
+            to_raw(&sample_response()),
+            "a.b,60,240,60|1.0,None,2.5\nc,120,121,1|-3.0\n"
+        );
+        assert_eq!(to_raw(&[]), "");
+    }
+}
@@ -0,0 +1,49 @@
+use serde::Serialize;
+
+use crate::render::RenderResponseEntry;
+
+#[derive(Serialize)]
+struct Point {
+    x: u32,
+    y: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Series<'a> {
+    target: &'a str,
+    datapoints: Vec<Point>,
+}
+
+/// `[{"target": name, "datapoints": [{"x": time, "y": value}, ...]}, ...]`
+pub fn to_rickshaw(response: &[RenderResponseEntry]) -> Result<String, serde_json::Error> {
+    let series: Vec<Series> = response
+        .iter()
+        .map(|entry| Series {
+            target: &entry.target,
+            datapoints: entry
+                .datapoints
+                .iter()
+                .map(|point| Point {
+                    x: point.1,
+                    y: point.0,
+                })
+                .collect(),
+        })
+        .collect();
+    serde_json::to_string(&series)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response_format::sample_response;
+
+    #[test]
+    fn test_to_rickshaw() {
+        assert_eq!(
+            to_rickshaw(&sample_response()).unwrap(),
+            r#"[{"target":"a.b","datapoints":[{"x":60,"y":1.0},{"x":120,"y":null},{"x":180,"y":2.5}]},{"target":"c","datapoints":[{"x":120,"y":-3.0}]}]"#
+        );
+        assert_eq!(to_rickshaw(&[]).unwrap(), "[]");
+    }
+}
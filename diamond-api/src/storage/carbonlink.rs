@@ -0,0 +1,299 @@
+//! Datapoints not written yet, queried from diamond-server or carbon-cache over carbonlink
+//! and merged into the points read from disk, as graphite-web does.
+
+use carbon::pickle::{Value, pickle, unpickle};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use whisper::interval::Interval;
+
+use super::storage::*;
+use crate::error::ResponseError;
+
+fn error(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// `{'type': 'cache-query-bulk', 'metrics': [...]}` pickled with protocol 2.
+fn bulk_request(metrics: &[String]) -> Vec<u8> {
+    let str = |s: &str| Value::Str(s.to_owned());
+    pickle(&Value::Dict(vec![
+        (str("type"), str("cache-query-bulk")),
+        (
+            str("metrics"),
+            Value::List(metrics.iter().map(|metric| str(metric)).collect()),
+        ),
+    ]))
+}
+
+/// Cached datapoints by metric of a `cache-query-bulk` response.
+fn bulk_response(value: Value) -> Result<HashMap<String, Vec<(u32, f64)>>, io::Error> {
+    if let Some(Value::Str(e)) = value.get("error") {
+        return Err(error(e.clone()));
+    }
+    let Some(Value::Dict(metrics)) = value.get("datapointsByMetric") else {
+        return Err(error("Expected 'datapointsByMetric'"));
+    };
+
+    let mut result = HashMap::new();
+    for (name, points) in metrics {
+        let (Value::Str(name), Value::List(points) | Value::Tuple(points)) = (name, points) else {
+            return Err(error("Expected a list of datapoints by metric name"));
+        };
+        let points = points
+            .iter()
+            .filter_map(|point| match point {
+                Value::List(point) | Value::Tuple(point) => match point.as_slice() {
+                    [Value::Int(t), Value::Float(v)] => Some((*t as u32, *v)),
+                    [Value::Int(t), Value::Int(v)] => Some((*t as u32, *v as f64)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        result.insert(name.clone(), points);
+    }
+    Ok(result)
+}
+
+/// Carbonlink client of one or more cache instances.
+#[derive(Debug, Clone)]
+pub struct Carbonlink {
+    pub hosts: Vec<SocketAddr>,
+    pub timeout: Duration,
+}
+
+impl Carbonlink {
+    fn query_host(
+        &self,
+        host: &SocketAddr,
+        request: &[u8],
+    ) -> Result<HashMap<String, Vec<(u32, f64)>>, io::Error> {
+        let mut sock = TcpStream::connect_timeout(host, self.timeout)?;
+        sock.set_read_timeout(Some(self.timeout))?;
+        sock.set_write_timeout(Some(self.timeout))?;
+        sock.write_all(&(request.len() as u32).to_be_bytes())?;
+        sock.write_all(request)?;
+
+        let mut len = [0; 4];
+        sock.read_exact(&mut len)?;
+        let mut response = vec![0; u32::from_be_bytes(len) as usize];
+        sock.read_exact(&mut response)?;
+        bulk_response(unpickle(&response).map_err(|e| error(e.0))?)
+    }
+
+    /// Cached datapoints of `metrics` from every host, sorted by timestamp.
+    /// Hosts that fail to answer are reported and skipped.
+    pub fn query(&self, metrics: &[String]) -> HashMap<String, Vec<(u32, f64)>> {
+        let mut result: HashMap<String, Vec<(u32, f64)>> = HashMap::new();
+        if metrics.is_empty() {
+            return result;
+        }
+        let request = bulk_request(metrics);
+        for host in &self.hosts {
+            match self.query_host(host, &request) {
+                Ok(points) => {
+                    for (name, points) in points {
+                        result.entry(name).or_default().extend(points);
+                    }
+                }
+                Err(e) => eprintln!("carbonlink {} error = {}", host, e),
+            }
+        }
+        result
+            .values_mut()
+            .for_each(|points| points.sort_by_key(|(t, _)| *t));
+        result
+    }
+}
+
+/// Replaces points with the average of cached datapoints of their interval.
+fn merge(data: &mut [RenderPoint], cached: &[(u32, f64)]) {
+    let step = match data {
+        [first, second, ..] => second.1 - first.1,
+        _ => 1,
+    };
+    for point in data.iter_mut() {
+        let values: Vec<f64> = cached
+            .iter()
+            .filter(|(t, _)| point.1 <= *t && *t - point.1 < step)
+            .map(|(_, v)| *v)
+            .collect();
+        if !values.is_empty() {
+            point.0 = Some(values.iter().sum::<f64>() / values.len() as f64);
+        }
+    }
+}
+
+/// Storage whose query results include datapoints cached by carbonlink hosts.
+pub struct CarbonlinkStorage<S> {
+    pub storage: S,
+    pub carbonlink: Carbonlink,
+}
+
+impl<S: Storage> Storage for CarbonlinkStorage<S> {
+    fn find(
+        &self,
+        path_expression: &PathExpression,
+        interval: Option<Interval>,
+        now: u64,
+    ) -> Result<Vec<MetricResponseLeaf>, ResponseError> {
+        self.storage.find(path_expression, interval, now)
+    }
+
+    fn query(
+        &self,
+        path_expression: &PathExpression,
+        interval: Interval,
+        now: u64,
+    ) -> Result<Vec<StorageResponse>, ResponseError> {
+        let mut responses = self.storage.query(path_expression, interval, now)?;
+        let names: Vec<String> = responses
+            .iter()
+            .map(|response| response.name.0.join("."))
+            .collect();
+        let cached = self.carbonlink.query(&names);
+        for (response, name) in responses.iter_mut().zip(&names) {
+            if let Some(points) = cached.get(name) {
+                merge(&mut response.data, points);
+            }
+        }
+        Ok(responses)
+    }
+
+    fn tagged_series(&self) -> Result<Vec<String>, ResponseError> {
+        self.storage.tagged_series()
+    }
+
+    fn query_tagged(
+        &self,
+        series: &str,
+        interval: Interval,
+        now: u64,
+    ) -> Result<StorageResponse, ResponseError> {
+        let mut response = self.storage.query_tagged(series, interval, now)?;
+        let series = series.to_owned();
+        if let Some(points) = self
+            .carbonlink
+            .query(std::slice::from_ref(&series))
+            .get(&series)
+        {
+            merge(&mut response.data, points);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::whisper_fs::WhisperFileSystemStorage;
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use whisper::WhisperBuilder;
+    use whisper::point::Point;
+    use whisper::retention::Retention;
+
+    // pickle.dumps({'datapointsByMetric': {'a.b': [(1545778320, 1.5), (1545778380, 2)], 'c': []}}, protocol)
+    const PROTOCOL_2: &[u8] = b"\x80\x02}q\x00X\x12\x00\x00\x00datapointsByMetricq\x01}q\x02(X\x03\x00\x00\x00a.bq\x03]q\x04(J\x90\xb4\"\\G?\xf8\x00\x00\x00\x00\x00\x00\x86q\x05J\xcc\xb4\"\\K\x02\x86q\x06eX\x01\x00\x00\x00cq\x07]q\x08us.";
+    const PROTOCOL_4: &[u8] = b"\x80\x04\x95F\x00\x00\x00\x00\x00\x00\x00}\x94\x8c\x12datapointsByMetric\x94}\x94(\x8c\x03a.b\x94]\x94(J\x90\xb4\"\\G?\xf8\x00\x00\x00\x00\x00\x00\x86\x94J\xcc\xb4\"\\K\x02\x86\x94e\x8c\x01c\x94]\x94us.";
+
+    #[test]
+    fn carbon_responses() {
+        for data in [PROTOCOL_2, PROTOCOL_4] {
+            let response = bulk_response(unpickle(data).unwrap()).unwrap();
+            assert_eq!(response["a.b"], vec![(1545778320, 1.5), (1545778380, 2.0)]);
+            assert_eq!(response["c"], vec![]);
+        }
+        // pickle.dumps({'type': 'cache-query-bulk', 'metrics': ['a.b', 'c']}, 2) without memo
+        assert_eq!(
+            bulk_request(&["a.b".to_owned(), "c".to_owned()]),
+            b"\x80\x02}(X\x04\x00\x00\x00typeX\x10\x00\x00\x00cache-query-bulkX\x07\x00\x00\x00metrics](X\x03\x00\x00\x00a.bX\x01\x00\x00\x00ceu."
+        );
+        assert!(
+            bulk_response(
+                unpickle(b"\x80\x02}X\x05\x00\x00\x00errorX\x01\x00\x00\x00xs.").unwrap()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn merge_into_intervals() {
+        let mut data = vec![
+            RenderPoint(Some(1.0), 60),
+            RenderPoint(None, 120),
+            RenderPoint(None, 180),
+        ];
+        merge(&mut data, &[(125, 2.0), (130, 4.0), (180, 5.0), (240, 6.0)]);
+        assert_eq!(
+            data,
+            vec![
+                RenderPoint(Some(1.0), 60),
+                RenderPoint(Some(3.0), 120),
+                RenderPoint(Some(5.0), 180),
+            ]
+        );
+    }
+
+    #[test]
+    fn query_merges_cached_points() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::Builder::new().prefix("diamond-api").tempdir()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+        let now = now - now % 60;
+        WhisperBuilder::default()
+            .add_retention(Retention {
+                seconds_per_point: 60,
+                points: 10,
+            })
+            .build(dir.path().join("a.wsp"))?
+            .update_many(
+                &[Point {
+                    interval: now - 120,
+                    value: 1.0,
+                }],
+                now,
+            )?;
+
+        // Cache answering once with a datapoint of the last minute
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let host = listener.local_addr()?;
+        let server = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut len = [0; 4];
+            sock.read_exact(&mut len).unwrap();
+            let mut request = vec![0; u32::from_be_bytes(len) as usize];
+            sock.read_exact(&mut request).unwrap();
+            assert_eq!(request, bulk_request(&["a".to_owned()]));
+
+            let mut response =
+                b"\x80\x02}X\x12\x00\x00\x00datapointsByMetric}(X\x01\x00\x00\x00a](J".to_vec();
+            response.extend_from_slice(&((now - 60) as i32).to_le_bytes());
+            response.extend_from_slice(b"K\x07\x86eus.");
+            sock.write_all(&(response.len() as u32).to_be_bytes())
+                .unwrap();
+            sock.write_all(&response).unwrap();
+        });
+
+        let storage = CarbonlinkStorage {
+            storage: WhisperFileSystemStorage(dir.path().to_owned()),
+            carbonlink: Carbonlink {
+                hosts: vec![host],
+                timeout: Duration::from_secs(5),
+            },
+        };
+        let responses = storage.query(
+            &PathExpression::from_str("a")?,
+            Interval::new(now - 180, now)?,
+            u64::from(now),
+        )?;
+        server.join().unwrap();
+
+        let values: Vec<Option<f64>> = responses[0].data.iter().map(|point| point.0).collect();
+        assert_eq!(values, vec![None, Some(1.0), Some(7.0)]);
+        Ok(())
+    }
+}
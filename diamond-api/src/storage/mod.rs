@@ -1,3 +1,4 @@
+pub mod carbonlink;
 pub mod storage;
 pub mod whisper_fs;
 
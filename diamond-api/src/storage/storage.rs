@@ -39,9 +39,13 @@ pub struct StorageResponse {
 }
 
 pub trait Storage {
+    /// Metrics and directories matching the expression. With an `interval` leaves
+    /// that have no data in it are left out.
     fn find(
         &self,
         path_expression: &PathExpression,
+        interval: Option<Interval>,
+        now: u64,
     ) -> Result<Vec<MetricResponseLeaf>, ResponseError>;
 
     fn query(
@@ -50,4 +54,15 @@ pub trait Storage {
         interval: Interval,
         now: u64,
     ) -> Result<Vec<StorageResponse>, ResponseError>;
+
+    /// Names of all tagged series, normalized and sorted.
+    fn tagged_series(&self) -> Result<Vec<String>, ResponseError>;
+
+    /// Points of a single tagged series, `ResponseError::NotFound` if it does not exist.
+    fn query_tagged(
+        &self,
+        series: &str,
+        interval: Interval,
+        now: u64,
+    ) -> Result<StorageResponse, ResponseError>;
 }
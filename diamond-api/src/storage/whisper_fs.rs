@@ -1,14 +1,18 @@
 use std::borrow::Cow;
+use std::collections::BTreeSet;
 use std::ffi::OsStr;
 use std::fs;
+use std::io;
 use std::iter::successors;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 use whisper::interval::Interval;
 use whisper::{ArchiveData, WhisperFile};
 
 use super::storage::*;
 use crate::error::ResponseError;
 pub use crate::render_target::ast::{PathExpression, PathWord};
+use crate::tags::index::{TAG_INDEX_FILE, TAGGED_DIR, TaggedSeries};
 
 #[derive(Clone)]
 pub struct WhisperFileSystemStorage(pub PathBuf);
@@ -17,6 +21,8 @@ impl Storage for WhisperFileSystemStorage {
     fn find(
         &self,
         path_expression: &PathExpression,
+        interval: Option<Interval>,
+        now: u64,
     ) -> Result<Vec<MetricResponseLeaf>, ResponseError> {
         let mut paths = Vec::new();
         walk_tree(
@@ -27,13 +33,25 @@ impl Storage for WhisperFileSystemStorage {
         )?;
         paths.sort_by_cached_key(|k| k.0.clone());
 
-        Ok(paths
-            .into_iter()
-            .map(|(metric_name, fs_path)| MetricResponseLeaf {
+        let mut leaves = Vec::with_capacity(paths.len());
+        for (metric_name, fs_path) in paths {
+            let is_leaf = fs_path.is_file();
+            if let Some(interval) = interval
+                && is_leaf
+                && !has_data(&fs_path, interval, now as u32).unwrap_or_else(|e| {
+                    // Listed, a broken file should not hide the others
+                    eprintln!("{}: {}", fs_path.display(), e);
+                    true
+                })
+            {
+                continue;
+            }
+            leaves.push(MetricResponseLeaf {
                 name: metric_name,
-                is_leaf: fs_path.is_file(),
-            })
-            .collect())
+                is_leaf,
+            });
+        }
+        Ok(leaves)
     }
 
     fn query(
@@ -50,28 +68,89 @@ impl Storage for WhisperFileSystemStorage {
             &mut paths,
         )?;
 
-        let mut responses = Vec::new();
-        for (metric_name, fs_path) in paths {
-            let ArchiveData {
-                from_interval,
-                step,
-                values,
-                ..
-            } = WhisperFile::open(&fs_path)?.fetch_auto_points(interval, now as u32)?;
-            let timestamps = successors(Some(from_interval), |i| i.checked_add(step));
-            let points = values
-                .into_iter()
-                .zip(timestamps)
-                .map(|(value, time)| RenderPoint(value, time))
-                .collect();
+        paths
+            .into_iter()
+            .map(|(metric_name, fs_path)| {
+                Ok(StorageResponse {
+                    name: metric_name,
+                    data: fetch_points(&fs_path, interval, now)?,
+                })
+            })
+            .collect()
+    }
 
-            responses.push(StorageResponse {
-                name: metric_name,
-                data: points,
-            });
+    fn tagged_series(&self) -> Result<Vec<String>, ResponseError> {
+        let index = match fs::read_to_string(self.0.join(TAGGED_DIR).join(TAG_INDEX_FILE)) {
+            Ok(index) => index,
+            // Nothing tagged was written yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let series: BTreeSet<String> = index
+            .lines()
+            .filter_map(|line| line.parse::<TaggedSeries>().ok())
+            .map(|series| series.name)
+            .collect();
+        Ok(series.into_iter().collect())
+    }
+
+    fn query_tagged(
+        &self,
+        series: &str,
+        interval: Interval,
+        now: u64,
+    ) -> Result<StorageResponse, ResponseError> {
+        let series: TaggedSeries = series.parse().map_err(|_| ResponseError::Path)?;
+        let fs_path = self.0.join(series.path());
+        if !fs_path.is_file() {
+            return Err(ResponseError::NotFound);
         }
-        Ok(responses)
+        Ok(StorageResponse {
+            name: MetricName(vec![series.name]),
+            data: fetch_points(&fs_path, interval, now)?,
+        })
+    }
+}
+
+fn fetch_points(
+    path: &Path,
+    interval: Interval,
+    now: u64,
+) -> Result<Vec<RenderPoint>, ResponseError> {
+    let ArchiveData {
+        from_interval,
+        step,
+        values,
+        ..
+    } = WhisperFile::open_readonly(path)?.fetch_auto_points(interval, now as u32)?;
+    let timestamps = successors(Some(from_interval), |i| i.checked_add(step));
+    Ok(values
+        .into_iter()
+        .zip(timestamps)
+        .map(|(value, time)| RenderPoint(value, time))
+        .collect())
+}
+
+/// Whether a whisper file has any non-null point in the interval.
+fn has_data(path: &Path, interval: Interval, now: u32) -> Result<bool, ResponseError> {
+    // Nothing was written since before the interval started
+    let modified = fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    if modified < u64::from(interval.from()) {
+        return Ok(false);
     }
+
+    let mut file = WhisperFile::open_readonly(path)?;
+    let Some(seconds_per_point) = file.suggest_archive(interval, now) else {
+        // The interval is beyond retention
+        return Ok(false);
+    };
+    let points = file.dump(seconds_per_point)?;
+    Ok(points
+        .iter()
+        .any(|point| interval.from() <= point.interval && point.interval <= interval.until()))
 }
 
 fn file_name(path: &Path) -> Option<Cow<'_, str>> {
@@ -84,6 +163,11 @@ fn file_name(path: &Path) -> Option<Cow<'_, str>> {
     }
 }
 
+/// Tagged series are served by the tags API and kept out of the metric tree.
+fn is_hidden(path_prefix: &MetricName, file_name: &str) -> bool {
+    path_prefix.0.is_empty() && file_name == TAGGED_DIR
+}
+
 fn walk_tree(
     dir: &Path,
     path_prefix: &MetricName,
@@ -97,7 +181,7 @@ fn walk_tree(
             for entry in fs::read_dir(dir)? {
                 let path = entry?.path();
                 if let Some(file_name) = file_name(&path) {
-                    if regex.is_match(&file_name) {
+                    if regex.is_match(&file_name) && !is_hidden(path_prefix, &file_name) {
                         let storage_path = path_prefix.join(file_name);
                         acc.push((storage_path, path));
                     }
@@ -109,7 +193,7 @@ fn walk_tree(
             for entry in fs::read_dir(dir)? {
                 let path = entry?.path();
                 if let Some(file_name) = file_name(&path) {
-                    if regex.is_match(&file_name) {
+                    if regex.is_match(&file_name) && !is_hidden(path_prefix, &file_name) {
                         let storage_path = path_prefix.join(file_name);
                         walk_tree(&path, &storage_path, &path_words[1..], acc)?;
                     }
@@ -127,6 +211,10 @@ mod tests {
     use std::fs::create_dir;
     use std::path::Path;
     use std::str::FromStr;
+    use std::time::{Duration, SystemTime};
+    use whisper::WhisperBuilder;
+    use whisper::point::Point;
+    use whisper::retention::Retention;
 
     fn get_temp_dir() -> tempfile::TempDir {
         tempfile::Builder::new()
@@ -149,8 +237,11 @@ mod tests {
         let _file1 = File::create(&path3)?;
         let _file2 = File::create(&path4)?;
 
-        let metric =
-            WhisperFileSystemStorage(path.to_owned()).find(&PathExpression::from_str("*")?)?;
+        let metric = WhisperFileSystemStorage(path.to_owned()).find(
+            &PathExpression::from_str("*")?,
+            None,
+            0,
+        )?;
 
         let mut metric_cmp = vec![
             MetricResponseLeaf {
@@ -170,8 +261,11 @@ mod tests {
         metric_cmp.sort_by_key(|k| k.name.clone());
         assert_eq!(metric, metric_cmp);
 
-        let metric2 =
-            WhisperFileSystemStorage(path.to_owned()).find(&PathExpression::from_str("foo.*")?)?;
+        let metric2 = WhisperFileSystemStorage(path.to_owned()).find(
+            &PathExpression::from_str("foo.*")?,
+            None,
+            0,
+        )?;
 
         let mut metric_cmp2 = vec![MetricResponseLeaf {
             name: "foo.bar".parse().unwrap(),
@@ -183,4 +277,118 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn find_in_interval() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = get_temp_dir();
+        let path = dir.path();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+
+        create_dir(path.join("dir"))?;
+        let create = |name: &str, points: &[Point]| -> Result<(), Box<dyn std::error::Error>> {
+            let mut file = WhisperBuilder::default()
+                .add_retention(Retention {
+                    seconds_per_point: 60,
+                    points: 100,
+                })
+                .build(path.join(name))?;
+            file.update_many(points, now)?;
+            Ok(())
+        };
+        let point = |interval, value| Point { interval, value };
+
+        create("fresh.wsp", &[point(now - 120, 1.0)])?;
+        create("stale.wsp", &[point(now - 3000, 2.0)])?;
+        create("empty.wsp", &[])?;
+        File::options()
+            .write(true)
+            .open(path.join("stale.wsp"))?
+            .set_modified(SystemTime::now() - Duration::from_secs(3000))?;
+
+        let storage = WhisperFileSystemStorage(path.to_owned());
+        let find = |from, until| -> Result<Vec<String>, ResponseError> {
+            let interval = Interval::new(from, until).unwrap();
+            Ok(storage
+                .find(
+                    &PathExpression::from_str("*").unwrap(),
+                    Some(interval),
+                    u64::from(now),
+                )?
+                .into_iter()
+                .map(|leaf| leaf.name.0.join("."))
+                .collect())
+        };
+
+        assert_eq!(find(now - 600, now)?, vec!["dir", "fresh"]);
+        assert_eq!(find(now - 3600, now)?, vec!["dir", "fresh", "stale"]);
+        assert_eq!(find(now - 10000, now)?, vec!["dir", "fresh", "stale"]);
+        assert_eq!(find(now - 3600, now - 600)?, vec!["dir", "stale"]);
+        // Beyond retention of every file
+        assert_eq!(find(now - 20000, now - 10000)?, vec!["dir"]);
+
+        // A broken file is listed and does not fail the others
+        std::fs::write(path.join("broken.wsp"), b"not whisper")?;
+        assert_eq!(find(now - 600, now)?, vec!["broken", "dir", "fresh"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tagged_series() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = get_temp_dir();
+        let path = dir.path();
+        let storage = WhisperFileSystemStorage(path.to_owned());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+        let interval = Interval::new(now - 600, now)?;
+
+        assert_eq!(storage.tagged_series()?, Vec::<String>::new());
+
+        let series: TaggedSeries = "disk.used;host=a".parse()?;
+        let file_path = path.join(series.path());
+        fs::create_dir_all(file_path.parent().unwrap())?;
+        WhisperBuilder::default()
+            .add_retention(Retention {
+                seconds_per_point: 60,
+                points: 100,
+            })
+            .build(&file_path)?
+            .update_many(
+                &[Point {
+                    interval: now - 120,
+                    value: 1.0,
+                }],
+                now,
+            )?;
+        fs::write(
+            path.join(TAGGED_DIR).join(TAG_INDEX_FILE),
+            "disk.used;host=a\ndisk.used;host=b\ndisk.used;host=a\ninvalid;\n",
+        )?;
+
+        assert_eq!(
+            storage.tagged_series()?,
+            vec!["disk.used;host=a", "disk.used;host=b"]
+        );
+
+        let response = storage.query_tagged("disk.used;host=a", interval, u64::from(now))?;
+        assert_eq!(response.name.0, vec!["disk.used;host=a"]);
+        assert!(
+            response
+                .data
+                .contains(&RenderPoint(Some(1.0), now - 120 - now % 60))
+        );
+        assert_eq!(
+            storage
+                .query_tagged("disk.used;host=b", interval, u64::from(now))
+                .err(),
+            Some(ResponseError::NotFound)
+        );
+
+        // The tagged directory is not a part of the metric tree
+        assert_eq!(
+            storage.find(&PathExpression::from_str("*")?, None, 0)?,
+            vec![]
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,327 @@
+use carbon::tagged::{TaggedName, series_path};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub use carbon::tagged::{TAG_INDEX_FILE, TAGGED_DIR};
+
+/// Series name with tags like `disk.used;dc=eu;host=a`. The series name is kept as tag `name`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaggedSeries {
+    pub name: String,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl FromStr for TaggedSeries {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tagged: TaggedName = s.parse()?;
+        let name = tagged.to_string();
+        let mut tags = tagged.tags;
+        tags.insert("name".to_owned(), tagged.name);
+        Ok(Self { name, tags })
+    }
+}
+
+impl TaggedSeries {
+    /// Whisper file of the series relative to the storage root, laid out as carbon does.
+    pub fn path(&self) -> PathBuf {
+        series_path(&self.name)
+    }
+
+    fn value(&self, tag: &str) -> &str {
+        self.tags.get(tag).map_or("", String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Match,
+    NotMatch,
+}
+
+/// Tag expression of `seriesByTag`: `tag=value`, `tag!=value`, `tag=~regex` or `tag!=~regex`.
+#[derive(Debug, Clone)]
+pub struct TagExpression {
+    pub tag: String,
+    operator: Operator,
+    value: String,
+    regex: Option<Regex>,
+}
+
+impl FromStr for TagExpression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let index = s
+            .find('=')
+            .filter(|index| *index > 0)
+            .ok_or_else(|| format!("Invalid tag expression '{}'", s))?;
+        let (tag, negated) = match s[..index].strip_suffix('!') {
+            Some(tag) if !tag.is_empty() => (tag, true),
+            Some(_) => return Err(format!("Invalid tag expression '{}'", s)),
+            None => (&s[..index], false),
+        };
+        let (value, regex) = match s[index + 1..].strip_prefix('~') {
+            Some(pattern) => {
+                // Patterns are anchored at the start of a value, as `re.match` does
+                let regex = Regex::new(&format!("^(?:{})", pattern))
+                    .map_err(|e| format!("Invalid tag expression '{}': {}", s, e))?;
+                (pattern, Some(regex))
+            }
+            None => (&s[index + 1..], None),
+        };
+        let operator = match (negated, regex.is_some()) {
+            (false, false) => Operator::Equal,
+            (true, false) => Operator::NotEqual,
+            (false, true) => Operator::Match,
+            (true, true) => Operator::NotMatch,
+        };
+
+        Ok(Self {
+            tag: tag.to_owned(),
+            operator,
+            value: value.to_owned(),
+            regex,
+        })
+    }
+}
+
+impl TagExpression {
+    fn matches_value(&self, value: &str) -> bool {
+        let regex_match = || self.regex.as_ref().is_some_and(|re| re.is_match(value));
+        match self.operator {
+            Operator::Equal => value == self.value,
+            Operator::NotEqual => value != self.value,
+            Operator::Match => regex_match(),
+            Operator::NotMatch => !regex_match(),
+        }
+    }
+
+    pub fn matches(&self, series: &TaggedSeries) -> bool {
+        self.matches_value(series.value(&self.tag))
+    }
+}
+
+/// In-memory index of tagged series.
+#[derive(Debug, Default)]
+pub struct TagIndex {
+    series: Vec<TaggedSeries>,
+}
+
+impl TagIndex {
+    /// Builds the index from series names, invalid and duplicate names are skipped.
+    pub fn new(names: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        let series: BTreeSet<TaggedSeries> = names
+            .into_iter()
+            .filter_map(|name| name.as_ref().parse().ok())
+            .collect();
+        Self {
+            series: series.into_iter().collect(),
+        }
+    }
+
+    /// All tag names, `name` included.
+    pub fn tags(&self) -> BTreeSet<&str> {
+        self.series
+            .iter()
+            .flat_map(|series| series.tags.keys().map(String::as_str))
+            .collect()
+    }
+
+    /// Values of a tag with the number of series having each.
+    pub fn values(&self, tag: &str) -> BTreeMap<&str, usize> {
+        let mut values = BTreeMap::new();
+        for value in self.series.iter().filter_map(|s| s.tags.get(tag)) {
+            *values.entry(value.as_str()).or_default() += 1;
+        }
+        values
+    }
+
+    /// Series matching all expressions. At least one expression has to require a
+    /// non-empty value, otherwise the whole index would match.
+    pub fn find_series(&self, expressions: &[TagExpression]) -> Result<Vec<&TaggedSeries>, String> {
+        if !expressions.iter().any(|e| !e.matches_value("")) {
+            return Err("At least one tag expression must require a non-empty value".to_owned());
+        }
+        Ok(self
+            .series
+            .iter()
+            .filter(|series| expressions.iter().all(|e| e.matches(series)))
+            .collect())
+    }
+
+    /// Tags starting with `prefix` of series matching the expressions, except tags
+    /// the expressions already use.
+    pub fn auto_complete_tags(
+        &self,
+        expressions: &[TagExpression],
+        prefix: &str,
+    ) -> Result<BTreeSet<&str>, String> {
+        if expressions.is_empty() {
+            let mut tags = self.tags();
+            tags.retain(|tag| tag.starts_with(prefix));
+            return Ok(tags);
+        }
+        let used: BTreeSet<&str> = expressions.iter().map(|e| e.tag.as_str()).collect();
+        Ok(self
+            .find_series(expressions)?
+            .into_iter()
+            .flat_map(|series| series.tags.keys().map(String::as_str))
+            .filter(|tag| tag.starts_with(prefix) && !used.contains(tag))
+            .collect())
+    }
+
+    /// Values of `tag` starting with `prefix` among series matching the expressions.
+    pub fn auto_complete_values(
+        &self,
+        expressions: &[TagExpression],
+        tag: &str,
+        prefix: &str,
+    ) -> Result<BTreeSet<&str>, String> {
+        let series = if expressions.is_empty() {
+            self.series.iter().collect()
+        } else {
+            self.find_series(expressions)?
+        };
+        Ok(series
+            .into_iter()
+            .filter_map(|series| series.tags.get(tag).map(String::as_str))
+            .filter(|value| value.starts_with(prefix))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> TagIndex {
+        TagIndex::new([
+            "disk.used;host=a;dc=eu",
+            "disk.used;dc=us;host=b",
+            "disk.free;dc=eu;host=a",
+            "cpu;dc=eu;host=a;core=0",
+            "disk.used;dc=eu;host=a",
+            "not;valid",
+        ])
+    }
+
+    fn exprs(expressions: &[&str]) -> Vec<TagExpression> {
+        expressions.iter().map(|e| e.parse().unwrap()).collect()
+    }
+
+    fn find(expressions: &[&str]) -> Result<Vec<String>, String> {
+        Ok(index()
+            .find_series(&exprs(expressions))?
+            .into_iter()
+            .map(|s| s.name.clone())
+            .collect())
+    }
+
+    #[test]
+    fn tagged_series_parse() {
+        let series: TaggedSeries = "disk.used;host=a;dc=eu".parse().unwrap();
+        assert_eq!(series.name, "disk.used;dc=eu;host=a");
+        assert_eq!(series.value("name"), "disk.used");
+        assert_eq!(series.value("host"), "a");
+        assert_eq!(series.value("rack"), "");
+        assert_eq!(
+            series.path(),
+            PathBuf::from("_tagged/1da/753/disk_DOT_used;dc=eu;host=a.wsp")
+        );
+
+        assert!("disk.used;host".parse::<TaggedSeries>().is_err());
+        assert!(";host=a".parse::<TaggedSeries>().is_err());
+        assert!("disk.used;host=../a".parse::<TaggedSeries>().is_err());
+    }
+
+    #[test]
+    fn tag_expression_parse() {
+        assert!("host=a".parse::<TagExpression>().is_ok());
+        assert!("host!=a".parse::<TagExpression>().is_ok());
+        assert!("host=~a.*".parse::<TagExpression>().is_ok());
+        assert!("host!=~a.*".parse::<TagExpression>().is_ok());
+        assert!("host".parse::<TagExpression>().is_err());
+        assert!("=a".parse::<TagExpression>().is_err());
+        assert!("!=a".parse::<TagExpression>().is_err());
+        assert!("host=~(".parse::<TagExpression>().is_err());
+    }
+
+    #[test]
+    fn tags_and_values() {
+        let index = index();
+        assert_eq!(
+            index.tags().into_iter().collect::<Vec<_>>(),
+            vec!["core", "dc", "host", "name"]
+        );
+        assert_eq!(
+            index.values("dc").into_iter().collect::<Vec<_>>(),
+            vec![("eu", 3), ("us", 1)]
+        );
+        assert!(index.values("rack").is_empty());
+    }
+
+    #[test]
+    fn find_series() {
+        assert_eq!(
+            find(&["name=disk.used"]),
+            Ok(vec![
+                "disk.used;dc=eu;host=a".to_owned(),
+                "disk.used;dc=us;host=b".to_owned()
+            ])
+        );
+        assert_eq!(
+            find(&["name=~disk", "dc!=us"]),
+            Ok(vec![
+                "disk.free;dc=eu;host=a".to_owned(),
+                "disk.used;dc=eu;host=a".to_owned()
+            ])
+        );
+        assert_eq!(
+            find(&["dc=eu", "core="]),
+            Ok(vec![
+                "disk.free;dc=eu;host=a".to_owned(),
+                "disk.used;dc=eu;host=a".to_owned()
+            ])
+        );
+        // Anchored at the start only
+        assert_eq!(find(&["name=~used"]), Ok(vec![]));
+        assert_eq!(find(&["name=~.*used"]).unwrap().len(), 2);
+        assert_eq!(
+            find(&["name!=~disk", "dc=eu"]),
+            Ok(vec!["cpu;core=0;dc=eu;host=a".to_owned()])
+        );
+        assert!(find(&["core="]).is_err());
+        assert!(find(&["host!=a"]).is_err());
+    }
+
+    #[test]
+    fn auto_complete() {
+        let index = index();
+        let tags = |e: &[&str], prefix| {
+            index
+                .auto_complete_tags(&exprs(e), prefix)
+                .map(|tags| tags.into_iter().collect::<Vec<_>>())
+        };
+        assert_eq!(tags(&[], "d"), Ok(vec!["dc"]));
+        assert_eq!(tags(&["name=cpu"], ""), Ok(vec!["core", "dc", "host"]));
+        assert_eq!(tags(&["dc=us"], ""), Ok(vec!["host", "name"]));
+
+        let values = |e: &[&str], tag, prefix| {
+            index
+                .auto_complete_values(&exprs(e), tag, prefix)
+                .map(|values| values.into_iter().collect::<Vec<_>>())
+        };
+        assert_eq!(
+            values(&[], "name", "disk"),
+            Ok(vec!["disk.free", "disk.used"])
+        );
+        assert_eq!(values(&["dc=us"], "host", ""), Ok(vec!["b"]));
+        assert!(values(&["dc!=us"], "host", "").is_err());
+    }
+}
@@ -0,0 +1,322 @@
+use actix_web::error::ErrorBadRequest;
+use actix_web::web::{Data, Path};
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse, dev};
+use futures::future::{FutureExt, LocalBoxFuture, ready};
+use regex::Regex;
+use serde::*;
+use std::str::FromStr;
+
+use crate::context::Context;
+use crate::error::ParseError;
+
+pub mod index;
+
+use index::{TagExpression, TagIndex};
+
+/// Parameters shared by the tags API endpoints.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TagsQuery {
+    filter: Option<String>,
+    limit: Option<usize>,
+    tag: Option<String>,
+    tag_prefix: String,
+    value_prefix: String,
+    expr: Vec<String>,
+}
+
+impl FromStr for TagsQuery {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let raw: Vec<(String, String)> = serde_urlencoded::from_str(s)?;
+
+        let mut q = TagsQuery::default();
+        for (key, value) in raw {
+            match key.as_str() {
+                "filter" => q.filter = Some(value),
+                "limit" => q.limit = Some(value.parse()?),
+                "tag" => q.tag = Some(value),
+                "tagPrefix" => q.tag_prefix = value,
+                "valuePrefix" => q.value_prefix = value,
+                "expr" | "expr[]" => q.expr.push(value),
+                _ => {}
+            };
+        }
+
+        Ok(q)
+    }
+}
+
+impl FromRequest for TagsQuery {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        match req.content_type().to_lowercase().as_str() {
+            "application/x-www-form-urlencoded" => String::from_request(req, payload)
+                .map(|r| r?.parse::<TagsQuery>().map_err(ErrorBadRequest))
+                .boxed_local(),
+            _ => ready(req.query_string().parse().map_err(ErrorBadRequest)).boxed_local(),
+        }
+    }
+}
+
+impl TagsQuery {
+    fn expressions(&self) -> Result<Vec<TagExpression>, actix_web::Error> {
+        self.expr
+            .iter()
+            .map(|expr| expr.parse().map_err(ErrorBadRequest))
+            .collect()
+    }
+
+    /// Matches the start of a name against `filter`, as graphite does.
+    fn filter(&self) -> Result<Option<Regex>, actix_web::Error> {
+        self.filter
+            .as_ref()
+            .map(|filter| Regex::new(&format!("^(?:{})", filter)).map_err(ErrorBadRequest))
+            .transpose()
+    }
+
+    fn limit<'a>(&self, items: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+        items
+            .into_iter()
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TagResponse {
+    tag: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TagValueResponse {
+    count: usize,
+    value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TagValuesResponse {
+    tag: String,
+    values: Vec<TagValueResponse>,
+}
+
+async fn tag_index(ctx: &Context) -> Result<TagIndex, actix_web::Error> {
+    Ok(TagIndex::new(
+        ctx.with_storage(|storage| storage.tagged_series()).await?,
+    ))
+}
+
+pub async fn tags_handler(
+    ctx: Data<Context>,
+    query: TagsQuery,
+) -> Result<HttpResponse, actix_web::Error> {
+    let index = tag_index(&ctx).await?;
+    let filter = query.filter()?;
+    let tags: Vec<TagResponse> = query
+        .limit(
+            index
+                .tags()
+                .into_iter()
+                .filter(|tag| filter.as_ref().is_none_or(|f| f.is_match(tag))),
+        )
+        .into_iter()
+        .map(|tag| TagResponse {
+            tag: tag.to_owned(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+pub async fn tag_handler(
+    ctx: Data<Context>,
+    tag: Path<String>,
+    query: TagsQuery,
+) -> Result<HttpResponse, actix_web::Error> {
+    let index = tag_index(&ctx).await?;
+    let filter = query.filter()?;
+    let values = index.values(&tag);
+    let values = query
+        .limit(
+            values
+                .keys()
+                .copied()
+                .filter(|value| filter.as_ref().is_none_or(|f| f.is_match(value))),
+        )
+        .into_iter()
+        .map(|value| TagValueResponse {
+            count: values[value],
+            value: value.to_owned(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(TagValuesResponse {
+        tag: tag.into_inner(),
+        values,
+    }))
+}
+
+pub async fn auto_complete_tags_handler(
+    ctx: Data<Context>,
+    query: TagsQuery,
+) -> Result<HttpResponse, actix_web::Error> {
+    let index = tag_index(&ctx).await?;
+    let tags = index
+        .auto_complete_tags(&query.expressions()?, &query.tag_prefix)
+        .map_err(ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(query.limit(tags)))
+}
+
+pub async fn auto_complete_values_handler(
+    ctx: Data<Context>,
+    query: TagsQuery,
+) -> Result<HttpResponse, actix_web::Error> {
+    let tag = query
+        .tag
+        .as_deref()
+        .ok_or_else(|| ErrorBadRequest("tag is required"))?;
+    let index = tag_index(&ctx).await?;
+    let values = index
+        .auto_complete_values(&query.expressions()?, tag, &query.value_prefix)
+        .map_err(ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(query.limit(values)))
+}
+
+pub async fn find_series_handler(
+    ctx: Data<Context>,
+    query: TagsQuery,
+) -> Result<HttpResponse, actix_web::Error> {
+    let index = tag_index(&ctx).await?;
+    let series = index
+        .find_series(&query.expressions()?)
+        .map_err(ErrorBadRequest)?;
+    let names = query.limit(series.into_iter().map(|series| series.name.as_str()));
+    Ok(HttpResponse::Ok().json(names))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opts::Args;
+    use crate::test_utils::ConstStorage;
+
+    use actix_web::body::to_bytes;
+    use actix_web::test::TestRequest;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn context() -> Data<Context> {
+        Data::new(Context {
+            args: Args {
+                path: PathBuf::new(),
+                force: false,
+                port: 0,
+                carbonlink: vec![],
+                carbonlink_timeout: 1000,
+                header_cache: 0,
+                lock: Default::default(),
+            },
+            storage: Arc::new(ConstStorage(vec![])),
+        })
+    }
+
+    fn query(s: &str) -> TagsQuery {
+        s.parse().unwrap()
+    }
+
+    async fn body(response: Result<HttpResponse, actix_web::Error>) -> String {
+        let body = to_bytes(response.unwrap().into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn tags_query_parse() {
+        assert_eq!(
+            query("tagPrefix=d&expr=name%3Ddisk.used&expr[]=dc!%3Deu&limit=5&other=1"),
+            TagsQuery {
+                limit: Some(5),
+                tag_prefix: "d".to_owned(),
+                expr: vec!["name=disk.used".to_owned(), "dc!=eu".to_owned()],
+                ..TagsQuery::default()
+            }
+        );
+        assert!("limit=x".parse::<TagsQuery>().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn tags_query_from_request() -> Result<(), actix_web::Error> {
+        let (req, mut pl) = TestRequest::with_uri("/tags/findSeries?expr=dc%3Deu")
+            .to_srv_request()
+            .into_parts();
+        assert_eq!(
+            TagsQuery::from_request(&req, &mut pl).await?,
+            query("expr=dc%3Deu")
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn tags() {
+        assert_eq!(
+            body(tags_handler(context(), query("")).await).await,
+            r#"[{"tag":"dc"},{"tag":"host"},{"tag":"name"}]"#
+        );
+        assert_eq!(
+            body(tags_handler(context(), query("filter=h|n&limit=1")).await).await,
+            r#"[{"tag":"host"}]"#
+        );
+    }
+
+    #[actix_rt::test]
+    async fn tag_values() {
+        assert_eq!(
+            body(tag_handler(context(), Path::from("name".to_owned()), query("")).await).await,
+            r#"{"tag":"name","values":[{"count":2,"value":"disk.used"}]}"#
+        );
+        assert_eq!(
+            body(tag_handler(context(), Path::from("dc".to_owned()), query("filter=u")).await)
+                .await,
+            r#"{"tag":"dc","values":[{"count":1,"value":"us"}]}"#
+        );
+    }
+
+    #[actix_rt::test]
+    async fn auto_complete() {
+        assert_eq!(
+            body(auto_complete_tags_handler(context(), query("tagPrefix=h")).await).await,
+            r#"["host"]"#
+        );
+        assert_eq!(
+            body(auto_complete_tags_handler(context(), query("expr=dc%3Deu")).await).await,
+            r#"["host","name"]"#
+        );
+        assert_eq!(
+            body(auto_complete_values_handler(context(), query("tag=host&expr=dc%3Dus")).await)
+                .await,
+            r#"["b"]"#
+        );
+        assert!(
+            auto_complete_values_handler(context(), query(""))
+                .await
+                .is_err()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn find_series() {
+        assert_eq!(
+            body(find_series_handler(context(), query("expr=host%3D~a|b&limit=1")).await).await,
+            r#"["disk.used;dc=eu;host=a"]"#
+        );
+        assert!(
+            find_series_handler(context(), query("expr=host!%3Da"))
+                .await
+                .is_err()
+        );
+        assert!(
+            find_series_handler(context(), query("expr=host"))
+                .await
+                .is_err()
+        );
+    }
+}
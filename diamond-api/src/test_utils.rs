@@ -2,7 +2,7 @@ use whisper::interval::Interval;
 
 use crate::error::ResponseError;
 use crate::render_target::PathExpression;
-use crate::storage::{MetricResponseLeaf, RenderPoint, Storage, StorageResponse};
+use crate::storage::{MetricName, MetricResponseLeaf, RenderPoint, Storage, StorageResponse};
 
 #[derive(Clone)]
 pub struct ConstStorage(pub Vec<RenderPoint>);
@@ -11,6 +11,8 @@ impl Storage for ConstStorage {
     fn find(
         &self,
         _path_expression: &PathExpression,
+        _interval: Option<Interval>,
+        _now: u64,
     ) -> Result<Vec<MetricResponseLeaf>, ResponseError> {
         Ok(vec![MetricResponseLeaf {
             name: "i.am.a.metric".parse().unwrap(),
@@ -29,4 +31,23 @@ impl Storage for ConstStorage {
             data: self.0.clone(),
         }])
     }
+
+    fn tagged_series(&self) -> Result<Vec<String>, ResponseError> {
+        Ok(vec![
+            "disk.used;dc=eu;host=a".to_owned(),
+            "disk.used;dc=us;host=b".to_owned(),
+        ])
+    }
+
+    fn query_tagged(
+        &self,
+        series: &str,
+        _interval: Interval,
+        _now: u64,
+    ) -> Result<StorageResponse, ResponseError> {
+        Ok(StorageResponse {
+            name: MetricName(vec![series.to_owned()]),
+            data: self.0.clone(),
+        })
+    }
 }
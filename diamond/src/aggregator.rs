@@ -0,0 +1,371 @@
+//! Aggregation of received datapoints, as carbon-aggregator does with rules like
+//! `<env>.applications.<app>.all.requests (60) = sum <env>.applications.<app>.*.requests`.
+
+use regex::Regex;
+use serde::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+use whisper::point::Point;
+
+use crate::MetricPoint;
+use crate::pipeline::Pipeline;
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct AggregatorConfig {
+    /// Rules in the syntax of carbon's `aggregation-rules.conf`.
+    pub rules: Vec<String>,
+    /// Carbon `aggregation-rules.conf`, its rules follow `rules`.
+    #[serde(default)]
+    pub rules_file: Option<PathBuf>,
+    /// Seconds to wait for late datapoints after an interval is over.
+    pub delay: u32,
+    /// Whether datapoints matching a rule are written as well.
+    pub forward_original: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(Method::Sum),
+            "avg" => Ok(Method::Avg),
+            "min" => Ok(Method::Min),
+            "max" => Ok(Method::Max),
+            "count" => Ok(Method::Count),
+            _ => Err(format!("Unknown aggregation method '{}'", s)),
+        }
+    }
+}
+
+impl Method {
+    fn apply(self, values: &[f64]) -> f64 {
+        match self {
+            Method::Sum => values.iter().sum(),
+            Method::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            Method::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Method::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Method::Count => values.len() as f64,
+        }
+    }
+}
+
+/// `output (frequency) = method input` rule.
+#[derive(Debug, Clone)]
+pub struct AggregatorRule {
+    output: String,
+    frequency: u32,
+    method: Method,
+    input: Regex,
+}
+
+/// Regex of one dot-separated part of an input pattern.
+fn part_regex(part: &str) -> String {
+    let field = |open: &str, close: &str, any: &str| {
+        let start = part.find(open)?;
+        let end = part[start..].find(close)? + start;
+        Some(format!(
+            "{}(?P<{}>{}){}",
+            &part[..start],
+            &part[start + open.len()..end],
+            any,
+            &part[end + close.len()..]
+        ))
+    };
+    if let Some(regex) = field("<<", ">>", ".+?").or_else(|| field("<", ">", "[^.]+?")) {
+        regex
+    } else if part == "*" {
+        "[^.]+".to_owned()
+    } else if let Some(alternatives) = part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+        format!("(?:{})", alternatives.replace(',', "|"))
+    } else {
+        part.replace('*', "[^.]*")
+    }
+}
+
+impl FromStr for AggregatorRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid aggregation rule '{}'", s);
+        let (left, right) = s.split_once('=').ok_or_else(invalid)?;
+        let (output, frequency) = left.trim().split_once('(').ok_or_else(invalid)?;
+        let frequency: u32 = frequency
+            .trim()
+            .strip_suffix(')')
+            .and_then(|f| f.trim().parse().ok())
+            .filter(|f| *f > 0)
+            .ok_or_else(invalid)?;
+        let (method, input) = right
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(invalid)?;
+
+        let parts: Vec<String> = input.trim().split('.').map(part_regex).collect();
+        let input = Regex::new(&format!("^{}$", parts.join("\\.")))
+            .map_err(|e| format!("{}: {}", invalid(), e))?;
+
+        Ok(AggregatorRule {
+            output: output.trim().to_owned(),
+            frequency,
+            method: method.parse()?,
+            input,
+        })
+    }
+}
+
+impl AggregatorRule {
+    /// Name of the aggregated metric a datapoint of `metric` goes to.
+    pub fn output_name(&self, metric: &str) -> Option<String> {
+        let captures = self.input.captures(metric)?;
+        let mut output = String::new();
+        let mut rest = self.output.as_str();
+        while let Some(start) = rest.find('<') {
+            output.push_str(&rest[..start]);
+            // Both `<field>` and `<<field>>` are substituted
+            let field = rest[start..].trim_start_matches('<');
+            let end = field.find('>')?;
+            output.push_str(captures.name(&field[..end])?.as_str());
+            rest = field[end..].trim_start_matches('>');
+        }
+        output.push_str(rest);
+        Some(output)
+    }
+}
+
+/// Parses rules of carbon's `aggregation-rules.conf`, one per line.
+pub fn parse_rules(s: &str) -> Result<Vec<AggregatorRule>, String> {
+    s.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Debug)]
+struct Buffer {
+    frequency: u32,
+    method: Method,
+    intervals: BTreeMap<u32, Vec<f64>>,
+}
+
+/// Buffers datapoints of aggregated metrics until their intervals are over.
+#[derive(Debug)]
+pub struct Aggregator {
+    rules: Vec<AggregatorRule>,
+    delay: u32,
+    forward_original: bool,
+    buffers: Mutex<HashMap<(String, usize), Buffer>>,
+}
+
+impl Aggregator {
+    pub fn new(config: &AggregatorConfig) -> Result<Self, String> {
+        let mut rules = config
+            .rules
+            .iter()
+            .map(|rule| rule.parse())
+            .collect::<Result<Vec<AggregatorRule>, String>>()?;
+        if let Some(path) = &config.rules_file {
+            let file =
+                fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            rules.extend(parse_rules(&file).map_err(|e| format!("{}: {}", path.display(), e))?);
+        }
+
+        Ok(Aggregator {
+            rules,
+            delay: config.delay,
+            forward_original: config.forward_original,
+            buffers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Buffers a datapoint for every rule it matches.
+    /// Returns whether the datapoint itself should be written too.
+    pub fn process(&self, metric: &MetricPoint) -> bool {
+        let mut matched = false;
+        let mut buffers = self.buffers.lock().unwrap();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if let Some(output) = rule.output_name(&metric.name) {
+                matched = true;
+                let interval = metric.point.interval - metric.point.interval % rule.frequency;
+                buffers
+                    .entry((output, index))
+                    .or_insert_with(|| Buffer {
+                        frequency: rule.frequency,
+                        method: rule.method,
+                        intervals: BTreeMap::new(),
+                    })
+                    .intervals
+                    .entry(interval)
+                    .or_default()
+                    .push(metric.point.value);
+            }
+        }
+        !matched || self.forward_original
+    }
+
+    /// Aggregated datapoints of intervals that ended at least `delay` seconds before `now`.
+    pub fn flush(&self, now: u32) -> Vec<MetricPoint> {
+        let mut metrics = Vec::new();
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.retain(|(output, _), buffer| {
+            while let Some(entry) = buffer.intervals.first_entry() {
+                let interval = *entry.key();
+                if interval
+                    .saturating_add(buffer.frequency)
+                    .saturating_add(self.delay)
+                    > now
+                {
+                    break;
+                }
+                let values = entry.remove();
+                metrics.push(MetricPoint {
+                    name: output.clone(),
+                    point: Point {
+                        interval,
+                        value: buffer.method.apply(&values),
+                    },
+                });
+            }
+            !buffer.intervals.is_empty()
+        });
+        metrics.sort_by(|a, b| (&a.name, a.point.interval).cmp(&(&b.name, b.point.interval)));
+        metrics
+    }
+
+    /// Passes aggregated datapoints down the pipeline once a second.
+    pub async fn run(&self, pipeline: Pipeline) {
+        loop {
+            sleep(Duration::from_secs(1)).await;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32;
+            for metric in self.flush(now) {
+                pipeline.receive_aggregated(metric);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: &str, interval: u32, value: f64) -> MetricPoint {
+        MetricPoint {
+            name: name.to_owned(),
+            point: Point { interval, value },
+        }
+    }
+
+    fn aggregator(rules: &[&str], forward_original: bool) -> Aggregator {
+        Aggregator::new(&AggregatorConfig {
+            rules: rules.iter().map(|rule| rule.to_string()).collect(),
+            rules_file: None,
+            delay: 10,
+            forward_original,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn rule_output_name() {
+        let rule: AggregatorRule =
+            "<env>.applications.<app>.all.requests (60) = sum <env>.applications.<app>.*.requests"
+                .parse()
+                .unwrap();
+        assert_eq!(rule.frequency, 60);
+        assert_eq!(rule.method, Method::Sum);
+        assert_eq!(
+            rule.output_name("prod.applications.apache.www01.requests"),
+            Some("prod.applications.apache.all.requests".to_owned())
+        );
+        assert_eq!(rule.output_name("prod.applications.apache.requests"), None);
+        assert_eq!(
+            rule.output_name("prod.applications.apache.www01.requests.count"),
+            None
+        );
+
+        let rule: AggregatorRule = "<<prefix>>.total (10) = avg <<prefix>>.{a,b}.cpu*"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.output_name("x.y.a.cpu0"), Some("x.y.total".to_owned()));
+        assert_eq!(rule.output_name("x.y.c.cpu0"), None);
+    }
+
+    #[test]
+    fn rule_parse_errors() {
+        assert!("a (60) = sum".parse::<AggregatorRule>().is_err());
+        assert!("a = sum b.*".parse::<AggregatorRule>().is_err());
+        assert!("a (0) = sum b.*".parse::<AggregatorRule>().is_err());
+        assert!("a (60) = median b.*".parse::<AggregatorRule>().is_err());
+        assert_eq!(
+            parse_rules("# rollups\n\na.all (60) = max a.*\n")
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn aggregate_and_flush() {
+        let aggregator = aggregator(
+            &[
+                "<app>.all (60) = sum <app>.*",
+                "<app>.count (60) = count <app>.*",
+            ],
+            false,
+        );
+        assert!(!aggregator.process(&metric("web.a", 120, 1.0)));
+        assert!(!aggregator.process(&metric("web.b", 150, 2.0)));
+        assert!(!aggregator.process(&metric("web.a", 180, 4.0)));
+        assert!(aggregator.process(&metric("other", 120, 8.0)));
+
+        // Waiting for late datapoints
+        assert_eq!(aggregator.flush(185), vec![]);
+        assert_eq!(
+            aggregator.flush(190),
+            vec![metric("web.all", 120, 3.0), metric("web.count", 120, 2.0)]
+        );
+        assert_eq!(
+            aggregator.flush(1000),
+            vec![metric("web.all", 180, 4.0), metric("web.count", 180, 1.0)]
+        );
+        assert_eq!(aggregator.flush(2000), vec![]);
+    }
+
+    #[test]
+    fn aggregation_methods() {
+        let values = [2.0, 8.0, 5.0];
+        assert_eq!(Method::Sum.apply(&values), 15.0);
+        assert_eq!(Method::Avg.apply(&values), 5.0);
+        assert_eq!(Method::Min.apply(&values), 2.0);
+        assert_eq!(Method::Max.apply(&values), 8.0);
+        assert_eq!(Method::Count.apply(&values), 3.0);
+    }
+
+    #[test]
+    fn forward_original() {
+        let aggregator = aggregator(&["all (60) = sum *"], true);
+        assert!(aggregator.process(&metric("a", 120, 1.0)));
+    }
+}
@@ -6,8 +6,9 @@ use std::io;
 use std::io::BufRead;
 use std::path::PathBuf;
 use std::process::exit;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use whisper::aggregation::AggregationMethod;
+use whisper::lock::LockArgs;
 use whisper::retention::Retention;
 
 /// Receive metrics from pipe
@@ -26,6 +27,14 @@ struct Args {
     #[arg(long = "aggregationMethod", default_value = "average")]
     aggregation_method: AggregationMethod,
 
+    /// Graphite storage-schemas.conf with retentions by metric pattern
+    #[arg(long = "storage-schemas")]
+    storage_schemas: Option<PathBuf>,
+
+    /// Graphite storage-aggregation.conf with aggregation by metric pattern
+    #[arg(long = "storage-aggregation")]
+    storage_aggregation: Option<PathBuf>,
+
     #[arg(
         name = "retentions",
         help = r#" Default retentions for new files
@@ -38,16 +47,27 @@ Specify lengths of time, for example:
         required = true
     )]
     retentions: Vec<Retention>,
+
+    #[command(flatten)]
+    lock: LockArgs,
 }
 
 fn run(args: Args) -> Result<(), Box<dyn Error>> {
     let stdin = io::stdin();
 
-    let conf = WhisperConfig {
+    let mut conf = WhisperConfig {
         retentions: args.retentions,
         aggregation_method: args.aggregation_method,
         x_files_factor: args.x_files_factor,
+        schemas: Vec::new(),
+        aggregations: Vec::new(),
+        storage_schemas: args.storage_schemas,
+        storage_aggregation: args.storage_aggregation,
+        lock_timeout: args.lock.lock.then_some(args.lock.lock_timeout),
+        header_cache: 0,
     };
+    conf.load_rule_files()?;
+    whisper::lock::set_timeout(conf.lock_timeout.map(Duration::from_millis));
 
     for line in stdin.lock().lines() {
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
@@ -1,15 +1,31 @@
 use clap::Parser;
-use diamond::settings::Settings;
-use diamond::update_silently;
-use futures::join;
+use diamond::aggregator::Aggregator;
+use diamond::cache::{MetricCache, Writer};
+use diamond::carbonlink::{self, CarbonlinkConfig};
+use diamond::filters::Filters;
+use diamond::influx::{self, InfluxConfig};
+use diamond::instrumentation::{Instrumentation, Stats};
+use diamond::pickle::unpickle_metrics;
+use diamond::pipeline::Pipeline;
+use diamond::relay::Relay;
+use diamond::remote_write::{self, RemoteWriteConfig};
+use diamond::settings::{Net, PickleConfig, Settings};
+use diamond::statsd::{Statsd, StatsdConfig};
+use diamond::wal::Wal;
 use futures::stream::StreamExt;
+use std::error::Error;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process::exit;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::{TcpListener, UdpSocket};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task::JoinHandle;
 use tokio_util::codec::Framed;
+use tokio_util::codec::LengthDelimitedCodec;
 use tokio_util::codec::LinesCodec;
+use tokio_util::sync::CancellationToken;
 use tokio_util::udp::UdpFramed;
 
 #[derive(Debug, clap::Parser)]
@@ -23,8 +39,341 @@ struct Args {
     generate: bool,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+enum ListenerConfig {
+    Tcp(Net),
+    Udp(Net),
+    Pickle(PickleConfig),
+    RemoteWrite(RemoteWriteConfig),
+    InfluxTcp(InfluxConfig),
+    InfluxUdp(InfluxConfig),
+    Statsd(StatsdConfig),
+    Carbonlink(CarbonlinkConfig),
+}
+
+impl ListenerConfig {
+    fn all(settings: &Settings) -> Vec<Self> {
+        vec![
+            ListenerConfig::Tcp(settings.tcp.clone()),
+            ListenerConfig::Udp(settings.udp.clone()),
+            ListenerConfig::Pickle(settings.pickle.clone()),
+            ListenerConfig::RemoteWrite(settings.remote_write.clone()),
+            ListenerConfig::InfluxTcp(settings.influx.clone()),
+            ListenerConfig::InfluxUdp(settings.influx.clone()),
+            ListenerConfig::Statsd(settings.statsd.clone()),
+            ListenerConfig::Carbonlink(settings.carbonlink.clone()),
+        ]
+    }
+
+    fn enabled(&self) -> bool {
+        match self {
+            ListenerConfig::RemoteWrite(config) => config.enabled,
+            ListenerConfig::InfluxTcp(config) | ListenerConfig::InfluxUdp(config) => config.enabled,
+            ListenerConfig::Statsd(config) => config.enabled,
+            ListenerConfig::Carbonlink(config) => config.enabled,
+            _ => true,
+        }
+    }
+}
+
+/// Protocol of line based listeners.
+#[derive(Debug, Clone)]
+enum Format {
+    Plaintext,
+    Influx(InfluxConfig),
+    Statsd(Arc<Statsd>),
+}
+
+impl Format {
+    fn receive_line(&self, pipeline: &Pipeline, line: &str) {
+        match self {
+            Format::Plaintext => pipeline.receive_line(line),
+            Format::Influx(config) => match influx::parse_line(line, config, now()) {
+                Ok(metrics) => metrics
+                    .into_iter()
+                    .for_each(|metric| pipeline.receive(metric)),
+                Err(e) => eprintln!("{}", e),
+            },
+            Format::Statsd(statsd) => {
+                if let Err(e) = statsd.receive_line(line) {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Where listeners pass what they receive.
+#[derive(Clone)]
+struct Handlers {
+    pipeline: Pipeline,
+    statsd: Arc<Statsd>,
+    writer: Arc<Writer>,
+}
+
+/// Accept loop of a listener. Stopping it keeps established connections,
+/// they are closed on shutdown only.
+struct Listener {
+    config: ListenerConfig,
+    stop: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl Listener {
+    async fn start(
+        config: ListenerConfig,
+        handlers: Handlers,
+        shutdown: &CancellationToken,
+    ) -> Result<Self, Box<dyn Error>> {
+        let Handlers {
+            pipeline,
+            statsd,
+            writer,
+        } = handlers;
+        let stop = shutdown.child_token();
+        let task = match &config {
+            // Disabled listener is kept to be started on reload
+            _ if !config.enabled() => tokio::spawn(async {}),
+            ListenerConfig::Tcp(net) => {
+                let addr: SocketAddr = format!("{0}:{1}", &net.host, net.port).parse()?;
+                let listener = TcpListener::bind(&addr).await?;
+                println!("server running on tcp {}", addr);
+                tokio::spawn(tcp_server(
+                    listener,
+                    Format::Plaintext,
+                    pipeline,
+                    stop.clone(),
+                    shutdown.clone(),
+                ))
+            }
+            ListenerConfig::Udp(net) => {
+                let addr: SocketAddr = format!("{0}:{1}", &net.host, net.port).parse()?;
+                let socket = UdpSocket::bind(&addr).await?;
+                println!("server running on udp {}", addr);
+                tokio::spawn(udp_server(
+                    socket,
+                    Format::Plaintext,
+                    pipeline,
+                    stop.clone(),
+                ))
+            }
+            ListenerConfig::Pickle(pickle) => {
+                let addr: SocketAddr = format!("{0}:{1}", &pickle.host, pickle.port).parse()?;
+                let listener = TcpListener::bind(&addr).await?;
+                println!("server running on pickle {}", addr);
+                tokio::spawn(pickle_server(
+                    listener,
+                    pickle.max_message_size,
+                    pipeline,
+                    stop.clone(),
+                    shutdown.clone(),
+                ))
+            }
+            ListenerConfig::RemoteWrite(config) => {
+                let addr: SocketAddr = format!("{0}:{1}", &config.host, config.port).parse()?;
+                let listener = TcpListener::bind(&addr).await?;
+                println!("server running on remote_write {}", addr);
+                tokio::spawn(remote_write::serve(
+                    listener,
+                    config.clone(),
+                    pipeline,
+                    stop.clone(),
+                    shutdown.clone(),
+                ))
+            }
+            ListenerConfig::InfluxTcp(config) => {
+                let addr: SocketAddr = format!("{0}:{1}", &config.host, config.port).parse()?;
+                let listener = TcpListener::bind(&addr).await?;
+                println!("server running on influx tcp {}", addr);
+                tokio::spawn(tcp_server(
+                    listener,
+                    Format::Influx(config.clone()),
+                    pipeline,
+                    stop.clone(),
+                    shutdown.clone(),
+                ))
+            }
+            ListenerConfig::InfluxUdp(config) => {
+                let addr: SocketAddr = format!("{0}:{1}", &config.host, config.port).parse()?;
+                let socket = UdpSocket::bind(&addr).await?;
+                println!("server running on influx udp {}", addr);
+                tokio::spawn(udp_server(
+                    socket,
+                    Format::Influx(config.clone()),
+                    pipeline,
+                    stop.clone(),
+                ))
+            }
+            ListenerConfig::Statsd(config) => {
+                let addr: SocketAddr = format!("{0}:{1}", &config.host, config.port).parse()?;
+                let socket = UdpSocket::bind(&addr).await?;
+                println!("server running on statsd {}", addr);
+                tokio::spawn(udp_server(
+                    socket,
+                    Format::Statsd(statsd),
+                    pipeline,
+                    stop.clone(),
+                ))
+            }
+            ListenerConfig::Carbonlink(config) => {
+                let addr: SocketAddr = format!("{0}:{1}", &config.host, config.port).parse()?;
+                let listener = TcpListener::bind(&addr).await?;
+                println!("server running on carbonlink {}", addr);
+                tokio::spawn(carbonlink::serve(
+                    listener,
+                    writer,
+                    config.max_message_size,
+                    stop.clone(),
+                    shutdown.clone(),
+                ))
+            }
+        };
+        Ok(Listener { config, stop, task })
+    }
+
+    async fn stop(self) {
+        self.stop.cancel();
+        self.task.await.unwrap_or_else(|e| eprintln!("{}", e));
+    }
+
+    /// Restarts the listener if its config changed, falling back to the old config.
+    /// A listener that could not be restarted before is started again.
+    async fn reload(
+        listener: Option<Self>,
+        config: ListenerConfig,
+        handlers: Handlers,
+        shutdown: &CancellationToken,
+    ) -> Option<Self> {
+        let Some(listener) = listener else {
+            return Listener::start(config, handlers, shutdown)
+                .await
+                .map_err(|e| eprintln!("listener cannot be started = {}", e))
+                .ok();
+        };
+        if config == listener.config {
+            return Some(listener);
+        }
+        let old = listener.config.clone();
+        listener.stop().await;
+        match Listener::start(config, handlers.clone(), shutdown).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                eprintln!("listener cannot be started = {}", e);
+                Listener::start(old, handlers, shutdown)
+                    .await
+                    .map_err(|e| eprintln!("listener stopped = {}", e))
+                    .ok()
+            }
+        }
+    }
+}
+
+async fn tcp_server(
+    listener: TcpListener,
+    format: Format,
+    pipeline: Pipeline,
+    stop: CancellationToken,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let accepted = tokio::select! {
+            _ = stop.cancelled() => break,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
+            Ok((sock, _)) => {
+                let local_pipeline = pipeline.clone();
+                let format = format.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    let mut framed_sock = Framed::new(sock, LinesCodec::new());
+                    while let Some(line) = tokio::select! {
+                        _ = shutdown.cancelled() => None,
+                        line = framed_sock.next() => line,
+                    } {
+                        match line {
+                            Ok(line) => format.receive_line(&local_pipeline, &line),
+                            Err(e) => eprintln!("tcp receive error = {:?}", e),
+                        }
+                    }
+                });
+            }
+            Err(e) => eprintln!("tcp accept error = {:?}", e),
+        }
+    }
+}
+
+async fn udp_server(
+    socket: UdpSocket,
+    format: Format,
+    pipeline: Pipeline,
+    stop: CancellationToken,
+) {
+    let mut incoming = UdpFramed::new(socket, LinesCodec::new());
+    while let Some(line) = tokio::select! {
+        _ = stop.cancelled() => None,
+        line = incoming.next() => line,
+    } {
+        match line {
+            Ok((line, _)) => format.receive_line(&pipeline, &line),
+            Err(e) => eprintln!("udp receive error = {:?}", e),
+        }
+    }
+}
+
+async fn pickle_server(
+    listener: TcpListener,
+    max_message_size: usize,
+    pipeline: Pipeline,
+    stop: CancellationToken,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let accepted = tokio::select! {
+            _ = stop.cancelled() => break,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
+            Ok((sock, _)) => {
+                let local_pipeline = pipeline.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    // Messages are prefixed with a 4-byte big-endian length
+                    let codec = LengthDelimitedCodec::builder()
+                        .max_frame_length(max_message_size)
+                        .new_codec();
+                    let mut framed_sock = Framed::new(sock, codec);
+                    while let Some(message) = tokio::select! {
+                        _ = shutdown.cancelled() => None,
+                        message = framed_sock.next() => message,
+                    } {
+                        match message.map(|message| unpickle_metrics(&message)) {
+                            Ok(Ok(metrics)) => metrics
+                                .into_iter()
+                                .for_each(|metric| local_pipeline.receive(metric)),
+                            Ok(Err(e)) => eprintln!("{}", e),
+                            Err(e) => {
+                                eprintln!("pickle receive error = {:?}", e);
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+            Err(e) => eprintln!("pickle accept error = {:?}", e),
+        }
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
     if args.generate {
@@ -32,54 +381,184 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         exit(1);
     }
 
-    let settings = Settings::new(args.config)?;
+    let settings = Settings::new(args.config.clone())?;
+    whisper::lock::set_timeout(settings.whisper.lock_timeout.map(Duration::from_millis));
+    whisper::header_cache::set_capacity(settings.whisper.header_cache);
 
-    let tcp_addr: SocketAddr = format!("{0}:{1}", &settings.tcp.host, settings.tcp.port).parse()?;
-    let udp_addr: SocketAddr = format!("{0}:{1}", &settings.udp.host, settings.udp.port).parse()?;
+    let cache = Arc::new(MetricCache::new(
+        settings.cache.max_size,
+        settings.cache.write_strategy,
+    ));
+    let settings = Arc::new(settings);
 
-    let tcp_listener = TcpListener::bind(&tcp_addr).await?;
-    println!("server running on tcp {}", tcp_addr);
+    let stats = Arc::new(Stats::default());
+    let filters = Arc::new(Filters::new(&settings.filters)?);
+    let mut pipeline = Pipeline::new(cache.clone())
+        .with_filters(filters.clone())
+        .with_stats(stats.clone());
+    let mut instrumentation = Instrumentation::new(
+        &settings.instrumentation,
+        stats.clone(),
+        cache.clone(),
+        filters.clone(),
+    );
 
-    let udp_listener = UdpSocket::bind(&udp_addr).await?;
-    println!("server running on udp {}", udp_addr);
+    let writer = Arc::new(Writer::new(cache, settings.clone()).with_stats(stats));
+    let mut wal = None;
+    let stop_writers = CancellationToken::new();
+    let mut writers = Vec::new();
+    let relay = if settings.relay.enabled {
+        let relay = Arc::new(Relay::new(&settings.relay)?);
+        pipeline = pipeline.with_relay(relay.clone());
+        instrumentation = instrumentation.with_relay(relay.clone());
+        let sender = relay.clone();
+        tokio::spawn(async move { sender.run().await });
+        Some(relay)
+    } else {
+        if settings.wal.enabled {
+            let log = Arc::new(Wal::open(&settings.wal)?);
+            // Datapoints logged before a crash are written before new ones are received
+            let replayed = log.replay(&settings.db_path, &settings.whisper, now())?;
+            println!("write-ahead log replayed {} datapoints", replayed);
+            pipeline = pipeline.with_wal(log.clone());
+            wal = Some(log.clone());
+            let writer = writer.clone();
+            tokio::spawn(log.run(writer));
+        }
+        for _ in 0..settings.cache.writers.max(1) {
+            let writer = writer.clone();
+            let stop = stop_writers.clone();
+            writers.push(std::thread::spawn(move || writer.run(&stop)));
+        }
+        None
+    };
 
-    let config_tcp = Arc::new(settings);
-    let config_udp = config_tcp.clone();
+    let aggregator = Arc::new(Aggregator::new(&settings.aggregator)?);
+    if !aggregator.is_empty() {
+        pipeline = pipeline.with_aggregator(aggregator.clone());
+        let aggregator = aggregator.clone();
+        let pipeline = pipeline.clone();
+        tokio::spawn(async move { aggregator.run(pipeline).await });
+    }
 
-    let tcp_server = async move {
-        loop {
-            match tcp_listener.accept().await {
-                Ok((sock, _)) => {
-                    let local_config = config_tcp.clone();
-                    tokio::spawn(async move {
-                        let mut framed_sock = Framed::new(sock, LinesCodec::new());
-                        while let Some(line) = framed_sock.next().await {
-                            match line {
-                                Ok(line) => update_silently(&line, &local_config),
-                                Err(e) => eprintln!("tcp receive error = {:?}", e),
-                            }
-                        }
-                    });
-                }
-                Err(e) => eprintln!("tcp accept error = {:?}", e),
+    let instrumentation = Arc::new(instrumentation);
+    let interval = settings.instrumentation.interval;
+    if interval > 0 {
+        let instrumentation = instrumentation.clone();
+        let pipeline = pipeline.clone();
+        tokio::spawn(async move { instrumentation.run(pipeline, interval).await });
+    }
+    if let Some(prometheus) = &settings.instrumentation.prometheus {
+        let prometheus_addr: SocketAddr =
+            format!("{0}:{1}", &prometheus.host, prometheus.port).parse()?;
+        let prometheus_listener = TcpListener::bind(&prometheus_addr).await?;
+        println!("server running on prometheus {}", prometheus_addr);
+        tokio::spawn(async move { instrumentation.serve_prometheus(prometheus_listener).await });
+    }
+
+    // Started regardless of `enabled`, the listener may be enabled on reload
+    let statsd = Arc::new(Statsd::new(&settings.statsd));
+    {
+        let statsd = statsd.clone();
+        let pipeline = pipeline.clone();
+        tokio::spawn(async move { statsd.run(pipeline).await });
+    }
+
+    let handlers = Handlers {
+        pipeline: pipeline.clone(),
+        statsd: statsd.clone(),
+        writer: writer.clone(),
+    };
+    let shutdown = CancellationToken::new();
+    // One slot per listener in the order of `ListenerConfig::all`
+    let mut listeners = Vec::new();
+    for config in ListenerConfig::all(&settings) {
+        listeners.push(Some(
+            Listener::start(config, handlers.clone(), &shutdown).await?,
+        ));
+    }
+
+    let started = settings.clone();
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+            _ = sighup.recv() => {}
+        }
+
+        let settings = match Settings::new(args.config.clone()) {
+            Ok(settings) => settings,
+            Err(e) => {
+                eprintln!("config is not reloaded = {}", e);
+                continue;
+            }
+        };
+        for (section, changed) in [
+            ("cache", settings.cache != started.cache),
+            ("relay", settings.relay != started.relay),
+            ("aggregator", settings.aggregator != started.aggregator),
+            ("wal", settings.wal != started.wal),
+            (
+                "instrumentation",
+                settings.instrumentation != started.instrumentation,
+            ),
+        ] {
+            if changed {
+                eprintln!(
+                    "{} settings are not reloaded, restart to apply them",
+                    section
+                );
             }
         }
-    };
+        if let Err(e) = filters.reload(&settings.filters) {
+            eprintln!("filters are not reloaded = {}", e);
+        }
+        for (slot, config) in listeners.iter_mut().zip(ListenerConfig::all(&settings)) {
+            *slot = Listener::reload(slot.take(), config, handlers.clone(), &shutdown).await;
+        }
+        statsd.reload(&settings.statsd);
+        whisper::lock::set_timeout(settings.whisper.lock_timeout.map(Duration::from_millis));
+        whisper::header_cache::set_capacity(settings.whisper.header_cache);
+        writer.reload(Arc::new(settings));
+        println!("config reloaded");
+    }
 
-    let udp_server = async move {
-        let mut incoming = UdpFramed::new(udp_listener, LinesCodec::new());
-        while let Some(line) = incoming.next().await {
-            let local_config = config_udp.clone();
-            tokio::spawn(async move {
-                match line {
-                    Ok((line, _)) => update_silently(&line, &local_config),
-                    Err(e) => eprintln!("udp receive error = {:?}", e),
+    println!("shutting down");
+    shutdown.cancel();
+    for listener in listeners.into_iter().flatten() {
+        listener.stop().await;
+    }
+
+    for metric in statsd.flush(now()) {
+        pipeline.receive(metric);
+    }
+    // Intervals still waiting for late datapoints are written as they are
+    for metric in aggregator.flush(u32::MAX) {
+        pipeline.receive_aggregated(metric);
+    }
+    match relay {
+        Some(relay) => relay.flush(Duration::from_secs(10)).await,
+        None => {
+            stop_writers.cancel();
+            let flushed = writer.clone();
+            tokio::task::spawn_blocking(move || {
+                // Metrics taken by a running writer would be skipped by the flush
+                for handle in writers {
+                    if handle.join().is_err() {
+                        eprintln!("writer panicked");
+                    }
                 }
-            });
+                flushed.flush(now())
+            })
+            .await?;
+            if let Some(wal) = wal {
+                wal.close(|| writer.oldest_segment())?;
+            }
         }
-    };
-
-    join!(udp_server, tcp_server);
+    }
 
     Ok(())
 }
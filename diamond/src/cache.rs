@@ -0,0 +1,628 @@
+use serde::*;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use whisper::point::Point;
+
+use crate::instrumentation::Stats;
+use crate::settings::Settings;
+use crate::token_bucket::TokenBucket;
+use crate::wal::Wal;
+use crate::{MetricPath, MetricPoint, MetricPoints, metric_file, points_update};
+
+/// Order in which writers take metrics out of the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DrainStrategy {
+    /// Metric with the most datapoints first, keeps the cache small.
+    Max,
+    /// Metrics in the order they entered the cache.
+    Naive,
+    /// Metrics sorted by the number of datapoints, re-sorted once all were written.
+    #[default]
+    Sorted,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct CacheConfig {
+    /// Maximum number of datapoints in the cache, new ones are dropped above it.
+    pub max_size: usize,
+    /// Maximum number of whisper file updates per second, 0 is unlimited.
+    pub max_updates_per_second: u32,
+    pub write_strategy: DrainStrategy,
+    /// Number of writer tasks.
+    pub writers: usize,
+    /// Maximum number of new whisper files per minute, 0 is unlimited.
+    pub max_creates_per_minute: u32,
+    /// Maximum number of datapoints kept while their files wait to be created.
+    pub max_deferred_creates: usize,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    metrics: HashMap<String, BTreeMap<u32, f64>>,
+    queue: VecDeque<String>,
+    size: usize,
+    /// Oldest write-ahead log segment with datapoints of a metric.
+    segments: HashMap<String, u64>,
+}
+
+/// Metrics taken out of the cache and not written yet, with their write-ahead log segments.
+type InFlight = Arc<Mutex<HashMap<String, Option<u64>>>>;
+
+/// In-memory cache of received datapoints, keyed by metric name.
+#[derive(Debug)]
+pub struct MetricCache {
+    max_size: usize,
+    strategy: DrainStrategy,
+    state: Mutex<CacheState>,
+    /// Locked after `state`, may be shared with another cache.
+    in_flight: InFlight,
+}
+
+impl MetricCache {
+    pub fn new(max_size: usize, strategy: DrainStrategy) -> Self {
+        Self {
+            max_size,
+            strategy,
+            state: Mutex::new(CacheState::default()),
+            in_flight: InFlight::default(),
+        }
+    }
+
+    /// Shares metrics in flight with `cache`, so that a metric is taken out of
+    /// one of them at a time.
+    fn with_in_flight_of(mut self, cache: &MetricCache) -> Self {
+        self.in_flight = cache.in_flight.clone();
+        self
+    }
+
+    /// Number of datapoints in the cache.
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+
+    /// Adds a datapoint, a later one for the same timestamp replaces the earlier.
+    /// Returns `false` if the cache is full and the datapoint was dropped.
+    pub fn store(&self, metric: MetricPoint) -> bool {
+        self.store_logged(metric, None)
+    }
+
+    /// Adds a datapoint appended to the write-ahead log `segment`.
+    pub fn store_logged(&self, metric: MetricPoint, segment: Option<u64>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let full = state.size >= self.max_size;
+        let state = &mut *state;
+
+        let points = match state.metrics.get_mut(&metric.name) {
+            Some(points) => points,
+            None if full => return false,
+            None => {
+                if self.strategy == DrainStrategy::Naive {
+                    state.queue.push_back(metric.name.clone());
+                }
+                state.metrics.entry(metric.name.clone()).or_default()
+            }
+        };
+        if full && !points.contains_key(&metric.point.interval) {
+            return false;
+        }
+        if points
+            .insert(metric.point.interval, metric.point.value)
+            .is_none()
+        {
+            state.size += 1;
+        }
+        if let Some(segment) = segment {
+            state.segments.entry(metric.name).or_insert(segment);
+        }
+        true
+    }
+
+    /// Datapoints of a metric not taken out of the cache yet, sorted by timestamp.
+    pub fn get(&self, name: &str) -> Vec<Point> {
+        let state = self.state.lock().unwrap();
+        state.metrics.get(name).map_or_else(Vec::new, |points| {
+            points
+                .iter()
+                .map(|(interval, value)| Point {
+                    interval: *interval,
+                    value: *value,
+                })
+                .collect()
+        })
+    }
+
+    /// Oldest write-ahead log segment with datapoints not written yet.
+    pub fn oldest_segment(&self) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        let in_flight = self.in_flight.lock().unwrap();
+        state
+            .segments
+            .values()
+            .chain(in_flight.values().flatten())
+            .min()
+            .copied()
+    }
+
+    fn in_flight_segment(&self, name: &str) -> Option<u64> {
+        self.in_flight.lock().unwrap().get(name).copied().flatten()
+    }
+
+    /// Marks datapoints of a metric taken out of the cache as written or moved,
+    /// returns their write-ahead log segment. Datapoints received meanwhile can
+    /// be taken again.
+    pub fn release(&self, name: &str) -> Option<u64> {
+        self.in_flight.lock().unwrap().remove(name).flatten()
+    }
+
+    /// Takes all datapoints of the next metric to write. Metrics taken and not
+    /// released yet are skipped, so that two writers never update the same file.
+    pub fn pop(&self) -> Option<MetricPoints> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        let name = match self.strategy {
+            DrainStrategy::Max => state
+                .metrics
+                .iter()
+                .filter(|(name, _)| !in_flight.contains_key(*name))
+                .max_by_key(|(_, points)| points.len())
+                .map(|(name, _)| name.clone())?,
+            DrainStrategy::Naive | DrainStrategy::Sorted => {
+                // Metrics in flight keep their place in the queue
+                let next = |state: &CacheState| {
+                    state
+                        .queue
+                        .iter()
+                        .position(|name| !in_flight.contains_key(name))
+                };
+                let mut index = next(state);
+                if index.is_none() && self.strategy == DrainStrategy::Sorted {
+                    let mut names: Vec<(&String, usize)> = state
+                        .metrics
+                        .iter()
+                        .map(|(name, points)| (name, points.len()))
+                        .collect();
+                    names.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+                    state.queue = names.into_iter().map(|(name, _)| name.clone()).collect();
+                    index = next(state);
+                }
+                state.queue.remove(index?)?
+            }
+        };
+
+        let points = state.metrics.remove(&name)?;
+        state.size -= points.len();
+        let segment = state.segments.remove(&name);
+        in_flight.insert(name.clone(), segment);
+        Some(MetricPoints {
+            name,
+            points: points
+                .into_iter()
+                .map(|(interval, value)| Point { interval, value })
+                .collect(),
+        })
+    }
+
+    /// Adds a received datapoint, reporting invalid names and drops.
+    pub fn store_metric_silently(&self, metric: MetricPoint) {
+        if let Err(e) = metric.name.parse::<MetricPath>() {
+            eprintln!("{}", e);
+        } else if !self.store(metric) {
+            eprintln!("cache is full, datapoint dropped");
+        }
+    }
+
+    /// Adds a received datapoint through the write-ahead log, reporting invalid names and drops.
+    pub fn store_metric_logged(&self, metric: MetricPoint, wal: &Wal) {
+        if let Err(e) = metric.name.parse::<MetricPath>() {
+            eprintln!("{}", e);
+            return;
+        }
+        let stored = wal.append(metric, |metric, segment| self.store_logged(metric, segment));
+        if !stored {
+            eprintln!("cache is full, datapoint dropped");
+        }
+    }
+}
+
+/// Drains the cache into whisper files.
+pub struct Writer {
+    cache: Arc<MetricCache>,
+    settings: RwLock<Arc<Settings>>,
+    bucket: Option<Mutex<TokenBucket>>,
+    create_bucket: Option<Mutex<TokenBucket>>,
+    /// Datapoints of metrics whose files wait for `max_creates_per_minute`.
+    deferred: MetricCache,
+    stats: Arc<Stats>,
+}
+
+impl Writer {
+    pub fn new(cache: Arc<MetricCache>, settings: Arc<Settings>) -> Self {
+        let rate = settings.cache.max_updates_per_second;
+        let bucket = (rate > 0).then(|| {
+            Mutex::new(TokenBucket::new(
+                f64::from(rate),
+                f64::from(rate),
+                Instant::now(),
+            ))
+        });
+        let creates = settings.cache.max_creates_per_minute;
+        let create_bucket = (creates > 0).then(|| {
+            Mutex::new(TokenBucket::new(
+                f64::from(creates),
+                f64::from(creates) / 60.0,
+                Instant::now(),
+            ))
+        });
+        let deferred = MetricCache::new(settings.cache.max_deferred_creates, DrainStrategy::Naive)
+            .with_in_flight_of(&cache);
+        Self {
+            cache,
+            settings: RwLock::new(settings),
+            bucket,
+            create_bucket,
+            deferred,
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Replaces the settings of new writes, like schema rules; limits are kept.
+    pub fn reload(&self, settings: Arc<Settings>) {
+        *self.settings.write().unwrap() = settings;
+    }
+
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Time to wait before the next file update is allowed.
+    fn wait_time(&self) -> Duration {
+        self.bucket.as_ref().map_or(Duration::ZERO, |bucket| {
+            bucket.lock().unwrap().wait_time(1.0, Instant::now())
+        })
+    }
+
+    fn count_update(&self) {
+        if let Some(bucket) = &self.bucket {
+            // Writers racing for the last token may go slightly over the limit
+            bucket.lock().unwrap().consume(1.0, Instant::now());
+        }
+    }
+
+    /// Takes a token for a new file if the limit allows it.
+    fn create_allowed(&self) -> bool {
+        self.create_bucket
+            .as_ref()
+            .is_none_or(|bucket| bucket.lock().unwrap().consume(1.0, Instant::now()))
+    }
+
+    /// Number of datapoints waiting for their files to be created.
+    pub fn deferred_size(&self) -> usize {
+        self.deferred.size()
+    }
+
+    /// Datapoints of a metric not written yet, including deferred ones.
+    pub fn query(&self, name: &str) -> Vec<Point> {
+        let points: BTreeMap<u32, f64> = self
+            .deferred
+            .get(name)
+            .into_iter()
+            .chain(self.cache.get(name))
+            .map(|point| (point.interval, point.value))
+            .collect();
+        points
+            .into_iter()
+            .map(|(interval, value)| Point { interval, value })
+            .collect()
+    }
+
+    /// Oldest write-ahead log segment with datapoints not written yet.
+    pub fn oldest_segment(&self) -> Option<u64> {
+        self.cache
+            .oldest_segment()
+            .into_iter()
+            .chain(self.deferred.oldest_segment())
+            .min()
+    }
+
+    /// Keeps datapoints in memory until their file can be created.
+    fn defer(&self, metric: MetricPoints) {
+        let name = metric.name.clone();
+        let segment = self.cache.in_flight_segment(&name);
+        let points: Vec<MetricPoint> = metric.into();
+        let dropped = points
+            .into_iter()
+            .filter(|point| !self.deferred.store_logged(point.clone(), segment))
+            .count();
+        self.cache.release(&name);
+        if dropped > 0 {
+            self.stats.dropped_create(dropped);
+            eprintln!(
+                "too many deferred creates, {} datapoints of {} dropped",
+                dropped, name
+            );
+        }
+    }
+
+    fn write(&self, metric: &MetricPoints, now: u32) {
+        self.count_update();
+        let start = Instant::now();
+        let settings = self.settings();
+        match points_update(metric, &settings.db_path, &settings.whisper, now) {
+            Ok(created) => self
+                .stats
+                .update(metric.points.len(), created, start.elapsed()),
+            Err(e) => {
+                self.stats.error();
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    /// Writes or defers the next metric, returns `false` if there was none.
+    fn write_next(&self, now: u32) -> bool {
+        if self.deferred.size() > 0
+            && self.create_allowed()
+            && let Some(metric) = self.deferred.pop()
+        {
+            self.write(&metric, now);
+            self.deferred.release(&metric.name);
+            return true;
+        }
+
+        let Some(metric) = self.cache.pop() else {
+            return false;
+        };
+        match metric_file(&metric.name, &self.settings().db_path) {
+            Ok(path) if !path.exists() && !self.create_allowed() => self.defer(metric),
+            _ => {
+                self.write(&metric, now);
+                self.cache.release(&metric.name);
+            }
+        }
+        true
+    }
+
+    /// Writes all cached and deferred datapoints regardless of the limits, as on shutdown.
+    pub fn flush(&self, now: u32) {
+        while let Some(metric) = self.deferred.pop() {
+            self.write(&metric, now);
+            self.deferred.release(&metric.name);
+        }
+        while let Some(metric) = self.cache.pop() {
+            self.write(&metric, now);
+            self.cache.release(&metric.name);
+        }
+    }
+
+    /// Writes metrics until `stop` is cancelled, one batch per file update.
+    /// Blocks on file I/O, run it on a thread of its own.
+    pub fn run(&self, stop: &CancellationToken) {
+        while !stop.is_cancelled() {
+            let wait = self.wait_time();
+            if !wait.is_zero() {
+                sleep(wait);
+                continue;
+            }
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32;
+            if !self.write_next(now) {
+                sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: &str, interval: u32, value: f64) -> MetricPoint {
+        MetricPoint {
+            name: name.to_owned(),
+            point: Point { interval, value },
+        }
+    }
+
+    fn fill(cache: &MetricCache) {
+        for (name, count) in [("a", 1), ("b", 3), ("c", 2)] {
+            for interval in 0..count {
+                assert!(cache.store(metric(name, interval, 1.0)));
+            }
+        }
+    }
+
+    fn drain(cache: &MetricCache) -> Vec<String> {
+        std::iter::from_fn(|| cache.pop()).map(|m| m.name).collect()
+    }
+
+    #[test]
+    fn store_and_pop() {
+        let cache = MetricCache::new(10, DrainStrategy::Max);
+        assert!(cache.store(metric("a", 20, 1.0)));
+        assert!(cache.store(metric("a", 10, 2.0)));
+        assert!(cache.store(metric("a", 20, 3.0)));
+        assert_eq!(cache.size(), 2);
+
+        let popped = cache.pop().unwrap();
+        assert_eq!(popped.name, "a");
+        assert_eq!(
+            popped.points,
+            vec![
+                Point {
+                    interval: 10,
+                    value: 2.0
+                },
+                Point {
+                    interval: 20,
+                    value: 3.0
+                }
+            ]
+        );
+        assert_eq!(cache.size(), 0);
+        assert!(cache.pop().is_none());
+    }
+
+    #[test]
+    fn full_cache() {
+        let cache = MetricCache::new(2, DrainStrategy::Max);
+        assert!(cache.store(metric("a", 10, 1.0)));
+        assert!(cache.store(metric("b", 10, 1.0)));
+        assert!(!cache.store(metric("c", 10, 1.0)));
+        assert!(!cache.store(metric("a", 20, 1.0)));
+        // Replacing a datapoint does not grow the cache
+        assert!(cache.store(metric("a", 10, 2.0)));
+        assert_eq!(cache.size(), 2);
+    }
+
+    #[test]
+    fn drain_strategies() {
+        let cache = MetricCache::new(100, DrainStrategy::Max);
+        fill(&cache);
+        assert_eq!(drain(&cache), vec!["b", "c", "a"]);
+
+        let cache = MetricCache::new(100, DrainStrategy::Naive);
+        fill(&cache);
+        assert_eq!(drain(&cache), vec!["a", "b", "c"]);
+
+        let cache = MetricCache::new(100, DrainStrategy::Sorted);
+        fill(&cache);
+        assert_eq!(cache.pop().unwrap().name, "b");
+        // Not re-sorted until the current pass is over
+        for interval in 0..5 {
+            cache.store(metric("d", interval, 1.0));
+        }
+        assert_eq!(drain(&cache), vec!["c", "a", "d"]);
+    }
+
+    #[test]
+    fn pop_skips_in_flight() {
+        for strategy in [
+            DrainStrategy::Max,
+            DrainStrategy::Naive,
+            DrainStrategy::Sorted,
+        ] {
+            let cache = MetricCache::new(100, strategy);
+            assert!(cache.store(metric("a", 10, 1.0)));
+            assert_eq!(cache.pop().unwrap().points.len(), 1);
+            // Received while "a" is written by another writer
+            assert!(cache.store(metric("a", 20, 1.0)));
+            assert!(cache.pop().is_none());
+
+            assert!(cache.store(metric("b", 10, 1.0)));
+            assert_eq!(cache.pop().unwrap().name, "b");
+            cache.release("a");
+            assert_eq!(cache.pop().unwrap().name, "a");
+            assert!(cache.pop().is_none());
+        }
+    }
+
+    #[test]
+    fn writer_defers_creates() {
+        let dir = tempfile::Builder::new()
+            .prefix("diamond")
+            .tempdir()
+            .unwrap();
+        let mut settings = Settings::new(None).unwrap();
+        settings.db_path = dir.path().to_path_buf();
+        settings.cache.max_updates_per_second = 0;
+        settings.cache.max_creates_per_minute = 1;
+        settings.cache.max_deferred_creates = 2;
+
+        let cache = Arc::new(MetricCache::new(100, DrainStrategy::Naive));
+        let stats = Arc::new(Stats::default());
+        let writer = Writer::new(cache.clone(), Arc::new(settings)).with_stats(stats.clone());
+        cache.store(metric("a", 60, 1.0));
+        cache.store(metric("b", 60, 1.0));
+        cache.store(metric("b", 120, 1.0));
+        cache.store(metric("c", 60, 1.0));
+
+        while writer.write_next(180) {}
+        assert!(dir.path().join("a.wsp").exists());
+        assert!(!dir.path().join("b.wsp").exists());
+        assert!(!dir.path().join("c.wsp").exists());
+        assert_eq!(writer.deferred_size(), 2);
+        // Deferred and cached datapoints are both visible
+        cache.store(metric("b", 120, 3.0));
+        assert_eq!(
+            writer.query("b"),
+            vec![
+                Point {
+                    interval: 60,
+                    value: 1.0
+                },
+                Point {
+                    interval: 120,
+                    value: 3.0
+                }
+            ]
+        );
+        assert_eq!(writer.query("a"), vec![]);
+        writer.write_next(180);
+
+        // Updates of existing files are not limited
+        cache.store(metric("a", 120, 2.0));
+        assert!(writer.write_next(180));
+        assert_eq!(cache.size(), 0);
+
+        assert_eq!(stats.creates(), 1);
+        assert_eq!(stats.dropped_creates(), 1);
+
+        writer.flush(180);
+        assert!(dir.path().join("b.wsp").exists());
+        assert_eq!(writer.deferred_size(), 0);
+        assert_eq!(stats.creates(), 2);
+    }
+
+    #[test]
+    fn writer_defers_partially() {
+        let dir = tempfile::Builder::new()
+            .prefix("diamond")
+            .tempdir()
+            .unwrap();
+        let mut settings = Settings::new(None).unwrap();
+        settings.db_path = dir.path().to_path_buf();
+        settings.cache.max_creates_per_minute = 1;
+        settings.cache.max_deferred_creates = 2;
+
+        let cache = Arc::new(MetricCache::new(100, DrainStrategy::Naive));
+        let stats = Arc::new(Stats::default());
+        let writer = Writer::new(cache.clone(), Arc::new(settings)).with_stats(stats.clone());
+        assert!(writer.create_allowed());
+        for interval in [60, 120, 180] {
+            cache.store(metric("a", interval, 1.0));
+        }
+        assert!(writer.write_next(180));
+        assert_eq!(writer.deferred_size(), 2);
+        assert_eq!(stats.dropped_creates(), 1);
+
+        // Taken out of the cache, so not out of the deferred ones
+        cache.store(metric("a", 240, 1.0));
+        let metric = cache.pop().unwrap();
+        assert!(writer.deferred.pop().is_none());
+        cache.release(&metric.name);
+        assert_eq!(writer.deferred.pop().unwrap().points.len(), 2);
+    }
+
+    #[test]
+    fn store_metric_silently_skips_invalid() {
+        let cache = MetricCache::new(10, DrainStrategy::Naive);
+        cache.store_metric_silently(metric("this.is.correct", 10, 1.0));
+        cache.store_metric_silently(metric("$this.is.incorrect", 10, 1.0));
+        cache.store_metric_silently(metric("this.is.incorrect;tag", 10, 1.0));
+        assert_eq!(drain(&cache), vec!["this.is.correct"]);
+    }
+}
@@ -0,0 +1,314 @@
+//! Carbonlink queries of datapoints not written yet, as graphite-web sends to carbon-cache.
+//!
+//! Requests and responses are pickled dicts prefixed with a 4-byte big-endian length:
+//! `{'type': 'cache-query', 'metric': name}` is answered with `{'datapoints': [(t, v), ...]}`,
+//! `{'type': 'cache-query-bulk', 'metrics': [name, ...]}` with `{'datapointsByMetric': {...}}`
+//! and `{'type': 'get-metadata', 'metric': name, 'key': 'aggregationMethod'}` with
+//! `{'value': method}`. Failed requests are answered with `{'error': message}`.
+
+use carbon::pickle::{Value, pickle, unpickle};
+use futures::{SinkExt, StreamExt};
+use serde::*;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
+use whisper::WhisperFile;
+use whisper::point::Point;
+
+use crate::cache::Writer;
+use crate::metric_file;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CarbonlinkConfig {
+    pub enabled: bool,
+    pub port: u32,
+    pub host: IpAddr,
+    /// Longest accepted request in bytes.
+    pub max_message_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    CacheQuery(String),
+    CacheQueryBulk(Vec<String>),
+    GetMetadata { metric: String, key: String },
+}
+
+fn string(value: Option<&Value>, key: &str) -> Result<String, String> {
+    match value {
+        Some(Value::Str(s)) => Ok(s.clone()),
+        _ => Err(format!("Missing '{}'", key)),
+    }
+}
+
+pub fn parse_request(data: &[u8]) -> Result<Request, String> {
+    let request = unpickle(data).map_err(|e| e.to_string())?;
+    let field = |key: &str| string(request.get(key), key);
+    match field("type")?.as_str() {
+        "cache-query" => Ok(Request::CacheQuery(field("metric")?)),
+        "cache-query-bulk" => match request.get("metrics") {
+            Some(Value::List(metrics) | Value::Tuple(metrics)) => metrics
+                .iter()
+                .map(|metric| string(Some(metric), "metrics"))
+                .collect::<Result<_, _>>()
+                .map(Request::CacheQueryBulk),
+            _ => Err("Missing 'metrics'".to_owned()),
+        },
+        "get-metadata" => Ok(Request::GetMetadata {
+            metric: field("metric")?,
+            key: field("key")?,
+        }),
+        other => Err(format!("Invalid request type '{}'", other)),
+    }
+}
+
+fn datapoints(points: Vec<Point>) -> Value {
+    Value::List(
+        points
+            .into_iter()
+            .map(|point| {
+                Value::Tuple(vec![
+                    Value::Int(i64::from(point.interval)),
+                    Value::Float(point.value),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn dict(key: &str, value: Value) -> Value {
+    Value::Dict(vec![(Value::Str(key.to_owned()), value)])
+}
+
+fn metadata(writer: &Writer, metric: &str, key: &str) -> Result<Value, String> {
+    if key != "aggregationMethod" {
+        return Err(format!("Unsupported metadata key '{}'", key));
+    }
+    let path = metric_file(metric, &writer.settings().db_path).map_err(|e| e.to_string())?;
+    let file =
+        WhisperFile::open_readonly(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Value::Str(file.info().aggregation_method.to_string()))
+}
+
+/// Pickled response to a pickled request.
+pub fn respond(writer: &Writer, data: &[u8]) -> Vec<u8> {
+    let response = match parse_request(data) {
+        Ok(Request::CacheQuery(metric)) => dict("datapoints", datapoints(writer.query(&metric))),
+        Ok(Request::CacheQueryBulk(metrics)) => dict(
+            "datapointsByMetric",
+            Value::Dict(
+                metrics
+                    .into_iter()
+                    .map(|metric| {
+                        let points = datapoints(writer.query(&metric));
+                        (Value::Str(metric), points)
+                    })
+                    .collect(),
+            ),
+        ),
+        Ok(Request::GetMetadata { metric, key }) => match metadata(writer, &metric, &key) {
+            Ok(value) => dict("value", value),
+            Err(e) => dict("error", Value::Str(e)),
+        },
+        Err(e) => dict("error", Value::Str(e)),
+    };
+    pickle(&response)
+}
+
+/// Accepts connections until `stop`, connections are served until `shutdown`.
+pub async fn serve(
+    listener: TcpListener,
+    writer: Arc<Writer>,
+    max_message_size: usize,
+    stop: CancellationToken,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let accepted = tokio::select! {
+            _ = stop.cancelled() => break,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
+            Ok((sock, _)) => {
+                let writer = writer.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    let codec = LengthDelimitedCodec::builder()
+                        .max_frame_length(max_message_size)
+                        .new_codec();
+                    let mut framed_sock = Framed::new(sock, codec);
+                    while let Some(request) = tokio::select! {
+                        _ = shutdown.cancelled() => None,
+                        request = framed_sock.next() => request,
+                    } {
+                        let request = match request {
+                            Ok(request) => request,
+                            Err(e) => {
+                                eprintln!("carbonlink receive error = {:?}", e);
+                                break;
+                            }
+                        };
+                        // Metadata is read from whisper files, which may wait for locks
+                        let writer = writer.clone();
+                        let response =
+                            match tokio::task::spawn_blocking(move || respond(&writer, &request))
+                                .await
+                            {
+                                Ok(response) => response,
+                                Err(e) => {
+                                    eprintln!("carbonlink respond error = {:?}", e);
+                                    break;
+                                }
+                            };
+                        if let Err(e) = framed_sock.send(response.into()).await {
+                            eprintln!("carbonlink send error = {:?}", e);
+                            break;
+                        }
+                    }
+                });
+            }
+            Err(e) => eprintln!("carbonlink accept error = {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MetricPoint;
+    use crate::cache::{DrainStrategy, MetricCache};
+    use crate::settings::Settings;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    fn writer() -> (Arc<Writer>, tempfile::TempDir) {
+        let dir = tempfile::Builder::new()
+            .prefix("diamond")
+            .tempdir()
+            .unwrap();
+        let mut settings = Settings::new(None).unwrap();
+        settings.db_path = dir.path().to_path_buf();
+        let cache = Arc::new(MetricCache::new(100, DrainStrategy::Naive));
+        for (interval, value) in [(120, 2.0), (60, 1.0)] {
+            cache.store(MetricPoint {
+                name: "a.b".to_owned(),
+                point: Point { interval, value },
+            });
+        }
+        (Arc::new(Writer::new(cache, Arc::new(settings))), dir)
+    }
+
+    fn request(items: &[(&str, Value)]) -> Vec<u8> {
+        pickle(&Value::Dict(
+            items
+                .iter()
+                .map(|(key, value)| (Value::Str(key.to_string()), value.clone()))
+                .collect(),
+        ))
+    }
+
+    fn str(s: &str) -> Value {
+        Value::Str(s.to_owned())
+    }
+
+    #[test]
+    fn cache_queries() {
+        let (writer, _dir) = writer();
+        let expected = datapoints(vec![
+            Point {
+                interval: 60,
+                value: 1.0,
+            },
+            Point {
+                interval: 120,
+                value: 2.0,
+            },
+        ]);
+
+        let response = respond(
+            &writer,
+            &request(&[("type", str("cache-query")), ("metric", str("a.b"))]),
+        );
+        assert_eq!(
+            unpickle(&response).unwrap(),
+            dict("datapoints", expected.clone())
+        );
+
+        let response = respond(
+            &writer,
+            &request(&[
+                ("type", str("cache-query-bulk")),
+                ("metrics", Value::List(vec![str("a.b"), str("c")])),
+            ]),
+        );
+        assert_eq!(
+            unpickle(&response).unwrap(),
+            dict(
+                "datapointsByMetric",
+                Value::Dict(vec![
+                    (str("a.b"), expected),
+                    (str("c"), Value::List(vec![]))
+                ])
+            )
+        );
+    }
+
+    #[test]
+    fn metadata_and_errors() {
+        let (writer, _dir) = writer();
+        writer.flush(180);
+
+        let response = respond(
+            &writer,
+            &request(&[
+                ("type", str("get-metadata")),
+                ("metric", str("a.b")),
+                ("key", str("aggregationMethod")),
+            ]),
+        );
+        assert_eq!(unpickle(&response).unwrap(), dict("value", str("average")));
+
+        let response = respond(&writer, &request(&[("type", str("set-metadata"))]));
+        assert_eq!(
+            unpickle(&response).unwrap(),
+            dict("error", str("Invalid request type 'set-metadata'"))
+        );
+        let response = respond(&writer, b"garbage");
+        assert!(unpickle(&response).unwrap().get("error").is_some());
+    }
+
+    #[tokio::test]
+    async fn serve_length_prefixed() {
+        let (writer, _dir) = writer();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        tokio::spawn(serve(
+            listener,
+            writer,
+            1024,
+            shutdown.clone(),
+            shutdown.clone(),
+        ));
+
+        let mut sock = TcpStream::connect(address).await.unwrap();
+        let data = request(&[("type", str("cache-query")), ("metric", str("a.b"))]);
+        sock.write_all(&(data.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        sock.write_all(&data).await.unwrap();
+
+        let len = sock.read_u32().await.unwrap();
+        let mut response = vec![0; len as usize];
+        sock.read_exact(&mut response).await.unwrap();
+        let value = unpickle(&response).unwrap();
+        assert!(matches!(value.get("datapoints"), Some(Value::List(points)) if points.len() == 2));
+
+        // Requests over the size limit close the connection
+        sock.write_all(&2048u32.to_be_bytes()).await.unwrap();
+        assert_eq!(sock.read(&mut [0; 4]).await.unwrap(), 0);
+        shutdown.cancel();
+    }
+}
@@ -0,0 +1,376 @@
+//! Allow/block lists and rewrite rules applied to received metric names, as carbon's
+//! `whitelist.conf`, `blacklist.conf` and `rewrite-rules.conf`.
+
+use serde::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::schemas::Pattern;
+
+/// When a rewrite rule is applied, relative to aggregation.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Pre,
+    Post,
+}
+
+/// Replaces every match of `pattern` in a metric name, `$1` or `\1` refer to groups.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RewriteRule {
+    pub stage: Stage,
+    pub pattern: Pattern,
+    pub replacement: String,
+}
+
+impl RewriteRule {
+    /// Rewritten name, if the rule matches.
+    pub fn apply(&self, name: &str) -> Option<String> {
+        if !self.pattern.is_match(name) {
+            return None;
+        }
+        Some(
+            self.pattern
+                .replace_all(name, &replacement(&self.replacement)),
+        )
+    }
+}
+
+/// Python group references like `\1` in the syntax of the `regex` crate.
+fn replacement(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(digit) if c == '\\' && digit.is_ascii_digit() => {
+                result.push_str("${");
+                while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                    result.push(digit);
+                }
+                result.push('}');
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[derive(Debug, PartialEq, Deserialize, Default)]
+pub struct FilterConfig {
+    #[serde(default)]
+    pub rewrite: Vec<RewriteRule>,
+    /// Carbon `rewrite-rules.conf`, its rules follow `rewrite`.
+    #[serde(default)]
+    pub rewrite_rules_file: Option<PathBuf>,
+    /// If not empty, only metrics matching one of the patterns are accepted.
+    #[serde(default)]
+    pub whitelist: Vec<Pattern>,
+    #[serde(default)]
+    pub whitelist_file: Option<PathBuf>,
+    /// Metrics matching one of the patterns are dropped.
+    #[serde(default)]
+    pub blacklist: Vec<Pattern>,
+    #[serde(default)]
+    pub blacklist_file: Option<PathBuf>,
+}
+
+/// Parses carbon's `rewrite-rules.conf` with `[pre]` and `[post]` sections of `regex = replacement`.
+pub fn parse_rewrite_rules(s: &str) -> Result<Vec<RewriteRule>, String> {
+    let mut stage = None;
+    let mut rules = Vec::new();
+    for line in s.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line {
+            "[pre]" => stage = Some(Stage::Pre),
+            "[post]" => stage = Some(Stage::Post),
+            _ => {
+                let invalid = || format!("Invalid rewrite rule '{}'", line);
+                let stage = stage.ok_or_else(invalid)?;
+                let (pattern, replacement) = line.split_once('=').ok_or_else(invalid)?;
+                rules.push(RewriteRule {
+                    stage,
+                    pattern: Pattern::new(pattern.trim())?,
+                    replacement: replacement.trim().to_owned(),
+                });
+            }
+        }
+    }
+    Ok(rules)
+}
+
+/// Parses a list of regular expressions, one per line.
+pub fn parse_patterns(s: &str) -> Result<Vec<Pattern>, String> {
+    s.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Pattern::new)
+        .collect()
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Number of metrics dropped or renamed since start.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FilterStats {
+    pub whitelist_rejects: u64,
+    pub blacklist_rejects: u64,
+    pub rewrites: u64,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    rewrite: Vec<RewriteRule>,
+    whitelist: Vec<Pattern>,
+    blacklist: Vec<Pattern>,
+}
+
+impl Rules {
+    fn load(config: &FilterConfig) -> Result<Self, String> {
+        let mut rewrite = config.rewrite.clone();
+        if let Some(path) = &config.rewrite_rules_file {
+            rewrite.extend(
+                parse_rewrite_rules(&read(path)?)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?,
+            );
+        }
+        let patterns = |inline: &[Pattern], file: &Option<PathBuf>| {
+            let mut patterns = inline.to_vec();
+            if let Some(path) = file {
+                patterns.extend(
+                    parse_patterns(&read(path)?)
+                        .map_err(|e| format!("{}: {}", path.display(), e))?,
+                );
+            }
+            Ok::<_, String>(patterns)
+        };
+
+        Ok(Rules {
+            rewrite,
+            whitelist: patterns(&config.whitelist, &config.whitelist_file)?,
+            blacklist: patterns(&config.blacklist, &config.blacklist_file)?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Filters {
+    rules: RwLock<Rules>,
+    whitelist_rejects: AtomicU64,
+    blacklist_rejects: AtomicU64,
+    rewrites: AtomicU64,
+}
+
+impl Filters {
+    pub fn new(config: &FilterConfig) -> Result<Self, String> {
+        Ok(Filters {
+            rules: RwLock::new(Rules::load(config)?),
+            ..Filters::default()
+        })
+    }
+
+    /// Replaces the rules, counts are kept.
+    pub fn reload(&self, config: &FilterConfig) -> Result<(), String> {
+        let rules = Rules::load(config)?;
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    /// Whether a metric passes the allow and block lists.
+    pub fn allow(&self, name: &str) -> bool {
+        let rules = self.rules.read().unwrap();
+        if !rules.whitelist.is_empty() && !rules.whitelist.iter().any(|p| p.is_match(name)) {
+            self.whitelist_rejects.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if rules.blacklist.iter().any(|p| p.is_match(name)) {
+            self.blacklist_rejects.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Applies rewrite rules of the stage in order.
+    pub fn rewrite(&self, stage: Stage, name: String) -> String {
+        let mut rewritten = false;
+        let name = self
+            .rules
+            .read()
+            .unwrap()
+            .rewrite
+            .iter()
+            .filter(|rule| rule.stage == stage)
+            .fold(name, |name, rule| match rule.apply(&name) {
+                Some(new_name) => {
+                    rewritten = true;
+                    new_name
+                }
+                None => name,
+            });
+        if rewritten {
+            self.rewrites.fetch_add(1, Ordering::Relaxed);
+        }
+        name
+    }
+
+    pub fn stats(&self) -> FilterStats {
+        FilterStats {
+            whitelist_rejects: self.whitelist_rejects.load(Ordering::Relaxed),
+            blacklist_rejects: self.blacklist_rejects.load(Ordering::Relaxed),
+            rewrites: self.rewrites.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<Pattern> {
+        patterns.iter().map(|p| Pattern::new(p).unwrap()).collect()
+    }
+
+    #[test]
+    fn rewrite_rules_file() {
+        let rules = parse_rewrite_rules(
+            r#"
+# Renames of legacy metrics
+[pre]
+^collectd_([a-z0-9]+)\. = \1.system.
+
+[post]
+_sum$ =
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].stage, Stage::Pre);
+        assert_eq!(
+            rules[0].apply("collectd_web01.cpu"),
+            Some("web01.system.cpu".to_owned())
+        );
+        assert_eq!(rules[0].apply("web01.cpu"), None);
+        assert_eq!(rules[1].stage, Stage::Post);
+        assert_eq!(rules[1].apply("requests_sum"), Some("requests".to_owned()));
+
+        assert!(parse_rewrite_rules("a = b").is_err());
+        assert!(parse_rewrite_rules("[pre]\na").is_err());
+        assert!(parse_rewrite_rules("[pre]\n( = b").is_err());
+    }
+
+    #[test]
+    fn replacement_groups() {
+        assert_eq!(replacement(r"\1.system.\12"), "${1}.system.${12}");
+        assert_eq!(replacement("$1.x"), "$1.x");
+        assert_eq!(replacement(r"a\b"), r"a\b");
+    }
+
+    #[test]
+    fn allow_and_block() {
+        let filters = Filters::new(&FilterConfig {
+            whitelist: patterns(&["^prod\\.", "^stage\\."]),
+            blacklist: patterns(&["\\.debug\\."]),
+            ..FilterConfig::default()
+        })
+        .unwrap();
+
+        assert!(filters.allow("prod.web.cpu"));
+        assert!(!filters.allow("dev.web.cpu"));
+        assert!(!filters.allow("stage.debug.cpu"));
+        assert_eq!(
+            filters.stats(),
+            FilterStats {
+                whitelist_rejects: 1,
+                blacklist_rejects: 1,
+                rewrites: 0,
+            }
+        );
+
+        let all = Filters::new(&FilterConfig::default()).unwrap();
+        assert!(all.allow("anything"));
+    }
+
+    #[test]
+    fn rewrite_stages() {
+        let filters = Filters::new(&FilterConfig {
+            rewrite: parse_rewrite_rules("[pre]\n^old\\. = new.\n[post]\n\\.sum$ = .total\n")
+                .unwrap(),
+            ..FilterConfig::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            filters.rewrite(Stage::Pre, "old.a.sum".to_owned()),
+            "new.a.sum"
+        );
+        assert_eq!(
+            filters.rewrite(Stage::Post, "new.a.sum".to_owned()),
+            "new.a.total"
+        );
+        assert_eq!(filters.rewrite(Stage::Pre, "other".to_owned()), "other");
+        assert_eq!(filters.stats().rewrites, 2);
+    }
+
+    #[test]
+    fn reload_keeps_counts() {
+        let filters = Filters::new(&FilterConfig {
+            blacklist: patterns(&["^a\\."]),
+            ..FilterConfig::default()
+        })
+        .unwrap();
+        assert!(!filters.allow("a.cpu"));
+        assert!(filters.allow("b.cpu"));
+
+        filters
+            .reload(&FilterConfig {
+                blacklist: patterns(&["^b\\."]),
+                ..FilterConfig::default()
+            })
+            .unwrap();
+        assert!(filters.allow("a.cpu"));
+        assert!(!filters.allow("b.cpu"));
+        assert_eq!(filters.stats().blacklist_rejects, 2);
+
+        assert!(
+            filters
+                .reload(&FilterConfig {
+                    rewrite_rules_file: Some(PathBuf::from("/nonexistent/rewrite-rules.conf")),
+                    ..FilterConfig::default()
+                })
+                .is_err()
+        );
+        assert!(!filters.allow("b.cpu"));
+    }
+
+    #[test]
+    fn lists_from_files() {
+        let dir = tempfile::Builder::new()
+            .prefix("diamond")
+            .tempdir()
+            .unwrap();
+        let blacklist = dir.path().join("blacklist.conf");
+        fs::write(&blacklist, "# noisy\n^noisy\\.\n").unwrap();
+
+        let filters = Filters::new(&FilterConfig {
+            blacklist_file: Some(blacklist),
+            ..FilterConfig::default()
+        })
+        .unwrap();
+        assert!(!filters.allow("noisy.metric"));
+        assert!(filters.allow("quiet.metric"));
+
+        assert!(
+            Filters::new(&FilterConfig {
+                whitelist_file: Some(dir.path().join("missing.conf")),
+                ..FilterConfig::default()
+            })
+            .is_err()
+        );
+    }
+}
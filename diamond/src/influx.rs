@@ -0,0 +1,224 @@
+//! InfluxDB line protocol, `measurement,tag=value field=1.5,other=2i 1545778338000000000`,
+//! as sent by Telegraf. Every numeric field is a datapoint named by a template.
+
+use serde::*;
+use std::net::IpAddr;
+use whisper::point::Point;
+
+use crate::MetricPoint;
+use crate::template::NameTemplate;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    Ns,
+    Us,
+    Ms,
+    S,
+}
+
+impl Precision {
+    fn seconds(self, timestamp: i64) -> i64 {
+        match self {
+            Precision::Ns => timestamp / 1_000_000_000,
+            Precision::Us => timestamp / 1_000_000,
+            Precision::Ms => timestamp / 1_000,
+            Precision::S => timestamp,
+        }
+    }
+}
+
+/// Listener of the line protocol on both tcp and udp.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct InfluxConfig {
+    pub enabled: bool,
+    pub port: u32,
+    pub host: IpAddr,
+    /// Metric name with `{measurement}`, `{field}` and `{<tag>}` placeholders.
+    pub template: NameTemplate,
+    /// Unit of timestamps.
+    pub precision: Precision,
+}
+
+/// Splits at `separator` not escaped with a backslash, nor quoted if `quotes` is set.
+fn split(s: &str, separator: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut escaped, mut quoted) = (0, false, false);
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            _ if c == separator && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+fn key_value(s: &str) -> Option<(String, &str)> {
+    let parts = split(s, '=', true);
+    match parts[..] {
+        [key, value] if !key.is_empty() => Some((unescape(key), value)),
+        _ => None,
+    }
+}
+
+/// Value of a numeric or boolean field, `None` for strings.
+fn field_value(value: &str) -> Result<Option<f64>, String> {
+    let number = |s: &str| s.parse::<f64>().map_err(|e| format!("{}: {}", value, e));
+    match value {
+        _ if value.starts_with('"') => Ok(None),
+        "t" | "T" | "true" | "True" | "TRUE" => Ok(Some(1.0)),
+        "f" | "F" | "false" | "False" | "FALSE" => Ok(Some(0.0)),
+        _ => match value.strip_suffix(['i', 'u']) {
+            Some(integer) => number(integer).map(Some),
+            None => number(value).map(Some),
+        },
+    }
+}
+
+/// Datapoints of a line, its timestamp is `now` if it has none.
+pub fn parse_line(line: &str, config: &InfluxConfig, now: u32) -> Result<Vec<MetricPoint>, String> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Vec::new());
+    }
+    let error = || format!("Invalid line protocol '{}'", line);
+
+    let (series, fields, timestamp) = match split(line, ' ', true)[..] {
+        [series, fields] => (series, fields, None),
+        [series, fields, timestamp] => (series, fields, Some(timestamp)),
+        _ => return Err(error()),
+    };
+    let interval = match timestamp {
+        Some(timestamp) => {
+            let timestamp = timestamp.parse().map_err(|_| error())?;
+            u32::try_from(config.precision.seconds(timestamp)).map_err(|_| error())?
+        }
+        None => now,
+    };
+
+    let mut series = split(series, ',', false).into_iter();
+    let measurement = unescape(series.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err(error());
+    }
+    let tags = series
+        .map(|tag| {
+            key_value(tag)
+                .map(|(key, value)| (key, unescape(value)))
+                .ok_or_else(error)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut metrics = Vec::new();
+    for field in split(fields, ',', true) {
+        let (key, value) = key_value(field).ok_or_else(error)?;
+        let Some(value) = field_value(value)? else {
+            continue;
+        };
+        let name = config
+            .template
+            .render(|name| match name {
+                "measurement" => Some(measurement.as_str()),
+                "field" => Some(key.as_str()),
+                _ => tags
+                    .iter()
+                    .find(|(tag, _)| tag == name)
+                    .map(|(_, value)| value.as_str()),
+            })
+            .ok_or_else(|| format!("{}: no tags for template", line))?;
+        metrics.push(MetricPoint {
+            name,
+            point: Point { interval, value },
+        });
+    }
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(template: &str, precision: Precision) -> InfluxConfig {
+        InfluxConfig {
+            enabled: true,
+            port: 8094,
+            host: "127.0.0.1".parse().unwrap(),
+            template: template.parse().unwrap(),
+            precision,
+        }
+    }
+
+    fn values(metrics: Vec<MetricPoint>) -> Vec<(String, u32, f64)> {
+        metrics
+            .into_iter()
+            .map(|metric| (metric.name, metric.point.interval, metric.point.value))
+            .collect()
+    }
+
+    #[test]
+    fn parse_fields_and_tags() {
+        let config = config("servers.{host}.{measurement}.{field}", Precision::Ns);
+        let line = r#"cpu,host=web01.example.com,region=eu usage_idle=97.5,cores=8i,up=true,model="x y, z" 1545778338000000000"#;
+        assert_eq!(
+            values(parse_line(line, &config, 0).unwrap()),
+            vec![
+                (
+                    "servers.web01_example_com.cpu.usage_idle".to_owned(),
+                    1545778338,
+                    97.5
+                ),
+                (
+                    "servers.web01_example_com.cpu.cores".to_owned(),
+                    1545778338,
+                    8.0
+                ),
+                (
+                    "servers.web01_example_com.cpu.up".to_owned(),
+                    1545778338,
+                    1.0
+                ),
+            ]
+        );
+
+        let line = r"disk\ io,host=a\,b,path=/var reads=3u";
+        assert_eq!(
+            values(parse_line(line, &config, 100).unwrap()),
+            vec![("servers.a_b.disk_io.reads".to_owned(), 100, 3.0)]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let s = config("{measurement}.{field}", Precision::S);
+        assert_eq!(
+            values(parse_line("mem used=1 1545778338", &s, 0).unwrap()),
+            vec![("mem.used".to_owned(), 1545778338, 1.0)]
+        );
+        assert!(parse_line("", &s, 0).unwrap().is_empty());
+        assert!(parse_line("mem", &s, 0).is_err());
+        assert!(parse_line("mem used=abc", &s, 0).is_err());
+        assert!(parse_line("mem used=1 now", &s, 0).is_err());
+        assert!(parse_line("mem used=1 1 2", &s, 0).is_err());
+        let tagged = config("{host}.{measurement}.{field}", Precision::S);
+        assert!(parse_line("mem used=1", &tagged, 0).is_err());
+    }
+}
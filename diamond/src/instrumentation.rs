@@ -0,0 +1,419 @@
+//! Internal metrics of diamond-server, recorded as carbon's `carbon.agents.<host>.*`
+//! and optionally served in the Prometheus text format.
+
+use serde::*;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout};
+use whisper::point::Point;
+
+use crate::MetricPoint;
+use crate::cache::MetricCache;
+use crate::filters::Filters;
+use crate::pipeline::Pipeline;
+use crate::relay::Relay;
+use crate::settings::Net;
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct InstrumentationConfig {
+    /// Seconds between records of internal metrics, 0 disables them.
+    pub interval: u32,
+    pub prefix: String,
+    /// Name used in metric paths instead of the hostname.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Listener of the Prometheus `/metrics` endpoint.
+    #[serde(default)]
+    pub prometheus: Option<Net>,
+}
+
+/// Counters of received and written datapoints since start.
+#[derive(Debug, Default)]
+pub struct Stats {
+    metrics_received: AtomicU64,
+    committed_points: AtomicU64,
+    creates: AtomicU64,
+    dropped_creates: AtomicU64,
+    errors: AtomicU64,
+    update_operations: AtomicU64,
+    update_micros: AtomicU64,
+}
+
+impl Stats {
+    pub fn received(&self) {
+        self.metrics_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a successful file update of `points` datapoints.
+    pub fn update(&self, points: usize, created: bool, elapsed: Duration) {
+        self.committed_points
+            .fetch_add(points as u64, Ordering::Relaxed);
+        self.update_operations.fetch_add(1, Ordering::Relaxed);
+        self.update_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        if created {
+            self.creates.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts datapoints dropped while their file waited to be created.
+    pub fn dropped_create(&self, points: usize) {
+        self.dropped_creates
+            .fetch_add(points as u64, Ordering::Relaxed);
+    }
+
+    pub fn creates(&self) -> u64 {
+        self.creates.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_creates(&self) -> u64 {
+        self.dropped_creates.load(Ordering::Relaxed)
+    }
+}
+
+/// Name of the host with dots replaced, as in carbon.
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|host| host.trim().to_owned())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "localhost".to_owned())
+        .replace('.', "_")
+}
+
+/// `metricsReceived` or `relay.sent` as `metrics_received` or `relay_sent`.
+fn snake_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            result.push('_');
+            result.push(c.to_ascii_lowercase());
+        } else if c == '.' {
+            result.push('_');
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Collects internal metrics from the parts of the server.
+#[derive(Debug)]
+pub struct Instrumentation {
+    prefix: String,
+    stats: Arc<Stats>,
+    cache: Arc<MetricCache>,
+    filters: Arc<Filters>,
+    relay: Option<Arc<Relay>>,
+    /// Counter values at the previous record.
+    last: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl Instrumentation {
+    pub fn new(
+        config: &InstrumentationConfig,
+        stats: Arc<Stats>,
+        cache: Arc<MetricCache>,
+        filters: Arc<Filters>,
+    ) -> Self {
+        let host = config
+            .host
+            .clone()
+            .unwrap_or_else(hostname)
+            .replace('.', "_");
+        Self {
+            prefix: format!("{}.{}", config.prefix, host),
+            stats,
+            cache,
+            filters,
+            relay: None,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_relay(mut self, relay: Arc<Relay>) -> Self {
+        self.relay = Some(relay);
+        self
+    }
+
+    /// Totals since start.
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let filters = self.filters.stats();
+        let mut counters = vec![
+            ("metricsReceived", load(&self.stats.metrics_received)),
+            ("committedPoints", load(&self.stats.committed_points)),
+            ("creates", load(&self.stats.creates)),
+            ("droppedCreates", load(&self.stats.dropped_creates)),
+            ("errors", load(&self.stats.errors)),
+            ("updateOperations", load(&self.stats.update_operations)),
+            ("updateTime", load(&self.stats.update_micros)),
+            ("whitelistRejects", filters.whitelist_rejects),
+            ("blacklistRejects", filters.blacklist_rejects),
+            ("rewrites", filters.rewrites),
+        ];
+        if let Some(relay) = &self.relay {
+            let stats = relay.stats();
+            counters.push(("relay.sent", stats.sent));
+            counters.push(("relay.dropped", stats.dropped));
+        }
+        counters
+    }
+
+    /// Current values.
+    fn gauges(&self) -> Vec<(&'static str, u64)> {
+        let mut gauges = vec![("cache.size", self.cache.size() as u64)];
+        if let Some(relay) = &self.relay {
+            gauges.push(("relay.queued", relay.stats().queued as u64));
+        }
+        gauges
+    }
+
+    /// Datapoints of counts since the previous record and of current values.
+    pub fn record(&self, now: u32) -> Vec<MetricPoint> {
+        let mut last = self.last.lock().unwrap();
+        let deltas: HashMap<&str, u64> = self
+            .counters()
+            .into_iter()
+            .map(|(name, value)| {
+                let previous = last.insert(name, value).unwrap_or(0);
+                (name, value.saturating_sub(previous))
+            })
+            .collect();
+
+        let mut values: Vec<(&str, f64)> = deltas
+            .iter()
+            .filter(|(name, _)| **name != "updateTime")
+            .map(|(name, value)| (*name, *value as f64))
+            .collect();
+        let updates = deltas["updateOperations"];
+        if updates > 0 {
+            values.push((
+                "pointsPerUpdate",
+                deltas["committedPoints"] as f64 / updates as f64,
+            ));
+            values.push((
+                "avgUpdateTime",
+                deltas["updateTime"] as f64 / updates as f64 / 1e6,
+            ));
+        }
+        values.extend(
+            self.gauges()
+                .into_iter()
+                .map(|(name, value)| (name, value as f64)),
+        );
+        values.sort_by(|a, b| a.0.cmp(b.0));
+
+        values
+            .into_iter()
+            .map(|(name, value)| MetricPoint {
+                name: format!("{}.{}", self.prefix, name),
+                point: Point {
+                    interval: now,
+                    value,
+                },
+            })
+            .collect()
+    }
+
+    /// Totals and current values in the Prometheus text format.
+    pub fn prometheus(&self) -> String {
+        let mut text = String::new();
+        for (name, value) in self.counters() {
+            let (name, value) = match name {
+                "updateTime" => ("update_time_seconds".to_owned(), value as f64 / 1e6),
+                _ => (snake_case(name), value as f64),
+            };
+            writeln!(text, "# TYPE diamond_{}_total counter", name).unwrap();
+            writeln!(text, "diamond_{}_total {}", name, value).unwrap();
+        }
+        for (name, value) in self.gauges() {
+            let name = snake_case(name);
+            writeln!(text, "# TYPE diamond_{} gauge", name).unwrap();
+            writeln!(text, "diamond_{} {}", name, value).unwrap();
+        }
+        text
+    }
+
+    /// Passes internal metrics down the pipeline every `interval` seconds.
+    pub async fn run(&self, pipeline: Pipeline, interval: u32) {
+        loop {
+            sleep(Duration::from_secs(u64::from(interval))).await;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32;
+            for metric in self.record(now) {
+                pipeline.store(metric);
+            }
+        }
+    }
+
+    /// Answers `GET /metrics` requests.
+    pub async fn serve_prometheus(&self, listener: TcpListener) {
+        loop {
+            let mut sock = match listener.accept().await {
+                Ok((sock, _)) => sock,
+                Err(e) => {
+                    eprintln!("prometheus accept error = {:?}", e);
+                    continue;
+                }
+            };
+            let mut buf = [0; 1024];
+            let request = match timeout(Duration::from_secs(5), sock.read(&mut buf)).await {
+                Ok(Ok(len)) => String::from_utf8_lossy(&buf[..len]).into_owned(),
+                _ => continue,
+            };
+            let response = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+                ["GET", "/metrics"] => {
+                    let body = self.prometheus();
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                }
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_owned(),
+            };
+            if let Err(e) = sock.write_all(response.as_bytes()).await {
+                eprintln!("prometheus send error = {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::DrainStrategy;
+    use tokio::net::TcpStream;
+
+    fn instrumentation(cache: Arc<MetricCache>) -> Instrumentation {
+        Instrumentation::new(
+            &InstrumentationConfig {
+                interval: 60,
+                prefix: "diamond.agents".to_owned(),
+                host: Some("box.local".to_owned()),
+                prometheus: None,
+            },
+            Arc::new(Stats::default()),
+            cache,
+            Arc::new(Filters::default()),
+        )
+    }
+
+    fn values(metrics: &[MetricPoint]) -> Vec<(&str, f64)> {
+        metrics
+            .iter()
+            .map(|metric| {
+                (
+                    metric.name.trim_start_matches("diamond.agents.box_local."),
+                    metric.point.value,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn record_intervals() {
+        let cache = Arc::new(MetricCache::new(10, DrainStrategy::Naive));
+        cache.store_metric_silently(MetricPoint {
+            name: "a".to_owned(),
+            point: Point {
+                interval: 1,
+                value: 1.0,
+            },
+        });
+        let instrumentation = instrumentation(cache);
+        let stats = &instrumentation.stats;
+        for _ in 0..3 {
+            stats.received();
+        }
+        stats.update(2, true, Duration::from_millis(3));
+        stats.update(1, false, Duration::from_millis(1));
+
+        let metrics = instrumentation.record(120);
+        assert!(metrics.iter().all(|metric| metric.point.interval == 120));
+        assert_eq!(
+            values(&metrics),
+            vec![
+                ("avgUpdateTime", 0.002),
+                ("blacklistRejects", 0.0),
+                ("cache.size", 1.0),
+                ("committedPoints", 3.0),
+                ("creates", 1.0),
+                ("droppedCreates", 0.0),
+                ("errors", 0.0),
+                ("metricsReceived", 3.0),
+                ("pointsPerUpdate", 1.5),
+                ("rewrites", 0.0),
+                ("updateOperations", 2.0),
+                ("whitelistRejects", 0.0),
+            ]
+        );
+
+        stats.received();
+        stats.error();
+        let metrics = instrumentation.record(180);
+        let values = values(&metrics);
+        assert!(values.contains(&("metricsReceived", 1.0)));
+        assert!(values.contains(&("errors", 1.0)));
+        assert!(values.contains(&("updateOperations", 0.0)));
+        assert!(!values.iter().any(|(name, _)| *name == "pointsPerUpdate"));
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let instrumentation = instrumentation(Arc::new(MetricCache::new(10, DrainStrategy::Naive)));
+        instrumentation.stats.received();
+        instrumentation
+            .stats
+            .update(4, true, Duration::from_millis(500));
+
+        let text = instrumentation.prometheus();
+        assert!(text.contains("# TYPE diamond_metrics_received_total counter\n"));
+        assert!(text.contains("diamond_metrics_received_total 1\n"));
+        assert!(text.contains("diamond_committed_points_total 4\n"));
+        assert!(text.contains("diamond_update_time_seconds_total 0.5\n"));
+        assert!(text.contains("# TYPE diamond_cache_size gauge\ndiamond_cache_size 0\n"));
+    }
+
+    #[tokio::test]
+    async fn prometheus_endpoint() {
+        let instrumentation = Arc::new(instrumentation(Arc::new(MetricCache::new(
+            10,
+            DrainStrategy::Naive,
+        ))));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = instrumentation.clone();
+        tokio::spawn(async move { server.serve_prometheus(listener).await });
+
+        let request = |path: &'static str| async move {
+            let mut sock = TcpStream::connect(address).await.unwrap();
+            sock.write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            sock.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = request("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&instrumentation.prometheus()));
+        assert!(request("/").await.starts_with("HTTP/1.1 404"));
+    }
+}
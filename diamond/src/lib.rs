@@ -1,3 +1,4 @@
+use carbon::pickle::PickleError;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::convert::From;
@@ -12,10 +13,27 @@ use whisper::WhisperFile;
 use whisper::builder::WhisperBuilder;
 use whisper::point::Point;
 
+pub mod aggregator;
+pub mod cache;
+pub mod carbonlink;
+pub mod filters;
+pub mod influx;
+pub mod instrumentation;
+pub mod pickle;
+pub mod pipeline;
+pub mod relay;
+pub mod remote_write;
+pub mod schemas;
 pub mod settings;
+pub mod statsd;
+pub mod tags;
+pub mod template;
+pub mod token_bucket;
+pub mod wal;
 
 use settings::Settings;
 use settings::WhisperConfig;
+use tags::TaggedName;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MetricPoint {
@@ -36,6 +54,7 @@ pub enum MetricError {
     LineParse(String),
     ParseIntError(ParseIntError),
     ParseFloatError(ParseFloatError),
+    Pickle(String),
 }
 
 impl Display for MetricError {
@@ -46,6 +65,7 @@ impl Display for MetricError {
             Self::LineParse(s) => write!(f, "Cannot parse metric from line: {}", s),
             Self::ParseIntError(e) => write!(f, "{}", e),
             Self::ParseFloatError(e) => write!(f, "{}", e),
+            Self::Pickle(s) => write!(f, "Cannot unpickle metrics: {}", s),
         }
     }
 }
@@ -64,10 +84,17 @@ impl From<ParseFloatError> for MetricError {
     }
 }
 
+impl From<PickleError> for MetricError {
+    fn from(error: PickleError) -> Self {
+        Self::Pickle(error.0)
+    }
+}
+
 impl MetricPoint {
     fn validate(s: &str) -> Result<(), MetricError> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"^[\d\w\._ -]+$").unwrap();
+            static ref RE: Regex =
+                Regex::new(r"^[\d\w\._-]+(;[^\s;!^=]+=[^\s;~]+)*[\d\w\._ -]*$").unwrap();
         }
 
         if RE.is_match(s) {
@@ -135,6 +162,14 @@ impl FromStr for MetricPath {
     type Err = MetricError;
 
     fn from_str(s: &str) -> Result<Self, MetricError> {
+        if s.contains(';') {
+            let tagged: TaggedName = s
+                .parse()
+                .map_err(|_| MetricError::NameValidate(s.to_owned()))?;
+            MetricPath::validate(&tagged.name)?;
+            return Ok(MetricPath(PathBuf::new().join(".").join(tagged.path())));
+        }
+
         MetricPath::validate(s)?;
         let segments: Vec<&str> = s.split('.').collect();
 
@@ -159,6 +194,41 @@ impl From<PathBuf> for MetricPath {
     }
 }
 
+/// Path of the whisper file of a metric in `dir`.
+pub fn metric_file<P: AsRef<Path>>(name: &str, dir: P) -> Result<PathBuf, MetricError> {
+    let metric_path: MetricPath = name.parse()?;
+    Ok(dir.as_ref().join(metric_path.0))
+}
+
+/// Opens the whisper file of a metric, creating it with `config` if it does not exist.
+/// Returns the file and whether it was created.
+fn open_or_create<P: AsRef<Path>>(
+    name: &str,
+    dir: P,
+    config: &WhisperConfig,
+) -> Result<(WhisperFile, bool), Box<dyn Error>> {
+    let file_path = metric_file(name, &dir)?;
+
+    if file_path.exists() {
+        return Ok((WhisperFile::open(&file_path)?, false));
+    }
+
+    let dir_path = file_path.parent().unwrap();
+    fs::create_dir_all(dir_path)?;
+
+    let (x_files_factor, aggregation_method) = config.aggregation_for(name);
+    let file = WhisperBuilder::default()
+        .add_retentions(config.retentions_for(name))
+        .x_files_factor(x_files_factor)
+        .aggregation_method(aggregation_method)
+        .build(&file_path)?;
+
+    if name.contains(';') {
+        tags::index_append(&dir, &name.parse()?)?;
+    }
+    Ok((file, true))
+}
+
 #[inline]
 pub fn line_update<P: AsRef<Path>>(
     message: &str,
@@ -167,23 +237,7 @@ pub fn line_update<P: AsRef<Path>>(
     now: u32,
 ) -> Result<(), Box<dyn Error>> {
     let metric: MetricPoint = message.parse()?;
-    let metric_path: MetricPath = metric.name.parse()?;
-
-    let file_path = dir.as_ref().join(metric_path.0);
-
-    let mut file = if file_path.exists() {
-        WhisperFile::open(&file_path)?
-    } else {
-        let dir_path = file_path.parent().unwrap();
-        fs::create_dir_all(dir_path)?;
-
-        WhisperBuilder::default()
-            .add_retentions(&config.retentions)
-            .x_files_factor(config.x_files_factor)
-            .aggregation_method(config.aggregation_method)
-            .build(&file_path)?
-    };
-
+    let (mut file, _) = open_or_create(&metric.name, dir, config)?;
     file.update(&metric.point, now)?;
 
     Ok(())
@@ -199,14 +253,30 @@ pub fn update_silently(line: &str, conf: &Settings) {
     line_update(line, &conf.db_path, &conf.whisper, now).unwrap_or_else(|e| eprintln!("{}", e));
 }
 
+/// Writes a batch of datapoints of one metric with a single file update.
+/// Returns whether the file was created.
+pub fn points_update<P: AsRef<Path>>(
+    metric: &MetricPoints,
+    dir: P,
+    config: &WhisperConfig,
+    now: u32,
+) -> Result<bool, Box<dyn Error>> {
+    let (mut file, created) = open_or_create(&metric.name, dir, config)?;
+    file.update_many(&metric.points, now)?;
+
+    Ok(created)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use settings::{Net, WhisperConfig};
+    use cache::{DrainStrategy, MetricCache};
+    use pipeline::Pipeline;
+    use settings::WhisperConfig;
     use std::convert::From;
     use std::io;
-    use std::net::IpAddr::V4;
     use std::path::{Path, PathBuf};
+    use std::sync::Arc;
     use tempfile::Builder;
     use whisper::aggregation::AggregationMethod;
     use whisper::retention::Retention;
@@ -229,6 +299,21 @@ mod tests {
         assert!(MetricPath::validate("#this.is.not.correct").is_err());
     }
 
+    #[test]
+    fn metric_path_tagged() {
+        let m: PathBuf = "some.metric;tag2=value.2;tag1=value2"
+            .parse::<MetricPath>()
+            .unwrap()
+            .into();
+        assert_eq!(
+            PathBuf::from("./_tagged/eff/aae/some_DOT_metric;tag1=value2;tag2=value_DOT_2.wsp"),
+            m
+        );
+
+        assert!("$some.metric;tag=value".parse::<MetricPath>().is_err());
+        assert!("some.metric;tag".parse::<MetricPath>().is_err());
+    }
+
     #[test]
     fn metric_path_conversion_ok() {
         let m: PathBuf = "this.is.ok".parse::<MetricPath>().unwrap().into();
@@ -254,6 +339,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_metric_tagged_parse() {
+        let metric = "disk.used;host=a;dc=eu 1 123"
+            .parse::<MetricPoint>()
+            .unwrap();
+        assert_eq!(metric.name, "disk.used;host=a;dc=eu");
+
+        assert!("disk.used;host a 1 123".parse::<MetricPoint>().is_err());
+        assert!("disk.used;host=a~b 1 123".parse::<MetricPoint>().is_err());
+    }
+
     #[test]
     fn test_metric_parse_incorrect_name() {
         let s = "this\\.is./incorrect 1 123";
@@ -366,6 +462,12 @@ mod tests {
             }],
             x_files_factor: 0.5,
             aggregation_method: AggregationMethod::Average,
+            schemas: vec![],
+            aggregations: vec![],
+            storage_schemas: None,
+            storage_aggregation: None,
+            lock_timeout: None,
+            header_cache: 0,
         };
         let now = 1_545_778_348;
         line_update(message, &dir, &config, now)?;
@@ -413,6 +515,12 @@ mod tests {
             }],
             x_files_factor: 0.5,
             aggregation_method: AggregationMethod::Average,
+            schemas: vec![],
+            aggregations: vec![],
+            storage_schemas: None,
+            storage_aggregation: None,
+            lock_timeout: None,
+            header_cache: 0,
         };
         let now = 1_545_778_348;
         line_update(message, &dir, &config, now)?;
@@ -428,6 +536,94 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn update_line_tagged() -> Result<(), Box<dyn Error>> {
+        let dir = Builder::new().prefix("diamond").tempdir()?;
+
+        let config = WhisperConfig {
+            retentions: vec![Retention {
+                seconds_per_point: 1,
+                points: 1000,
+            }],
+            x_files_factor: 0.5,
+            aggregation_method: AggregationMethod::Average,
+            schemas: vec![],
+            aggregations: vec![],
+            storage_schemas: None,
+            storage_aggregation: None,
+            lock_timeout: None,
+            header_cache: 0,
+        };
+        let now = 1_545_778_348;
+        line_update(
+            "disk.used;host=a;dc=eu 1545778338 1",
+            dir.path(),
+            &config,
+            now,
+        )?;
+        line_update(
+            "disk.used;dc=eu;host=a 1545778339 2",
+            dir.path(),
+            &config,
+            now,
+        )?;
+
+        let path: PathBuf = "disk.used;dc=eu;host=a".parse::<MetricPath>()?.into();
+        assert_eq!(
+            WhisperFile::open(dir.path().join(path))?.dump(1)?.len(),
+            1000
+        );
+
+        let index = fs::read_to_string(dir.path().join("_tagged").join("tags.idx"))?;
+        assert_eq!(index, "disk.used;dc=eu;host=a\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn update_points_from_cache() -> Result<(), Box<dyn Error>> {
+        let dir = Builder::new().prefix("diamond_cache").tempdir()?;
+
+        let config = WhisperConfig {
+            x_files_factor: 0.5,
+            retentions: vec![Retention {
+                seconds_per_point: 1,
+                points: 1000,
+            }],
+            aggregation_method: AggregationMethod::Average,
+            schemas: vec![],
+            aggregations: vec![],
+            storage_schemas: None,
+            storage_aggregation: None,
+            lock_timeout: None,
+            header_cache: 0,
+        };
+        let now = 1_545_778_348;
+
+        let cache = Arc::new(MetricCache::new(100, DrainStrategy::Sorted));
+        let pipeline = Pipeline::new(cache.clone());
+        pipeline.receive_line("this.is.correct1 1545778338 124");
+        pipeline.receive_line("this.is.correct1 1545778339 125");
+        points_update(&cache.pop().unwrap(), dir.path(), &config, now)?;
+
+        let file = dir.path().join("this").join("is").join("correct1.wsp");
+        assert_eq!(
+            WhisperFile::open(&file)?.dump(1)?[..2],
+            [
+                Point {
+                    interval: now - 10,
+                    value: 124.0
+                },
+                Point {
+                    interval: now - 9,
+                    value: 125.0
+                }
+            ]
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn update_silently_with_absent_wsp() -> Result<(), io::Error> {
         let dir = Builder::new()
@@ -437,25 +633,14 @@ mod tests {
             .path()
             .to_path_buf();
 
-        let config = Settings {
-            db_path: dir.clone(),
-            tcp: Net {
-                port: 6142,
-                host: V4("0.0.0.0".parse().unwrap()),
-            },
-            udp: Net {
-                port: 6142,
-                host: V4("0.0.0.0".parse().unwrap()),
-            },
-            whisper: WhisperConfig {
-                x_files_factor: 0.5,
-                retentions: vec![Retention {
-                    seconds_per_point: 1,
-                    points: 1000,
-                }],
-                aggregation_method: AggregationMethod::Average,
-            },
-        };
+        let mut config = Settings::new(None).unwrap();
+        config.db_path = dir.clone();
+        config.whisper.x_files_factor = 0.5;
+        config.whisper.retentions = vec![Retention {
+            seconds_per_point: 1,
+            points: 1000,
+        }];
+        config.whisper.aggregation_method = AggregationMethod::Average;
 
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
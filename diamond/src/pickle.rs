@@ -0,0 +1,163 @@
+//! Metrics of the carbon pickle protocol.
+//!
+//! A message is a pickled list of `(name, (timestamp, value))` tuples. Relayed messages
+//! are pickled with protocol 2, as carbon-relay does.
+
+use carbon::pickle::{Value, unpickle};
+use whisper::point::Point;
+
+use crate::{MetricError, MetricPoint};
+
+fn error(message: impl Into<String>) -> MetricError {
+    MetricError::Pickle(message.into())
+}
+
+/// Decodes a pickled list of `(name, (timestamp, value))`.
+pub fn unpickle_metrics(data: &[u8]) -> Result<Vec<MetricPoint>, MetricError> {
+    let items = match unpickle(data)? {
+        Value::List(items) | Value::Tuple(items) => items,
+        _ => return Err(error("Expected a list of metrics")),
+    };
+
+    items
+        .into_iter()
+        .map(|item| match item {
+            Value::List(metric) | Value::Tuple(metric) => match metric.as_slice() {
+                [
+                    Value::Str(name),
+                    Value::List(datapoint) | Value::Tuple(datapoint),
+                ] => match datapoint.as_slice() {
+                    [timestamp, value] => Ok(MetricPoint {
+                        name: name.clone(),
+                        point: Point {
+                            interval: timestamp
+                                .number()
+                                .filter(|t| *t >= 0.0 && *t <= f64::from(u32::MAX))
+                                .ok_or_else(|| error("Invalid timestamp"))?
+                                as u32,
+                            value: value.number().ok_or_else(|| error("Invalid value"))?,
+                        },
+                    }),
+                    _ => Err(error("Expected a (timestamp, value) datapoint")),
+                },
+                _ => Err(error("Expected a (name, (timestamp, value)) metric")),
+            },
+            _ => Err(error("Expected a (name, (timestamp, value)) metric")),
+        })
+        .collect()
+}
+
+/// Encodes metrics as a pickled list of `(name, (timestamp, value))`.
+pub fn pickle_metrics(metrics: &[MetricPoint]) -> Vec<u8> {
+    // PROTO 2, EMPTY_LIST, MARK
+    let mut data = vec![0x80, 2, b']', b'('];
+    for metric in metrics {
+        // BINUNICODE
+        data.push(b'X');
+        data.extend_from_slice(&(metric.name.len() as u32).to_le_bytes());
+        data.extend_from_slice(metric.name.as_bytes());
+        match i32::try_from(metric.point.interval) {
+            // BININT
+            Ok(interval) => {
+                data.push(b'J');
+                data.extend_from_slice(&interval.to_le_bytes());
+            }
+            // LONG1 with a zero sign byte
+            Err(_) => {
+                data.extend_from_slice(&[0x8a, 5]);
+                data.extend_from_slice(&metric.point.interval.to_le_bytes());
+                data.push(0);
+            }
+        }
+        // BINFLOAT, TUPLE2, TUPLE2
+        data.push(b'G');
+        data.extend_from_slice(&metric.point.value.to_be_bytes());
+        data.extend_from_slice(&[0x86, 0x86]);
+    }
+    // APPENDS, STOP
+    data.extend_from_slice(b"e.");
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expected() -> Vec<MetricPoint> {
+        let metric = |name: &str, interval, value| MetricPoint {
+            name: name.to_owned(),
+            point: Point { interval, value },
+        };
+        vec![
+            metric("a.b", 1_545_778_338, 2.5),
+            metric("c.d", 1_545_778_339, 3.0),
+            metric("a.b", 1_545_778_340, 4.0),
+        ]
+    }
+
+    // pickle.dumps([('a.b', (1545778338, 2.5)), ('c.d', (1545778339.0, 3)), ('a.b', (1545778340, '4'))], protocol)
+    const PROTOCOL_0: &[u8] = b"(lp0\n(Va.b\np1\n(I1545778338\nF2.5\ntp2\ntp3\na(Vc.d\np4\n(F1545778339.0\nI3\ntp5\ntp6\na(g1\n(I1545778340\nV4\np7\ntp8\ntp9\na.";
+    const PROTOCOL_1: &[u8] = b"]q\x00((X\x03\x00\x00\x00a.bq\x01(J\xa2\xb4\"\\G@\x04\x00\x00\x00\x00\x00\x00tq\x02tq\x03(X\x03\x00\x00\x00c.dq\x04(GA\xd7\x08\xad(\xc0\x00\x00K\x03tq\x05tq\x06(h\x01(J\xa4\xb4\"\\X\x01\x00\x00\x004q\x07tq\x08tq\te.";
+    const PROTOCOL_2: &[u8] = b"\x80\x02]q\x00(X\x03\x00\x00\x00a.bq\x01J\xa2\xb4\"\\G@\x04\x00\x00\x00\x00\x00\x00\x86q\x02\x86q\x03X\x03\x00\x00\x00c.dq\x04GA\xd7\x08\xad(\xc0\x00\x00K\x03\x86q\x05\x86q\x06h\x01J\xa4\xb4\"\\X\x01\x00\x00\x004q\x07\x86q\x08\x86q\te.";
+    const PROTOCOL_4: &[u8] = b"\x80\x04\x95A\x00\x00\x00\x00\x00\x00\x00]\x94(\x8c\x03a.b\x94J\xa2\xb4\"\\G@\x04\x00\x00\x00\x00\x00\x00\x86\x94\x86\x94\x8c\x03c.d\x94GA\xd7\x08\xad(\xc0\x00\x00K\x03\x86\x94\x86\x94h\x01J\xa4\xb4\"\\\x8c\x014\x94\x86\x94\x86\x94e.";
+
+    #[test]
+    fn unpickle_protocols() {
+        for data in [PROTOCOL_0, PROTOCOL_1, PROTOCOL_2, PROTOCOL_4] {
+            assert_eq!(unpickle_metrics(data).unwrap(), expected());
+        }
+    }
+
+    #[test]
+    fn unpickle_python2() {
+        // Python 2 carbon-relay: pickle.dumps([('a.b', (1545778338L, 2.5))], 2)
+        let data = b"\x80\x02]q\x00U\x03a.bq\x01\x8a\x04\xa2\xb4\"\\G@\x04\x00\x00\x00\x00\x00\x00\x86q\x02\x86q\x03a.";
+        assert_eq!(unpickle_metrics(data).unwrap(), expected()[..1]);
+
+        let data = b"(lp0\n(S'a.b'\np1\n(L1545778338L\nF2.5\ntp2\ntp3\na.";
+        assert_eq!(unpickle_metrics(data).unwrap(), expected()[..1]);
+    }
+
+    #[test]
+    fn pickle_round_trip() {
+        let metrics = vec![
+            MetricPoint {
+                name: "a.b".to_owned(),
+                point: Point {
+                    interval: 1545778338,
+                    value: 2.5,
+                },
+            },
+            MetricPoint {
+                name: "c.d".to_owned(),
+                point: Point {
+                    interval: u32::MAX,
+                    value: -3.0,
+                },
+            },
+        ];
+        assert_eq!(
+            unpickle_metrics(&pickle_metrics(&metrics)).unwrap(),
+            metrics
+        );
+        assert_eq!(unpickle_metrics(&pickle_metrics(&[])).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn unpickle_rejects_globals() {
+        // pickle.dumps([('a', (1, os.system))], 2)
+        let data =
+            b"\x80\x02]q\x00X\x01\x00\x00\x00aq\x01K\x01cposix\nsystem\nq\x02\x86q\x03\x86q\x04a.";
+        assert!(matches!(
+            unpickle_metrics(data),
+            Err(MetricError::Pickle(message)) if message.starts_with("Opcode 0x63")
+        ));
+    }
+
+    #[test]
+    fn unpickle_malformed() {
+        assert!(unpickle_metrics(b"").is_err());
+        assert!(unpickle_metrics(b"K\x01.").is_err());
+        assert!(unpickle_metrics(b"]K\x01a.").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use crate::MetricPoint;
+use crate::aggregator::Aggregator;
+use crate::cache::MetricCache;
+use crate::filters::{Filters, Stage};
+use crate::instrumentation::Stats;
+use crate::relay::Relay;
+use crate::wal::Wal;
+
+/// Path of received datapoints from the listeners to the cache: allow and block lists,
+/// pre-aggregation rewrites, aggregation and post-aggregation rewrites.
+/// With a relay datapoints are forwarded to its destinations instead of the cache,
+/// with a write-ahead log they are logged before they are cached.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    cache: Arc<MetricCache>,
+    aggregator: Option<Arc<Aggregator>>,
+    filters: Arc<Filters>,
+    relay: Option<Arc<Relay>>,
+    stats: Arc<Stats>,
+    wal: Option<Arc<Wal>>,
+}
+
+impl Pipeline {
+    pub fn new(cache: Arc<MetricCache>) -> Self {
+        Self {
+            cache,
+            aggregator: None,
+            filters: Arc::new(Filters::default()),
+            relay: None,
+            stats: Arc::new(Stats::default()),
+            wal: None,
+        }
+    }
+
+    pub fn with_aggregator(mut self, aggregator: Arc<Aggregator>) -> Self {
+        self.aggregator = Some(aggregator);
+        self
+    }
+
+    pub fn with_filters(mut self, filters: Arc<Filters>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    pub fn with_relay(mut self, relay: Arc<Relay>) -> Self {
+        self.relay = Some(relay);
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn with_wal(mut self, wal: Arc<Wal>) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    /// Parses a plaintext line, reporting malformed lines.
+    pub fn receive_line(&self, line: &str) {
+        match line.parse::<MetricPoint>() {
+            Ok(metric) => self.receive(metric),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    pub fn receive(&self, mut metric: MetricPoint) {
+        self.stats.received();
+        if !self.filters.allow(&metric.name) {
+            return;
+        }
+        metric.name = self.filters.rewrite(Stage::Pre, metric.name);
+
+        if let Some(aggregator) = &self.aggregator
+            && !aggregator.process(&metric)
+        {
+            return;
+        }
+        self.receive_aggregated(metric);
+    }
+
+    /// Datapoint past aggregation, either forwarded or produced by the aggregator.
+    pub fn receive_aggregated(&self, mut metric: MetricPoint) {
+        metric.name = self.filters.rewrite(Stage::Post, metric.name);
+        self.store(metric);
+    }
+
+    /// Datapoint written or relayed as is, like internal metrics.
+    pub fn store(&self, metric: MetricPoint) {
+        match (&self.relay, &self.wal) {
+            (Some(relay), _) => relay.send(metric),
+            (None, Some(wal)) => self.cache.store_metric_logged(metric, wal),
+            (None, None) => self.cache.store_metric_silently(metric),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::AggregatorConfig;
+    use crate::cache::DrainStrategy;
+    use crate::filters::{FilterConfig, parse_rewrite_rules};
+    use crate::schemas::Pattern;
+
+    #[test]
+    fn filter_rewrite_and_aggregate() {
+        let cache = Arc::new(MetricCache::new(100, DrainStrategy::Naive));
+        let aggregator = Arc::new(
+            Aggregator::new(&AggregatorConfig {
+                rules: vec!["<app>.all.requests (60) = sum <app>.*.requests".to_owned()],
+                rules_file: None,
+                delay: 0,
+                forward_original: true,
+            })
+            .unwrap(),
+        );
+        let filters = Arc::new(
+            Filters::new(&FilterConfig {
+                rewrite: parse_rewrite_rules(
+                    "[pre]\n^legacy_([a-z]+)\\. = \\1.\n[post]\n\\.requests$ = .rps\n",
+                )
+                .unwrap(),
+                blacklist: vec![Pattern::new("^noisy\\.").unwrap()],
+                ..FilterConfig::default()
+            })
+            .unwrap(),
+        );
+        let pipeline = Pipeline::new(cache.clone())
+            .with_aggregator(aggregator.clone())
+            .with_filters(filters.clone());
+
+        pipeline.receive_line("legacy_web.a.requests 60 1");
+        pipeline.receive_line("web.b.requests 60 2");
+        pipeline.receive_line("noisy.b.requests 60 4");
+        for metric in aggregator.flush(1000) {
+            pipeline.receive_aggregated(metric);
+        }
+
+        let mut names: Vec<String> = std::iter::from_fn(|| cache.pop())
+            .map(|metric| format!("{} {:?}", metric.name, metric.points[0].value))
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["web.a.rps 1.0", "web.all.rps 3.0", "web.b.rps 2.0"]
+        );
+        assert_eq!(filters.stats().blacklist_rejects, 1);
+    }
+}
@@ -0,0 +1,699 @@
+//! Relay of received datapoints to other diamond or carbon instances, as carbon-relay
+//! does with `RELAY_METHOD = consistent-hashing`.
+
+use futures::future::join_all;
+use serde::*;
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+
+use crate::MetricPoint;
+use crate::pickle::pickle_metrics;
+
+/// Number of ring positions of every destination, as in carbon.
+const REPLICAS: u32 = 100;
+
+/// Hashing of metric names to destinations, named as carbon's `ConsistentHashRing` types.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+pub enum HashType {
+    /// Ring positions from the first 16 bits of md5.
+    #[default]
+    #[serde(rename = "carbon_ch")]
+    CarbonCh,
+    /// Ring positions from fnv1a, destinations need an instance name.
+    #[serde(rename = "fnv1a_ch")]
+    Fnv1aCh,
+    /// Jump consistent hash of fnv1a over the list of destinations.
+    #[serde(rename = "jump_fnv1a_ch")]
+    JumpFnv1aCh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// Lines of `name timestamp value`.
+    Plaintext,
+    /// Carbon pickle messages with a 4-byte length prefix.
+    #[default]
+    Pickle,
+}
+
+/// `host:port[:instance]` of carbon's `DESTINATIONS`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Destination {
+    pub host: String,
+    pub port: u16,
+    pub instance: Option<String>,
+}
+
+impl Destination {
+    /// Key of the destination on the ring, the Python `repr` of carbon's `(host, instance)`.
+    fn ring_key(&self) -> String {
+        match &self.instance {
+            Some(instance) => format!("('{}', '{}')", self.host, instance),
+            None => format!("('{}', None)", self.host),
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid destination '{}'", s);
+        let mut parts = s.split(':');
+        let host = parts.next().filter(|h| !h.is_empty()).ok_or_else(invalid)?;
+        let port = parts
+            .next()
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(invalid)?;
+        let instance = parts.next().map(str::to_owned);
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Destination {
+            host: host.to_owned(),
+            port,
+            instance,
+        })
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)?;
+        if let Some(instance) = &self.instance {
+            write!(f, ":{}", instance)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Destination {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct RelayConfig {
+    /// Forward metrics to `destinations` instead of writing them to `db_path`.
+    pub enabled: bool,
+    pub destinations: Vec<Destination>,
+    pub hashing: HashType,
+    /// Number of distinct destinations every datapoint is sent to.
+    pub replication_factor: usize,
+    pub protocol: Protocol,
+    /// Maximum number of datapoints queued for a destination, new ones are dropped above it.
+    pub max_queue_size: usize,
+    /// Maximum number of datapoints in one message.
+    pub max_datapoints_per_message: usize,
+}
+
+/// 32-bit FNV-1a.
+pub fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Jump consistent hash of Lamping and Veach, a bucket in `0..buckets`.
+pub fn jump_hash(mut key: u64, buckets: usize) -> usize {
+    let mut bucket: i64 = -1;
+    let mut next: i64 = 0;
+    while next < buckets as i64 {
+        bucket = next;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket as usize
+}
+
+/// Carbon-compatible placement of metric names on a list of destinations.
+#[derive(Debug)]
+pub struct HashRing {
+    hashing: HashType,
+    nodes: usize,
+    /// `(position, destination index)` sorted by position.
+    ring: Vec<(u32, usize)>,
+}
+
+impl HashRing {
+    pub fn new(destinations: &[Destination], hashing: HashType) -> Self {
+        let mut ring = Vec::new();
+        if hashing != HashType::JumpFnv1aCh {
+            let mut positions = HashSet::new();
+            for (index, destination) in destinations.iter().enumerate() {
+                for replica in 0..REPLICAS {
+                    let key = match hashing {
+                        HashType::Fnv1aCh => format!(
+                            "{}-{}",
+                            replica,
+                            destination.instance.as_deref().unwrap_or("None")
+                        ),
+                        _ => format!("{}:{}", destination.ring_key(), replica),
+                    };
+                    let mut position = Self::position(hashing, &key);
+                    while !positions.insert(position) {
+                        position += 1;
+                    }
+                    ring.push((position, index));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        HashRing {
+            hashing,
+            nodes: destinations.len(),
+            ring,
+        }
+    }
+
+    fn position(hashing: HashType, key: &str) -> u32 {
+        match hashing {
+            HashType::Fnv1aCh => {
+                let hash = fnv1a(key.as_bytes());
+                (hash >> 16) ^ (hash & 0xffff)
+            }
+            _ => {
+                let digest = md5::compute(key.as_bytes());
+                u32::from(u16::from_be_bytes([digest[0], digest[1]]))
+            }
+        }
+    }
+
+    /// Indices of the first `count` distinct destinations of a metric.
+    pub fn destinations(&self, name: &str, count: usize) -> Vec<usize> {
+        let count = count.min(self.nodes);
+        if self.nodes == 0 {
+            return Vec::new();
+        }
+        if self.hashing == HashType::JumpFnv1aCh {
+            let first = jump_hash(u64::from(fnv1a(name.as_bytes())), self.nodes);
+            return (0..count).map(|i| (first + i) % self.nodes).collect();
+        }
+
+        let position = Self::position(self.hashing, name);
+        let start = self.ring.partition_point(|(p, _)| *p < position);
+        let mut result = Vec::with_capacity(count);
+        for offset in 0..self.ring.len() {
+            if result.len() == count {
+                break;
+            }
+            let (_, index) = self.ring[(start + offset) % self.ring.len()];
+            if !result.contains(&index) {
+                result.push(index);
+            }
+        }
+        result
+    }
+}
+
+/// Exponential delay between reconnects.
+#[derive(Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    /// Delay before the next attempt, doubled after every failure.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+/// Bounded queue of datapoints waiting to be sent to a destination.
+#[derive(Debug)]
+pub struct SendQueue {
+    pub destination: Destination,
+    max_size: usize,
+    points: Mutex<VecDeque<MetricPoint>>,
+    notify: Notify,
+    dropped: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl SendQueue {
+    pub fn new(destination: Destination, max_size: usize) -> Self {
+        SendQueue {
+            destination,
+            max_size,
+            points: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            dropped: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queues a datapoint, returns `false` if the queue is full and it was dropped.
+    pub fn push(&self, metric: MetricPoint) -> bool {
+        let mut points = self.points.lock().unwrap();
+        if points.len() >= self.max_size {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        points.push_back(metric);
+        drop(points);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Takes up to `max` datapoints from the front of the queue.
+    pub fn pop_batch(&self, max: usize) -> Vec<MetricPoint> {
+        let mut points = self.points.lock().unwrap();
+        let len = points.len().min(max);
+        points.drain(..len).collect()
+    }
+
+    /// Puts back a batch that was not sent, dropping what no longer fits.
+    pub fn requeue(&self, batch: Vec<MetricPoint>) {
+        let mut points = self.points.lock().unwrap();
+        let free = self.max_size.saturating_sub(points.len());
+        let dropped = batch.len().saturating_sub(free);
+        self.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+        for metric in batch.into_iter().take(free).rev() {
+            points.push_front(metric);
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+/// Encodes a batch of datapoints as one message.
+pub fn encode(protocol: Protocol, metrics: &[MetricPoint]) -> Vec<u8> {
+    match protocol {
+        Protocol::Plaintext => metrics
+            .iter()
+            .map(|metric| {
+                format!(
+                    "{} {} {}\n",
+                    metric.name, metric.point.interval, metric.point.value
+                )
+            })
+            .collect::<String>()
+            .into_bytes(),
+        Protocol::Pickle => {
+            let payload = pickle_metrics(metrics);
+            let mut message = (payload.len() as u32).to_be_bytes().to_vec();
+            message.extend(payload);
+            message
+        }
+    }
+}
+
+/// Totals over all destinations.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RelayStats {
+    pub queued: usize,
+    pub dropped: u64,
+    pub sent: u64,
+}
+
+/// Routes datapoints to the send queues of their destinations.
+#[derive(Debug)]
+pub struct Relay {
+    ring: HashRing,
+    replication_factor: usize,
+    protocol: Protocol,
+    max_datapoints_per_message: usize,
+    queues: Vec<SendQueue>,
+}
+
+impl Relay {
+    pub fn new(config: &RelayConfig) -> Result<Self, String> {
+        if config.destinations.is_empty() {
+            return Err("Relay has no destinations".to_owned());
+        }
+        if config.replication_factor == 0 || config.max_datapoints_per_message == 0 {
+            return Err("Relay replication factor and message size must be positive".to_owned());
+        }
+        if config.hashing == HashType::Fnv1aCh
+            && let Some(destination) = config.destinations.iter().find(|d| d.instance.is_none())
+        {
+            return Err(format!(
+                "Destination '{}' needs an instance name for fnv1a_ch",
+                destination
+            ));
+        }
+
+        Ok(Relay {
+            ring: HashRing::new(&config.destinations, config.hashing),
+            replication_factor: config.replication_factor,
+            protocol: config.protocol,
+            max_datapoints_per_message: config.max_datapoints_per_message,
+            queues: config
+                .destinations
+                .iter()
+                .map(|destination| SendQueue::new(destination.clone(), config.max_queue_size))
+                .collect(),
+        })
+    }
+
+    pub fn queues(&self) -> &[SendQueue] {
+        &self.queues
+    }
+
+    /// Queues a datapoint for each of its destinations.
+    pub fn send(&self, metric: MetricPoint) {
+        let destinations = self
+            .ring
+            .destinations(&metric.name, self.replication_factor);
+        if let Some((last, rest)) = destinations.split_last() {
+            for index in rest {
+                self.queues[*index].push(metric.clone());
+            }
+            self.queues[*last].push(metric);
+        }
+    }
+
+    pub fn stats(&self) -> RelayStats {
+        self.queues
+            .iter()
+            .fold(RelayStats::default(), |stats, queue| RelayStats {
+                queued: stats.queued + queue.len(),
+                dropped: stats.dropped + queue.dropped(),
+                sent: stats.sent + queue.sent(),
+            })
+    }
+
+    /// Waits up to `wait` for the queues to be sent, as on shutdown.
+    pub async fn flush(&self, wait: Duration) {
+        let sent = async {
+            while self.stats().queued > 0 {
+                sleep(Duration::from_millis(100)).await;
+            }
+        };
+        if timeout(wait, sent).await.is_err() {
+            eprintln!("relay queues not sent, {} dropped", self.stats().queued);
+        }
+    }
+
+    /// Sends queued datapoints to every destination, reconnecting with a backoff.
+    pub async fn run(&self) {
+        join_all(self.queues.iter().map(|queue| self.send_loop(queue))).await;
+    }
+
+    async fn send_loop(&self, queue: &SendQueue) {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(30));
+        loop {
+            let address = format!("{}:{}", queue.destination.host, queue.destination.port);
+            let mut stream = match TcpStream::connect(&address).await {
+                Ok(stream) => {
+                    backoff.reset();
+                    stream
+                }
+                Err(e) => {
+                    eprintln!("relay connect error {} = {:?}", queue.destination, e);
+                    sleep(backoff.next_delay()).await;
+                    continue;
+                }
+            };
+
+            loop {
+                let batch = queue.pop_batch(self.max_datapoints_per_message);
+                if batch.is_empty() {
+                    queue.notify.notified().await;
+                    continue;
+                }
+                if let Err(e) = stream.write_all(&encode(self.protocol, &batch)).await {
+                    eprintln!("relay send error {} = {:?}", queue.destination, e);
+                    queue.requeue(batch);
+                    sleep(backoff.next_delay()).await;
+                    break;
+                }
+                queue.sent.fetch_add(batch.len() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pickle::unpickle_metrics;
+    use futures::StreamExt;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_util::codec::{Framed, LengthDelimitedCodec, LinesCodec};
+    use whisper::point::Point;
+
+    const NAMES: [&str; 4] = ["carbon.agents.host.cpu", "a.b.c", "servers.web01.load", "x"];
+
+    fn destinations(list: &[&str]) -> Vec<Destination> {
+        list.iter().map(|d| d.parse().unwrap()).collect()
+    }
+
+    fn metric(name: &str, interval: u32, value: f64) -> MetricPoint {
+        MetricPoint {
+            name: name.to_owned(),
+            point: Point { interval, value },
+        }
+    }
+
+    fn config(destinations: Vec<Destination>, protocol: Protocol) -> RelayConfig {
+        RelayConfig {
+            enabled: true,
+            destinations,
+            hashing: HashType::CarbonCh,
+            replication_factor: 1,
+            protocol,
+            max_queue_size: 100,
+            max_datapoints_per_message: 2,
+        }
+    }
+
+    #[test]
+    fn destination_parse() {
+        assert_eq!(
+            "127.0.0.1:2004:a".parse::<Destination>().unwrap(),
+            Destination {
+                host: "127.0.0.1".to_owned(),
+                port: 2004,
+                instance: Some("a".to_owned()),
+            }
+        );
+        let destination: Destination = "carbon:2004".parse().unwrap();
+        assert_eq!(destination.instance, None);
+        assert_eq!(destination.to_string(), "carbon:2004");
+
+        assert!("carbon".parse::<Destination>().is_err());
+        assert!(":2004".parse::<Destination>().is_err());
+        assert!("carbon:port".parse::<Destination>().is_err());
+        assert!("carbon:2004:a:b".parse::<Destination>().is_err());
+    }
+
+    #[test]
+    fn carbon_ch_placement() {
+        // Same placement as carbon's ConsistentHashRing
+        let ring = HashRing::new(
+            &destinations(&["127.0.0.1:2104:a", "127.0.0.1:2204:b", "127.0.0.2:2004"]),
+            HashType::CarbonCh,
+        );
+        let placements: Vec<Vec<usize>> = NAMES
+            .iter()
+            .map(|name| ring.destinations(name, 3))
+            .collect();
+        assert_eq!(
+            placements,
+            vec![vec![0, 2, 1], vec![2, 0, 1], vec![2, 1, 0], vec![0, 1, 2]]
+        );
+        assert_eq!(ring.destinations("a.b.c", 1), vec![2]);
+        assert_eq!(ring.destinations("a.b.c", 5), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn fnv1a_ch_placement() {
+        let ring = HashRing::new(
+            &destinations(&["127.0.0.1:2104:a", "127.0.0.1:2204:b", "127.0.0.2:2004:c"]),
+            HashType::Fnv1aCh,
+        );
+        let placements: Vec<Vec<usize>> = NAMES
+            .iter()
+            .map(|name| ring.destinations(name, 3))
+            .collect();
+        assert_eq!(
+            placements,
+            vec![vec![1, 2, 0], vec![0, 1, 2], vec![2, 0, 1], vec![0, 1, 2]]
+        );
+    }
+
+    #[test]
+    fn jump_placement() {
+        assert_eq!(fnv1a(b""), 0x811c_9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c_292c);
+
+        let buckets: Vec<usize> = [1, 2, 3, 5, 10]
+            .iter()
+            .map(|n| jump_hash(u64::from(fnv1a(b"servers.web01.load")), *n))
+            .collect();
+        assert_eq!(buckets, vec![0, 1, 2, 4, 7]);
+
+        let ring = HashRing::new(
+            &destinations(&["a:1", "b:1", "c:1", "d:1", "e:1"]),
+            HashType::JumpFnv1aCh,
+        );
+        assert_eq!(ring.destinations("servers.web01.load", 2), vec![4, 0]);
+        assert_eq!(ring.destinations("a.b.c", 1), vec![2]);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn send_queue_is_bounded() {
+        let queue = SendQueue::new("a:1".parse().unwrap(), 3);
+        for i in 0..4 {
+            queue.push(metric("a", i, 1.0));
+        }
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.dropped(), 1);
+
+        let batch = queue.pop_batch(2);
+        assert_eq!(batch, vec![metric("a", 0, 1.0), metric("a", 1, 1.0)]);
+        queue.push(metric("a", 4, 1.0));
+        queue.requeue(batch);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(
+            queue.pop_batch(10),
+            vec![
+                metric("a", 0, 1.0),
+                metric("a", 2, 1.0),
+                metric("a", 4, 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn relay_config_errors() {
+        assert!(Relay::new(&config(vec![], Protocol::Pickle)).is_err());
+        let mut fnv = config(destinations(&["a:1:a", "b:1"]), Protocol::Pickle);
+        fnv.hashing = HashType::Fnv1aCh;
+        assert!(Relay::new(&fnv).is_err());
+    }
+
+    #[tokio::test]
+    async fn relay_pickle_to_destinations() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destinations = vec![
+            format!("127.0.0.1:{}:a", first.local_addr().unwrap().port())
+                .parse()
+                .unwrap(),
+            format!("127.0.0.1:{}:b", second.local_addr().unwrap().port())
+                .parse()
+                .unwrap(),
+        ];
+        let relay = Arc::new(Relay::new(&config(destinations, Protocol::Pickle)).unwrap());
+
+        let metrics: Vec<MetricPoint> = NAMES
+            .iter()
+            .enumerate()
+            .map(|(i, name)| metric(name, 1545778338 + i as u32, i as f64))
+            .collect();
+        let mut expected = vec![Vec::new(), Vec::new()];
+        for metric in &metrics {
+            expected[relay.ring.destinations(&metric.name, 1)[0]].push(metric.clone());
+            relay.send(metric.clone());
+        }
+
+        let sender = relay.clone();
+        tokio::spawn(async move { sender.run().await });
+
+        for (listener, expected) in [first, second].into_iter().zip(expected) {
+            let mut received = Vec::new();
+            if !expected.is_empty() {
+                let (sock, _) = listener.accept().await.unwrap();
+                let mut framed = Framed::new(sock, LengthDelimitedCodec::new());
+                while received.len() < expected.len() {
+                    let message = timeout(Duration::from_secs(5), framed.next())
+                        .await
+                        .unwrap()
+                        .unwrap()
+                        .unwrap();
+                    received.extend(unpickle_metrics(&message).unwrap());
+                }
+            }
+            assert_eq!(received, expected);
+        }
+        assert_eq!(relay.stats().sent, 4);
+    }
+
+    #[tokio::test]
+    async fn relay_plaintext_with_replication() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut config = config(
+            destinations(&[&format!("127.0.0.1:{}:a", port), "127.0.0.1:1:b"]),
+            Protocol::Plaintext,
+        );
+        config.replication_factor = 2;
+        let relay = Arc::new(Relay::new(&config).unwrap());
+        relay.send(metric("a.b.c", 1545778338, 2.5));
+
+        let sender = relay.clone();
+        tokio::spawn(async move { sender.run().await });
+
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(sock, LinesCodec::new());
+        let line = timeout(Duration::from_secs(5), framed.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            line.parse::<MetricPoint>().unwrap(),
+            metric("a.b.c", 1545778338, 2.5)
+        );
+        // The second replica waits for its destination to come up
+        assert_eq!(relay.queues()[1].len(), 1);
+    }
+}
@@ -0,0 +1,407 @@
+//! Prometheus remote_write receiver: snappy-compressed protobuf `WriteRequest`s
+//! posted over HTTP, see https://prometheus.io/docs/concepts/remote_write_spec/.
+
+use serde::*;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use whisper::point::Point;
+
+use crate::MetricPoint;
+use crate::pipeline::Pipeline;
+use crate::template::{NameTemplate, sanitize};
+
+const WRITE_PATH: &str = "/api/v1/write";
+const MAX_HEADERS_SIZE: usize = 16 * 1024;
+/// Idle time after which a keep-alive connection is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Listener of Prometheus remote_write requests on POST `/api/v1/write`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RemoteWriteConfig {
+    pub enabled: bool,
+    pub port: u32,
+    pub host: IpAddr,
+    /// Largest accepted request body, compressed or not, in bytes.
+    pub max_message_size: usize,
+    /// Metric name with `{label}` placeholders, like `{job}.{instance}.{__name__}`.
+    /// Without it series are written as Graphite tagged series.
+    #[serde(default)]
+    pub template: Option<NameTemplate>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// Milliseconds since the epoch.
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+fn label<'a>(labels: &'a [Label], name: &str) -> Option<&'a str> {
+    labels
+        .iter()
+        .find(|label| label.name == name && !label.value.is_empty())
+        .map(|label| label.value.as_str())
+}
+
+/// Metric name of a series, `None` if the series lacks a label the name needs.
+pub fn metric_name(labels: &[Label], template: Option<&NameTemplate>) -> Option<String> {
+    match template {
+        Some(template) => template.render(|name| label(labels, name)),
+        None => {
+            let mut name = sanitize(label(labels, "__name__")?);
+            let mut tags: Vec<&Label> = labels
+                .iter()
+                .filter(|label| label.name != "__name__" && !label.value.is_empty())
+                .collect();
+            tags.sort_by(|a, b| a.name.cmp(&b.name));
+            for tag in tags {
+                let value: String = tag
+                    .value
+                    .chars()
+                    .map(|c| {
+                        if c == ';' || c == '~' || c.is_whitespace() {
+                            '_'
+                        } else {
+                            c
+                        }
+                    })
+                    .collect();
+                name.push_str(&format!(";{}={}", tag.name, value));
+            }
+            Some(name)
+        }
+    }
+}
+
+/// Datapoints of a decoded request. Stale markers and other NaN samples are skipped.
+pub fn metrics(request: WriteRequest, template: Option<&NameTemplate>) -> Vec<MetricPoint> {
+    let mut metrics = Vec::new();
+    for series in request.timeseries {
+        let Some(name) = metric_name(&series.labels, template) else {
+            eprintln!(
+                "remote_write series without template labels = {:?}",
+                series.labels
+            );
+            continue;
+        };
+        metrics.extend(
+            series
+                .samples
+                .into_iter()
+                .filter(|sample| !sample.value.is_nan() && sample.timestamp >= 0)
+                .map(|sample| MetricPoint {
+                    name: name.clone(),
+                    point: Point {
+                        interval: (sample.timestamp / 1000) as u32,
+                        value: sample.value,
+                    },
+                }),
+        );
+    }
+    metrics
+}
+
+/// Decompresses and decodes a request body.
+pub fn decode(body: &[u8], max_message_size: usize) -> Result<WriteRequest, String> {
+    let len = snap::raw::decompress_len(body).map_err(|e| e.to_string())?;
+    if len > max_message_size {
+        return Err(format!("decompressed size {} is too large", len));
+    }
+    let data = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|e| e.to_string())?;
+    prost::Message::decode(data.as_slice()).map_err(|e| e.to_string())
+}
+
+fn response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Serves requests of a connection until it is closed, an error or `shutdown`.
+async fn connection(
+    mut sock: TcpStream,
+    config: &RemoteWriteConfig,
+    pipeline: &Pipeline,
+    shutdown: &CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buf = Vec::new();
+    loop {
+        // Headers
+        let (header_len, method, path, content_length, close) = loop {
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut request = httparse::Request::new(&mut headers);
+            if let httparse::Status::Complete(len) = request.parse(&buf)? {
+                let header = |name: &str| {
+                    request
+                        .headers
+                        .iter()
+                        .find(|header| header.name.eq_ignore_ascii_case(name))
+                        .and_then(|header| std::str::from_utf8(header.value).ok())
+                };
+                let content_length = header("Content-Length").and_then(|v| v.parse().ok());
+                let close = header("Connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+                break (
+                    len,
+                    request.method.unwrap_or_default().to_owned(),
+                    request.path.unwrap_or_default().to_owned(),
+                    content_length,
+                    close,
+                );
+            }
+            if buf.len() > MAX_HEADERS_SIZE {
+                let reply = response("431 Request Header Fields Too Large", "");
+                return Ok(sock.write_all(reply.as_bytes()).await?);
+            }
+            let mut chunk = [0; 4096];
+            let read = tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                read = timeout(IDLE_TIMEOUT, sock.read(&mut chunk)) => read,
+            };
+            match read {
+                Ok(Ok(0)) | Err(_) => return Ok(()),
+                Ok(Ok(len)) => buf.extend_from_slice(&chunk[..len]),
+                Ok(Err(e)) => return Err(e.into()),
+            }
+        };
+        buf.drain(..header_len);
+
+        let content_length = match content_length {
+            Some(content_length) => content_length,
+            None if method != "POST" => 0,
+            None => {
+                let reply = response("411 Length Required", "");
+                return Ok(sock.write_all(reply.as_bytes()).await?);
+            }
+        };
+        if content_length > config.max_message_size {
+            let reply = response("413 Payload Too Large", "");
+            return Ok(sock.write_all(reply.as_bytes()).await?);
+        }
+        while buf.len() < content_length {
+            let mut chunk = vec![0; content_length - buf.len()];
+            match timeout(IDLE_TIMEOUT, sock.read(&mut chunk)).await {
+                Ok(Ok(0)) | Err(_) => return Ok(()),
+                Ok(Ok(len)) => buf.extend_from_slice(&chunk[..len]),
+                Ok(Err(e)) => return Err(e.into()),
+            }
+        }
+        let body: Vec<u8> = buf.drain(..content_length).collect();
+
+        let reply = match (method.as_str(), path.as_str()) {
+            ("POST", WRITE_PATH) => match decode(&body, config.max_message_size) {
+                Ok(request) => {
+                    metrics(request, config.template.as_ref())
+                        .into_iter()
+                        .for_each(|metric| pipeline.receive(metric));
+                    "HTTP/1.1 204 No Content\r\n\r\n".to_owned()
+                }
+                Err(e) => {
+                    eprintln!("remote_write decode error = {}", e);
+                    response("400 Bad Request", &e)
+                }
+            },
+            _ => response("404 Not Found", ""),
+        };
+        sock.write_all(reply.as_bytes()).await?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+/// Accepts connections until `stop`, connections are served until `shutdown`.
+pub async fn serve(
+    listener: TcpListener,
+    config: RemoteWriteConfig,
+    pipeline: Pipeline,
+    stop: CancellationToken,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let accepted = tokio::select! {
+            _ = stop.cancelled() => break,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
+            Ok((sock, _)) => {
+                let config = config.clone();
+                let pipeline = pipeline.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = connection(sock, &config, &pipeline, &shutdown).await {
+                        eprintln!("remote_write receive error = {:?}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("remote_write accept error = {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{DrainStrategy, MetricCache};
+    use prost::Message;
+    use std::sync::Arc;
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<Label> {
+        pairs
+            .iter()
+            .map(|(name, value)| Label {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    fn template(s: &str) -> NameTemplate {
+        s.parse().unwrap()
+    }
+
+    fn series() -> TimeSeries {
+        TimeSeries {
+            labels: labels(&[
+                ("__name__", "node_load1"),
+                ("job", "node"),
+                ("instance", "web01.example.com:9100"),
+            ]),
+            samples: vec![
+                Sample {
+                    value: 0.5,
+                    timestamp: 1_545_778_338_123,
+                },
+                Sample {
+                    value: f64::NAN,
+                    timestamp: 1_545_778_348_000,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn names_from_labels() {
+        let series = series();
+        assert_eq!(
+            metric_name(&series.labels, None).unwrap(),
+            "node_load1;instance=web01.example.com:9100;job=node"
+        );
+        assert_eq!(
+            metric_name(
+                &series.labels,
+                Some(&template("prometheus.{job}.{instance}.{__name__}"))
+            )
+            .unwrap(),
+            "prometheus.node.web01_example_com_9100.node_load1"
+        );
+        assert_eq!(
+            metric_name(&series.labels, Some(&template("{env}.{__name__}"))),
+            None
+        );
+        assert_eq!(
+            metric_name(&labels(&[("__name__", "job:rate5m"), ("a", "")]), None).unwrap(),
+            "job_rate5m"
+        );
+    }
+
+    #[tokio::test]
+    async fn receive_write_request() {
+        let cache = Arc::new(MetricCache::new(100, DrainStrategy::Naive));
+        let config = RemoteWriteConfig {
+            enabled: true,
+            port: 0,
+            host: "127.0.0.1".parse().unwrap(),
+            max_message_size: 1024,
+            template: None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        tokio::spawn(serve(
+            listener,
+            config,
+            Pipeline::new(cache.clone()),
+            shutdown.clone(),
+            shutdown.clone(),
+        ));
+
+        let request = WriteRequest {
+            timeseries: vec![series()],
+        };
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+        let mut sock = TcpStream::connect(address).await.unwrap();
+        let post = |path: &str, body: &[u8]| {
+            let mut message = format!(
+                "POST {} HTTP/1.1\r\nContent-Encoding: snappy\r\nContent-Length: {}\r\n\r\n",
+                path,
+                body.len()
+            )
+            .into_bytes();
+            message.extend_from_slice(body);
+            message
+        };
+
+        // Both requests on a keep-alive connection
+        sock.write_all(&post("/api/v1/write", &body)).await.unwrap();
+        sock.write_all(&post("/api/v1/write", b"garbage"))
+            .await
+            .unwrap();
+        sock.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        sock.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n\r\nHTTP/1.1 400 Bad Request"));
+        assert!(response.contains("HTTP/1.1 404 Not Found"));
+
+        let metric = cache.pop().unwrap();
+        assert_eq!(
+            metric.name,
+            "node_load1;instance=web01.example.com:9100;job=node"
+        );
+        assert_eq!(
+            metric.points,
+            vec![Point {
+                interval: 1_545_778_338,
+                value: 0.5
+            }]
+        );
+        assert!(cache.pop().is_none());
+        shutdown.cancel();
+    }
+}
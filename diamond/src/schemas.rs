@@ -0,0 +1,217 @@
+//! Per-metric retention and aggregation rules, as carbon's `storage-schemas.conf`
+//! and `storage-aggregation.conf`.
+
+use regex::Regex;
+use serde::*;
+use whisper::aggregation::AggregationMethod;
+use whisper::retention::Retention;
+
+/// Regular expression matched against metric names.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        Regex::new(pattern)
+            .map(Pattern)
+            .map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.0.is_match(name)
+    }
+
+    pub fn replace_all(&self, name: &str, replacement: &str) -> String {
+        self.0.replace_all(name, replacement).into_owned()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Pattern::new(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// Retentions of new files whose name matches `pattern`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SchemaRule {
+    #[serde(default)]
+    pub name: String,
+    pub pattern: Pattern,
+    pub retentions: Vec<Retention>,
+}
+
+/// Aggregation of new files whose name matches `pattern`, unset values fall back to defaults.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AggregationRule {
+    #[serde(default)]
+    pub name: String,
+    pub pattern: Pattern,
+    #[serde(default)]
+    pub x_files_factor: Option<f32>,
+    #[serde(default)]
+    pub aggregation_method: Option<AggregationMethod>,
+}
+
+/// Ini file section as `(name, [(key, value)])`.
+type Section = (String, Vec<(String, String)>);
+
+fn parse_ini(s: &str) -> Result<Vec<Section>, String> {
+    let mut sections: Vec<Section> = Vec::new();
+    for (number, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((name.trim().to_owned(), Vec::new()));
+        } else if let Some((key, value)) = line.split_once('=')
+            && let Some((_, entries)) = sections.last_mut()
+        {
+            entries.push((key.trim().to_owned(), value.trim().to_owned()));
+        } else {
+            return Err(format!("Invalid line {}: {}", number + 1, line));
+        }
+    }
+    Ok(sections)
+}
+
+fn required<'a>(
+    section: &str,
+    entries: &'a [(String, String)],
+    key: &str,
+) -> Result<&'a str, String> {
+    entries
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+        .ok_or_else(|| format!("Section [{}] has no {}", section, key))
+}
+
+/// Parses graphite's `storage-schemas.conf`.
+pub fn parse_storage_schemas(s: &str) -> Result<Vec<SchemaRule>, String> {
+    parse_ini(s)?
+        .into_iter()
+        .map(|(name, entries)| {
+            let pattern = Pattern::new(required(&name, &entries, "pattern")?)?;
+            let retentions = required(&name, &entries, "retentions")?
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<Retention>, String>>()?;
+            Ok(SchemaRule {
+                name,
+                pattern,
+                retentions,
+            })
+        })
+        .collect()
+}
+
+/// Parses graphite's `storage-aggregation.conf`.
+pub fn parse_storage_aggregation(s: &str) -> Result<Vec<AggregationRule>, String> {
+    parse_ini(s)?
+        .into_iter()
+        .map(|(name, entries)| {
+            let pattern = Pattern::new(required(&name, &entries, "pattern")?)?;
+            let mut rule = AggregationRule {
+                name,
+                pattern,
+                x_files_factor: None,
+                aggregation_method: None,
+            };
+            for (key, value) in entries {
+                match key.as_str() {
+                    "xFilesFactor" => {
+                        rule.x_files_factor = Some(
+                            value
+                                .parse()
+                                .map_err(|_| format!("Invalid xFilesFactor '{}'", value))?,
+                        )
+                    }
+                    "aggregationMethod" => rule.aggregation_method = Some(value.parse()?),
+                    _ => {}
+                }
+            }
+            Ok(rule)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage_schemas() {
+        let rules = parse_storage_schemas(
+            r#"
+# Schema definitions for Whisper files
+[carbon]
+pattern = ^carbon\.
+retentions = 60:90d
+
+[default_1min_for_1day]
+pattern = .*
+retentions = 10s:6h,1m:7d
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].name, "carbon");
+        assert!(rules[0].pattern.is_match("carbon.agents.a"));
+        assert!(!rules[0].pattern.is_match("servers.carbon"));
+        assert_eq!(
+            rules[1].retentions,
+            vec![
+                Retention {
+                    seconds_per_point: 10,
+                    points: 2160
+                },
+                Retention {
+                    seconds_per_point: 60,
+                    points: 10080
+                }
+            ]
+        );
+
+        assert!(parse_storage_schemas("[a]\npattern = .*\n").is_err());
+        assert!(parse_storage_schemas("[a]\npattern = (\nretentions = 1:1\n").is_err());
+        assert!(parse_storage_schemas("pattern = .*\n").is_err());
+    }
+
+    #[test]
+    fn storage_aggregation() {
+        let rules = parse_storage_aggregation(
+            r#"
+[min]
+pattern = \.min$
+xFilesFactor = 0.1
+aggregationMethod = min
+
+[count]
+pattern = \.count$
+aggregationMethod = sum
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].x_files_factor, Some(0.1));
+        assert_eq!(rules[0].aggregation_method, Some(AggregationMethod::Min));
+        assert_eq!(rules[1].x_files_factor, None);
+        assert_eq!(rules[1].aggregation_method, Some(AggregationMethod::Sum));
+
+        assert!(parse_storage_aggregation("[a]\npattern = .*\nxFilesFactor = x\n").is_err());
+        assert!(parse_storage_aggregation("[a]\npattern = .*\naggregationMethod = x\n").is_err());
+    }
+}
@@ -8,19 +8,102 @@ use std::path::{Path, PathBuf};
 use whisper::aggregation::AggregationMethod;
 use whisper::retention::Retention;
 
+use crate::aggregator::AggregatorConfig;
+use crate::cache::CacheConfig;
+use crate::carbonlink::CarbonlinkConfig;
+use crate::filters::FilterConfig;
+use crate::influx::InfluxConfig;
+use crate::instrumentation::InstrumentationConfig;
+use crate::relay::RelayConfig;
+use crate::remote_write::RemoteWriteConfig;
+use crate::schemas::{
+    AggregationRule, SchemaRule, parse_storage_aggregation, parse_storage_schemas,
+};
+use crate::statsd::StatsdConfig;
+use crate::wal::WalConfig;
+
 const CONFIG: &str = include_str!("config.toml");
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Net {
     pub port: u32,
     pub host: IpAddr,
 }
 
+/// Listener of the carbon pickle protocol.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PickleConfig {
+    pub port: u32,
+    pub host: IpAddr,
+    /// Largest accepted message, in bytes.
+    pub max_message_size: usize,
+}
+
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct WhisperConfig {
     pub x_files_factor: f32,
     pub retentions: Vec<Retention>,
     pub aggregation_method: AggregationMethod,
+    /// Retentions by metric pattern, the first matching rule applies.
+    #[serde(default)]
+    pub schemas: Vec<SchemaRule>,
+    /// xFilesFactor and aggregation method by metric pattern, the first matching rule applies.
+    #[serde(default)]
+    pub aggregations: Vec<AggregationRule>,
+    /// Graphite `storage-schemas.conf`, its rules are checked after `schemas`.
+    #[serde(default)]
+    pub storage_schemas: Option<PathBuf>,
+    /// Graphite `storage-aggregation.conf`, its rules are checked after `aggregations`.
+    #[serde(default)]
+    pub storage_aggregation: Option<PathBuf>,
+    /// Milliseconds to wait for a lock of a whisper file, files are not locked if not set.
+    #[serde(default)]
+    pub lock_timeout: Option<u64>,
+    /// Number of whisper file headers to keep in memory, 0 turns the cache off.
+    #[serde(default)]
+    pub header_cache: usize,
+}
+
+impl WhisperConfig {
+    /// Adds the rules of `storage_schemas` and `storage_aggregation` files.
+    pub fn load_rule_files(&mut self) -> Result<(), String> {
+        let read = |path: &Path| {
+            fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))
+        };
+        if let Some(path) = &self.storage_schemas {
+            let rules = parse_storage_schemas(&read(path)?)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            self.schemas.extend(rules);
+        }
+        if let Some(path) = &self.storage_aggregation {
+            let rules = parse_storage_aggregation(&read(path)?)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            self.aggregations.extend(rules);
+        }
+        Ok(())
+    }
+
+    /// Retentions of a new file for the metric.
+    pub fn retentions_for(&self, name: &str) -> &[Retention] {
+        self.schemas
+            .iter()
+            .find(|rule| rule.pattern.is_match(name))
+            .map_or(&self.retentions, |rule| &rule.retentions)
+    }
+
+    /// xFilesFactor and aggregation method of a new file for the metric.
+    pub fn aggregation_for(&self, name: &str) -> (f32, AggregationMethod) {
+        let rule = self
+            .aggregations
+            .iter()
+            .find(|rule| rule.pattern.is_match(name));
+        (
+            rule.and_then(|rule| rule.x_files_factor)
+                .unwrap_or(self.x_files_factor),
+            rule.and_then(|rule| rule.aggregation_method)
+                .unwrap_or(self.aggregation_method),
+        )
+    }
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
@@ -28,7 +111,19 @@ pub struct Settings {
     pub db_path: PathBuf,
     pub tcp: Net,
     pub udp: Net,
+    pub pickle: PickleConfig,
+    pub remote_write: RemoteWriteConfig,
+    pub influx: InfluxConfig,
+    pub statsd: StatsdConfig,
+    pub carbonlink: CarbonlinkConfig,
     pub whisper: WhisperConfig,
+    pub cache: CacheConfig,
+    pub aggregator: AggregatorConfig,
+    #[serde(default)]
+    pub filters: FilterConfig,
+    pub relay: RelayConfig,
+    pub instrumentation: InstrumentationConfig,
+    pub wal: WalConfig,
 }
 
 impl Settings {
@@ -39,7 +134,11 @@ impl Settings {
             builder = builder.add_source(File::from(file));
         }
 
-        let settings = builder.build()?.try_deserialize()?;
+        let mut settings: Settings = builder.build()?.try_deserialize()?;
+        settings
+            .whisper
+            .load_rule_files()
+            .map_err(ConfigError::Message)?;
         Ok(settings)
     }
 
@@ -51,6 +150,9 @@ impl Settings {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cache::DrainStrategy;
+    use crate::influx::Precision;
+    use crate::relay::{HashType, Protocol};
     use std::fs::read_to_string;
     use std::net::IpAddr::V4;
     use tempfile::Builder;
@@ -70,6 +172,39 @@ mod tests {
                 port: 6142,
                 host: V4("0.0.0.0".parse().unwrap()),
             },
+            pickle: PickleConfig {
+                port: 2004,
+                host: V4("0.0.0.0".parse().unwrap()),
+                max_message_size: 1_048_576,
+            },
+            remote_write: RemoteWriteConfig {
+                enabled: false,
+                port: 9201,
+                host: V4("0.0.0.0".parse().unwrap()),
+                max_message_size: 33_554_432,
+                template: None,
+            },
+            influx: InfluxConfig {
+                enabled: false,
+                port: 8094,
+                host: V4("0.0.0.0".parse().unwrap()),
+                template: "{measurement}.{field}".parse().unwrap(),
+                precision: Precision::Ns,
+            },
+            statsd: StatsdConfig {
+                enabled: false,
+                port: 8125,
+                host: V4("0.0.0.0".parse().unwrap()),
+                flush_interval: 10,
+                prefix: "stats".to_owned(),
+                percentiles: vec![90.0],
+            },
+            carbonlink: CarbonlinkConfig {
+                enabled: false,
+                port: 7002,
+                host: V4("127.0.0.1".parse().unwrap()),
+                max_message_size: 1_048_576,
+            },
             whisper: WhisperConfig {
                 x_files_factor: 0.5,
                 retentions: vec![Retention {
@@ -77,6 +212,48 @@ mod tests {
                     points: 1440,
                 }],
                 aggregation_method: AggregationMethod::Average,
+                schemas: vec![],
+                aggregations: vec![],
+                storage_schemas: None,
+                storage_aggregation: None,
+                lock_timeout: None,
+                header_cache: 0,
+            },
+            cache: CacheConfig {
+                max_size: 2_000_000,
+                max_updates_per_second: 500,
+                write_strategy: DrainStrategy::Sorted,
+                writers: 1,
+                max_creates_per_minute: 50,
+                max_deferred_creates: 1_000_000,
+            },
+            aggregator: AggregatorConfig {
+                rules: vec![],
+                rules_file: None,
+                delay: 10,
+                forward_original: false,
+            },
+            filters: FilterConfig::default(),
+            relay: RelayConfig {
+                enabled: false,
+                destinations: vec![],
+                hashing: HashType::CarbonCh,
+                replication_factor: 1,
+                protocol: Protocol::Pickle,
+                max_queue_size: 100_000,
+                max_datapoints_per_message: 500,
+            },
+            instrumentation: InstrumentationConfig {
+                interval: 60,
+                prefix: "diamond.agents".to_owned(),
+                host: None,
+                prometheus: None,
+            },
+            wal: WalConfig {
+                enabled: false,
+                path: PathBuf::from("/var/db/diamond/wal"),
+                max_segment_size: 67_108_864,
+                sync_interval: 1,
             },
         };
 
@@ -108,6 +285,39 @@ mod tests {
                 port: 6142,
                 host: V4("0.0.0.0".parse().unwrap()),
             },
+            pickle: PickleConfig {
+                port: 2004,
+                host: V4("0.0.0.0".parse().unwrap()),
+                max_message_size: 1_048_576,
+            },
+            remote_write: RemoteWriteConfig {
+                enabled: false,
+                port: 9201,
+                host: V4("0.0.0.0".parse().unwrap()),
+                max_message_size: 33_554_432,
+                template: None,
+            },
+            influx: InfluxConfig {
+                enabled: false,
+                port: 8094,
+                host: V4("0.0.0.0".parse().unwrap()),
+                template: "{measurement}.{field}".parse().unwrap(),
+                precision: Precision::Ns,
+            },
+            statsd: StatsdConfig {
+                enabled: false,
+                port: 8125,
+                host: V4("0.0.0.0".parse().unwrap()),
+                flush_interval: 10,
+                prefix: "stats".to_owned(),
+                percentiles: vec![90.0],
+            },
+            carbonlink: CarbonlinkConfig {
+                enabled: false,
+                port: 7002,
+                host: V4("127.0.0.1".parse().unwrap()),
+                max_message_size: 1_048_576,
+            },
             whisper: WhisperConfig {
                 x_files_factor: 0.5,
                 retentions: vec![Retention {
@@ -115,12 +325,122 @@ mod tests {
                     points: 1440,
                 }],
                 aggregation_method: AggregationMethod::Average,
+                schemas: vec![],
+                aggregations: vec![],
+                storage_schemas: None,
+                storage_aggregation: None,
+                lock_timeout: None,
+                header_cache: 0,
+            },
+            cache: CacheConfig {
+                max_size: 2_000_000,
+                max_updates_per_second: 500,
+                write_strategy: DrainStrategy::Sorted,
+                writers: 1,
+                max_creates_per_minute: 50,
+                max_deferred_creates: 1_000_000,
+            },
+            aggregator: AggregatorConfig {
+                rules: vec![],
+                rules_file: None,
+                delay: 10,
+                forward_original: false,
+            },
+            filters: FilterConfig::default(),
+            relay: RelayConfig {
+                enabled: false,
+                destinations: vec![],
+                hashing: HashType::CarbonCh,
+                replication_factor: 1,
+                protocol: Protocol::Pickle,
+                max_queue_size: 100_000,
+                max_datapoints_per_message: 500,
+            },
+            instrumentation: InstrumentationConfig {
+                interval: 60,
+                prefix: "diamond.agents".to_owned(),
+                host: None,
+                prometheus: None,
+            },
+            wal: WalConfig {
+                enabled: false,
+                path: PathBuf::from("/var/db/diamond/wal"),
+                max_segment_size: 67_108_864,
+                sync_interval: 1,
             },
         };
 
         assert_eq!(config, etalon);
     }
 
+    #[test]
+    fn test_config_rules() {
+        let dir = Builder::new().prefix("diamond").tempdir().unwrap();
+        let schemas_path = dir.path().join("storage-schemas.conf");
+        fs::write(
+            &schemas_path,
+            "[latency]\npattern = \\.latency\\.\nretentions = 10s:1d\n",
+        )
+        .unwrap();
+        let aggregation_path = dir.path().join("storage-aggregation.conf");
+        fs::write(
+            &aggregation_path,
+            "[max]\npattern = \\.p99$\naggregationMethod = max\n",
+        )
+        .unwrap();
+
+        let config_path = dir.path().join("config.toml");
+        let s = format!(
+            r#"
+[whisper]
+x_files_factor = 0.5
+retentions = [ [60,1440] ]
+aggregation_method = "average"
+storage_schemas = "{}"
+storage_aggregation = "{}"
+
+[[whisper.schemas]]
+name = "counters"
+pattern = '\.count$'
+retentions = [ [10,8640] ]
+
+[[whisper.aggregations]]
+pattern = '\.count$'
+x_files_factor = 0.0
+aggregation_method = "sum"
+"#,
+            schemas_path.display(),
+            aggregation_path.display()
+        );
+        fs::write(&config_path, s).unwrap();
+
+        let whisper = Settings::new(Some(config_path)).unwrap().whisper;
+        let retention = |seconds_per_point, points| Retention {
+            seconds_per_point,
+            points,
+        };
+
+        assert_eq!(whisper.retentions_for("a.count"), [retention(10, 8640)]);
+        assert_eq!(
+            whisper.retentions_for("a.latency.p99"),
+            [retention(10, 8640)]
+        );
+        assert_eq!(whisper.retentions_for("a.gauge"), [retention(60, 1440)]);
+
+        assert_eq!(
+            whisper.aggregation_for("a.count"),
+            (0.0, AggregationMethod::Sum)
+        );
+        assert_eq!(
+            whisper.aggregation_for("a.latency.p99"),
+            (0.5, AggregationMethod::Max)
+        );
+        assert_eq!(
+            whisper.aggregation_for("a.gauge"),
+            (0.5, AggregationMethod::Average)
+        );
+    }
+
     #[test]
     fn test_generate_config() {
         let path = Builder::new()
@@ -0,0 +1,362 @@
+//! StatsD server: counters, gauges, timers and sets like `api.requests:1|c|@0.1`,
+//! aggregated and written once per flush interval, as Etsy statsd's graphite backend does.
+
+use serde::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+use whisper::point::Point;
+
+use crate::MetricPoint;
+use crate::pipeline::Pipeline;
+
+/// Listener of StatsD on udp.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StatsdConfig {
+    pub enabled: bool,
+    pub port: u32,
+    pub host: IpAddr,
+    /// Seconds between flushes, rates are per second over it.
+    pub flush_interval: u32,
+    /// Root of written metrics, like `stats.counters.<name>.count`.
+    pub prefix: String,
+    /// Timer percentiles, like `stats.timers.<name>.upper_90`.
+    pub percentiles: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Sample {
+    Counter(f64),
+    Gauge(f64),
+    /// Gauge change, sent with a sign.
+    GaugeDelta(f64),
+    Timer(f64),
+    Set(String),
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    counters: HashMap<String, f64>,
+    /// Values and count, which differs from the number of values with a sample rate.
+    timers: HashMap<String, (Vec<f64>, f64)>,
+    /// Kept between flushes.
+    gauges: HashMap<String, f64>,
+    sets: HashMap<String, HashSet<String>>,
+}
+
+/// Replaces characters not allowed in metric names, as statsd does.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .filter_map(|c| match c {
+            _ if c.is_whitespace() => Some('_'),
+            '/' => Some('-'),
+            _ if c.is_alphanumeric() || c == '_' || c == '-' || c == '.' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Name and sample of a line, DogStatsD `#tag:value` tags become Graphite tags.
+fn parse_line(line: &str) -> Result<(String, Sample, f64), String> {
+    let error = || format!("Invalid statsd line '{}'", line);
+    let (name, rest) = line.split_once(':').ok_or_else(error)?;
+    let mut parts = rest.split('|');
+    let value = parts.next().ok_or_else(error)?;
+    let kind = parts.next().ok_or_else(error)?;
+
+    let mut rate = 1.0;
+    let mut tags = BTreeMap::new();
+    for part in parts {
+        if let Some(sample_rate) = part.strip_prefix('@') {
+            rate = sample_rate.parse().map_err(|_| error())?;
+            if !(rate > 0.0 && rate <= 1.0) {
+                return Err(error());
+            }
+        } else if let Some(list) = part.strip_prefix('#') {
+            for tag in list.split(',').filter(|tag| !tag.is_empty()) {
+                let (tag, value) = tag.split_once(':').unwrap_or((tag, "true"));
+                tags.insert(sanitize(tag), sanitize(value));
+            }
+        }
+    }
+
+    let name = sanitize(name);
+    if name.is_empty() {
+        return Err(error());
+    }
+    let name = tags.iter().fold(name, |name, (tag, value)| {
+        format!("{};{}={}", name, tag, value)
+    });
+
+    let number = || value.parse::<f64>().map_err(|_| error());
+    let sample = match kind {
+        "c" => Sample::Counter(number()?),
+        "g" if value.starts_with(['+', '-']) => Sample::GaugeDelta(number()?),
+        "g" => Sample::Gauge(number()?),
+        "ms" | "h" => Sample::Timer(number()?),
+        "s" => Sample::Set(value.to_owned()),
+        _ => return Err(error()),
+    };
+    Ok((name, sample, rate))
+}
+
+/// `<prefix>.<kind>.<name>.<suffix>` keeping Graphite tags of `name` at the end.
+fn path(prefix: &str, kind: &str, name: &str, suffix: &str) -> String {
+    let (name, tags) = name.split_at(name.find(';').unwrap_or(name.len()));
+    let mut path = format!("{}.{}.{}", prefix, kind, name);
+    if !suffix.is_empty() {
+        path.push('.');
+        path.push_str(suffix);
+    }
+    path.push_str(tags);
+    path
+}
+
+/// Upper bound, sum and count of the lowest `percentile` percent of sorted timer values.
+fn percentile_stats(values: &[f64], percentile: f64) -> Option<(f64, f64, usize)> {
+    let count = (percentile / 100.0 * values.len() as f64).round() as usize;
+    if count == 0 {
+        return None;
+    }
+    let values = &values[..count.min(values.len())];
+    Some((values[values.len() - 1], values.iter().sum(), values.len()))
+}
+
+#[derive(Debug)]
+pub struct Statsd {
+    config: RwLock<StatsdConfig>,
+    buckets: Mutex<Buckets>,
+}
+
+impl Statsd {
+    pub fn new(config: &StatsdConfig) -> Self {
+        Self {
+            config: RwLock::new(config.clone()),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Applies new prefix, percentiles and flush interval, keeping aggregated values.
+    pub fn reload(&self, config: &StatsdConfig) {
+        *self.config.write().unwrap() = config.clone();
+    }
+
+    pub fn receive_line(&self, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let (name, sample, rate) = parse_line(line)?;
+        let mut buckets = self.buckets.lock().unwrap();
+        match sample {
+            Sample::Counter(value) => *buckets.counters.entry(name).or_default() += value / rate,
+            Sample::Gauge(value) => {
+                buckets.gauges.insert(name, value);
+            }
+            Sample::GaugeDelta(value) => *buckets.gauges.entry(name).or_default() += value,
+            Sample::Timer(value) => {
+                let (values, count) = buckets.timers.entry(name).or_default();
+                values.push(value);
+                *count += 1.0 / rate;
+            }
+            Sample::Set(value) => {
+                buckets.sets.entry(name).or_default().insert(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Aggregated values received since the last flush, and all gauges.
+    pub fn flush(&self, now: u32) -> Vec<MetricPoint> {
+        let config = self.config.read().unwrap().clone();
+        let interval = f64::from(config.flush_interval.max(1));
+        let prefix = config.prefix.as_str();
+
+        let mut values: Vec<(String, f64)> = Vec::new();
+        let mut buckets = self.buckets.lock().unwrap();
+        for (name, count) in buckets.counters.drain() {
+            values.push((path(prefix, "counters", &name, "count"), count));
+            values.push((path(prefix, "counters", &name, "rate"), count / interval));
+        }
+        for (name, (mut timings, count)) in buckets.timers.drain() {
+            timings.sort_by(f64::total_cmp);
+            let len = timings.len() as f64;
+            let sum: f64 = timings.iter().sum();
+            let mean = sum / len;
+            let std = (timings.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / len).sqrt();
+            let middle = timings.len() / 2;
+            let median = if timings.len() % 2 == 0 {
+                (timings[middle - 1] + timings[middle]) / 2.0
+            } else {
+                timings[middle]
+            };
+            let mut timer = |suffix: &str, value: f64| {
+                values.push((path(prefix, "timers", &name, suffix), value));
+            };
+            timer("count", count);
+            timer("count_ps", count / interval);
+            timer("lower", timings[0]);
+            timer("upper", timings[timings.len() - 1]);
+            timer("sum", sum);
+            timer("mean", mean);
+            timer("median", median);
+            timer("std", std);
+            for percentile in &config.percentiles {
+                let Some((bound, sum, count)) = percentile_stats(&timings, *percentile) else {
+                    continue;
+                };
+                let key = percentile.to_string().replace('.', "_");
+                timer(&format!("count_{}", key), count as f64);
+                timer(&format!("upper_{}", key), bound);
+                timer(&format!("sum_{}", key), sum);
+                timer(&format!("mean_{}", key), sum / count as f64);
+            }
+        }
+        for (name, set) in buckets.sets.drain() {
+            values.push((path(prefix, "sets", &name, "count"), set.len() as f64));
+        }
+        for (name, value) in &buckets.gauges {
+            values.push((path(prefix, "gauges", name, ""), *value));
+        }
+        drop(buckets);
+
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        values
+            .into_iter()
+            .map(|(name, value)| MetricPoint {
+                name,
+                point: Point {
+                    interval: now,
+                    value,
+                },
+            })
+            .collect()
+    }
+
+    /// Passes aggregated values down the pipeline every flush interval.
+    pub async fn run(&self, pipeline: Pipeline) {
+        loop {
+            let interval = self.config.read().unwrap().flush_interval.max(1);
+            sleep(Duration::from_secs(u64::from(interval))).await;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32;
+            for metric in self.flush(now) {
+                pipeline.receive(metric);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statsd() -> Statsd {
+        Statsd::new(&StatsdConfig {
+            enabled: true,
+            port: 8125,
+            host: "127.0.0.1".parse().unwrap(),
+            flush_interval: 10,
+            prefix: "stats".to_owned(),
+            percentiles: vec![90.0, 99.5],
+        })
+    }
+
+    fn values(metrics: Vec<MetricPoint>) -> Vec<(String, f64)> {
+        metrics
+            .into_iter()
+            .map(|metric| (metric.name, metric.point.value))
+            .collect()
+    }
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(
+            parse_line("api.requests:2|c|@0.5").unwrap(),
+            ("api.requests".to_owned(), Sample::Counter(2.0), 0.5)
+        );
+        assert_eq!(
+            parse_line("queue size:-3|g|#host:a,dc:eu").unwrap(),
+            (
+                "queue_size;dc=eu;host=a".to_owned(),
+                Sample::GaugeDelta(-3.0),
+                1.0
+            )
+        );
+        assert_eq!(
+            parse_line("users/online:bob|s").unwrap(),
+            (
+                "users-online".to_owned(),
+                Sample::Set("bob".to_owned()),
+                1.0
+            )
+        );
+        assert!(parse_line("api.requests").is_err());
+        assert!(parse_line("api.requests:1|x").is_err());
+        assert!(parse_line("api.requests:a|c").is_err());
+        assert!(parse_line("api.requests:1|c|@2").is_err());
+    }
+
+    #[test]
+    fn counters_gauges_and_sets() {
+        let statsd = statsd();
+        for line in [
+            "hits:1|c",
+            "hits:2|c|@0.5",
+            "temp:20|g",
+            "temp:+2|g",
+            "users:a|s",
+            "users:b|s",
+            "users:a|s",
+            "conns;ignored:1|c|#host:a",
+        ] {
+            statsd.receive_line(line).unwrap();
+        }
+        assert_eq!(
+            values(statsd.flush(100)),
+            vec![
+                ("stats.counters.connsignored.count;host=a".to_owned(), 1.0),
+                ("stats.counters.connsignored.rate;host=a".to_owned(), 0.1),
+                ("stats.counters.hits.count".to_owned(), 5.0),
+                ("stats.counters.hits.rate".to_owned(), 0.5),
+                ("stats.gauges.temp".to_owned(), 22.0),
+                ("stats.sets.users.count".to_owned(), 2.0),
+            ]
+        );
+
+        // Gauges are kept, deltas apply to the last value
+        statsd.receive_line("temp:-5|g").unwrap();
+        assert_eq!(
+            values(statsd.flush(110)),
+            vec![("stats.gauges.temp".to_owned(), 17.0)]
+        );
+    }
+
+    #[test]
+    fn timers() {
+        let statsd = statsd();
+        for value in 1..=10 {
+            statsd
+                .receive_line(&format!("db.query:{}|ms", value))
+                .unwrap();
+        }
+        statsd.receive_line("db.query:100|ms|@0.5").unwrap();
+        let flushed: HashMap<String, f64> = values(statsd.flush(100)).into_iter().collect();
+        let value = |suffix: &str| flushed[&format!("stats.timers.db.query.{}", suffix)];
+        assert_eq!(value("count"), 12.0);
+        assert_eq!(value("count_ps"), 1.2);
+        assert_eq!(value("lower"), 1.0);
+        assert_eq!(value("upper"), 100.0);
+        assert_eq!(value("sum"), 155.0);
+        assert_eq!(value("median"), 6.0);
+        assert_eq!(value("count_90"), 10.0);
+        assert_eq!(value("upper_90"), 10.0);
+        assert_eq!(value("mean_90"), 5.5);
+        assert_eq!(value("upper_99_5"), 100.0);
+        assert!(statsd.flush(110).is_empty());
+    }
+}
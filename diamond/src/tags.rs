@@ -0,0 +1,35 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+pub use carbon::tagged::{TAG_INDEX_FILE, TAGGED_DIR, TaggedName};
+
+/// Records a newly created tagged series in the tag index of the database at `dir`.
+pub fn index_append<P: AsRef<Path>>(dir: P, name: &TaggedName) -> Result<(), io::Error> {
+    let tagged_dir = dir.as_ref().join(TAGGED_DIR);
+    fs::create_dir_all(&tagged_dir)?;
+
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(tagged_dir.join(TAG_INDEX_FILE))?;
+    // A single write keeps lines whole when several tasks append at once
+    index.write_all(format!("{}\n", name).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::Builder;
+
+    #[test]
+    fn index_append_lines() -> Result<(), io::Error> {
+        let dir = Builder::new().prefix("diamond").tempdir()?;
+        index_append(dir.path(), &"a;x=1".parse().unwrap())?;
+        index_append(dir.path(), &"b;y=2;x=1".parse().unwrap())?;
+
+        let index = fs::read_to_string(dir.path().join(TAGGED_DIR).join(TAG_INDEX_FILE))?;
+        assert_eq!(index, "a;x=1\nb;x=1;y=2\n");
+        Ok(())
+    }
+}
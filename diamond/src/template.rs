@@ -0,0 +1,106 @@
+//! Metric names built from tags or labels of other protocols, like `{job}.{instance}.{__name__}`.
+
+use serde::*;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Tag(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("Unclosed '{{' in name template '{}'", s))?;
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_owned()));
+            }
+            parts.push(Part::Tag(rest[start + 1..end].to_owned()));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_owned()));
+        }
+        Ok(NameTemplate { parts })
+    }
+}
+
+impl<'de> Deserialize<'de> for NameTemplate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl NameTemplate {
+    /// Name with placeholders replaced by sanitized values of `tag`,
+    /// `None` if a tag has no value.
+    pub fn render<'a, F>(&self, tag: F) -> Option<String>
+    where
+        F: Fn(&str) -> Option<&'a str>,
+    {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => name.push_str(text),
+                Part::Tag(key) => name.push_str(&sanitize(tag(key)?)),
+            }
+        }
+        Some(name)
+    }
+}
+
+/// Replaces characters not allowed in a metric path node, including dots.
+pub fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_tags() {
+        let template: NameTemplate = "servers.{host}.{measurement}".parse().unwrap();
+        let tag = |key: &str| match key {
+            "host" => Some("web01.example.com"),
+            "measurement" => Some("cpu"),
+            _ => None,
+        };
+        assert_eq!(
+            template.render(tag).unwrap(),
+            "servers.web01_example_com.cpu"
+        );
+        assert_eq!(
+            "{dc}.{host}".parse::<NameTemplate>().unwrap().render(tag),
+            None
+        );
+        assert!("servers.{host".parse::<NameTemplate>().is_err());
+    }
+}
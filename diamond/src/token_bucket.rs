@@ -0,0 +1,74 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket rate limiter, as carbon's `TokenBucket`.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    fill_rate: f64,
+    timestamp: Instant,
+}
+
+impl TokenBucket {
+    /// Bucket of `capacity` tokens, full at start, refilled with `fill_rate` tokens a second.
+    pub fn new(capacity: f64, fill_rate: f64, now: Instant) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            fill_rate,
+            timestamp: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.timestamp).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.fill_rate).min(self.capacity);
+        self.timestamp = now;
+    }
+
+    /// Takes `tokens` if there are enough of them.
+    pub fn consume(&mut self, tokens: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= tokens {
+            self.tokens -= tokens;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time until `tokens` can be consumed.
+    pub fn wait_time(&mut self, tokens: f64, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= tokens {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((tokens - self.tokens) / self.fill_rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consume_and_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 4.0, start);
+
+        assert!(bucket.consume(1.0, start));
+        assert!(bucket.consume(1.0, start));
+        assert!(!bucket.consume(1.0, start));
+        assert_eq!(bucket.wait_time(1.0, start), Duration::from_millis(250));
+
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.wait_time(1.0, later), Duration::ZERO);
+        assert!(bucket.consume(1.0, later));
+
+        // Never more than the capacity
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.consume(2.0, much_later));
+        assert!(!bucket.consume(1.0, much_later));
+    }
+}
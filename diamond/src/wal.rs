@@ -0,0 +1,506 @@
+//! Write-ahead log of received datapoints, replayed into whisper files after a crash.
+//!
+//! The log is a directory of numbered segment files. A record is the payload length and
+//! its CRC-32, both little-endian `u32`, followed by the payload: timestamp as `u32`,
+//! value as `f64` and the metric name. Segments are removed once every datapoint in
+//! them is written to its whisper file.
+//!
+//! Segments are synced to disk when they are full, and every `sync_interval` seconds
+//! or after every record with `sync_interval = 0`.
+
+use serde::*;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::interval;
+use whisper::point::Point;
+
+use crate::cache::Writer;
+use crate::settings::WhisperConfig;
+use crate::{MetricPoint, MetricPoints, points_update};
+
+const EXTENSION: &str = "wal";
+/// Longest accepted record payload, larger lengths mean a corrupted segment.
+const MAX_RECORD_SIZE: u32 = 64 * 1024;
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct WalConfig {
+    /// Log received datapoints before they are cached.
+    pub enabled: bool,
+    pub path: PathBuf,
+    /// Segment size in bytes after which a new segment is started.
+    pub max_segment_size: u64,
+    /// Seconds between syncs of the current segment, `0` syncs every record.
+    pub sync_interval: u64,
+}
+
+fn encode(metric: &MetricPoint) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(12 + metric.name.len());
+    payload.extend_from_slice(&metric.point.interval.to_le_bytes());
+    payload.extend_from_slice(&metric.point.value.to_le_bytes());
+    payload.extend_from_slice(metric.name.as_bytes());
+
+    let mut record = Vec::with_capacity(8 + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend(payload);
+    record
+}
+
+fn decode(payload: &[u8]) -> Option<MetricPoint> {
+    let interval = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?);
+    let value = f64::from_le_bytes(payload.get(4..12)?.try_into().ok()?);
+    let name = String::from_utf8(payload[12..].to_vec()).ok()?;
+    Some(MetricPoint {
+        name,
+        point: Point { interval, value },
+    })
+}
+
+/// Datapoints of a segment up to the first incomplete or corrupted record,
+/// which is where the process stopped writing.
+pub fn read_segment(path: &Path) -> Result<Vec<MetricPoint>, io::Error> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    let mut metrics = Vec::new();
+    let mut rest = data.as_slice();
+    while rest.len() >= 8 {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        if len > MAX_RECORD_SIZE || rest.len() < 8 + len as usize {
+            break;
+        }
+        let payload = &rest[8..8 + len as usize];
+        match decode(payload).filter(|_| crc32fast::hash(payload) == crc) {
+            Some(metric) => metrics.push(metric),
+            None => break,
+        }
+        rest = &rest[8 + len as usize..];
+    }
+    if !rest.is_empty() {
+        eprintln!(
+            "{}: {} bytes of incomplete or corrupted records skipped",
+            path.display(),
+            rest.len()
+        );
+    }
+    Ok(metrics)
+}
+
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    file: File,
+    /// Bytes of complete records.
+    size: u64,
+}
+
+impl Segment {
+    /// Cuts off bytes of a record written partially, records appended after them
+    /// would not be replayed.
+    fn cut_torn(&self) -> Result<(), io::Error> {
+        self.file.set_len(self.size)
+    }
+}
+
+/// Append-only log split into segments.
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    max_segment_size: u64,
+    sync_interval: u64,
+    current: Mutex<Segment>,
+}
+
+impl Wal {
+    /// Opens the log in `config.path`, appending to a new segment after the existing ones.
+    pub fn open(config: &WalConfig) -> Result<Self, io::Error> {
+        fs::create_dir_all(&config.path)?;
+        let next = Self::list(&config.path)?.last().map_or(1, |id| id + 1);
+        Ok(Wal {
+            dir: config.path.clone(),
+            max_segment_size: config.max_segment_size,
+            sync_interval: config.sync_interval,
+            current: Mutex::new(Self::create(&config.path, next)?),
+        })
+    }
+
+    fn segment_path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{:020}.{}", id, EXTENSION))
+    }
+
+    fn create(dir: &Path, id: u64) -> Result<Segment, io::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::segment_path(dir, id))?;
+        let size = file.metadata()?.len();
+        Ok(Segment { id, file, size })
+    }
+
+    /// Ids of the segments in `dir`, sorted.
+    fn list(dir: &Path) -> Result<Vec<u64>, io::Error> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == EXTENSION)
+                && let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse().ok())
+            {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Id of the segment being appended to.
+    pub fn current_segment(&self) -> u64 {
+        self.current.lock().unwrap().id
+    }
+
+    /// Logs a datapoint and passes it to `store` with its segment, while no segment
+    /// can be truncated. The segment is `None` if the datapoint could not be logged.
+    pub fn append<F, T>(&self, metric: MetricPoint, store: F) -> T
+    where
+        F: FnOnce(MetricPoint, Option<u64>) -> T,
+    {
+        let mut current = self.current.lock().unwrap();
+        match self.write(&mut current, &metric) {
+            Ok(()) => {
+                let id = current.id;
+                store(metric, Some(id))
+            }
+            Err(e) => {
+                eprintln!("write-ahead log error = {:?}", e);
+                store(metric, None)
+            }
+        }
+    }
+
+    fn write(&self, current: &mut Segment, metric: &MetricPoint) -> Result<(), io::Error> {
+        if current.size >= self.max_segment_size {
+            current.file.sync_all()?;
+            *current = Self::create(&self.dir, current.id + 1)?;
+        }
+        let record = encode(metric);
+        if let Err(e) = current.file.write_all(&record) {
+            if current.cut_torn().is_err() {
+                // Records after the torn one go to the next segment
+                *current = Self::create(&self.dir, current.id + 1)?;
+            }
+            return Err(e);
+        }
+        current.size += record.len() as u64;
+        if self.sync_interval == 0 {
+            current.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the current segment to disk, appends are not blocked meanwhile.
+    pub fn sync(&self) -> Result<(), io::Error> {
+        let file = self.current.lock().unwrap().file.try_clone()?;
+        file.sync_data()
+    }
+
+    /// Removes full segments older than `oldest()`, the oldest segment with datapoints
+    /// not written yet, or all full segments if there are none.
+    /// Returns the number of removed segments.
+    pub fn truncate<F>(&self, oldest: F) -> Result<usize, io::Error>
+    where
+        F: FnOnce() -> Option<u64>,
+    {
+        let current = self.current.lock().unwrap();
+        let keep = oldest().unwrap_or(current.id).min(current.id);
+        let mut removed = 0;
+        for id in Self::list(&self.dir)?.into_iter().filter(|id| *id < keep) {
+            fs::remove_file(Self::segment_path(&self.dir, id))?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Removes all segments, including the current one, if every datapoint is written.
+    /// Used on shutdown after the cache is flushed.
+    pub fn close<F>(&self, oldest: F) -> Result<(), io::Error>
+    where
+        F: FnOnce() -> Option<u64>,
+    {
+        let _current = self.current.lock().unwrap();
+        match oldest() {
+            Some(id) => eprintln!("write-ahead log kept from segment {}", id),
+            None => {
+                for id in Self::list(&self.dir)? {
+                    fs::remove_file(Self::segment_path(&self.dir, id))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes datapoints of the segments before the current one into whisper files
+    /// and removes the segments. Returns the number of replayed datapoints.
+    ///
+    /// If any metric cannot be written the segments are kept for the next start,
+    /// after the cause is fixed, and an error is returned.
+    pub fn replay<P: AsRef<Path>>(
+        &self,
+        db_path: P,
+        config: &WhisperConfig,
+        now: u32,
+    ) -> Result<usize, Box<dyn Error>> {
+        let current = self.current_segment();
+        let ids: Vec<u64> = Self::list(&self.dir)?
+            .into_iter()
+            .filter(|id| *id < current)
+            .collect();
+
+        // Later datapoints for the same timestamp replace the earlier, as in the cache
+        let mut metrics: BTreeMap<String, BTreeMap<u32, f64>> = BTreeMap::new();
+        let mut count = 0;
+        for id in &ids {
+            for metric in read_segment(&Self::segment_path(&self.dir, *id))? {
+                metrics
+                    .entry(metric.name)
+                    .or_default()
+                    .insert(metric.point.interval, metric.point.value);
+                count += 1;
+            }
+        }
+        let total = metrics.len();
+        let mut failed = 0;
+        for (name, points) in metrics {
+            let metric = MetricPoints {
+                name,
+                points: points
+                    .into_iter()
+                    .map(|(interval, value)| Point { interval, value })
+                    .collect(),
+            };
+            if let Err(e) = points_update(&metric, &db_path, config, now) {
+                eprintln!("{}: {}", metric.name, e);
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(format!(
+                "{} of {} metrics of the write-ahead log not written, segments kept in {}",
+                failed,
+                total,
+                self.dir.display()
+            )
+            .into());
+        }
+        for id in ids {
+            fs::remove_file(Self::segment_path(&self.dir, id))?;
+        }
+        Ok(count)
+    }
+
+    /// Removes segments whose datapoints were written every 10 seconds and syncs
+    /// the current segment every `sync_interval` seconds.
+    pub async fn run(self: Arc<Self>, writer: Arc<Writer>) {
+        let mut truncate = interval(Duration::from_secs(10));
+        let mut sync = interval(Duration::from_secs(self.sync_interval.max(1)));
+        loop {
+            tokio::select! {
+                _ = truncate.tick() => {
+                    let wal = self.clone();
+                    let writer = writer.clone();
+                    let truncated = tokio::task::spawn_blocking(move || {
+                        wal.truncate(|| writer.oldest_segment())
+                    })
+                    .await;
+                    if let Ok(Err(e)) = truncated {
+                        eprintln!("write-ahead log truncate error = {:?}", e);
+                    }
+                }
+                _ = sync.tick(), if self.sync_interval > 0 => {
+                    let wal = self.clone();
+                    if let Ok(Err(e)) = tokio::task::spawn_blocking(move || wal.sync()).await {
+                        eprintln!("write-ahead log sync error = {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{DrainStrategy, MetricCache};
+    use tempfile::Builder;
+    use whisper::aggregation::AggregationMethod;
+    use whisper::retention::Retention;
+
+    fn metric(name: &str, interval: u32, value: f64) -> MetricPoint {
+        MetricPoint {
+            name: name.to_owned(),
+            point: Point { interval, value },
+        }
+    }
+
+    fn config(path: &Path, max_segment_size: u64) -> WalConfig {
+        WalConfig {
+            enabled: true,
+            path: path.to_path_buf(),
+            max_segment_size,
+            sync_interval: 1,
+        }
+    }
+
+    fn whisper_config() -> WhisperConfig {
+        WhisperConfig {
+            x_files_factor: 0.5,
+            retentions: vec![Retention {
+                seconds_per_point: 60,
+                points: 10,
+            }],
+            aggregation_method: AggregationMethod::Average,
+            schemas: vec![],
+            aggregations: vec![],
+            storage_schemas: None,
+            storage_aggregation: None,
+            lock_timeout: None,
+            header_cache: 0,
+        }
+    }
+
+    #[test]
+    fn segments_and_checksums() {
+        let dir = Builder::new().prefix("diamond").tempdir().unwrap();
+        let wal = Wal::open(&config(dir.path(), 40)).unwrap();
+        for interval in 0..4 {
+            let segment = wal.append(metric("a.b", interval, 1.5), |_, segment| segment);
+            assert_eq!(segment, Some(1 + u64::from(interval) / 2));
+        }
+        assert_eq!(Wal::list(dir.path()).unwrap(), vec![1, 2]);
+        assert_eq!(
+            read_segment(&Wal::segment_path(dir.path(), 1)).unwrap(),
+            vec![metric("a.b", 0, 1.5), metric("a.b", 1, 1.5)]
+        );
+
+        // A torn write and a flipped bit end the segment
+        let path = Wal::segment_path(dir.path(), 2);
+        let mut data = fs::read(&path).unwrap();
+        data.extend_from_slice(&encode(&metric("c", 5, 1.0))[..10]);
+        fs::write(&path, &data).unwrap();
+        assert_eq!(read_segment(&path).unwrap().len(), 2);
+
+        data[12] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert_eq!(read_segment(&path).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn appends_after_torn_record() {
+        let dir = Builder::new().prefix("diamond").tempdir().unwrap();
+        let wal = Wal::open(&config(dir.path(), 1000)).unwrap();
+        wal.append(metric("a", 1, 1.0), |_, segment| segment);
+        {
+            // As left by a write failed halfway
+            let mut current = wal.current.lock().unwrap();
+            current
+                .file
+                .write_all(&encode(&metric("b", 2, 1.0))[..10])
+                .unwrap();
+            current.cut_torn().unwrap();
+        }
+        wal.append(metric("c", 3, 1.0), |_, segment| segment);
+        assert_eq!(
+            read_segment(&Wal::segment_path(dir.path(), 1)).unwrap(),
+            vec![metric("a", 1, 1.0), metric("c", 3, 1.0)]
+        );
+    }
+
+    #[test]
+    fn truncate_written_segments() {
+        let dir = Builder::new().prefix("diamond").tempdir().unwrap();
+        let wal = Wal::open(&config(dir.path(), 1)).unwrap();
+        let cache = MetricCache::new(100, DrainStrategy::Naive);
+        for (name, interval) in [("a", 1), ("b", 2), ("a", 3)] {
+            cache.store_metric_logged(metric(name, interval, 1.0), &wal);
+        }
+        assert_eq!(Wal::list(dir.path()).unwrap(), vec![1, 2, 3]);
+        assert_eq!(cache.oldest_segment(), Some(1));
+
+        let a = cache.pop().unwrap();
+        assert_eq!(a.points.len(), 2);
+        assert_eq!(wal.truncate(|| cache.oldest_segment()).unwrap(), 0);
+        // Written, "b" of segment 2 is still cached
+        cache.release(&a.name);
+        assert_eq!(wal.truncate(|| cache.oldest_segment()).unwrap(), 1);
+        assert_eq!(Wal::list(dir.path()).unwrap(), vec![2, 3]);
+
+        let b = cache.pop().unwrap();
+        cache.release(&b.name);
+        assert_eq!(cache.oldest_segment(), None);
+        // The current segment is kept
+        assert_eq!(wal.truncate(|| cache.oldest_segment()).unwrap(), 1);
+        assert_eq!(Wal::list(dir.path()).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn replay_into_whisper_files() {
+        let dir = Builder::new().prefix("diamond").tempdir().unwrap();
+        let wal_dir = dir.path().join("wal");
+        let whisper = whisper_config();
+
+        let now = 1_545_778_380;
+
+        let wal = Wal::open(&config(&wal_dir, 1024)).unwrap();
+        wal.append(metric("a.b", now - 120, 1.0), |_, _| ());
+        wal.append(metric("a.b", now - 60, 2.0), |_, _| ());
+        wal.append(metric("a.b", now - 120, 3.0), |_, _| ());
+        drop(wal);
+
+        // As after a crash
+        let wal = Wal::open(&config(&wal_dir, 1024)).unwrap();
+        assert_eq!(wal.current_segment(), 2);
+        assert_eq!(wal.replay(dir.path(), &whisper, now).unwrap(), 3);
+        assert_eq!(Wal::list(&wal_dir).unwrap(), vec![2]);
+
+        let mut file = whisper::WhisperFile::open(dir.path().join("a").join("b.wsp")).unwrap();
+        assert_eq!(
+            file.dump(60).unwrap()[..2],
+            [
+                Point {
+                    interval: now - 120,
+                    value: 3.0
+                },
+                Point {
+                    interval: now - 60,
+                    value: 2.0
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_keeps_segments_on_errors() {
+        let dir = Builder::new().prefix("diamond").tempdir().unwrap();
+        let wal_dir = dir.path().join("wal");
+        let now = 1_545_778_380;
+
+        let wal = Wal::open(&config(&wal_dir, 1024)).unwrap();
+        wal.append(metric("a.b", now - 60, 1.0), |_, _| ());
+        wal.append(metric("c", now - 60, 2.0), |_, _| ());
+        drop(wal);
+        // A directory in place of the whisper file of "a.b"
+        fs::create_dir_all(dir.path().join("a").join("b.wsp")).unwrap();
+
+        let wal = Wal::open(&config(&wal_dir, 1024)).unwrap();
+        assert!(wal.replay(dir.path(), &whisper_config(), now).is_err());
+        assert_eq!(Wal::list(&wal_dir).unwrap(), vec![1, 2]);
+        assert!(dir.path().join("c.wsp").exists());
+    }
+}
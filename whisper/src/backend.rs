@@ -0,0 +1,223 @@
+use memmap2::MmapMut;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Stream holding a whisper database, a file or any `Read + Write + Seek` implementation
+/// like `Cursor<Vec<u8>>`, as `impl Storage for BlockStore {}`.
+pub trait Storage: Read + Write + Seek {
+    /// File to lock when locking is on, see `lock::set_timeout`.
+    fn file(&self) -> Option<&fs::File> {
+        None
+    }
+
+    /// Makes written data durable.
+    fn sync_data(&mut self) -> Result<(), io::Error> {
+        self.flush()
+    }
+}
+
+impl Storage for fs::File {
+    fn file(&self) -> Option<&fs::File> {
+        Some(self)
+    }
+
+    fn sync_data(&mut self) -> Result<(), io::Error> {
+        fs::File::sync_data(self)
+    }
+}
+
+impl<T> Storage for io::Cursor<T> where io::Cursor<T>: Read + Write + Seek {}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn file(&self) -> Option<&fs::File> {
+        (**self).file()
+    }
+
+    fn sync_data(&mut self) -> Result<(), io::Error> {
+        (**self).sync_data()
+    }
+}
+
+/// How points of a whisper file are read and written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Seeks, reads and writes of the file, a syscall for every point.
+    #[default]
+    File,
+    /// Copies from and to a shared memory mapping of the file.
+    Mmap,
+}
+
+/// File mapped to memory with a cursor, as a fixed size `Read + Write + Seek` stream.
+///
+/// The file must not be truncated by other processes while it is mapped.
+pub struct MmapFile {
+    file: fs::File,
+    map: MmapMut,
+    position: usize,
+}
+
+impl MmapFile {
+    pub fn new(file: fs::File) -> Result<Self, io::Error> {
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(Self {
+            file,
+            map,
+            position: 0,
+        })
+    }
+
+    fn remaining(&self) -> usize {
+        self.map.len().saturating_sub(self.position)
+    }
+}
+
+impl Storage for MmapFile {
+    fn file(&self) -> Option<&fs::File> {
+        Some(&self.file)
+    }
+
+    /// Writes modified pages to the file.
+    fn sync_data(&mut self) -> Result<(), io::Error> {
+        self.map.flush()
+    }
+}
+
+impl Read for MmapFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining());
+        buf[..len].copy_from_slice(&self.map[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl Write for MmapFile {
+    /// Writes up to the end of the file, the mapping is not extended.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining());
+        self.map[self.position..self.position + len].copy_from_slice(&buf[..len]);
+        self.position += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MmapFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.map.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => (self.position as u64).checked_add_signed(offset),
+        };
+        let position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )
+        })?;
+        self.position = usize::try_from(position).unwrap_or(usize::MAX);
+        Ok(position)
+    }
+}
+
+/// File opened with a `Backend`.
+pub enum FileHandle {
+    File(fs::File),
+    Mmap(MmapFile),
+}
+
+impl FileHandle {
+    pub(crate) fn open(path: &Path, backend: Backend) -> Result<Self, io::Error> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        match backend {
+            Backend::File => Ok(FileHandle::File(file)),
+            Backend::Mmap => Ok(FileHandle::Mmap(MmapFile::new(file)?)),
+        }
+    }
+}
+
+impl Storage for FileHandle {
+    fn file(&self) -> Option<&fs::File> {
+        match self {
+            FileHandle::File(file) => Some(file),
+            FileHandle::Mmap(mmap) => mmap.file(),
+        }
+    }
+
+    fn sync_data(&mut self) -> Result<(), io::Error> {
+        match self {
+            FileHandle::File(file) => file.sync_data(),
+            FileHandle::Mmap(mmap) => Storage::sync_data(mmap),
+        }
+    }
+}
+
+impl Read for FileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            FileHandle::File(file) => file.read(buf),
+            FileHandle::Mmap(mmap) => mmap.read(buf),
+        }
+    }
+}
+
+impl Write for FileHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            FileHandle::File(file) => file.write(buf),
+            FileHandle::Mmap(mmap) => mmap.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            FileHandle::File(file) => file.flush(),
+            FileHandle::Mmap(mmap) => mmap.flush(),
+        }
+    }
+}
+
+impl Seek for FileHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            FileHandle::File(file) => file.seek(pos),
+            FileHandle::Mmap(mmap) => mmap.seek(pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mmap_read_write_seek() -> Result<(), io::Error> {
+        let mut tmp = tempfile::tempfile()?;
+        tmp.write_all(b"0123456789")?;
+        let mut mmap = MmapFile::new(tmp)?;
+
+        let mut buf = [0; 4];
+        mmap.seek(SeekFrom::Start(3))?;
+        mmap.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"3456");
+
+        mmap.seek(SeekFrom::Current(-2))?;
+        mmap.write_all(b"ab")?;
+        assert_eq!(mmap.seek(SeekFrom::End(-1))?, 9);
+        assert!(mmap.write_all(b"cd").is_err());
+        assert!(mmap.seek(SeekFrom::Current(-20)).is_err());
+        mmap.sync_data()?;
+
+        let mut content = String::new();
+        let mut file = mmap.file().unwrap();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut content)?;
+        assert_eq!(content, "01234ab78c");
+        Ok(())
+    }
+}
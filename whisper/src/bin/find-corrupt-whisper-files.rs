@@ -19,6 +19,9 @@ struct Args {
     /// Directory containing Whisper files.
     #[arg(name = "WHISPER_DIR", required = true)]
     directories: Vec<PathBuf>,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn is_whisper_file(path: &Path) -> bool {
@@ -82,6 +85,7 @@ fn run(args: &Args) -> io::Result<()> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
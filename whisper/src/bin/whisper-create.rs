@@ -50,6 +50,9 @@ struct Args {
         required = true
     )]
     retentions: Vec<Retention>,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn estimate_info(retentions: &[Retention]) {
@@ -116,6 +119,7 @@ fn run(args: &Args) -> Result<(), Box<dyn Error>> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
@@ -41,6 +41,9 @@ struct Args {
     /// Output results in JSON form
     #[arg(long = "json")]
     json: bool,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn print_details(
@@ -124,6 +127,7 @@ fn run(args: &Args) -> Result<(), Box<dyn Error>> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
@@ -13,6 +13,9 @@ struct Args {
     /// Path to data file
     #[arg(name = "path")]
     path: PathBuf,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn run(args: &Args) -> io::Result<()> {
@@ -55,6 +58,7 @@ fn run(args: &Args) -> io::Result<()> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
@@ -36,6 +36,9 @@ struct Args {
     /// Path to data file
     #[arg(name = "path")]
     path: PathBuf,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn is_not_zero(value: &Option<f64>) -> bool {
@@ -101,6 +104,7 @@ fn run(args: &Args) -> Result<(), Box<dyn Error>> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
@@ -8,10 +8,6 @@ use whisper::fill::fill;
 /// Copies data from src to dst, if missing.
 #[derive(Debug, clap::Parser)]
 struct Args {
-    /// Lock whisper files (is not implemented).
-    #[arg(long = "lock")]
-    lock: bool,
-
     /// Source whisper file.
     #[arg(name = "SRC")]
     src: PathBuf,
@@ -19,6 +15,9 @@ struct Args {
     /// Destination whisper file.
     #[arg(name = "DST")]
     dst: PathBuf,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn run(args: &Args) -> Result<(), Box<dyn Error>> {
@@ -29,6 +28,7 @@ fn run(args: &Args) -> Result<(), Box<dyn Error>> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
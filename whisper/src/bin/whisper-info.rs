@@ -16,6 +16,9 @@ struct Args {
 
     /// File info field to display
     field: Option<String>,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn format_info(meta: &whisper::WhisperMetadata, json: bool) -> Result<(), Box<dyn Error>> {
@@ -77,6 +80,7 @@ fn run(args: &Args) -> Result<(), Box<dyn Error>> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
@@ -22,6 +22,9 @@ struct Args {
     /// End of interval, unix timestamp (default: now)
     #[arg(long = "until")]
     until: Option<u32>,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn run(args: &Args) -> Result<(), Box<dyn Error>> {
@@ -42,6 +45,7 @@ fn run(args: &Args) -> Result<(), Box<dyn Error>> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
@@ -51,6 +51,9 @@ struct Args {
         required = true
     )]
     retentions: Vec<Retention>,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn run(args: &Args) -> Result<(), Box<dyn Error>> {
@@ -85,6 +88,7 @@ fn run(args: &Args) -> Result<(), Box<dyn Error>> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
@@ -18,6 +18,9 @@ struct Args {
     /// XFILESFACTOR
     #[arg(name = "xFilesFactor", default_value = "0.5")]
     x_files_factor: f32,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn run(args: &Args) -> io::Result<()> {
@@ -40,6 +43,7 @@ fn run(args: &Args) -> io::Result<()> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
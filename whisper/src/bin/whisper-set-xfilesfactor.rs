@@ -13,6 +13,9 @@ struct Args {
     /// new xFilesFactor, a float between 0 and 1
     #[arg(name = "xFilesFactor")]
     x_files_factor: f32,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn run(args: &Args) -> io::Result<()> {
@@ -34,6 +37,7 @@ fn run(args: &Args) -> io::Result<()> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
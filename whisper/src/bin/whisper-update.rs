@@ -15,6 +15,9 @@ struct Args {
     /// Set of data points
     #[arg(name = "timestamp:value")]
     points: Vec<Point>,
+
+    #[command(flatten)]
+    lock: whisper::lock::LockArgs,
 }
 
 fn run(args: &Args) -> Result<(), Box<dyn Error>> {
@@ -28,6 +31,7 @@ fn run(args: &Args) -> Result<(), Box<dyn Error>> {
 
 fn main() {
     let args = Args::parse();
+    args.lock.apply();
     if let Err(err) = run(&args) {
         eprintln!("{}", err);
         exit(1);
@@ -97,6 +97,12 @@ impl WhisperBuilder {
             WhisperFile::create(&metadata, path.as_ref(), sparse).map_err(BuilderError::Io)?;
         Ok(file)
     }
+
+    /// Creates a database in an empty storage like `Cursor::new(Vec::new())`.
+    pub fn build_in<S: Storage>(self, storage: S) -> Result<WhisperFile<S>, BuilderError> {
+        let metadata = self.into_metadata()?;
+        WhisperFile::create_in(&metadata, storage).map_err(BuilderError::Io)
+    }
 }
 
 /**
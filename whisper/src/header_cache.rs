@@ -0,0 +1,90 @@
+//! Headers of opened files, as `CACHE_HEADERS` of the Python original.
+//!
+//! The cache is off unless a capacity is set for the process. A header is reused while
+//! the file at its path has the same inode and modification time, so files written or
+//! replaced since are read again. The cache is cleared when it is full.
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use crate::WhisperMetadata;
+
+static CAPACITY: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref HEADERS: Mutex<HashMap<PathBuf, (Version, WhisperMetadata)>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Version {
+    inode: u64,
+    modified: SystemTime,
+}
+
+pub(crate) struct Key {
+    path: PathBuf,
+    version: Version,
+}
+
+/// Sets the number of cached headers, `0` turns the cache off.
+pub fn set_capacity(capacity: usize) {
+    CAPACITY.store(capacity, Ordering::Relaxed);
+    if capacity == 0 {
+        clear();
+    }
+}
+
+pub fn capacity() -> usize {
+    CAPACITY.load(Ordering::Relaxed)
+}
+
+pub fn clear() {
+    HEADERS.lock().unwrap().clear();
+}
+
+#[cfg(unix)]
+fn inode(stat: &fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(stat)
+}
+
+#[cfg(not(unix))]
+fn inode(_stat: &fs::Metadata) -> u64 {
+    0
+}
+
+/// Key of an opened file, `None` if the cache is off.
+pub(crate) fn key(path: &Path, file: &fs::File) -> Result<Option<Key>, io::Error> {
+    if capacity() == 0 {
+        return Ok(None);
+    }
+    let stat = file.metadata()?;
+    Ok(Some(Key {
+        path: path.to_path_buf(),
+        version: Version {
+            inode: inode(&stat),
+            modified: stat.modified()?,
+        },
+    }))
+}
+
+pub(crate) fn get(key: &Key) -> Option<WhisperMetadata> {
+    match HEADERS.lock().unwrap().get(&key.path) {
+        Some((version, metadata)) if *version == key.version => Some(metadata.clone()),
+        _ => None,
+    }
+}
+
+pub(crate) fn insert(key: Key, metadata: &WhisperMetadata) {
+    let mut headers = HEADERS.lock().unwrap();
+    if headers.len() >= capacity() && !headers.contains_key(&key.path) {
+        headers.clear();
+    }
+    headers.insert(key.path, (key.version, metadata.clone()));
+}
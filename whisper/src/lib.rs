@@ -38,13 +38,16 @@ __headerCache = {}
 
 pub mod aggregation;
 pub mod archive_info;
+mod backend;
 pub mod builder;
 pub mod diff;
 pub mod error;
 mod fallocate;
 pub mod fill;
 pub mod format_ts;
+pub mod header_cache;
 pub mod interval;
+pub mod lock;
 pub mod merge;
 pub mod point;
 pub mod resize;
@@ -55,6 +58,7 @@ use crate::archive_info::*;
 use crate::interval::*;
 use crate::point::*;
 
+pub use crate::backend::{Backend, FileHandle, MmapFile, Storage};
 pub use crate::builder::WhisperBuilder;
 
 pub const METADATA_SIZE: usize = 16;
@@ -137,9 +141,10 @@ impl WhisperMetadata {
     }
 }
 
-pub struct WhisperFile {
+/// Whisper database in a file or another `Storage`.
+pub struct WhisperFile<S = FileHandle> {
     metadata: WhisperMetadata,
-    file: fs::File,
+    file: S,
 }
 
 impl WhisperFile {
@@ -157,9 +162,7 @@ impl WhisperFile {
             .create_new(true)
             .open(path)?;
 
-        // if LOCK {
-        //     fcntl.flock(fh.fileno(), fcntl.LOCK_EX)
-        // }
+        let _lock = lock::exclusive(Some(&fh))?;
 
         fh.write_all(&metainfo_bytes)?;
         if sparse {
@@ -177,15 +180,77 @@ impl WhisperFile {
 
         Ok(Self {
             metadata: header.clone(),
-            file: fh,
+            file: FileHandle::File(fh),
         })
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
-        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
-        let metadata = WhisperMetadata::read(&mut file)?;
+        Self::open_with(path, Backend::File)
+    }
+
+    pub fn open_with<P: AsRef<Path>>(path: P, backend: Backend) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+        Self::open_file(path, FileHandle::open(path, backend)?)
+    }
+
+    /// Opens a file for reading only, as on read-only mounts and snapshots.
+    /// Updates of the file fail.
+    pub fn open_readonly<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+        Self::open_file(path, FileHandle::File(fs::File::open(path)?))
+    }
+
+    fn open_file(path: &Path, mut file: FileHandle) -> Result<Self, io::Error> {
+        let guard = lock::shared(file.file())?;
+        let key = match file.file() {
+            Some(fh) => header_cache::key(path, fh)?,
+            None => None,
+        };
+        let metadata = match key.as_ref().and_then(header_cache::get) {
+            Some(metadata) => metadata,
+            None => {
+                let metadata = WhisperMetadata::read(&mut file)?;
+                if let Some(key) = key {
+                    header_cache::insert(key, &metadata);
+                }
+                metadata
+            }
+        };
+        drop(guard);
         Ok(Self { metadata, file })
     }
+}
+
+impl<S: Storage> WhisperFile<S> {
+    /// Writes the header of a new database, its archives are extended with zeroes.
+    fn create_in(header: &WhisperMetadata, mut storage: S) -> Result<Self, io::Error> {
+        storage.seek(io::SeekFrom::Start(0))?;
+        header.write(&mut storage)?;
+        storage.seek(io::SeekFrom::Start(header.file_size() as u64 - 1))?;
+        storage.write_all(&[0u8])?;
+        storage.sync_data()?;
+
+        Ok(Self {
+            metadata: header.clone(),
+            file: storage,
+        })
+    }
+
+    /// Database of a storage with a whisper header.
+    pub fn new(mut storage: S) -> Result<Self, io::Error> {
+        let metadata = {
+            let _lock = lock::shared(storage.file())?;
+            WhisperMetadata::read(&mut storage)?
+        };
+        Ok(Self {
+            metadata,
+            file: storage,
+        })
+    }
+
+    pub fn into_inner(self) -> S {
+        self.file
+    }
 
     pub fn info(&self) -> &WhisperMetadata {
         &self.metadata
@@ -199,8 +264,7 @@ impl WhisperFile {
             ));
         }
 
-        // if LOCK:
-        //     fcntl.flock(fh.fileno(), fcntl.LOCK_EX)
+        let _lock = lock::exclusive(self.file.file())?;
 
         self.file.seek(io::SeekFrom::Start(0))?;
         self.metadata.x_files_factor = x_files_factor; // TODO: transactional update
@@ -214,8 +278,7 @@ impl WhisperFile {
         &mut self,
         aggregation_method: AggregationMethod,
     ) -> Result<(), io::Error> {
-        // if LOCK:
-        //     fcntl.flock(fh.fileno(), fcntl.LOCK_EX)
+        let _lock = lock::exclusive(self.file.file())?;
 
         self.file.seek(io::SeekFrom::Start(0))?;
         self.metadata.aggregation_method = aggregation_method; // TODO: transactional update
@@ -226,8 +289,7 @@ impl WhisperFile {
     }
 
     pub fn update(&mut self, point: &Point, now: u32) -> Result<(), io::Error> {
-        // if LOCK:
-        //     fcntl.flock(fh.fileno(), fcntl.LOCK_EX)
+        let _lock = lock::exclusive(self.file.file())?;
         file_update(&mut self.file, &self.metadata, point, now)
     }
 
@@ -236,8 +298,7 @@ impl WhisperFile {
             return Ok(());
         }
 
-        // if LOCK:
-        //     fcntl.flock(fh.fileno(), fcntl.LOCK_EX)
+        let _lock = lock::exclusive(self.file.file())?;
 
         // if CAN_FADVISE and FADVISE_RANDOM:
         //     posix_fadvise(fh.fileno(), 0, 0, POSIX_FADV_RANDOM)
@@ -291,7 +352,10 @@ impl WhisperFile {
         let adjusted_interval = adjust_interval(interval, archive.seconds_per_point)
             .map_err(|s| io::Error::new(io::ErrorKind::Other, s))?;
 
-        let points = archive_fetch_interval(&mut self.file, &archive, adjusted_interval)?;
+        let points = {
+            let _lock = lock::shared(self.file.file())?;
+            archive_fetch_interval(&mut self.file, &archive, adjusted_interval)?
+        };
 
         Ok((adjusted_interval, points))
     }
@@ -323,6 +387,7 @@ impl WhisperFile {
 
     pub fn dump(&mut self, seconds_per_point: u32) -> Result<Vec<Point>, io::Error> {
         let archive = self.find_archive(seconds_per_point)?;
+        let _lock = lock::shared(self.file.file())?;
         read_archive(&mut self.file, &archive, 0, archive.points)
     }
 }
@@ -496,8 +561,8 @@ fn __propagate<F: Read + Write + Seek>(
     }
 }
 
-fn file_update(
-    fh: &mut fs::File,
+fn file_update<F: Read + Write + Seek>(
+    fh: &mut F,
     header: &WhisperMetadata,
     point: &Point,
     now: u32,
@@ -546,8 +611,8 @@ fn file_update(
     Ok(())
 }
 
-fn file_update_many(
-    fh: &mut fs::File,
+fn file_update_many<F: Read + Write + Seek>(
+    fh: &mut F,
     header: &WhisperMetadata,
     points: &[Point],
     now: u32,
@@ -0,0 +1,160 @@
+//! Advisory locks of whisper files, as `LOCK` of the Python original.
+//!
+//! Locking is off unless a timeout is set for the process. Then writes take an exclusive
+//! `flock` and reads a shared one, waiting up to the timeout for other writers and readers.
+//! The wait blocks the thread, async servers open whisper files on blocking threads.
+
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const UNLOCKED: u64 = u64::MAX;
+
+static TIMEOUT: AtomicU64 = AtomicU64::new(UNLOCKED);
+
+/// Turns locking on with the time to wait for a lock, or off with `None`.
+pub fn set_timeout(timeout: Option<Duration>) {
+    let millis = timeout.map_or(UNLOCKED, |timeout| {
+        u64::try_from(timeout.as_millis())
+            .unwrap_or(UNLOCKED)
+            .min(UNLOCKED - 1)
+    });
+    TIMEOUT.store(millis, Ordering::Relaxed);
+}
+
+pub fn timeout() -> Option<Duration> {
+    match TIMEOUT.load(Ordering::Relaxed) {
+        UNLOCKED => None,
+        millis => Some(Duration::from_millis(millis)),
+    }
+}
+
+/// Locking options shared by the command line tools.
+#[derive(Debug, Clone, clap::Args)]
+pub struct LockArgs {
+    /// Lock whisper files while reading and writing them
+    #[arg(long = "lock")]
+    pub lock: bool,
+
+    /// Milliseconds to wait for a lock held by another process
+    #[arg(long = "lock-timeout", default_value = "5000")]
+    pub lock_timeout: u64,
+}
+
+impl Default for LockArgs {
+    fn default() -> Self {
+        Self {
+            lock: false,
+            lock_timeout: 5000,
+        }
+    }
+}
+
+impl LockArgs {
+    /// Sets the locking of the process.
+    pub fn apply(&self) {
+        set_timeout(self.lock.then(|| Duration::from_millis(self.lock_timeout)));
+    }
+}
+
+/// Held lock, released on drop.
+pub(crate) struct Guard {
+    #[cfg(unix)]
+    fd: std::os::unix::io::RawFd,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::flock(self.fd, libc::LOCK_UN);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn flock(file: &File, operation: libc::c_int, timeout: Duration) -> Result<Guard, io::Error> {
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    let fd = file.as_raw_fd();
+    let deadline = Instant::now() + timeout;
+    loop {
+        if unsafe { libc::flock(fd, operation | libc::LOCK_NB) } == 0 {
+            return Ok(Guard { fd });
+        }
+        let error = io::Error::last_os_error();
+        match error.kind() {
+            io::ErrorKind::WouldBlock => {}
+            io::ErrorKind::Interrupted => continue,
+            _ => return Err(error),
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for a lock of whisper file",
+            ));
+        }
+        std::thread::sleep((deadline - now).min(Duration::from_millis(10)));
+    }
+}
+
+#[cfg(not(unix))]
+fn flock(_file: &File, _operation: i32, _timeout: Duration) -> Result<Guard, io::Error> {
+    Ok(Guard {})
+}
+
+#[cfg(unix)]
+const EXCLUSIVE: libc::c_int = libc::LOCK_EX;
+#[cfg(unix)]
+const SHARED: libc::c_int = libc::LOCK_SH;
+#[cfg(not(unix))]
+const EXCLUSIVE: i32 = 0;
+#[cfg(not(unix))]
+const SHARED: i32 = 0;
+
+fn lock(file: Option<&File>, operation: i32) -> Result<Option<Guard>, io::Error> {
+    match (file, timeout()) {
+        (Some(file), Some(timeout)) => flock(file, operation, timeout).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Lock for writing `file`, if locking is on and the storage is a file.
+pub(crate) fn exclusive(file: Option<&File>) -> Result<Option<Guard>, io::Error> {
+    lock(file, EXCLUSIVE)
+}
+
+/// Lock for reading `file`, if locking is on and the storage is a file.
+pub(crate) fn shared(file: Option<&File>) -> Result<Option<Guard>, io::Error> {
+    lock(file, SHARED)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_for_other_locks() -> Result<(), io::Error> {
+        let file = tempfile::NamedTempFile::new()?;
+        let writer = File::open(file.path())?;
+        let reader = File::open(file.path())?;
+        let other_reader = File::open(file.path())?;
+        let timeout = Duration::from_millis(50);
+
+        let write = flock(&writer, EXCLUSIVE, timeout)?;
+        let error = flock(&reader, SHARED, timeout).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        drop(write);
+
+        let read = flock(&reader, SHARED, timeout)?;
+        let other_read = flock(&other_reader, SHARED, timeout)?;
+        assert!(flock(&writer, EXCLUSIVE, timeout).is_err());
+        drop(read);
+        drop(other_read);
+        flock(&writer, EXCLUSIVE, timeout)?;
+        Ok(())
+    }
+}
@@ -2,16 +2,21 @@ use bencher::Bencher;
 use bencher::{benchmark_group, benchmark_main};
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
-use whisper::WhisperFile;
 use whisper::builder::{BuilderError, WhisperBuilder};
 use whisper::interval::Interval;
 use whisper::point::Point;
 use whisper::retention::Retention;
+use whisper::{Backend, WhisperFile};
 use whisper_tests::*;
 
 const SECONDS_AGO: u32 = 3500;
 const VALUE_STEP: f64 = 0.2;
 
+fn open_file(path: &Path, backend: Backend) -> WhisperFile {
+    create_file(path).expect("Create file");
+    WhisperFile::open_with(path, backend).expect("Open file")
+}
+
 fn create_file(path: &Path) -> Result<WhisperFile, BuilderError> {
     WhisperBuilder::default()
         .add_retention(Retention {
@@ -46,10 +51,10 @@ fn test_create(bench: &mut Bencher) {
     });
 }
 
-fn test_update(bench: &mut Bencher) {
+fn update(bench: &mut Bencher, backend: Backend) {
     let temp_dir = get_temp_dir();
     let path = get_file_path(&temp_dir, "whisper_update");
-    let mut file = create_file(&path).expect("Create file for update");
+    let mut file = open_file(&path, backend);
 
     let mut current_value = 0.5;
     let i = &mut current_value;
@@ -70,10 +75,10 @@ fn test_update(bench: &mut Bencher) {
     });
 }
 
-fn test_fetch(bench: &mut Bencher) {
+fn fetch(bench: &mut Bencher, backend: Backend) {
     let temp_dir = get_temp_dir();
     let path = get_file_path(&temp_dir, "whisper_fetch");
-    let mut file = create_file(&path).expect("Create file for fetching");
+    let mut file = open_file(&path, backend);
 
     let mut current_value = 0.5;
     let now = current_time();
@@ -99,10 +104,10 @@ fn test_fetch(bench: &mut Bencher) {
     });
 }
 
-fn test_update_fetch(bench: &mut Bencher) {
+fn update_fetch(bench: &mut Bencher, backend: Backend) {
     let temp_dir = get_temp_dir();
     let path = get_file_path(&temp_dir, "whisper_update");
-    let mut file = create_file(&path).expect("Create file for update");
+    let mut file = open_file(&path, backend);
 
     let mut current_value = 0.5;
     let i = &mut current_value;
@@ -128,11 +133,69 @@ fn test_update_fetch(bench: &mut Bencher) {
     });
 }
 
+fn update_many(bench: &mut Bencher, backend: Backend) {
+    let temp_dir = get_temp_dir();
+    let path = get_file_path(&temp_dir, "whisper_update_many");
+    let mut file = open_file(&path, backend);
+
+    let now = current_time();
+    let mut value = 0.5;
+    bench.iter(|| {
+        let points: Vec<Point> = (0..SECONDS_AGO)
+            .map(|j| {
+                value += VALUE_STEP;
+                Point {
+                    interval: now - SECONDS_AGO + j,
+                    value,
+                }
+            })
+            .collect();
+        file.update_many(&points, now).expect("update_many");
+    });
+}
+
+fn test_update(bench: &mut Bencher) {
+    update(bench, Backend::File);
+}
+
+fn test_update_mmap(bench: &mut Bencher) {
+    update(bench, Backend::Mmap);
+}
+
+fn test_update_many(bench: &mut Bencher) {
+    update_many(bench, Backend::File);
+}
+
+fn test_update_many_mmap(bench: &mut Bencher) {
+    update_many(bench, Backend::Mmap);
+}
+
+fn test_fetch(bench: &mut Bencher) {
+    fetch(bench, Backend::File);
+}
+
+fn test_fetch_mmap(bench: &mut Bencher) {
+    fetch(bench, Backend::Mmap);
+}
+
+fn test_update_fetch(bench: &mut Bencher) {
+    update_fetch(bench, Backend::File);
+}
+
+fn test_update_fetch_mmap(bench: &mut Bencher) {
+    update_fetch(bench, Backend::Mmap);
+}
+
 benchmark_group!(
     benches,
     test_create,
     test_update,
+    test_update_mmap,
+    test_update_many,
+    test_update_many_mmap,
     test_fetch,
+    test_fetch_mmap,
     test_update_fetch,
+    test_update_fetch_mmap,
 );
 benchmark_main!(benches);
@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime};
+use whisper::point::Point;
+use whisper::retention::Retention;
+use whisper::*;
+use whisper_tests::*;
+
+fn x_files_factor(path: &std::path::Path) -> Result<f32, Box<dyn Error>> {
+    Ok(WhisperFile::open_readonly(path)?.info().x_files_factor)
+}
+
+/// Writes x_files_factor bypassing `WhisperFile`, then sets the modification time.
+fn write_x_files_factor(
+    path: &std::path::Path,
+    value: f32,
+    modified: SystemTime,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(8))?;
+    file.write_all(&value.to_be_bytes())?;
+    file.set_modified(modified)?;
+    Ok(())
+}
+
+#[test]
+fn header_cache_versions() -> Result<(), Box<dyn Error>> {
+    let temp_dir = get_temp_dir();
+    let path = get_file_path(&temp_dir, "header_cache");
+    WhisperBuilder::default()
+        .add_retention(Retention {
+            seconds_per_point: 60,
+            points: 10,
+        })
+        .x_files_factor(0.5)
+        .build(&path)?;
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(modified)?;
+
+    header_cache::set_capacity(10);
+    assert_eq!(x_files_factor(&path)?, 0.5);
+
+    // Same inode and modification time
+    write_x_files_factor(&path, 0.25, modified)?;
+    assert_eq!(x_files_factor(&path)?, 0.5);
+
+    // Modified file
+    write_x_files_factor(&path, 0.25, modified + Duration::from_secs(1))?;
+    assert_eq!(x_files_factor(&path)?, 0.25);
+
+    // Replaced file with the same modification time
+    let copy = get_file_path(&temp_dir, "header_cache_copy");
+    fs::copy(&path, &copy)?;
+    write_x_files_factor(&copy, 0.75, modified + Duration::from_secs(1))?;
+    fs::rename(&copy, &path)?;
+    assert_eq!(x_files_factor(&path)?, 0.75);
+
+    header_cache::set_capacity(0);
+    write_x_files_factor(&path, 0.1, modified + Duration::from_secs(1))?;
+    assert_eq!(x_files_factor(&path)?, 0.1);
+    Ok(())
+}
+
+#[test]
+fn open_readonly() -> Result<(), Box<dyn Error>> {
+    let temp_dir = get_temp_dir();
+    let path = get_file_path(&temp_dir, "readonly");
+    let now = 1528240800;
+    create_and_update_points(
+        &path,
+        &[Point {
+            interval: now - 60,
+            value: 1.0,
+        }],
+        now,
+    )?;
+    let mut permissions = fs::metadata(&path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&path, permissions)?;
+
+    let mut file = WhisperFile::open_readonly(&path)?;
+    assert_eq!(
+        file.dump(60)?[0],
+        Point {
+            interval: now - 60,
+            value: 1.0
+        }
+    );
+    assert!(
+        file.update(
+            &Point {
+                interval: now,
+                value: 2.0
+            },
+            now
+        )
+        .is_err()
+    );
+    assert!(file.set_x_files_factor(0.1).is_err());
+    Ok(())
+}
@@ -1,9 +1,11 @@
 use std::error::Error;
-use whisper::ArchiveData;
+use std::fs;
+use std::io::Cursor;
 use whisper::builder::WhisperBuilder;
 use whisper::interval::Interval;
 use whisper::point::Point;
 use whisper::retention::Retention;
+use whisper::{ArchiveData, Storage, WhisperFile};
 use whisper_tests::*;
 
 #[test]
@@ -125,3 +127,38 @@ fn whisper_fetch_auto_points() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn whisper_in_memory() -> Result<(), Box<dyn Error>> {
+    let temp_dir = get_temp_dir();
+    let path = get_file_path(&temp_dir, "in_memory");
+    let now = 1528240800;
+    let points: Vec<Point> = (0..5)
+        .map(|i| Point {
+            interval: now - i * 60,
+            value: f64::from(i),
+        })
+        .collect();
+
+    let builder = || {
+        WhisperBuilder::default().add_retention(Retention {
+            seconds_per_point: 60,
+            points: 10,
+        })
+    };
+    builder().build(&path)?.update_many(&points, now)?;
+    let mut memory = builder().build_in(Cursor::new(Vec::new()))?;
+    memory.update_many(&points, now)?;
+
+    let bytes = memory.into_inner().into_inner();
+    assert_eq!(bytes, fs::read(&path)?);
+
+    let storage: Box<dyn Storage> = Box::new(Cursor::new(bytes));
+    let mut file = WhisperFile::new(storage)?;
+    let interval = Interval::new(now - 300, now)?;
+    assert_eq!(
+        file.fetch(60, interval, now)?,
+        WhisperFile::open(&path)?.fetch(60, interval, now)?
+    );
+    Ok(())
+}
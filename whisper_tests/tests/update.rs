@@ -426,3 +426,56 @@ fn test_update_and_aggregate_snapshot() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test]
+fn test_update_mmap_backend() -> Result<(), Box<dyn Error>> {
+    let temp_dir = get_temp_dir();
+    let now = 1_528_240_800;
+    let points: Vec<Point> = (0..50)
+        .map(|i| Point {
+            interval: now - i * 37,
+            value: f64::from(i),
+        })
+        .collect();
+
+    let mut files = Vec::new();
+    for backend in [Backend::File, Backend::Mmap] {
+        let path = get_file_path(&temp_dir, "update_backend");
+        WhisperBuilder::default()
+            .add_retention(Retention {
+                seconds_per_point: 60,
+                points: 20,
+            })
+            .add_retention(Retention {
+                seconds_per_point: 300,
+                points: 10,
+            })
+            .build(&path)?;
+
+        let mut file = WhisperFile::open_with(&path, backend)?;
+        file.update_many(&points[10..], now)?;
+        for point in &points[..10] {
+            file.update(point, now)?;
+        }
+        file.set_x_files_factor(0.2)?;
+        files.push((path, file));
+    }
+
+    // Slots of lower archives depend on the order of propagation, points do not
+    for seconds_per_point in [60, 300] {
+        let mut dumps = Vec::new();
+        for (path, _) in &files {
+            let mut points = WhisperFile::open(path)?.dump(seconds_per_point)?;
+            points.sort_by_key(|point| point.interval);
+            dumps.push(points);
+        }
+        assert_eq!(dumps[0], dumps[1]);
+    }
+    let interval = interval::Interval::past(now, 3000);
+    assert_eq!(
+        files[0].1.fetch_auto_points(interval, now)?,
+        files[1].1.fetch_auto_points(interval, now)?
+    );
+    assert_eq!(WhisperFile::open(&files[1].0)?.info().x_files_factor, 0.2);
+    Ok(())
+}